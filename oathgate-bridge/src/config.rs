@@ -1,51 +1,215 @@
 //! Configuration file module
 
+pub(crate) mod capture;
 pub(crate) mod dhcp;
+pub(crate) mod dhcp6;
+pub(crate) mod dns;
+pub(crate) mod firewall;
+pub(crate) mod forward;
+pub(crate) mod mirror;
+pub(crate) mod route;
+pub(crate) mod vlan;
 
-use std::{fs::File, io, net::SocketAddr, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+};
 
-use oathgate_net::types::Ipv4Network;
+use oathgate_net::{
+    nat::NatConfig,
+    types::{Ipv4Network, Ipv6Network},
+};
 use serde::{Deserialize, Serialize};
 
-use crate::{config::dhcp::DhcpConfig, net::wan::WgConfig};
+use crate::{
+    config::{
+        capture::CaptureConfig,
+        dhcp::DhcpConfig,
+        dhcp6::Dhcp6Config,
+        dns::DnsConfig,
+        firewall::FirewallConfig,
+        forward::ForwardConfig,
+        mirror::MirrorConfig,
+        route::{PolicyConfig, RouteConfig},
+        vlan::{PortConfig, VlanConfig},
+    },
+    net::{
+        switch::DEFAULT_MAC_TIMEOUT,
+        wan::{UserConfig, WgConfig},
+    },
+};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
-    pub wan: WanConfig,
+    /// Upstream connection, added to `wans` with the name `default`
+    #[serde(default)]
+    pub wan: Option<WanConfig>,
+
+    /// Named upstream connections, referenced by `routes` and `policies`
+    #[serde(default)]
+    pub wans: BTreeMap<String, WanConfig>,
+
+    /// Static routes for traffic leaving the LAN.  If empty and only one WAN is configured,
+    /// all traffic is routed to that WAN
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+
+    /// Source-based rules, evaluated before `routes`
+    #[serde(default)]
+    pub policies: Vec<PolicyConfig>,
+
+    /// Filters traffic forwarded between the LAN and WANs (and between hosts on the LAN)
+    #[serde(default)]
+    pub firewall: Option<FirewallConfig>,
+
+    /// Ports on the WANs forwarded to hosts on the LAN
+    #[serde(default)]
+    pub forwards: Vec<ForwardConfig>,
+
     pub router: RouterConfig,
+
+    /// Additional networks served by the router, each on its own VLAN
+    #[serde(default)]
+    pub vlans: Vec<VlanConfig>,
+
+    /// Additional vhost sockets (`<bridge>-<port>.sock`) connecting devices with the
+    /// configured VLAN membership.  Devices connected to the bridge's main socket are
+    /// untagged members of VLAN 1
+    #[serde(default)]
+    pub ports: BTreeMap<String, PortConfig>,
+
     pub virtio: VirtioConfig,
+
+    #[serde(default)]
+    pub switch: SwitchConfig,
+
+    /// Captures the traffic transiting the switch
+    #[serde(default)]
+    pub capture: CaptureConfig,
+
+    #[serde(default)]
+    pub nat: NatConfig,
+
+    /// Local address (i.e., `127.0.0.1:9100`) to serve the bridge's counters on, in the
+    /// Prometheus text format
+    #[serde(default)]
+    pub metrics: Option<SocketAddr>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum WanConfig {
     Tap(TapConfig),
+    Tun(TunConfig),
     Udp(UdpConfig),
+    User(UserConfig),
     Wireguard(WgConfig),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TapConfig {
+    /// Name of the tap device to create
     pub device: String,
+
+    /// Address (and subnet) used by the bridge on the tap network
+    pub ipv4: Ipv4Network,
+
+    /// Next hop for destinations outside of the tap network (typically the host's address
+    /// on the tap device).  If not set, all destinations are assumed to be on-link.
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+
+    /// Maximum size of an ipv4 packet sent or received over the tap device
+    #[serde(default = "TapConfig::default_mtu")]
+    pub mtu: u16,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TunConfig {
+    /// Name of the tun device to create
+    pub device: String,
+
+    /// Address used by the bridge as the source of (masqueraded) packets sent to the host
+    pub ipv4: Ipv4Network,
+
+    /// Address (and prefix) to assign to the host's side of the tun device
+    #[serde(default)]
+    pub address: Option<Ipv4Network>,
+
+    /// Additional networks the host should route over the tun device
+    #[serde(default)]
+    pub routes: Vec<Ipv4Network>,
+
+    /// Maximum size of an ipv4 packet sent or received over the tun device
+    #[serde(default = "TapConfig::default_mtu")]
+    pub mtu: u16,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct UdpConfig {
     pub endpoint: SocketAddr,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RouterConfig {
     pub ipv4: Ipv4Network,
+
+    /// Address (and prefix) of the router on the LAN.  If set, the prefix is advertised to
+    /// hosts for stateless address autoconfiguration (SLAAC)
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Network>,
+
     pub dhcp: DhcpConfig,
-    pub dns: bool,
+
+    /// Stateful address assignment for hosts on the LAN (requires `ipv6`)
+    #[serde(default)]
+    pub dhcp6: Option<Dhcp6Config>,
+
+    #[serde(default)]
+    pub dns: DnsConfig,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct VirtioConfig {
     pub queues: u8,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SwitchConfig {
+    /// Time (in seconds) a learned MAC address remains associated with a port without being
+    /// seen.  Frames for unknown (or aged out) addresses are flooded to all ports
+    #[serde(default = "SwitchConfig::default_mac_timeout")]
+    pub mac_timeout: u64,
+
+    /// Mirror sessions, copying traffic to monitor ports
+    #[serde(default)]
+    pub mirrors: Vec<MirrorConfig>,
+}
+
+impl TapConfig {
+    fn default_mtu() -> u16 {
+        1500
+    }
+}
+
+impl SwitchConfig {
+    fn default_mac_timeout() -> u64 {
+        DEFAULT_MAC_TIMEOUT.as_secs()
+    }
+}
+
+impl Default for SwitchConfig {
+    fn default() -> Self {
+        Self {
+            mac_timeout: Self::default_mac_timeout(),
+            mirrors: Vec::new(),
+        }
+    }
+}
+
 impl Config {
     /// Loads a configuration file from disk
     ///
@@ -57,4 +221,13 @@ impl Config {
             serde_yaml::from_reader(f).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
         Ok(cfg)
     }
+
+    /// Saves the configuration to disk, replacing the file if it exists
+    ///
+    /// ### Arguments
+    /// * `path` - Path to the configuration file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let f = File::create(path)?;
+        serde_yaml::to_writer(f, self).map_err(io::Error::other)
+    }
 }
@@ -0,0 +1,96 @@
+//! Packet capture configuration
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::net::capture::CaptureFilter;
+
+/// Configuration for capturing the traffic transiting the switch to pcapng files
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CaptureConfig {
+    /// Path of the capture file, or None to disable capture (unless a path is given when
+    /// starting the bridge)
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+
+    /// Maximum number of bytes saved of each frame
+    #[serde(default = "CaptureConfig::default_snaplen")]
+    pub snaplen: u32,
+
+    /// Size (in megabytes) after which the capture file is rotated
+    #[serde(default)]
+    pub max_size: Option<u64>,
+
+    /// Time (in seconds) after which the capture file is rotated
+    #[serde(default)]
+    pub interval: Option<u64>,
+
+    /// Number of capture files kept, including the file being written.  Rotated files are
+    /// suffixed with `.1` (the most recent), `.2`, etc.
+    #[serde(default = "CaptureConfig::default_files")]
+    pub files: usize,
+
+    /// Number of frames waiting to be written before new frames are dropped
+    #[serde(default = "CaptureConfig::default_queue")]
+    pub queue: usize,
+
+    /// Only capture frames matching this (tcpdump-like) expression
+    #[serde(default)]
+    pub filter: Option<CaptureFilter>,
+}
+
+impl CaptureConfig {
+    fn default_snaplen() -> u32 {
+        65535
+    }
+
+    fn default_files() -> usize {
+        10
+    }
+
+    fn default_queue() -> usize {
+        4096
+    }
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            snaplen: Self::default_snaplen(),
+            max_size: None,
+            interval: None,
+            files: Self::default_files(),
+            queue: Self::default_queue(),
+            filter: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CaptureConfig;
+
+    #[test]
+    fn capture_parse() {
+        let input = r#"
+path: /var/lib/oathgate/capture.pcapng
+max_size: 100
+files: 5
+filter: not arp and host 10.67.213.50
+"#;
+
+        let cfg: CaptureConfig = serde_yaml::from_str(input).unwrap();
+        assert_eq!(cfg.snaplen, 65535);
+        assert_eq!(cfg.max_size, Some(100));
+        assert_eq!(cfg.interval, None);
+        assert_eq!(cfg.files, 5);
+        assert_eq!(
+            cfg.filter.map(|filter| filter.to_string()).as_deref(),
+            Some("not arp and host 10.67.213.50")
+        );
+
+        assert!(serde_yaml::from_str::<CaptureConfig>("filter: port").is_err());
+    }
+}
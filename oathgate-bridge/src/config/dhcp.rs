@@ -2,16 +2,111 @@
 
 use std::{net::Ipv4Addr, str::FromStr};
 
+use oathgate_net::types::{Ipv4Network, MacAddress};
 use serde::{Deserialize, Serialize};
 
 /// Configuration for the internal DHCP server
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DhcpConfig {
     /// Start address for the DHCP pool
     pub start: Ipv4Addr,
 
     /// End address for the DHCP pool
     pub end: Ipv4Addr,
+
+    /// Length of a lease, in seconds
+    #[serde(default = "DhcpConfig::default_lease_time")]
+    pub lease_time: u32,
+
+    /// Addresses that are always leased to a specific client
+    #[serde(default)]
+    pub reservations: Vec<DhcpReservation>,
+
+    /// Domain name (option 15), overrides the DNS server's domain
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    /// Domain search list (option 119)
+    #[serde(default)]
+    pub search: Vec<String>,
+
+    /// Nameservers (option 6), overrides the router / upstream servers
+    #[serde(default)]
+    pub dns: Vec<Ipv4Addr>,
+
+    /// NTP servers (option 42)
+    #[serde(default)]
+    pub ntp: Vec<Ipv4Addr>,
+
+    /// Interface MTU (option 26)
+    #[serde(default)]
+    pub mtu: Option<u16>,
+
+    /// Classless static routes (option 121)
+    #[serde(default)]
+    pub routes: Vec<DhcpRoute>,
+
+    /// Additional options sent to clients as-is
+    #[serde(default)]
+    pub options: Vec<DhcpRawOption>,
+}
+
+/// A static address assignment
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DhcpReservation {
+    /// MAC address of the client
+    pub mac: MacAddress,
+
+    /// Address to lease to the client
+    pub ip: Ipv4Addr,
+
+    /// Hostname to register for the client (instead of the one it provides)
+    #[serde(default)]
+    pub hostname: Option<String>,
+}
+
+/// A route to a network via a gateway
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DhcpRoute {
+    /// Destination network
+    pub network: Ipv4Network,
+
+    /// Next hop used to reach the network
+    pub gateway: Ipv4Addr,
+}
+
+/// An arbitrary DHCP option
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DhcpRawOption {
+    /// Option code
+    pub code: u8,
+
+    /// Value of the option, as a hex string (i.e., `c0a80101`)
+    pub data: String,
+}
+
+impl DhcpConfig {
+    fn default_lease_time() -> u32 {
+        86400 // 1 day
+    }
+}
+
+impl DhcpRawOption {
+    /// Decodes the value of this option
+    pub fn bytes(&self) -> Result<Vec<u8>, String> {
+        let data = self.data.trim();
+        if !data.len().is_multiple_of(2) || !data.is_ascii() {
+            return Err(format!("option {}: invalid hex string", self.code));
+        }
+
+        (0..data.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&data[i..i + 2], 16)
+                    .map_err(|_| format!("option {}: invalid hex string", self.code))
+            })
+            .collect()
+    }
 }
 
 impl FromStr for DhcpConfig {
@@ -31,13 +126,25 @@ impl FromStr for DhcpConfig {
             return Err("end address is before start address");
         }
 
-        Ok(Self { start, end })
+        Ok(Self {
+            start,
+            end,
+            lease_time: Self::default_lease_time(),
+            reservations: Vec::new(),
+            domain: None,
+            search: Vec::new(),
+            dns: Vec::new(),
+            ntp: Vec::new(),
+            mtu: None,
+            routes: Vec::new(),
+            options: Vec::new(),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::DhcpConfig;
+    use super::{DhcpConfig, DhcpRawOption};
 
     #[test]
     fn dhcp_parse_string_good() {
@@ -73,4 +180,41 @@ mod tests {
         let cfg = input.parse::<DhcpConfig>();
         assert!(cfg.is_err())
     }
+
+    #[test]
+    fn dhcp_parse_options() {
+        let input = r#"
+start: 10.0.0.100
+end: 10.0.0.200
+lease_time: 600
+reservations:
+  - mac: 52:54:00:00:00:0a
+    ip: 10.0.0.10
+    hostname: db
+search: [oathgate, example.com]
+ntp: [10.0.0.1]
+mtu: 1400
+routes:
+  - network: 192.168.0.0/16
+    gateway: 10.0.0.2
+options:
+  - code: 252
+    data: 68747470
+"#;
+        let cfg: DhcpConfig = serde_yaml::from_str(input).unwrap();
+        assert_eq!(cfg.lease_time, 600);
+        assert_eq!(cfg.reservations.len(), 1);
+        assert_eq!(cfg.reservations[0].hostname.as_deref(), Some("db"));
+        assert_eq!(cfg.routes[0].network.subnet_mask_bits(), 16);
+        assert_eq!(cfg.options[0].bytes().unwrap(), b"http");
+    }
+
+    #[test]
+    fn dhcp_raw_option_invalid_hex() {
+        let opt = DhcpRawOption {
+            code: 252,
+            data: String::from("6g"),
+        };
+        assert!(opt.bytes().is_err());
+    }
 }
@@ -0,0 +1,55 @@
+//! DHCPv6 server configuration
+
+use std::net::Ipv6Addr;
+
+use serde::{Deserialize, Serialize};
+
+/// Configuration for the internal DHCPv6 server
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Dhcp6Config {
+    /// Start address for the DHCPv6 pool
+    pub start: Ipv6Addr,
+
+    /// End address for the DHCPv6 pool
+    pub end: Ipv6Addr,
+
+    /// Valid (and preferred) lifetime of a lease, in seconds
+    #[serde(default = "Dhcp6Config::default_lease_time")]
+    pub lease_time: u32,
+
+    /// Nameservers (option 23)
+    #[serde(default)]
+    pub dns: Vec<Ipv6Addr>,
+
+    /// Commit leases immediately when a client requests rapid commit (option 14)
+    #[serde(default = "Dhcp6Config::default_rapid_commit")]
+    pub rapid_commit: bool,
+}
+
+impl Dhcp6Config {
+    fn default_lease_time() -> u32 {
+        86400 // 1 day
+    }
+
+    fn default_rapid_commit() -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dhcp6Config;
+
+    #[test]
+    fn dhcp6_parse_defaults() {
+        let input = r#"
+start: fd00::100
+end: fd00::1ff
+"#;
+
+        let cfg: Dhcp6Config = serde_yaml::from_str(input).unwrap();
+        assert_eq!(cfg.lease_time, 86400);
+        assert!(cfg.rapid_commit);
+        assert!(cfg.dns.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+//! DNS server configuration
+
+use std::{collections::HashMap, net::Ipv4Addr};
+
+use serde::{Deserialize, Serialize};
+
+/// Configuration for the internal DNS server
+///
+/// For compatibility, a plain boolean (i.e., `dns: true`) enables or disables the server
+/// with the default settings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "DnsConfigRepr")]
+pub struct DnsConfig {
+    /// True to serve DNS on the router's address (and advertise it via DHCP)
+    pub enabled: bool,
+
+    /// Servers to forward queries that can't be answered locally, tried in order
+    pub upstream: Vec<Ipv4Addr>,
+
+    /// Local domain appended to DHCP hostnames (i.e., `shard.oathgate`)
+    pub domain: Option<String>,
+
+    /// Time to live of locally answered records, in seconds
+    pub ttl: u32,
+
+    /// Static hostname to address mappings
+    pub hosts: HashMap<String, Ipv4Addr>,
+}
+
+/// All supported representations of the DNS configuration
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DnsConfigRepr {
+    Enabled(bool),
+    Full(DnsConfigFull),
+}
+
+#[derive(Deserialize)]
+struct DnsConfigFull {
+    #[serde(default = "DnsConfig::default_enabled")]
+    enabled: bool,
+
+    #[serde(default = "DnsConfig::default_upstream")]
+    upstream: Vec<Ipv4Addr>,
+
+    #[serde(default)]
+    domain: Option<String>,
+
+    #[serde(default = "DnsConfig::default_ttl")]
+    ttl: u32,
+
+    #[serde(default)]
+    hosts: HashMap<String, Ipv4Addr>,
+}
+
+impl DnsConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_upstream() -> Vec<Ipv4Addr> {
+        vec![Ipv4Addr::new(1, 1, 1, 1)]
+    }
+
+    fn default_ttl() -> u32 {
+        60
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            upstream: Self::default_upstream(),
+            domain: None,
+            ttl: Self::default_ttl(),
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+impl From<DnsConfigRepr> for DnsConfig {
+    fn from(repr: DnsConfigRepr) -> Self {
+        match repr {
+            DnsConfigRepr::Enabled(enabled) => Self {
+                enabled,
+                ..Default::default()
+            },
+            DnsConfigRepr::Full(cfg) => Self {
+                enabled: cfg.enabled,
+                upstream: cfg.upstream,
+                domain: cfg.domain,
+                ttl: cfg.ttl,
+                hosts: cfg.hosts,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::DnsConfig;
+
+    #[test]
+    fn dns_parse_bool() {
+        let cfg: DnsConfig = serde_yaml::from_str("true").unwrap();
+        assert!(cfg.enabled);
+        assert_eq!(cfg.upstream, vec![Ipv4Addr::new(1, 1, 1, 1)]);
+    }
+
+    #[test]
+    fn dns_parse_full() {
+        let input = "upstream: [9.9.9.9]\ndomain: oathgate\nhosts:\n  db: 10.67.213.10\n";
+        let cfg: DnsConfig = serde_yaml::from_str(input).unwrap();
+        assert!(cfg.enabled);
+        assert_eq!(cfg.upstream, vec![Ipv4Addr::new(9, 9, 9, 9)]);
+        assert_eq!(cfg.domain.as_deref(), Some("oathgate"));
+        assert_eq!(cfg.hosts["db"], Ipv4Addr::new(10, 67, 213, 10));
+    }
+}
@@ -0,0 +1,249 @@
+//! Firewall configuration
+
+use oathgate_net::{
+    conntrack::ConnState,
+    protocols::{NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+    types::Ipv4Network,
+};
+use serde::{Deserialize, Serialize};
+
+/// Configuration for the router's firewall
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FirewallConfig {
+    /// Action taken on packets that do not match any rule
+    #[serde(default)]
+    pub policy: FirewallPolicy,
+
+    /// Rules, evaluated in order.  The first matching rule decides the packet's fate
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// Default action of each direction
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FirewallPolicy {
+    #[serde(default)]
+    pub lan_to_wan: Action,
+
+    #[serde(default)]
+    pub wan_to_lan: Action,
+
+    #[serde(default)]
+    pub lan_to_lan: Action,
+
+    #[serde(default)]
+    pub wan_to_wan: Action,
+}
+
+/// A firewall rule.  All configured fields must match for the rule to apply
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RuleConfig {
+    /// Name of the rule, used when reporting hit counters
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Direction the packet is travelling
+    #[serde(default)]
+    pub direction: Option<Direction>,
+
+    /// Source network
+    #[serde(default)]
+    pub src: Option<Ipv4Network>,
+
+    /// Destination network
+    #[serde(default)]
+    pub dst: Option<Ipv4Network>,
+
+    /// Transport protocol
+    #[serde(default)]
+    pub protocol: Option<Protocol>,
+
+    /// Source port (or range, e.g. `1024-65535`), TCP and UDP only
+    #[serde(default)]
+    pub src_port: Option<PortRange>,
+
+    /// Destination port (or range), TCP and UDP only
+    #[serde(default)]
+    pub dst_port: Option<PortRange>,
+
+    /// ICMP message type (e.g., 8 for an echo request)
+    #[serde(default)]
+    pub icmp_type: Option<u8>,
+
+    /// States of the connection the packet belongs to
+    #[serde(default)]
+    pub state: Vec<ConnState>,
+
+    /// Action taken on matching packets
+    pub action: Action,
+}
+
+/// Direction a packet travels through the router
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    LanToWan,
+    WanToLan,
+    LanToLan,
+    WanToWan,
+}
+
+/// Action taken on a packet
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Forward the packet
+    #[default]
+    Accept,
+
+    /// Silently discard the packet
+    Drop,
+
+    /// Discard the packet and notify the sender (ICMP administratively prohibited)
+    Reject,
+}
+
+/// Transport protocols that may be matched by a rule
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+/// An inclusive range of ports
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "PortSpec", into = "PortSpec")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Representation of a port range in the configuration file, either a single port or a
+/// string in the form `start-end`
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Port(u16),
+    Range(String),
+}
+
+impl Protocol {
+    /// Returns the ip protocol number of this protocol
+    pub fn number(&self) -> u8 {
+        match self {
+            Self::Tcp => NET_PROTOCOL_TCP,
+            Self::Udp => NET_PROTOCOL_UDP,
+            Self::Icmp => NET_PROTOCOL_ICMP,
+        }
+    }
+}
+
+impl PortRange {
+    /// Returns true if the port is within this range
+    ///
+    /// ### Arguments
+    /// * `port` - Port to check
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl TryFrom<PortSpec> for PortRange {
+    type Error = String;
+
+    fn try_from(spec: PortSpec) -> Result<Self, Self::Error> {
+        match spec {
+            PortSpec::Port(port) => Ok(Self {
+                start: port,
+                end: port,
+            }),
+            PortSpec::Range(range) => {
+                let (start, end) = range.split_once('-').unwrap_or((&range, &range));
+                let parse = |port: &str| {
+                    port.trim()
+                        .parse::<u16>()
+                        .map_err(|_| format!("invalid port range: {range}"))
+                };
+
+                let (start, end) = (parse(start)?, parse(end)?);
+                match start <= end {
+                    true => Ok(Self { start, end }),
+                    false => Err(format!("invalid port range: {range}")),
+                }
+            }
+        }
+    }
+}
+
+impl From<PortRange> for PortSpec {
+    fn from(range: PortRange) -> Self {
+        match range.start == range.end {
+            true => Self::Port(range.start),
+            false => Self::Range(format!("{}-{}", range.start, range.end)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oathgate_net::conntrack::ConnState;
+
+    use super::{Action, Direction, FirewallConfig, PortRange, Protocol};
+
+    #[test]
+    fn firewall_parse() {
+        let input = r#"
+policy:
+  wan_to_lan: drop
+rules:
+  - name: return-traffic
+    direction: wan_to_lan
+    state: [established, related]
+    action: accept
+  - direction: lan_to_wan
+    dst: 10.0.0.0/8
+    protocol: tcp
+    dst_port: 8000-8080
+    action: reject
+  - protocol: udp
+    src_port: 53
+    action: drop
+"#;
+
+        let cfg: FirewallConfig = serde_yaml::from_str(input).unwrap();
+        assert_eq!(cfg.policy.wan_to_lan, Action::Drop);
+        assert_eq!(cfg.policy.lan_to_wan, Action::Accept);
+        assert_eq!(cfg.rules.len(), 3);
+
+        assert_eq!(
+            cfg.rules[0].state,
+            vec![ConnState::Established, ConnState::Related]
+        );
+        assert_eq!(cfg.rules[1].direction, Some(Direction::LanToWan));
+        assert_eq!(cfg.rules[1].protocol, Some(Protocol::Tcp));
+        assert_eq!(
+            cfg.rules[1].dst_port,
+            Some(PortRange {
+                start: 8000,
+                end: 8080
+            })
+        );
+        assert_eq!(
+            cfg.rules[2].src_port,
+            Some(PortRange { start: 53, end: 53 })
+        );
+    }
+
+    #[test]
+    fn firewall_parse_invalid_range() {
+        let input = r#"
+rules:
+  - dst_port: 90-80
+    action: drop
+"#;
+
+        assert!(serde_yaml::from_str::<FirewallConfig>(input).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+//! Port forwarding configuration
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use serde::{Deserialize, Serialize};
+
+use super::firewall::Protocol;
+
+/// Forwards a port on a WAN to a host on the LAN
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ForwardConfig {
+    /// Name of the WAN to accept traffic on.  May be omitted if only one WAN is configured
+    #[serde(default)]
+    pub wan: Option<String>,
+
+    /// Transport protocol (tcp or udp)
+    pub protocol: Protocol,
+
+    /// Port on the WAN's address or, for WANs without an address of their own (udp and
+    /// user), on the host
+    pub port: u16,
+
+    /// Host address to listen on (udp and user WANs only)
+    #[serde(default = "ForwardConfig::default_listen")]
+    pub listen: IpAddr,
+
+    /// Address of the host on the LAN
+    pub to: Ipv4Addr,
+
+    /// Port on the LAN host, defaults to `port`
+    #[serde(default)]
+    pub to_port: Option<u16>,
+}
+
+impl ForwardConfig {
+    fn default_listen() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::config::firewall::Protocol;
+
+    use super::ForwardConfig;
+
+    #[test]
+    fn forward_parse() {
+        let input = r#"
+- protocol: tcp
+  port: 2222
+  to: 10.67.213.50
+  to_port: 22
+- wan: internet
+  protocol: udp
+  port: 5353
+  listen: 127.0.0.1
+  to: 10.67.213.51
+"#;
+
+        let forwards: Vec<ForwardConfig> = serde_yaml::from_str(input).unwrap();
+        assert_eq!(forwards.len(), 2);
+        assert_eq!(forwards[0].wan, None);
+        assert_eq!(forwards[0].protocol, Protocol::Tcp);
+        assert_eq!(forwards[0].listen, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(forwards[0].to_port, Some(22));
+        assert_eq!(forwards[1].wan.as_deref(), Some("internet"));
+        assert_eq!(forwards[1].listen, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(forwards[1].to_port, None);
+    }
+}
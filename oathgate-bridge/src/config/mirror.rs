@@ -0,0 +1,72 @@
+//! Port mirroring configuration
+
+use serde::{Deserialize, Serialize};
+
+/// A mirror session, copying traffic of the source ports and VLANs to a monitor port
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MirrorConfig {
+    /// Name of the port (in `ports`, with `mode: monitor`) receiving the copies
+    pub destination: String,
+
+    /// Names of the ports whose traffic is copied (`default` for the bridge's main socket)
+    #[serde(default)]
+    pub ports: Vec<String>,
+
+    /// VLANs whose traffic is copied (in both directions)
+    #[serde(default)]
+    pub vlans: Vec<u16>,
+
+    /// Traffic of the source ports to copy
+    #[serde(default)]
+    pub direction: MirrorDirection,
+}
+
+/// Traffic of a port copied by a mirror session
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MirrorDirection {
+    /// Frames received from the port
+    Ingress,
+
+    /// Frames sent to the port
+    Egress,
+
+    /// Frames received from and sent to the port
+    #[default]
+    Both,
+}
+
+impl MirrorDirection {
+    /// Returns true if frames received from a source port are copied
+    pub fn ingress(&self) -> bool {
+        matches!(self, Self::Ingress | Self::Both)
+    }
+
+    /// Returns true if frames sent to a source port are copied
+    pub fn egress(&self) -> bool {
+        matches!(self, Self::Egress | Self::Both)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MirrorConfig, MirrorDirection};
+
+    #[test]
+    fn mirror_parse() {
+        let input = r#"
+- destination: ids
+  ports: [guest]
+  direction: ingress
+- destination: ids
+  vlans: [20, 30]
+"#;
+
+        let mirrors: Vec<MirrorConfig> = serde_yaml::from_str(input).unwrap();
+        assert_eq!(mirrors[0].ports, vec![String::from("guest")]);
+        assert_eq!(mirrors[0].direction, MirrorDirection::Ingress);
+        assert!(!mirrors[0].direction.egress());
+        assert_eq!(mirrors[1].vlans, vec![20, 30]);
+        assert_eq!(mirrors[1].direction, MirrorDirection::Both);
+    }
+}
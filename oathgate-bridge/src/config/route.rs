@@ -0,0 +1,76 @@
+//! Routing table configuration
+
+use std::net::Ipv4Addr;
+
+use oathgate_net::types::Ipv4Network;
+use serde::{Deserialize, Serialize};
+
+/// A static route
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RouteConfig {
+    /// Destination network (`0.0.0.0/0` for the default route)
+    pub prefix: Ipv4Network,
+
+    /// Where to send packets destined for `prefix`
+    #[serde(flatten)]
+    pub target: RouteTarget,
+}
+
+/// Next hop of a static route
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteTarget {
+    /// Name of a WAN
+    Wan(String),
+
+    /// Address of a gateway on the LAN
+    Via(Ipv4Addr),
+}
+
+/// A source-based policy rule
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PolicyConfig {
+    /// Source network of packets matching this rule
+    pub source: Ipv4Network,
+
+    /// Name of the WAN to send matching packets to
+    pub wan: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{PolicyConfig, RouteConfig, RouteTarget};
+
+    #[test]
+    fn route_parse() {
+        let input = r#"
+- prefix: 10.0.0.0/8
+  wan: corp
+- prefix: 192.168.50.0/24
+  via: 10.67.213.5
+"#;
+
+        let routes: Vec<RouteConfig> = serde_yaml::from_str(input).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].prefix.subnet_mask_bits(), 8);
+        assert!(matches!(&routes[0].target, RouteTarget::Wan(wan) if wan == "corp"));
+        assert!(matches!(
+            routes[1].target,
+            RouteTarget::Via(ip) if ip == Ipv4Addr::new(10, 67, 213, 5)
+        ));
+    }
+
+    #[test]
+    fn policy_parse() {
+        let input = r#"
+source: 10.67.213.128/25
+wan: internet
+"#;
+
+        let policy: PolicyConfig = serde_yaml::from_str(input).unwrap();
+        assert_eq!(policy.wan, "internet");
+        assert!(policy.source.contains(Ipv4Addr::new(10, 67, 213, 200)));
+    }
+}
@@ -0,0 +1,106 @@
+//! VLAN configuration
+
+use oathgate_net::types::Ipv4Network;
+use serde::{Deserialize, Serialize};
+
+use super::{dhcp::DhcpConfig, dns::DnsConfig};
+
+/// A network served by the router on its own VLAN
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VlanConfig {
+    /// VLAN identifier (2-4094, VLAN 1 is the router's primary network)
+    pub id: u16,
+
+    /// Address (and subnet) of the router on the VLAN
+    pub ipv4: Ipv4Network,
+
+    /// Drop traffic routed between this VLAN and the router's other networks
+    #[serde(default = "VlanConfig::default_isolated")]
+    pub isolated: bool,
+
+    pub dhcp: DhcpConfig,
+
+    #[serde(default)]
+    pub dns: DnsConfig,
+}
+
+/// VLAN membership of the devices connected to a vhost socket
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum PortConfig {
+    /// Devices send and receive untagged frames on a single VLAN
+    Access { vlan: u16 },
+
+    /// Devices send and receive frames tagged with their VLAN, except frames on the native
+    /// VLAN (if any), which are untagged
+    Trunk {
+        #[serde(default)]
+        native: Option<u16>,
+
+        vlans: Vec<u16>,
+    },
+
+    /// Devices only receive the traffic copied by mirror sessions, frames sent by the
+    /// devices are dropped
+    Monitor,
+}
+
+impl VlanConfig {
+    fn default_isolated() -> bool {
+        true
+    }
+}
+
+impl PortConfig {
+    /// Returns all VLANs a port is a member of
+    pub fn vlans(&self) -> Vec<u16> {
+        match self {
+            Self::Access { vlan } => vec![*vlan],
+            Self::Trunk { native, vlans } => native.iter().chain(vlans.iter()).copied().collect(),
+            Self::Monitor => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{PortConfig, VlanConfig};
+
+    #[test]
+    fn vlan_parse() {
+        let input = r#"
+id: 20
+ipv4: 10.67.20.1/24
+dhcp:
+  start: 10.67.20.100
+  end: 10.67.20.200
+"#;
+
+        let vlan: VlanConfig = serde_yaml::from_str(input).unwrap();
+        assert_eq!(vlan.id, 20);
+        assert!(vlan.isolated);
+        assert!(!vlan.dns.enabled);
+    }
+
+    #[test]
+    fn port_parse() {
+        let input = r#"
+guest:
+  mode: access
+  vlan: 20
+uplink:
+  mode: trunk
+  native: 1
+  vlans: [20, 30]
+ids:
+  mode: monitor
+"#;
+
+        let ports: BTreeMap<String, PortConfig> = serde_yaml::from_str(input).unwrap();
+        assert!(matches!(ports["guest"], PortConfig::Access { vlan: 20 }));
+        assert_eq!(ports["uplink"].vlans(), vec![1, 20, 30]);
+        assert!(matches!(ports["ids"], PortConfig::Monitor));
+    }
+}
@@ -0,0 +1,699 @@
+//! Control socket of a running bridge
+//!
+//! Clients send a single request, as a line of JSON, and receive a single response (also a
+//! line of JSON).  Requests that stream data (e.g., captures) continue sending data on the
+//! socket after the response until the client disconnects
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use flume::Sender;
+use mio::Waker;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    error::Error,
+    net::{
+        capture::Capture,
+        dhcp::DhcpLease,
+        firewall::{Firewall, RuleCounters},
+        router::{LanCounters, NatInfo, NeighborInfo, RouterHandle, WanCounters, WanInfo},
+        switch::{MacInfo, PortInfo, VirtioSwitch},
+    },
+};
+
+/// Time allowed for a client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of frames waiting to be streamed to a capture client before new frames are dropped
+const CAPTURE_QUEUE: usize = 1024;
+
+/// Request sent to a bridge's control socket
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Returns an overview of the bridge
+    Status,
+
+    /// Returns the devices connected to the switch
+    Ports,
+
+    /// Returns the MAC addresses learned by the switch
+    MacTable,
+
+    /// Returns the neighbors learned by the router (ARP/NDP)
+    Neighbors,
+
+    /// Returns the entries of each WAN's NAT table
+    Nat,
+
+    /// Returns the active DHCP leases of every network
+    Leases,
+
+    /// Returns the bridge's counters
+    Counters,
+
+    /// Disconnects the device connected to a switch port
+    Kick { port: usize },
+
+    /// Removes all entries from one of the bridge's tables
+    Flush { table: FlushTable },
+
+    /// Applies a new configuration to the running bridge
+    Reload { config: Box<Config> },
+
+    /// Streams the frames received by the switch (in pcapng format) until the client
+    /// disconnects
+    Capture {
+        /// Only stream frames matching this (tcpdump-like) expression
+        filter: Option<String>,
+
+        /// Maximum number of bytes sent of each frame
+        snaplen: u32,
+    },
+}
+
+/// Response to a request sent to a bridge's control socket
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlResponse {
+    /// Request succeeded, any data requested follows the response
+    Ok,
+
+    /// Request failed
+    Error(String),
+
+    Status(BridgeStatus),
+    Ports(Vec<PortInfo>),
+    MacTable(Vec<MacInfo>),
+    Neighbors(Vec<NeighborInfo>),
+    Nat(Vec<NatInfo>),
+    Leases(Vec<DhcpLease>),
+    Counters(Counters),
+}
+
+/// Tables that can be flushed through the control socket
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushTable {
+    /// MAC addresses learned by the switch
+    Mac,
+
+    /// Neighbors learned by the router (ARP/NDP)
+    Neighbors,
+
+    /// NAT tables of every WAN
+    Nat,
+}
+
+/// Overview of a running bridge
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BridgeStatus {
+    /// Name of the bridge
+    pub name: String,
+
+    /// Process id of the bridge
+    pub pid: u32,
+
+    /// Seconds since the bridge started
+    pub uptime: u64,
+
+    /// Number of devices connected to the switch (including the router)
+    pub ports: usize,
+
+    /// Number of MAC addresses learned by the switch
+    pub macs: usize,
+
+    /// State of each WAN
+    pub wans: Vec<WanInfo>,
+}
+
+/// Counters of a running bridge
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Counters {
+    /// Devices connected to the switch, with the counters of each port and its queues
+    pub ports: Vec<PortInfo>,
+
+    /// Counters of each WAN (and its NAT table)
+    pub wans: Vec<WanCounters>,
+
+    /// Counters of the protocol handlers and services of each network
+    pub lans: Vec<LanCounters>,
+
+    /// Hit counters of each firewall rule, in order
+    pub firewall: Vec<RuleCounters>,
+
+    /// Number of frames dropped by the capture because its queue was full, None if the
+    /// bridge is not capturing traffic
+    pub capture_dropped: Option<u64>,
+}
+
+/// A configuration waiting to be applied by the bridge's main loop
+pub(crate) struct ReloadRequest {
+    /// Configuration to apply
+    pub config: Config,
+
+    /// Receives the outcome of the reload
+    pub reply: Sender<Result<(), String>>,
+}
+
+/// Forwards reload requests to the bridge's main loop, which owns the configuration
+#[derive(Clone)]
+pub(crate) struct Reloader {
+    tx: Sender<ReloadRequest>,
+
+    /// Wakes the main loop when a request is queued
+    waker: Arc<Waker>,
+}
+
+/// State of the bridge exposed through the control socket
+#[derive(Clone)]
+pub(crate) struct ControlState {
+    /// Name of the bridge
+    pub name: String,
+
+    /// Time the bridge started
+    pub started: Instant,
+
+    pub switch: VirtioSwitch,
+    pub router: RouterHandle,
+    /// Firewall of the bridge, if enabled.  Replaced when a reload enables or disables it
+    pub firewall: Arc<RwLock<Option<Firewall>>>,
+    pub capture: Option<Capture>,
+
+    /// Lease files of every network served by the router, replaced when a reload changes
+    /// the VLANs
+    pub leases: Arc<RwLock<Vec<PathBuf>>>,
+
+    pub reloader: Reloader,
+}
+
+/// Accepts clients on a bridge's control socket, serving each client on its own thread
+pub struct ControlServer {
+    listener: UnixListener,
+
+    /// State of the bridge, shared with each client's thread
+    state: ControlState,
+}
+
+/// Client connected to the control socket of a running bridge
+pub struct ControlClient {
+    stream: BufReader<UnixStream>,
+}
+
+impl FromStr for FlushTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mac" | "macs" => Ok(Self::Mac),
+            "arp" | "neighbors" => Ok(Self::Neighbors),
+            "nat" => Ok(Self::Nat),
+            _ => Err(format!("unknown table: {s} (expected mac, arp or nat)")),
+        }
+    }
+}
+
+impl Reloader {
+    /// Creates a reloader queueing requests on a channel and waking the main loop
+    ///
+    /// ### Arguments
+    /// * `tx` - Channel read by the main loop
+    /// * `waker` - Wakes the main loop's poller
+    pub fn new(tx: Sender<ReloadRequest>, waker: Waker) -> Self {
+        Self {
+            tx,
+            waker: Arc::new(waker),
+        }
+    }
+
+    /// Queues a configuration to be applied by the main loop, waiting for the outcome
+    ///
+    /// ### Arguments
+    /// * `config` - Configuration to apply
+    fn reload(&self, config: Config) -> Result<(), String> {
+        let (reply, rx) = flume::bounded(1);
+        self.tx
+            .send(ReloadRequest { config, reply })
+            .map_err(|_| String::from("bridge is shutting down"))?;
+
+        self.waker.wake().map_err(|error| error.to_string())?;
+        rx.recv()
+            .map_err(|_| String::from("bridge is shutting down"))?
+    }
+}
+
+impl ControlServer {
+    /// Creates the control socket, replacing any stale socket left at the same path
+    ///
+    /// ### Arguments
+    /// * `path` - Path of the control socket
+    /// * `state` - State of the bridge
+    pub(crate) fn bind<P: AsRef<Path>>(path: P, state: ControlState) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self { listener, state })
+    }
+
+    /// Accepts all pending clients, spawning a thread to serve each client
+    pub fn accept(&self) -> Result<(), Error> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error.into()),
+            };
+
+            tracing::debug!("[control] accepted client");
+
+            let state = self.state.clone();
+            std::thread::Builder::new()
+                .name(String::from("bridge-control"))
+                .spawn(move || {
+                    if let Err(error) = serve(stream, state) {
+                        tracing::warn!(%error, "[control] unable to serve client");
+                    }
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for ControlServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// Writes a response to a client
+///
+/// ### Arguments
+/// * `stream` - Socket connected to the client
+/// * `response` - Response to send
+fn respond(mut stream: &UnixStream, response: &ControlResponse) -> Result<(), Error> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Reads and executes a client's request
+///
+/// ### Arguments
+/// * `stream` - Socket connected to the client
+/// * `state` - State of the bridge
+fn serve(stream: UnixStream, state: ControlState) -> Result<(), Error> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let request = match serde_json::from_str::<ControlRequest>(&line) {
+        Ok(request) => request,
+        Err(error) => {
+            let msg = format!("invalid request: {error}");
+            return respond(&stream, &ControlResponse::Error(msg));
+        }
+    };
+
+    tracing::debug!(?request, "[control] received request");
+    let response = match request {
+        ControlRequest::Capture { filter, snaplen } => {
+            let filter = match filter.as_deref().map(str::parse).transpose() {
+                Ok(filter) => filter,
+                Err(msg) => return respond(&stream, &ControlResponse::Error(msg)),
+            };
+
+            if snaplen == 0 {
+                let msg = String::from("snaplen must be greater than zero");
+                return respond(&stream, &ControlResponse::Error(msg));
+            }
+
+            respond(&stream, &ControlResponse::Ok)?;
+            stream.set_read_timeout(None)?;
+
+            let tap = Capture::stream(stream, filter, snaplen, CAPTURE_QUEUE)?;
+            state.switch.taps().attach(tap);
+            tracing::info!("[control] live capture attached");
+            return Ok(());
+        }
+        request => execute(request, &state).unwrap_or_else(|error| {
+            tracing::warn!(%error, "[control] request failed");
+            ControlResponse::Error(error.to_string())
+        }),
+    };
+
+    respond(&stream, &response)
+}
+
+/// Executes a request that does not stream data, returning the response to send
+///
+/// ### Arguments
+/// * `request` - Request to execute
+/// * `state` - State of the bridge
+fn execute(request: ControlRequest, state: &ControlState) -> Result<ControlResponse, Error> {
+    let response = match request {
+        ControlRequest::Status => ControlResponse::Status(BridgeStatus {
+            name: state.name.clone(),
+            pid: std::process::id(),
+            uptime: state.started.elapsed().as_secs(),
+            ports: state.switch.ports().len(),
+            macs: state.switch.mac_table().len(),
+            wans: state.router.wans()?,
+        }),
+        ControlRequest::Ports => ControlResponse::Ports(state.switch.ports()),
+        ControlRequest::MacTable => ControlResponse::MacTable(state.switch.mac_table()),
+        ControlRequest::Neighbors => ControlResponse::Neighbors(state.router.neighbors()?),
+        ControlRequest::Nat => ControlResponse::Nat(state.router.nat()?),
+        ControlRequest::Leases => {
+            let mut leases = Vec::new();
+            for path in state.leases.read().iter() {
+                leases.extend(DhcpLease::load(path)?);
+            }
+
+            leases.retain(|lease| lease.is_active());
+            leases.sort_by_key(|lease| lease.ip);
+            ControlResponse::Leases(leases)
+        }
+        ControlRequest::Counters => ControlResponse::Counters(state.counters()?),
+        ControlRequest::Kick { port } => match state.switch.kick(port) {
+            true => {
+                tracing::info!(port, "[control] kicked device");
+                ControlResponse::Ok
+            }
+            false => ControlResponse::Error(format!("no device to disconnect on port {port}")),
+        },
+        ControlRequest::Flush { table } => {
+            match table {
+                FlushTable::Mac => state.switch.flush_macs(),
+                FlushTable::Neighbors => state.router.flush_neighbors()?,
+                FlushTable::Nat => state.router.flush_nat()?,
+            }
+
+            tracing::info!(?table, "[control] flushed table");
+            ControlResponse::Ok
+        }
+        ControlRequest::Reload { config } => match state.reloader.reload(*config) {
+            Ok(()) => ControlResponse::Ok,
+            Err(msg) => ControlResponse::Error(msg),
+        },
+        ControlRequest::Capture { .. } => unreachable!("captures are streamed"),
+    };
+
+    Ok(response)
+}
+
+impl ControlState {
+    /// Returns the counters of every layer of the bridge
+    pub fn counters(&self) -> Result<Counters, Error> {
+        let router = self.router.counters()?;
+
+        Ok(Counters {
+            ports: self.switch.ports(),
+            wans: router.wans,
+            lans: router.lans,
+            firewall: self
+                .firewall
+                .read()
+                .as_ref()
+                .map(Firewall::counters)
+                .unwrap_or_default(),
+            capture_dropped: self.capture.as_ref().map(Capture::dropped),
+        })
+    }
+}
+
+/// Unwraps a response of the expected variant, returning an error for any other response
+macro_rules! expect_response {
+    ($response:expr, $variant:ident) => {
+        match $response {
+            ControlResponse::$variant(data) => Ok(data),
+            response => Err(Error::from(format!("unexpected response: {response:?}"))),
+        }
+    };
+}
+
+impl ControlClient {
+    /// Connects to the control socket of a running bridge
+    ///
+    /// ### Arguments
+    /// * `path` - Path of the control socket
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Sends a request and waits for its response, returning an error if the request failed
+    ///
+    /// ### Arguments
+    /// * `request` - Request to send
+    fn request(&mut self, request: &ControlRequest) -> Result<ControlResponse, Error> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        self.stream.get_mut().write_all(line.as_bytes())?;
+
+        let mut line = String::new();
+        self.stream.read_line(&mut line)?;
+        match serde_json::from_str(&line)? {
+            ControlResponse::Error(msg) => Err(msg.into()),
+            response => Ok(response),
+        }
+    }
+
+    /// Sends a request that returns no data
+    ///
+    /// ### Arguments
+    /// * `request` - Request to send
+    fn execute(mut self, request: ControlRequest) -> Result<(), Error> {
+        match self.request(&request)? {
+            ControlResponse::Ok => Ok(()),
+            response => Err(format!("unexpected response: {response:?}").into()),
+        }
+    }
+
+    /// Returns an overview of the bridge
+    pub fn status(mut self) -> Result<BridgeStatus, Error> {
+        expect_response!(self.request(&ControlRequest::Status)?, Status)
+    }
+
+    /// Returns the devices connected to the switch
+    pub fn ports(mut self) -> Result<Vec<PortInfo>, Error> {
+        expect_response!(self.request(&ControlRequest::Ports)?, Ports)
+    }
+
+    /// Returns the MAC addresses learned by the switch
+    pub fn mac_table(mut self) -> Result<Vec<MacInfo>, Error> {
+        expect_response!(self.request(&ControlRequest::MacTable)?, MacTable)
+    }
+
+    /// Returns the neighbors learned by the router
+    pub fn neighbors(mut self) -> Result<Vec<NeighborInfo>, Error> {
+        expect_response!(self.request(&ControlRequest::Neighbors)?, Neighbors)
+    }
+
+    /// Returns the entries of each WAN's NAT table
+    pub fn nat(mut self) -> Result<Vec<NatInfo>, Error> {
+        expect_response!(self.request(&ControlRequest::Nat)?, Nat)
+    }
+
+    /// Returns the active DHCP leases of every network
+    pub fn leases(mut self) -> Result<Vec<DhcpLease>, Error> {
+        expect_response!(self.request(&ControlRequest::Leases)?, Leases)
+    }
+
+    /// Returns the bridge's counters
+    pub fn counters(mut self) -> Result<Counters, Error> {
+        expect_response!(self.request(&ControlRequest::Counters)?, Counters)
+    }
+
+    /// Disconnects the device connected to a switch port
+    ///
+    /// ### Arguments
+    /// * `port` - Switch port of the device
+    pub fn kick(self, port: usize) -> Result<(), Error> {
+        self.execute(ControlRequest::Kick { port })
+    }
+
+    /// Removes all entries from one of the bridge's tables
+    ///
+    /// ### Arguments
+    /// * `table` - Table to flush
+    pub fn flush(self, table: FlushTable) -> Result<(), Error> {
+        self.execute(ControlRequest::Flush { table })
+    }
+
+    /// Applies a new configuration to the running bridge
+    ///
+    /// ### Arguments
+    /// * `config` - Configuration to apply
+    pub fn reload(self, config: Config) -> Result<(), Error> {
+        self.execute(ControlRequest::Reload {
+            config: Box::new(config),
+        })
+    }
+
+    /// Starts a live capture, returning the stream of captured frames (in pcapng format).
+    /// The capture stops when the stream is dropped
+    ///
+    /// ### Arguments
+    /// * `filter` - Only capture frames matching this (tcpdump-like) expression, if set
+    /// * `snaplen` - Maximum number of bytes captured of each frame
+    pub fn capture(
+        mut self,
+        filter: Option<String>,
+        snaplen: u32,
+    ) -> Result<BufReader<UnixStream>, Error> {
+        match self.request(&ControlRequest::Capture { filter, snaplen })? {
+            ControlResponse::Ok => Ok(self.stream),
+            response => Err(format!("unexpected response: {response:?}").into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use mio::{Poll, Token, Waker};
+    use pcap_file::pcapng::{Block, PcapNgReader};
+
+    use crate::net::{router::Router, switch::VirtioSwitch};
+
+    use super::{ControlClient, ControlServer, ControlState, FlushTable, Reloader};
+
+    fn server(switch: &VirtioSwitch) -> (ControlServer, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("oathgate-{}.ctl", rand::random::<u32>()));
+        let router = Router::builder()
+            .spawn("10.0.0.1/24".parse().unwrap(), switch.clone())
+            .unwrap();
+
+        let poll = Poll::new().unwrap();
+        let waker = Waker::new(poll.registry(), Token(0)).unwrap();
+        let state = ControlState {
+            name: String::from("test"),
+            started: Instant::now(),
+            switch: switch.clone(),
+            router,
+            firewall: Default::default(),
+            capture: None,
+            leases: Default::default(),
+            reloader: Reloader::new(flume::unbounded().0, waker),
+        };
+
+        (ControlServer::bind(&path, state).unwrap(), path)
+    }
+
+    fn frame(ethertype: u16) -> Vec<u8> {
+        let mut pkt = vec![0xFF; 12];
+        pkt.extend_from_slice(&ethertype.to_be_bytes());
+        pkt.extend_from_slice(&[0u8; 28]);
+        pkt
+    }
+
+    #[test]
+    fn control_capture() {
+        let switch = VirtioSwitch::default();
+        let (server, path) = server(&switch);
+
+        // invalid filters are rejected before anything is streamed
+        let client = ControlClient::connect(&path).unwrap();
+        let handle = std::thread::spawn(move || client.capture(Some("port".into()), 65535));
+        server.accept().unwrap();
+        assert!(handle.join().unwrap().is_err());
+
+        let client = ControlClient::connect(&path).unwrap();
+        let handle = std::thread::spawn(move || client.capture(Some("arp".into()), 65535));
+        server.accept().unwrap();
+        let stream = handle.join().unwrap().unwrap();
+
+        let taps = switch.taps();
+        while taps.is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        taps.log(0, None, &frame(0x0800));
+        taps.log(3, Some("guest"), &frame(0x0806));
+
+        let mut reader = PcapNgReader::new(stream).unwrap();
+        assert!(matches!(
+            reader.next_block().unwrap().unwrap(),
+            Block::InterfaceDescription(_)
+        ));
+
+        match reader.next_block().unwrap().unwrap() {
+            Block::EnhancedPacket(pkt) => assert_eq!(&pkt.data[12..14], &[0x08, 0x06]),
+            block => panic!("unexpected block: {block:?}"),
+        }
+
+        // the capture is removed once the client detaches
+        drop(reader);
+        for _ in 0..100 {
+            taps.log(0, None, &frame(0x0806));
+            if taps.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(taps.is_empty());
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn control_inspect() {
+        let switch = VirtioSwitch::default();
+        let (server, path) = server(&switch);
+
+        let client = ControlClient::connect(&path).unwrap();
+        let handle = std::thread::spawn(move || client.status());
+        server.accept().unwrap();
+        let status = handle.join().unwrap().unwrap();
+        assert_eq!(status.name, "test");
+        assert_eq!(status.ports, 1);
+        assert!(status.wans.is_empty());
+
+        // the router is connected to the switch, but cannot be kicked
+        let client = ControlClient::connect(&path).unwrap();
+        let handle = std::thread::spawn(move || client.ports());
+        server.accept().unwrap();
+        let ports = handle.join().unwrap().unwrap();
+        assert_eq!(ports[0].name, None);
+
+        let client = ControlClient::connect(&path).unwrap();
+        let handle = std::thread::spawn(move || client.kick(ports[0].port));
+        server.accept().unwrap();
+        assert!(handle.join().unwrap().is_err());
+
+        let client = ControlClient::connect(&path).unwrap();
+        let handle = std::thread::spawn(move || client.flush(FlushTable::Neighbors));
+        server.accept().unwrap();
+        handle.join().unwrap().unwrap();
+
+        let client = ControlClient::connect(&path).unwrap();
+        let handle = std::thread::spawn(move || client.neighbors());
+        server.accept().unwrap();
+        assert!(handle.join().unwrap().unwrap().is_empty());
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -16,6 +16,9 @@ pub enum Error {
     #[error("network: {0}")]
     Network(#[from] crate::net::NetworkError),
 
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+
     #[error("system: {0}")]
     System(#[from] nix::errno::Errno),
 
@@ -1,34 +1,80 @@
 mod config;
+mod control;
 mod error;
+mod metrics;
 mod net;
 
-use std::{os::fd::AsRawFd, path::PathBuf};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use mio::{unix::SourceFd, Events, Interest, Poll, Token};
+use mio::{unix::SourceFd, Events, Interest, Poll, Token, Waker};
 use nix::sys::signalfd::SignalFd;
+use oathgate_net::{
+    nat::{NatConfig, PortForward},
+    types::Ipv4Network,
+};
 use oathgate_vhost::{DeviceOpts, VHostSocket};
+use parking_lot::RwLock;
 
-pub use self::config::Config as BridgeConfig;
+pub use self::{
+    config::Config as BridgeConfig,
+    control::{BridgeStatus, ControlClient, Counters, FlushTable},
+    net::{
+        dhcp::DhcpLease,
+        dhcp6::Dhcp6Lease,
+        firewall::RuleCounters,
+        router::{
+            handler::ServiceCounters, LanCounters, NatInfo, NeighborInfo, ProtocolCounters,
+            WanCounters, WanInfo,
+        },
+        switch::{MacInfo, PortInfo, PortMode},
+    },
+};
 
 const DEFAULT_BASE_PATH: &str = "/tmp/oathgate/network";
 
+/// Name given to the WAN configured with the `wan` key
+const DEFAULT_WAN: &str = "default";
+
+/// Name given to devices connected to the bridge's main socket
+const DEFAULT_PORT: &str = "default";
+
+const DHCP6_REQUIRES_IPV6: &str = "dhcp6 requires an ipv6 address to be configured on the router";
+
 use crate::{
-    config::WanConfig,
+    config::{
+        firewall::Protocol,
+        route::RouteTarget,
+        vlan::{PortConfig, VlanConfig},
+        WanConfig,
+    },
+    control::{ControlServer, ControlState, Reloader},
     error::Error,
     net::{
+        capture::Capture,
         dhcp::DhcpServer,
+        dhcp6::Dhcp6Server,
+        dns::{DnsServer, HostTable},
+        firewall::Firewall,
         router::{
             handler::{IcmpHandler, UdpHandler},
-            Router,
+            table::{NextHop, RoutingTable},
+            Lan, RespawnError, Router, RouterBuilder,
         },
-        switch::VirtioSwitch,
-        wan::{TunTap, UdpDevice, Wan, WgDevice},
+        switch::{VirtioSwitch, DEFAULT_VLAN},
+        wan::{TunTap, UdpDevice, UserNet, Wan, WgDevice},
     },
 };
 
 #[derive(Default)]
 pub struct BridgeBuilder {
-    /// Path to pcap file, or None to disable pcap
+    /// Path to pcapng file, overriding the configured capture path
     pcap: Option<PathBuf>,
 
     /// Path to base directory for bridge-related files
@@ -36,16 +82,21 @@ pub struct BridgeBuilder {
 }
 
 pub struct Bridge {
+    name: String,
+    base: PathBuf,
     socket_path: PathBuf,
+    lease_path: PathBuf,
+    lease6_path: PathBuf,
     pcap: Option<PathBuf>,
     cfg: BridgeConfig,
 }
 
 impl BridgeBuilder {
-    /// Configures bridge to log all traffic transiting the switch
+    /// Configures bridge to capture all traffic transiting the switch
     ///
     /// ### Arguments
-    /// * `pcap` - Path to location to save pcap file, or None to disable
+    /// * `pcap` - Path to location to save pcapng file, or None to use the configured
+    ///   capture path (if any)
     pub fn pcap(mut self, pcap: Option<PathBuf>) -> Self {
         self.pcap = pcap;
         self
@@ -62,62 +113,517 @@ impl BridgeBuilder {
 
     pub fn build<S: Into<String>>(self, cfg: BridgeConfig, name: S) -> Result<Bridge, Error> {
         let name = name.into();
+        let base = self.base.unwrap_or_else(|| DEFAULT_BASE_PATH.into());
 
-        let socket_path = self.base
-            .unwrap_or_else(|| DEFAULT_BASE_PATH.into())
-            .join(name)
-            .with_extension("sock");
+        let socket_path = base.join(&name).with_extension("sock");
 
         Ok(Bridge {
             socket_path,
+            lease_path: lease_path(&base, &name),
+            lease6_path: lease6_path(&base, &name),
+            name,
+            base,
             pcap: self.pcap,
             cfg,
         })
     }
 }
 
-fn parse_wan(cfg: WanConfig) -> Result<Option<Box<dyn Wan>>, Error> {
+/// Returns the path of the file used to persist a bridge's DHCP leases
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+pub fn lease_path<P: AsRef<Path>>(base: P, name: &str) -> PathBuf {
+    base.as_ref().join(name).with_extension("leases")
+}
+
+/// Returns the path of the file used to persist a bridge's DHCPv6 leases
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+pub fn lease6_path<P: AsRef<Path>>(base: P, name: &str) -> PathBuf {
+    base.as_ref().join(name).with_extension("leases6")
+}
+
+/// Returns the path of the file used to persist the DHCP leases of a bridge's VLAN
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+/// * `vlan` - VLAN identifier
+pub fn vlan_lease_path<P: AsRef<Path>>(base: P, name: &str, vlan: u16) -> PathBuf {
+    base.as_ref()
+        .join(format!("{name}-vlan{vlan}"))
+        .with_extension("leases")
+}
+
+/// Returns the paths of the files used to persist the DHCP leases of every network served by
+/// a bridge: the primary network followed by each VLAN
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+/// * `cfg` - Configuration of the bridge
+pub fn lease_paths<P: AsRef<Path>>(base: P, name: &str, cfg: &BridgeConfig) -> Vec<PathBuf> {
+    let base = base.as_ref();
+    let mut paths = vec![lease_path(base, name)];
+    for vlan in &cfg.vlans {
+        paths.push(vlan_lease_path(base, name, vlan.id));
+    }
+
+    paths
+}
+
+/// Returns the path of a bridge's control socket
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+pub fn control_path<P: AsRef<Path>>(base: P, name: &str) -> PathBuf {
+    base.as_ref().join(name).with_extension("ctl")
+}
+
+/// Returns the path of the configuration file of a running bridge.  The bridge reloads the
+/// file when it receives SIGHUP
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+pub fn config_path<P: AsRef<Path>>(base: P, name: &str) -> PathBuf {
+    base.as_ref().join(name).with_extension("yml")
+}
+
+/// Returns the path of the vhost socket of one of a bridge's additional ports
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+/// * `port` - Name of the port
+pub fn port_socket_path<P: AsRef<Path>>(base: P, name: &str, port: &str) -> PathBuf {
+    base.as_ref()
+        .join(format!("{name}-{port}"))
+        .with_extension("sock")
+}
+
+/// Loads the DHCP leases persisted by every network of a bridge
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+/// * `cfg` - Configuration of the bridge
+pub fn load_leases<P: AsRef<Path>>(
+    base: P,
+    name: &str,
+    cfg: &BridgeConfig,
+) -> Result<Vec<DhcpLease>, Error> {
+    let mut leases = Vec::new();
+    for path in lease_paths(base, name, cfg) {
+        leases.extend(DhcpLease::load(path)?);
+    }
+
+    Ok(leases)
+}
+
+/// Loads the DHCPv6 leases persisted by a bridge
+///
+/// ### Arguments
+/// * `base` - Base path (directory) for bridge-related files
+/// * `name` - Name of the bridge
+pub fn load_leases6<P: AsRef<Path>>(base: P, name: &str) -> Result<Vec<Dhcp6Lease>, Error> {
+    let leases = Dhcp6Lease::load(lease6_path(base, name))?;
+    Ok(leases)
+}
+
+fn parse_wan(cfg: WanConfig, nat: NatConfig) -> Result<Box<dyn Wan>, Error> {
     match cfg {
         WanConfig::Tap(opts) => {
-            let wan = TunTap::create_tap(opts.device)?;
-            Ok(Some(Box::new(wan)))
+            let wan = TunTap::create_tap(opts, nat)?;
+            Ok(Box::new(wan))
+        }
+        WanConfig::Tun(opts) => {
+            let wan = TunTap::create_tun(opts, nat)?;
+            Ok(Box::new(wan))
         }
         WanConfig::Udp(opts) => {
             let wan = UdpDevice::connect(opts.endpoint)?;
-            Ok(Some(Box::new(wan)))
+            Ok(Box::new(wan))
         }
+        WanConfig::User(opts) => {
+            let wan = UserNet::new(opts)?;
+            Ok(Box::new(wan))
+        }
+        WanConfig::Wireguard(opts) => {
+            let wan = WgDevice::create(opts, nat)?;
+            Ok(Box::new(wan))
+        }
+    }
+}
+
+/// Validates the settings of a WAN that are checked when it is created, without creating it
+///
+/// ### Arguments
+/// * `cfg` - WAN configuration
+fn validate_wan(cfg: &WanConfig) -> Result<(), Error> {
+    match cfg {
+        WanConfig::Tap(opts) => TunTap::validate_name(&opts.device)?,
+        WanConfig::Tun(opts) => TunTap::validate_name(&opts.device)?,
         WanConfig::Wireguard(opts) => {
-            let wan = WgDevice::create(opts)?;
-            Ok(Some(Box::new(wan)))
+            opts.keys()?;
         }
+        WanConfig::Udp(_) | WanConfig::User(_) => (),
     }
+
+    Ok(())
+}
+
+/// Validates the configured VLANs and ports
+///
+/// Each VLAN must have a unique identifier and a subnet that does not overlap with the
+/// router's other networks
+///
+/// ### Arguments
+/// * `cfg` - Bridge configuration
+fn validate_vlans(cfg: &BridgeConfig) -> Result<(), Error> {
+    let mut networks = vec![(DEFAULT_VLAN, cfg.router.ipv4)];
+    for vlan in &cfg.vlans {
+        if !(2..=4094).contains(&vlan.id) {
+            return Err(format!("invalid vlan id {}, must be between 2 and 4094", vlan.id).into());
+        }
+
+        if let Some((id, _)) = networks.iter().find(|(id, network)| {
+            *id == vlan.id
+                || network.contains(vlan.ipv4.network())
+                || vlan.ipv4.contains(network.network())
+        }) {
+            return Err(format!("vlan {} conflicts with vlan {id}", vlan.id).into());
+        }
+
+        networks.push((vlan.id, vlan.ipv4));
+    }
+
+    for (name, port) in &cfg.ports {
+        if name == DEFAULT_PORT {
+            return Err(format!("port name `{DEFAULT_PORT}` is reserved").into());
+        }
+
+        if port.vlans().iter().any(|id| !(1..=4094).contains(id)) {
+            return Err(format!("port `{name}` has an invalid vlan id").into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates the configured mirror sessions
+///
+/// Each session must copy traffic to a monitor port and select its sources among the
+/// configured ports (or `default`, the bridge's main socket)
+///
+/// ### Arguments
+/// * `cfg` - Bridge configuration
+fn validate_mirrors(cfg: &BridgeConfig) -> Result<(), Error> {
+    for mirror in &cfg.switch.mirrors {
+        match cfg.ports.get(&mirror.destination) {
+            Some(PortConfig::Monitor) => (),
+            Some(_) => {
+                return Err(format!(
+                    "mirror destination `{}` is not a monitor port",
+                    mirror.destination
+                )
+                .into())
+            }
+            None => {
+                return Err(format!("mirror destination `{}` not found", mirror.destination).into())
+            }
+        }
+
+        for port in &mirror.ports {
+            match cfg.ports.get(port) {
+                Some(PortConfig::Monitor) => {
+                    return Err(format!("mirror source `{port}` is a monitor port").into())
+                }
+                Some(_) => (),
+                None if port == DEFAULT_PORT => (),
+                None => return Err(format!("mirror source `{port}` not found").into()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates a bridge configuration before it is applied, running the checks made when the
+/// bridge (and its router) starts without creating any device or socket
+///
+/// ### Arguments
+/// * `cfg` - Bridge configuration
+pub fn validate_config(cfg: &BridgeConfig) -> Result<(), Error> {
+    validate_vlans(cfg)?;
+    validate_mirrors(cfg)?;
+    plan_routes(cfg)?;
+
+    // the servers are created, but not started, to check their address ranges
+    let router = &cfg.router;
+    DhcpServer::new(
+        router.ipv4,
+        router.dhcp.clone(),
+        &router.dns,
+        HostTable::default(),
+    )?;
+    for vlan in &cfg.vlans {
+        DhcpServer::new(
+            vlan.ipv4,
+            vlan.dhcp.clone(),
+            &vlan.dns,
+            HostTable::default(),
+        )?;
+    }
+
+    if let Some(ref dhcp6) = router.dhcp6 {
+        let network = router.ipv6.ok_or(DHCP6_REQUIRES_IPV6)?;
+        Dhcp6Server::new(network, dhcp6.clone())?;
+    }
+
+    Ok(())
+}
+
+/// Returns the address and subnet of the router on each network (VLAN)
+///
+/// ### Arguments
+/// * `cfg` - Bridge configuration
+fn lan_networks(cfg: &BridgeConfig) -> Vec<Ipv4Network> {
+    std::iter::once(cfg.router.ipv4)
+        .chain(cfg.vlans.iter().map(|vlan| vlan.ipv4))
+        .collect()
+}
+
+/// Creates the handlers (DHCP, DNS) of a VLAN's network
+///
+/// ### Arguments
+/// * `vlan` - VLAN configuration
+/// * `leases` - Path to the file used to persist the VLAN's DHCP leases
+fn parse_vlan(vlan: VlanConfig, leases: PathBuf) -> Result<Lan, Error> {
+    // hostnames are not shared between (isolated) networks
+    let hosts = HostTable::default();
+
+    let mut dhcp = DhcpServer::new(vlan.ipv4, vlan.dhcp, &vlan.dns, hosts.clone())?;
+    dhcp.persist(leases)?;
+
+    let mut udp_handler = UdpHandler::default();
+    udp_handler.register_port_handler(dhcp);
+
+    if vlan.dns.enabled {
+        udp_handler.register_port_handler(DnsServer::new(vlan.ipv4, vlan.dns, hosts));
+    }
+
+    Ok(Lan::new(vlan.id, vlan.ipv4)
+        .isolated(vlan.isolated)
+        .register_proto_handler(IcmpHandler)
+        .register_proto_handler(udp_handler))
+}
+
+/// WANs of a configuration, in the order they are referenced (by index) by the routing table
+/// and port forwards
+struct RoutePlan {
+    wans: Vec<(String, WanConfig)>,
+    table: RoutingTable,
+
+    /// Port forwards, along with the WAN accepting them and the host address to listen on
+    forwards: Vec<(usize, PortForward, IpAddr)>,
+}
+
+/// Validates the configured WANs, routes, policies and port forwards and builds the routing
+/// table that references them.  No WAN is created
+///
+/// ### Arguments
+/// * `cfg` - Bridge configuration
+fn plan_routes(cfg: &BridgeConfig) -> Result<RoutePlan, Error> {
+    let mut wans = Vec::new();
+    if let Some(ref wan) = cfg.wan {
+        if cfg.wans.contains_key(DEFAULT_WAN) {
+            return Err(format!("wan `{DEFAULT_WAN}` is configured twice").into());
+        }
+        wans.push((String::from(DEFAULT_WAN), wan.clone()));
+    }
+    wans.extend(
+        cfg.wans
+            .iter()
+            .map(|(name, wan)| (name.clone(), wan.clone())),
+    );
+
+    for (name, wan) in &wans {
+        validate_wan(wan).map_err(|error| format!("wan `{name}`: {error}"))?;
+    }
+
+    let find = |name: &str| {
+        wans.iter()
+            .position(|(n, _)| n == name)
+            .ok_or_else(|| Error::from(format!("unknown wan `{name}`")))
+    };
+
+    let on_lan = |ip: Ipv4Addr| {
+        cfg.router.ipv4.contains(ip) || cfg.vlans.iter().any(|vlan| vlan.ipv4.contains(ip))
+    };
+
+    let mut table = RoutingTable::default();
+    for route in &cfg.routes {
+        let hop = match route.target {
+            RouteTarget::Wan(ref name) => NextHop::Wan(find(name)?),
+            RouteTarget::Via(gateway) if on_lan(gateway) => NextHop::Lan(gateway),
+            RouteTarget::Via(gateway) => {
+                return Err(format!("gateway {gateway} is not on the lan").into())
+            }
+        };
+        table.add_route(route.prefix, hop);
+    }
+
+    for policy in &cfg.policies {
+        table.add_policy(policy.source, find(&policy.wan)?);
+    }
+
+    // a lone wan is the default route, unless told otherwise
+    if cfg.routes.is_empty() && wans.len() == 1 {
+        table.add_route(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0), NextHop::Wan(0));
+    }
+
+    let mut forwards = Vec::new();
+    let mut ports = HashSet::new();
+    for fwd in &cfg.forwards {
+        let wan = match (&fwd.wan, wans.len()) {
+            (Some(name), _) => find(name)?,
+            (None, 1) => 0,
+            (None, _) => return Err("port forwards require a `wan` with multiple wans".into()),
+        };
+
+        if fwd.protocol == Protocol::Icmp {
+            return Err("only tcp and udp ports can be forwarded".into());
+        }
+
+        if !on_lan(fwd.to) {
+            return Err(format!("port forward target {} is not on the lan", fwd.to).into());
+        }
+
+        let pf = PortForward {
+            protocol: fwd.protocol.number(),
+            port: fwd.port,
+            to: fwd.to,
+            to_port: fwd.to_port.unwrap_or(fwd.port),
+        };
+
+        if !ports.insert((wan, pf.protocol, pf.port)) {
+            return Err(format!("port {} is forwarded twice", pf.port).into());
+        }
+
+        table.add_forward(pf, wan);
+        forwards.push((wan, pf, fwd.listen));
+    }
+
+    Ok(RoutePlan {
+        wans,
+        table,
+        forwards,
+    })
+}
+
+/// Creates the configured WANs and their port forwards and adds them, along with the routing
+/// table that references them, to the router
+///
+/// Routes, policies and port forwards are validated before any WAN is created
+///
+/// ### Arguments
+/// * `cfg` - Bridge configuration
+/// * `builder` - Router to add the WANs and routes to
+fn parse_routes(cfg: &BridgeConfig, builder: RouterBuilder) -> Result<RouterBuilder, Error> {
+    let plan = plan_routes(cfg)?;
+
+    let mut builder = builder.routes(plan.table);
+    for (idx, (name, wan)) in plan.wans.into_iter().enumerate() {
+        let mut wan = parse_wan(wan, cfg.nat.clone())?;
+        for (_, fwd, listen) in plan.forwards.iter().filter(|(wan, _, _)| *wan == idx) {
+            wan.add_forward(*fwd, *listen)?;
+        }
+
+        builder = builder.wan(name, wan);
+    }
+
+    Ok(builder)
 }
 
 impl Bridge {
-    pub fn run(self, sfd: SignalFd) -> Result<(), Error> {
+    pub fn run(mut self, sfd: SignalFd) -> Result<(), Error> {
         const TOKEN_VHOST: Token = Token(0);
         const TOKEN_SIGNAL: Token = Token(1);
+        const TOKEN_CONTROL: Token = Token(2);
+        const TOKEN_RELOAD: Token = Token(3);
+
+        /// Token of the first additional port, the others follow in order
+        const TOKEN_PORTS: Token = Token(4);
 
         tracing::debug!(socket = %self.socket_path.display(), "bridge starting");
 
+        validate_config(&self.cfg)?;
+
         let mut socket = VHostSocket::new(&self.socket_path)?;
-        let switch = VirtioSwitch::new(self.pcap)?;
+        let capture = match self.pcap.take().or_else(|| self.cfg.capture.path.clone()) {
+            Some(path) => {
+                tracing::debug!(path = %path.display(), "capturing traffic");
+                Some(Capture::spawn(path, &self.cfg.capture)?)
+            }
+            None => None,
+        };
 
-        // spawn the default route / upstream
-        let wan = parse_wan(self.cfg.wan)?;
+        let switch = VirtioSwitch::new(capture.clone());
+        switch.set_mac_timeout(Duration::from_secs(self.cfg.switch.mac_timeout));
+        switch.set_mirrors(self.cfg.switch.mirrors.clone());
 
-        let mut udp_handler = UdpHandler::default();
-        udp_handler
-            .register_port_handler(DhcpServer::new(self.cfg.router.ipv4, self.cfg.router.dhcp));
+        let mut ports = Vec::new();
+        for (name, port) in &self.cfg.ports {
+            let path = port_socket_path(&self.base, &self.name, name);
+            let socket = VHostSocket::new(&path)?;
+            tracing::debug!(port = %name, socket = %path.display(), "created vhost socket");
+            ports.push((socket, path, PortMode::from(port), name.clone()));
+        }
+
+        let firewall = self
+            .cfg
+            .firewall
+            .clone()
+            .map(|cfg| Firewall::new(cfg, self.cfg.nat.clone()));
+
+        switch.set_firewall(lan_networks(&self.cfg), firewall.clone());
 
         // spawn thread to receive messages/packets
-        let _router = Router::builder()
-            .wan(wan)
-            .register_proto_handler(IcmpHandler::default())
-            .register_proto_handler(udp_handler)
-            .spawn(self.cfg.router.ipv4, switch.clone())?;
+        let (builder, network, leases) = self.router_builder(self.cfg.clone(), firewall.clone())?;
+        let router_handle = builder.spawn(network, switch.clone())?;
 
         let mut poller = Poll::new()?;
+
+        let (reload_tx, reload_rx) = flume::unbounded();
+        let waker = Waker::new(poller.registry(), TOKEN_RELOAD)?;
+        let control_path = control_path(&self.base, &self.name);
+        let state = ControlState {
+            name: self.name.clone(),
+            started: Instant::now(),
+            switch: switch.clone(),
+            router: router_handle,
+            firewall: Arc::new(RwLock::new(firewall)),
+            capture: capture.clone(),
+            leases: Arc::new(RwLock::new(leases)),
+            reloader: Reloader::new(reload_tx, waker),
+        };
+
+        if let Some(addr) = self.cfg.metrics {
+            metrics::spawn(addr, state.clone())?;
+        }
+
+        let control = ControlServer::bind(&control_path, state.clone())?;
+
+        // the running configuration, reloaded on SIGHUP
+        let config_path = config_path(&self.base, &self.name);
+        self.cfg.save(&config_path)?;
+
         poller
             .registry()
             .register(&mut socket, TOKEN_VHOST, Interest::READABLE)?;
@@ -128,6 +634,18 @@ impl Bridge {
             Interest::READABLE,
         )?;
 
+        poller.registry().register(
+            &mut SourceFd(&control.as_raw_fd()),
+            TOKEN_CONTROL,
+            Interest::READABLE,
+        )?;
+
+        for (idx, (socket, _, _, _)) in ports.iter_mut().enumerate() {
+            poller
+                .registry()
+                .register(socket, Token(TOKEN_PORTS.0 + idx), Interest::READABLE)?;
+        }
+
         tracing::info!(socket = %self.socket_path.display(), "bridge started");
         let mut events = Events::with_capacity(10);
         'poll: loop {
@@ -136,15 +654,23 @@ impl Bridge {
             for event in &events {
                 match event.token() {
                     TOKEN_VHOST => {
-                        if let Err(error) =
-                            socket.accept_and_spawn(DeviceOpts::default(), switch.clone())
-                        {
+                        let switch = switch.with_name(DEFAULT_PORT);
+                        if let Err(error) = socket.accept_and_spawn(DeviceOpts::default(), switch) {
                             tracing::error!(%error, "unable to accet connection");
                         }
                     }
                     TOKEN_SIGNAL => match sfd.read_signal() {
                         Ok(None) => { /* no nothing, no signal read */ }
                         Ok(Some(sig)) => match sig.ssi_signo {
+                            1 /* SIGHUP */ => {
+                                let result = BridgeConfig::load(&config_path)
+                                    .map_err(Error::from)
+                                    .and_then(|cfg| self.reload(cfg, &state));
+
+                                if let Err(error) = result {
+                                    tracing::warn!(%error, "unable to reload configuration");
+                                }
+                            }
                             15 /* SIGTERM */ => break 'poll,
                             signo => tracing::warn!(%signo, "unhandled signal"),
                         },
@@ -152,14 +678,281 @@ impl Bridge {
                             tracing::error!(%error, "unable to read signal");
                         }
                     },
-                    Token(token) => tracing::debug!(%token, "[main] unknown mio token"),
+                    TOKEN_CONTROL => {
+                        if let Err(error) = control.accept() {
+                            tracing::error!(%error, "unable to accept control connection");
+                        }
+                    }
+                    TOKEN_RELOAD => {
+                        for request in reload_rx.try_iter() {
+                            let result = self
+                                .reload(request.config, &state)
+                                .map_err(|error| error.to_string());
+
+                            if let Err(ref error) = result {
+                                tracing::warn!(%error, "unable to reload configuration");
+                            }
+
+                            request.reply.send(result).ok();
+                        }
+                    }
+                    Token(token) => match token
+                        .checked_sub(TOKEN_PORTS.0)
+                        .and_then(|idx| ports.get_mut(idx))
+                    {
+                        Some((socket, _, mode, name)) => {
+                            let switch = switch.with_mode(mode.clone()).with_name(name.as_str());
+                            if let Err(error) =
+                                socket.accept_and_spawn(DeviceOpts::default(), switch)
+                            {
+                                tracing::error!(%error, "unable to accept connection");
+                            }
+                        }
+                        None => tracing::debug!(%token, "[main] unknown mio token"),
+                    },
                 }
             }
         }
 
+        if let Some(firewall) = state.firewall.read().as_ref() {
+            for rule in firewall.counters() {
+                tracing::info!(
+                    rule = %rule.name,
+                    action = ?rule.action,
+                    packets = rule.packets,
+                    bytes = rule.bytes,
+                    "firewall rule counters"
+                );
+            }
+        }
+
+        if let Some(capture) = capture {
+            tracing::info!(dropped = capture.dropped(), "capture stopped");
+        }
+
         std::fs::remove_file(&self.socket_path).ok();
+        std::fs::remove_file(&control_path).ok();
+        std::fs::remove_file(&config_path).ok();
+        for (_, path, _, _) in ports {
+            std::fs::remove_file(path).ok();
+        }
+
         tracing::info!(socket = %self.socket_path.display(), "bridge stopped");
 
         Ok(())
     }
+
+    /// Applies a new configuration to the running bridge without disconnecting its devices.
+    /// The router is shut down and replaced, along with its WANs, VLANs and handlers, while
+    /// the switch keeps its ports, learned MAC addresses and the router's port.  If the new
+    /// router (or any of its WANs) fails to start, the previous configuration is restored
+    ///
+    /// Ports, virtio and capture settings and the metrics address are only applied the next
+    /// time the bridge starts
+    ///
+    /// ### Arguments
+    /// * `cfg` - New bridge configuration
+    /// * `state` - State of the running bridge, shared with the control socket
+    fn reload(&mut self, cfg: BridgeConfig, state: &ControlState) -> Result<(), Error> {
+        validate_config(&cfg)?;
+
+        if cfg.ports.keys().ne(self.cfg.ports.keys()) || cfg.metrics != self.cfg.metrics {
+            tracing::warn!("ports or metrics changed, restart the bridge to apply them");
+        }
+
+        // keep the running firewall (and its tracked connections) if it stays enabled
+        let previous = state.firewall.read().clone();
+        let firewall = match (&previous, &cfg.firewall) {
+            (Some(firewall), Some(_)) => Some(firewall.clone()),
+            (None, Some(rules)) => Some(Firewall::new(rules.clone(), cfg.nat.clone())),
+            (_, None) => None,
+        };
+
+        // the wans are closed before the new ones are created, as they may use the same
+        // devices (e.g., a tap interface) or ports
+        let handover = state.router.shutdown()?;
+        let (error, handover) = match self.router_builder(cfg.clone(), firewall.clone()) {
+            Ok((builder, network, leases)) => {
+                let result = builder.require_wans(true).respawn(
+                    network,
+                    state.switch.clone(),
+                    &state.router,
+                    handover,
+                );
+
+                match result.map_err(|error| *error) {
+                    Ok(()) => {
+                        self.apply(cfg, firewall, leases, state);
+                        tracing::info!("configuration reloaded");
+                        return Ok(());
+                    }
+                    Err(RespawnError { error, handover }) => (Error::from(error), handover),
+                }
+            }
+            Err(error) => (error, handover),
+        };
+
+        tracing::warn!(%error, "unable to start router, restoring previous configuration");
+        let restored =
+            self.router_builder(self.cfg.clone(), previous)
+                .and_then(|(builder, network, _)| {
+                    builder
+                        .respawn(network, state.switch.clone(), &state.router, handover)
+                        .map_err(|error| Error::from(error.error))
+                });
+
+        match restored {
+            Ok(()) => Err(error),
+            Err(restore) => {
+                tracing::error!(%restore, "unable to restore previous configuration, router stopped");
+                Err(format!("{error} (unable to restore previous configuration: {restore})").into())
+            }
+        }
+    }
+
+    /// Applies the settings of a configuration to the switch and firewall of the running
+    /// bridge, once its router has been replaced
+    ///
+    /// ### Arguments
+    /// * `cfg` - New bridge configuration
+    /// * `firewall` - Firewall used by the new router, if enabled
+    /// * `leases` - Lease files of every network served by the new router
+    /// * `state` - State of the running bridge, shared with the control socket
+    fn apply(
+        &mut self,
+        cfg: BridgeConfig,
+        firewall: Option<Firewall>,
+        leases: Vec<PathBuf>,
+        state: &ControlState,
+    ) {
+        state
+            .switch
+            .set_mac_timeout(Duration::from_secs(cfg.switch.mac_timeout));
+        state.switch.set_mirrors(cfg.switch.mirrors.clone());
+        state
+            .switch
+            .set_firewall(lan_networks(&cfg), firewall.clone());
+
+        if let (Some(firewall), Some(rules)) = (&firewall, &cfg.firewall) {
+            firewall.reload(rules.clone());
+        }
+
+        *state.firewall.write() = firewall;
+        *state.leases.write() = leases;
+
+        if let Err(error) = cfg.save(config_path(&self.base, &self.name)) {
+            tracing::warn!(%error, "unable to save configuration, SIGHUP reloads the previous one");
+        }
+
+        self.cfg = cfg;
+    }
+
+    /// Creates the WANs, VLANs and handlers (DHCP, DNS) of the router, returning the router
+    /// ready to be spawned along with its primary network and the lease files of every
+    /// network it serves
+    ///
+    /// ### Arguments
+    /// * `cfg` - Bridge configuration
+    /// * `firewall` - Firewall filtering traffic to and through the router, if enabled
+    fn router_builder(
+        &self,
+        mut cfg: BridgeConfig,
+        firewall: Option<Firewall>,
+    ) -> Result<(RouterBuilder, Ipv4Network, Vec<PathBuf>), Error> {
+        // create the upstreams and the routes to them
+        let mut builder = parse_routes(&cfg, Router::builder())?;
+
+        let leases = lease_paths(&self.base, &self.name, &cfg);
+        for (vlan, path) in std::mem::take(&mut cfg.vlans).into_iter().zip(&leases[1..]) {
+            builder = builder.vlan(parse_vlan(vlan, path.clone())?);
+        }
+
+        let router = cfg.router;
+        let hosts = HostTable::default();
+
+        let mut dhcp = DhcpServer::new(router.ipv4, router.dhcp, &router.dns, hosts.clone())?;
+        dhcp.persist(&self.lease_path)?;
+
+        let mut udp_handler = UdpHandler::default();
+        udp_handler.register_port_handler(dhcp);
+
+        if router.dns.enabled {
+            udp_handler.register_port_handler(DnsServer::new(router.ipv4, router.dns, hosts));
+        }
+
+        let managed = router.dhcp6.is_some();
+        if let Some(cfg) = router.dhcp6 {
+            let network = router.ipv6.ok_or(DHCP6_REQUIRES_IPV6)?;
+
+            let mut dhcp6 = Dhcp6Server::new(network, cfg)?;
+            dhcp6.persist(&self.lease6_path)?;
+            udp_handler.register_port_handler(dhcp6);
+        }
+
+        let builder = builder
+            .firewall(firewall)
+            .ipv6(router.ipv6)
+            .managed(managed)
+            .register_proto_handler(IcmpHandler::default())
+            .register_proto_handler(udp_handler);
+
+        Ok((builder, router.ipv4, leases))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_config, BridgeConfig};
+
+    /// Parses a configuration, adding the given sections to a minimal configuration
+    fn config(extra: &str) -> BridgeConfig {
+        let base = r#"
+router:
+    ipv4: 10.67.213.1/24
+    dhcp:
+        start: 10.67.213.100
+        end: 10.67.213.200
+virtio:
+    queues: 1
+"#;
+        serde_yaml::from_str(&format!("{base}{extra}")).unwrap()
+    }
+
+    #[test]
+    fn validate_config_checks_router() {
+        assert!(validate_config(&config("")).is_ok());
+
+        // port forward to a host that is not on the lan
+        let cfg = config(
+            r#"
+wan:
+    type: udp
+    endpoint: 127.0.0.1:5000
+forwards:
+    - protocol: tcp
+      port: 22
+      to: 192.168.1.10
+"#,
+        );
+        assert!(validate_config(&cfg).is_err());
+
+        // dhcp range outside of the vlan's subnet
+        let cfg = config(
+            r#"
+vlans:
+    - id: 20
+      ipv4: 10.67.20.1/24
+      dhcp:
+          start: 10.67.21.100
+          end: 10.67.21.200
+"#,
+        );
+        assert!(validate_config(&cfg).is_err());
+
+        // dhcp6 without an ipv6 address on the router
+        let mut cfg = config("");
+        cfg.router.dhcp6 = serde_yaml::from_str("{ start: 'fd00::100', end: 'fd00::200' }").ok();
+        assert!(cfg.router.dhcp6.is_some());
+        assert!(validate_config(&cfg).is_err());
+    }
 }
@@ -0,0 +1,447 @@
+//! Metrics endpoint of a running bridge
+//!
+//! Serves the bridge's counters in the Prometheus text format over plain HTTP.  Every
+//! request receives the current counters, regardless of its method or path
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+use oathgate_net::{
+    protocols::{NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+    stats::{Counters as TrafficCounters, InterfaceCounters},
+};
+
+use crate::{
+    control::{ControlState, Counters},
+    error::Error,
+};
+
+/// Time allowed for a client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Content type of the Prometheus text format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Labels identifying a sample, as (name, value) pairs
+type Labels<'a> = &'a [(&'a str, &'a str)];
+
+/// A metric and its samples
+struct Family {
+    /// Type of the metric (counter or gauge)
+    kind: &'static str,
+
+    /// Description of the metric
+    help: String,
+
+    /// Formatted labels and value of each sample
+    samples: Vec<(String, u64)>,
+}
+
+/// Metrics being collected, grouped by name so the samples of each metric are written
+/// together
+#[derive(Default)]
+struct Metrics {
+    families: BTreeMap<String, Family>,
+}
+
+/// Starts serving the counters of a bridge on its own thread
+///
+/// ### Arguments
+/// * `addr` - Address to listen on
+/// * `state` - State of the bridge
+pub(crate) fn spawn(addr: SocketAddr, state: ControlState) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)?;
+    tracing::info!(%addr, "[metrics] serving counters");
+
+    std::thread::Builder::new()
+        .name(String::from("bridge-metrics"))
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(Error::from)
+                    .and_then(|stream| serve(stream, &state));
+
+                if let Err(error) = result {
+                    tracing::debug!(%error, "[metrics] unable to serve client");
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Reads a client's request (ignoring its content) and responds with the current counters
+///
+/// ### Arguments
+/// * `stream` - Socket connected to the client
+/// * `state` - State of the bridge
+fn serve(stream: TcpStream, state: &ControlState) -> Result<(), Error> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // the request line and headers end with an empty line
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = match state.counters() {
+        Ok(counters) => ("200 OK", render(&counters)),
+        Err(error) => ("503 Service Unavailable", format!("{error}\n")),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.0 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Formats the counters of a bridge in the Prometheus text format
+///
+/// ### Arguments
+/// * `counters` - Counters of the bridge
+pub(crate) fn render(counters: &Counters) -> String {
+    let mut metrics = Metrics::default();
+
+    for port in &counters.ports {
+        let idx = port.port.to_string();
+        let name = port.name.as_deref().unwrap_or_default();
+        let labels = [("port", idx.as_str()), ("name", name)];
+        metrics.interface("port", "switch port", &labels, &port.counters);
+
+        for (queue, stats) in port.queues.iter().enumerate() {
+            let queue = queue.to_string();
+            let labels = [("port", idx.as_str()), ("name", name), ("queue", &queue)];
+            metrics.counters("virtqueue", "virtqueue", &labels, &[], stats);
+        }
+    }
+
+    for wan in &counters.wans {
+        let labels = [("wan", wan.name.as_str())];
+        metrics.interface("wan", "wan", &labels, &wan.counters);
+
+        if let Some(nat) = wan.nat {
+            metrics.gauge(
+                "nat_entries",
+                "Flows tracked by the NAT table",
+                &labels,
+                nat.entries,
+            );
+            metrics.counter("nat_created", "NAT entries created", &labels, nat.created);
+            metrics.counter("nat_expired", "NAT entries expired", &labels, nat.expired);
+            metrics.counter(
+                "nat_outbound",
+                "Packets translated to the WAN",
+                &labels,
+                nat.outbound,
+            );
+            metrics.counter(
+                "nat_inbound",
+                "Packets translated to the LAN",
+                &labels,
+                nat.inbound,
+            );
+            metrics.counter(
+                "nat_unmatched",
+                "Packets from the WAN matching no NAT entry",
+                &labels,
+                nat.unmatched,
+            );
+            metrics.counter(
+                "nat_failed",
+                "Packets that failed to be translated",
+                &labels,
+                nat.failed,
+            );
+        }
+    }
+
+    for lan in &counters.lans {
+        let vlan = lan.vlan.to_string();
+        for handler in &lan.protocols {
+            let protocol = protocol_name(handler.protocol);
+            let labels = [("vlan", vlan.as_str()), ("protocol", protocol.as_str())];
+            metrics.interface("handler", "protocol handler", &labels, &handler.counters);
+        }
+
+        for service in &lan.services {
+            let labels = [("vlan", vlan.as_str())];
+            for (name, value) in &service.counters {
+                let metric = format!("{}_{name}", service.service);
+                let help = format!("{} {name} counter", service.service);
+                metrics.counter(&metric, &help, &labels, *value);
+            }
+
+            for (name, value) in &service.gauges {
+                let metric = format!("{}_{name}", service.service);
+                let help = format!("{} {name}", service.service);
+                metrics.gauge(&metric, &help, &labels, *value);
+            }
+        }
+    }
+
+    for rule in &counters.firewall {
+        let action = format!("{:?}", rule.action).to_lowercase();
+        let labels = [("rule", rule.name.as_str()), ("action", action.as_str())];
+        metrics.counter(
+            "firewall_packets",
+            "Packets matching the firewall rule",
+            &labels,
+            rule.packets,
+        );
+        metrics.counter(
+            "firewall_bytes",
+            "Bytes matching the firewall rule",
+            &labels,
+            rule.bytes,
+        );
+    }
+
+    if let Some(dropped) = counters.capture_dropped {
+        metrics.counter(
+            "capture_dropped",
+            "Frames dropped by the capture",
+            &[],
+            dropped,
+        );
+    }
+
+    metrics.render()
+}
+
+impl Metrics {
+    /// Adds a sample of a counter
+    ///
+    /// ### Arguments
+    /// * `name` - Name of the metric, without the `oathgate_` prefix or `_total` suffix
+    /// * `help` - Description of the metric
+    /// * `labels` - Labels of the sample
+    /// * `value` - Value of the sample
+    fn counter(&mut self, name: &str, help: &str, labels: Labels, value: u64) {
+        self.add(
+            format!("oathgate_{name}_total"),
+            "counter",
+            help,
+            labels,
+            value,
+        );
+    }
+
+    /// Adds a sample of a gauge
+    ///
+    /// ### Arguments
+    /// * `name` - Name of the metric, without the `oathgate_` prefix
+    /// * `help` - Description of the metric
+    /// * `labels` - Labels of the sample
+    /// * `value` - Value of the sample
+    fn gauge(&mut self, name: &str, help: &str, labels: Labels, value: u64) {
+        self.add(format!("oathgate_{name}"), "gauge", help, labels, value);
+    }
+
+    /// Adds the counters of both directions of an interface
+    ///
+    /// ### Arguments
+    /// * `name` - Prefix of the metrics
+    /// * `what` - Kind of interface, used in the descriptions of the metrics
+    /// * `labels` - Labels identifying the interface
+    /// * `counters` - Counters of the interface
+    fn interface(&mut self, name: &str, what: &str, labels: Labels, counters: &InterfaceCounters) {
+        self.counters(name, what, labels, &[("direction", "rx")], &counters.rx);
+        self.counters(name, what, labels, &[("direction", "tx")], &counters.tx);
+    }
+
+    /// Adds the packets, bytes, dropped and errors counters of an interface (or queue)
+    ///
+    /// ### Arguments
+    /// * `name` - Prefix of the metrics
+    /// * `what` - Kind of interface, used in the descriptions of the metrics
+    /// * `labels` - Labels identifying the interface
+    /// * `extra` - Additional labels (i.e., direction)
+    /// * `counters` - Counters to add
+    fn counters(
+        &mut self,
+        name: &str,
+        what: &str,
+        labels: Labels,
+        extra: Labels,
+        counters: &TrafficCounters,
+    ) {
+        let labels = labels.iter().chain(extra).copied().collect::<Vec<_>>();
+        let values = [
+            ("packets", "Packets moved by the", counters.packets),
+            ("bytes", "Bytes moved by the", counters.bytes),
+            ("dropped", "Packets dropped by the", counters.dropped),
+            (
+                "errors",
+                "Packets that failed to be processed by the",
+                counters.errors,
+            ),
+        ];
+
+        for (metric, help, value) in values {
+            let help = format!("{help} {what}");
+            self.counter(&format!("{name}_{metric}"), &help, &labels, value);
+        }
+    }
+
+    /// Adds a sample, creating its metric if this is the metric's first sample
+    fn add(&mut self, name: String, kind: &'static str, help: &str, labels: Labels, value: u64) {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        self.families
+            .entry(name)
+            .or_insert_with(|| Family {
+                kind,
+                help: help.to_owned(),
+                samples: Vec::new(),
+            })
+            .samples
+            .push((labels, value));
+    }
+
+    /// Writes every metric in the Prometheus text format
+    fn render(self) -> String {
+        let mut out = String::new();
+        for (name, family) in self.families {
+            writeln!(out, "# HELP {name} {}", family.help).ok();
+            writeln!(out, "# TYPE {name} {}", family.kind).ok();
+            for (labels, value) in family.samples {
+                match labels.is_empty() {
+                    true => writeln!(out, "{name} {value}").ok(),
+                    false => writeln!(out, "{name}{{{labels}}} {value}").ok(),
+                };
+            }
+        }
+
+        out
+    }
+}
+
+/// Returns the name of an IP protocol handled by the router
+///
+/// ### Arguments
+/// * `protocol` - IP protocol number
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        NET_PROTOCOL_ICMP => String::from("icmp"),
+        NET_PROTOCOL_TCP => String::from("tcp"),
+        NET_PROTOCOL_UDP => String::from("udp"),
+        protocol => protocol.to_string(),
+    }
+}
+
+/// Escapes a label value (backslashes, quotes and newlines)
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use oathgate_net::{nat::NatStats, stats::InterfaceCounters};
+
+    use crate::{
+        control::Counters,
+        net::{
+            router::{handler::ServiceCounters, LanCounters, ProtocolCounters, WanCounters},
+            switch::{PortInfo, PortMode},
+        },
+    };
+
+    use super::render;
+
+    #[test]
+    fn metrics_render() {
+        let mut port = InterfaceCounters::default();
+        port.rx.record(60);
+        port.tx.record_drop();
+
+        let counters = Counters {
+            ports: vec![PortInfo {
+                port: 1,
+                name: Some(String::from("ma\"in")),
+                mode: PortMode::Access(1),
+                macs: 1,
+                counters: port,
+                queues: vec![port.rx],
+            }],
+            wans: vec![WanCounters {
+                name: String::from("default"),
+                counters: InterfaceCounters::default(),
+                nat: Some(NatStats {
+                    entries: 3,
+                    ..Default::default()
+                }),
+            }],
+            lans: vec![LanCounters {
+                vlan: 1,
+                protocols: vec![ProtocolCounters {
+                    protocol: 17,
+                    counters: port,
+                }],
+                services: vec![ServiceCounters {
+                    service: String::from("dhcp"),
+                    counters: BTreeMap::from([(String::from("discover"), 4)]),
+                    gauges: BTreeMap::from([(String::from("leases"), 2)]),
+                }],
+            }],
+            firewall: Vec::new(),
+            capture_dropped: Some(5),
+        };
+
+        let text = render(&counters);
+        let lines = text.lines().collect::<Vec<_>>();
+
+        // each metric is described once, followed by all of its samples
+        let idx = lines
+            .iter()
+            .position(|l| *l == "# TYPE oathgate_port_packets_total counter")
+            .unwrap();
+        assert_eq!(
+            lines[idx + 1],
+            r#"oathgate_port_packets_total{port="1",name="ma\"in",direction="rx"} 1"#
+        );
+        assert_eq!(
+            lines[idx + 2],
+            r#"oathgate_port_packets_total{port="1",name="ma\"in",direction="tx"} 0"#
+        );
+
+        assert!(lines
+            .contains(&r#"oathgate_port_dropped_total{port="1",name="ma\"in",direction="tx"} 1"#));
+        assert!(lines
+            .contains(&r#"oathgate_virtqueue_bytes_total{port="1",name="ma\"in",queue="0"} 60"#));
+        assert!(lines.contains(&r#"oathgate_nat_entries{wan="default"} 3"#));
+        assert!(lines.contains(
+            &r#"oathgate_handler_packets_total{vlan="1",protocol="udp",direction="rx"} 1"#
+        ));
+        assert!(lines.contains(&r#"oathgate_dhcp_discover_total{vlan="1"} 4"#));
+        assert!(lines.contains(&"# TYPE oathgate_dhcp_leases gauge"));
+        assert!(lines.contains(&r#"oathgate_dhcp_leases{vlan="1"} 2"#));
+        assert!(lines.contains(&"oathgate_capture_dropped_total 5"));
+        assert_eq!(
+            text.matches("# TYPE oathgate_port_packets_total").count(),
+            1
+        );
+    }
+}
@@ -2,11 +2,15 @@
 
 pub(crate) const ETHERNET_HDR_SZ: usize = 14;
 
+pub mod capture;
 mod error;
+pub mod firewall;
 pub mod router;
 pub mod switch;
 pub mod wan;
 
 pub mod dhcp;
+pub mod dhcp6;
+pub mod dns;
 
 pub use self::error::NetworkError;
@@ -0,0 +1,578 @@
+//! Packet capture to rotating pcapng files
+
+pub mod filter;
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{BufWriter, Write},
+    os::{fd::AsRawFd, unix::net::UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use flume::{Receiver, RecvTimeoutError, Sender, TrySendError};
+use nix::sys::socket::MsgFlags;
+use parking_lot::RwLock;
+use pcap_file::{
+    pcapng::{
+        blocks::{
+            enhanced_packet::{EnhancedPacketBlock, EnhancedPacketOption},
+            interface_description::{InterfaceDescriptionBlock, InterfaceDescriptionOption},
+        },
+        PcapNgWriter,
+    },
+    DataLink,
+};
+
+use crate::config::capture::CaptureConfig;
+
+use super::NetworkError;
+
+pub use self::filter::CaptureFilter;
+
+/// Value of the `epb_flags` option of frames received from a port (inbound direction)
+const EPB_FLAG_INBOUND: u32 = 0b01;
+
+/// Interval at which an idle stream checks whether its client has detached
+const STREAM_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A frame waiting to be written
+struct CapturedFrame {
+    /// Port the frame was received on
+    port: usize,
+
+    /// Name of the port, if any
+    name: Option<String>,
+
+    /// Time the frame was received, since the unix epoch
+    timestamp: Duration,
+
+    /// Length of the frame before truncation to the snaplen
+    orig_len: u32,
+
+    /// Frame (header and payload), truncated to the snaplen
+    data: Vec<u8>,
+}
+
+/// Handle to a capture, queueing frames to be written by the capture thread
+#[derive(Clone)]
+pub struct Capture {
+    /// Queue of frames waiting to be written
+    tx: Sender<CapturedFrame>,
+
+    /// Only frames matching this filter are captured, if set
+    filter: Option<CaptureFilter>,
+
+    /// Maximum number of bytes saved of each frame
+    snaplen: usize,
+
+    /// Number of frames dropped because the queue was full
+    dropped: Arc<AtomicU64>,
+}
+
+/// Live captures attached to a running bridge, each streaming to a client of the control
+/// socket
+#[derive(Clone, Default)]
+pub struct Taps(Arc<RwLock<Vec<Capture>>>);
+
+/// Writes captured frames as pcapng blocks, describing each port as an interface
+struct FrameWriter<W: Write> {
+    writer: PcapNgWriter<W>,
+
+    /// Maximum number of bytes saved of each frame
+    snaplen: u32,
+
+    /// Interface id of each port (and name) seen
+    interfaces: HashMap<(usize, Option<String>), u32>,
+}
+
+/// Writes captured frames to a pcapng file, rotating the file when it exceeds its size or
+/// age limits
+struct CaptureWriter {
+    /// Path of the file being written
+    path: PathBuf,
+
+    /// Maximum number of bytes saved of each frame
+    snaplen: u32,
+
+    /// Size (in bytes) after which the file is rotated
+    max_size: Option<u64>,
+
+    /// Time after which the file is rotated
+    interval: Option<Duration>,
+
+    /// Number of files kept, including the file being written
+    files: usize,
+
+    /// Writer of the current file
+    writer: FrameWriter<BufWriter<File>>,
+
+    /// Number of bytes written to the current file
+    size: u64,
+
+    /// Time the current file was opened
+    opened: Instant,
+}
+
+impl Capture {
+    /// Starts capturing to a file, spawning the thread writing captured frames
+    ///
+    /// ### Arguments
+    /// * `path` - Path of the capture file
+    /// * `cfg` - Rotation, snaplen, queue and filter settings
+    pub fn spawn(path: PathBuf, cfg: &CaptureConfig) -> Result<Self, NetworkError> {
+        let writer = CaptureWriter::new(path, cfg)?;
+        let (tx, rx) = flume::bounded(cfg.queue.max(1));
+
+        std::thread::Builder::new()
+            .name(String::from("pcap-logger"))
+            .spawn(move || writer.run(rx))?;
+
+        Ok(Self {
+            tx,
+            filter: cfg.filter.clone(),
+            snaplen: cfg.snaplen as usize,
+            dropped: Arc::default(),
+        })
+    }
+
+    /// Starts streaming captured frames (in pcapng format) to a client, spawning the thread
+    /// writing to the client's socket.  The thread stops once the client detaches
+    ///
+    /// ### Arguments
+    /// * `stream` - Socket connected to the client
+    /// * `filter` - Only frames matching this filter are streamed, if set
+    /// * `snaplen` - Maximum number of bytes sent of each frame
+    /// * `queue` - Number of frames waiting to be sent before new frames are dropped
+    pub fn stream(
+        stream: UnixStream,
+        filter: Option<CaptureFilter>,
+        snaplen: u32,
+        queue: usize,
+    ) -> Result<Self, NetworkError> {
+        let writer = FrameWriter::new(BufWriter::new(stream.try_clone()?), snaplen)?;
+        let (tx, rx) = flume::bounded(queue.max(1));
+
+        std::thread::Builder::new()
+            .name(String::from("pcap-stream"))
+            .spawn(move || stream_frames(stream, writer, rx))?;
+
+        Ok(Self {
+            tx,
+            filter,
+            snaplen: snaplen as usize,
+            dropped: Arc::default(),
+        })
+    }
+
+    /// Queues a frame to be written, if it matches the capture filter.  The frame is dropped
+    /// if the queue is full
+    ///
+    /// ### Arguments
+    /// * `port` - Port the frame was received on
+    /// * `name` - Name of the port, if any
+    /// * `pkt` - Ethernet frame (header and payload)
+    pub fn log(&self, port: usize, name: Option<&str>, pkt: &[u8]) {
+        if let Some(ref filter) = self.filter {
+            if !filter.matches(pkt) {
+                return;
+            }
+        }
+
+        let frame = CapturedFrame {
+            port,
+            name: name.map(String::from),
+            timestamp: UNIX_EPOCH.elapsed().unwrap_or_default(),
+            orig_len: pkt.len() as u32,
+            data: pkt[..pkt.len().min(self.snaplen)].to_vec(),
+        };
+
+        match self.tx.try_send(frame) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                tracing::trace!(port, "[capture] capture thread stopped, dropping frame");
+            }
+        }
+    }
+
+    /// Returns the number of frames dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the thread writing captured frames has stopped
+    pub fn is_detached(&self) -> bool {
+        self.tx.is_disconnected()
+    }
+}
+
+impl Taps {
+    /// Attaches a live capture
+    ///
+    /// ### Arguments
+    /// * `tap` - Capture streaming to a client
+    pub fn attach(&self, tap: Capture) {
+        self.0.write().push(tap);
+    }
+
+    /// Returns true if no live capture is attached
+    pub fn is_empty(&self) -> bool {
+        self.0.read().is_empty()
+    }
+
+    /// Queues a frame on each live capture, removing the captures whose clients have detached
+    ///
+    /// ### Arguments
+    /// * `port` - Port the frame was received on
+    /// * `name` - Name of the port, if any
+    /// * `pkt` - Ethernet frame (header and payload)
+    pub fn log(&self, port: usize, name: Option<&str>, pkt: &[u8]) {
+        let mut detached = false;
+        for tap in self.0.read().iter() {
+            tap.log(port, name, pkt);
+            detached |= tap.is_detached();
+        }
+
+        if detached {
+            self.0.write().retain(|tap| match tap.is_detached() {
+                true => {
+                    tracing::debug!(dropped = tap.dropped(), "[capture] live capture detached");
+                    false
+                }
+                false => true,
+            });
+        }
+    }
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Wraps a writer, writing the pcapng section header
+    ///
+    /// ### Arguments
+    /// * `writer` - Destination of the pcapng blocks
+    /// * `snaplen` - Maximum number of bytes saved of each frame
+    fn new(writer: W, snaplen: u32) -> Result<Self, NetworkError> {
+        Ok(Self {
+            writer: PcapNgWriter::new(writer)?,
+            snaplen,
+            interfaces: HashMap::new(),
+        })
+    }
+
+    /// Writes a frame, describing the port it was received on first if the port has not been
+    /// seen yet.  Returns the number of bytes written
+    ///
+    /// ### Arguments
+    /// * `frame` - Captured frame
+    fn write(&mut self, frame: CapturedFrame) -> Result<u64, NetworkError> {
+        let mut size = 0;
+        let key = (frame.port, frame.name);
+        let interface_id = match self.interfaces.get(&key) {
+            Some(id) => *id,
+            None => {
+                let mut options = vec![InterfaceDescriptionOption::IfName(Cow::Owned(format!(
+                    "port{}",
+                    key.0
+                )))];
+
+                if let Some(ref name) = key.1 {
+                    options.push(InterfaceDescriptionOption::IfDescription(Cow::Owned(
+                        name.clone(),
+                    )));
+                }
+
+                size += self.writer.write_pcapng_block(InterfaceDescriptionBlock {
+                    linktype: DataLink::ETHERNET,
+                    snaplen: self.snaplen,
+                    options,
+                })? as u64;
+
+                let id = self.interfaces.len() as u32;
+                self.interfaces.insert(key, id);
+                id
+            }
+        };
+
+        size += self.writer.write_pcapng_block(EnhancedPacketBlock {
+            interface_id,
+            timestamp: frame.timestamp,
+            original_len: frame.orig_len,
+            data: Cow::Owned(frame.data),
+            options: vec![EnhancedPacketOption::Flags(EPB_FLAG_INBOUND)],
+        })? as u64;
+
+        Ok(size)
+    }
+
+    /// Flushes the blocks buffered by the underlying writer
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.get_mut().flush()
+    }
+}
+
+/// Returns true if the client connected to a socket has closed its end
+///
+/// ### Arguments
+/// * `stream` - Socket connected to the client
+fn client_closed(stream: &UnixStream) -> bool {
+    let mut buf = [0u8; 1];
+    match nix::sys::socket::recv(
+        stream.as_raw_fd(),
+        &mut buf,
+        MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT,
+    ) {
+        Ok(0) => true,
+        Ok(_) | Err(nix::errno::Errno::EAGAIN) => false,
+        Err(_) => true,
+    }
+}
+
+/// Writes captured frames to a client until the client detaches (or the bridge stops),
+/// flushing the stream whenever the queue is empty
+///
+/// ### Arguments
+/// * `stream` - Socket connected to the client
+/// * `writer` - Writer wrapping the socket
+/// * `rx` - Queue of frames waiting to be written
+fn stream_frames(
+    stream: UnixStream,
+    mut writer: FrameWriter<BufWriter<UnixStream>>,
+    rx: Receiver<CapturedFrame>,
+) {
+    loop {
+        match rx.recv_timeout(STREAM_IDLE_INTERVAL) {
+            Ok(frame) => {
+                if writer.write(frame).is_err() {
+                    break;
+                }
+
+                if rx.is_empty() && writer.flush().is_err() {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => match client_closed(&stream) {
+                true => break,
+                false => {
+                    writer.flush().ok();
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                writer.flush().ok();
+                break;
+            }
+        }
+    }
+
+    tracing::debug!("[capture] live capture stream closed");
+}
+
+/// Returns the path of a rotated capture file
+///
+/// ### Arguments
+/// * `path` - Path of the capture file being written
+/// * `idx` - Index of the rotated file (1 being the most recent)
+fn rotated_path(path: &Path, idx: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{idx}"));
+    PathBuf::from(name)
+}
+
+impl CaptureWriter {
+    /// Opens a capture file, rotating any previous capture out of the way if rotation is
+    /// enabled (or overwriting it otherwise)
+    ///
+    /// ### Arguments
+    /// * `path` - Path of the capture file
+    /// * `cfg` - Rotation and snaplen settings
+    fn new(path: PathBuf, cfg: &CaptureConfig) -> Result<Self, NetworkError> {
+        let max_size = cfg.max_size.map(|mb| mb * 1_000_000);
+        let interval = cfg.interval.map(Duration::from_secs);
+        let files = cfg.files.max(1);
+
+        let exists = path.metadata().map(|m| m.len() > 0).unwrap_or(false);
+        if exists && (max_size.is_some() || interval.is_some()) {
+            Self::shift(&path, files)?;
+        }
+
+        Ok(Self {
+            writer: Self::create(&path, cfg.snaplen)?,
+            path,
+            snaplen: cfg.snaplen,
+            max_size,
+            interval,
+            files,
+            size: 0,
+            opened: Instant::now(),
+        })
+    }
+
+    /// Creates (or truncates) a capture file, writing the pcapng section header
+    ///
+    /// ### Arguments
+    /// * `path` - Path of the capture file
+    /// * `snaplen` - Maximum number of bytes saved of each frame
+    fn create(path: &Path, snaplen: u32) -> Result<FrameWriter<BufWriter<File>>, NetworkError> {
+        let file = File::create(path)?;
+        FrameWriter::new(BufWriter::new(file), snaplen)
+    }
+
+    /// Renames the capture file and previously rotated files to make room for a new file,
+    /// removing the oldest files beyond the retention count
+    ///
+    /// ### Arguments
+    /// * `path` - Path of the capture file
+    /// * `files` - Number of files kept, including the file being written
+    fn shift(path: &Path, files: usize) -> Result<(), NetworkError> {
+        if files == 1 {
+            return Ok(());
+        }
+
+        match std::fs::remove_file(rotated_path(path, files - 1)) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error.into()),
+            _ => (),
+        }
+
+        for idx in (1..files - 1).rev() {
+            let from = rotated_path(path, idx);
+            if from.exists() {
+                std::fs::rename(from, rotated_path(path, idx + 1))?;
+            }
+        }
+
+        std::fs::rename(path, rotated_path(path, 1))?;
+        Ok(())
+    }
+
+    /// Closes the current capture file and starts a new one
+    fn rotate(&mut self) -> Result<(), NetworkError> {
+        self.writer.flush()?;
+        Self::shift(&self.path, self.files)?;
+        self.writer = Self::create(&self.path, self.snaplen)?;
+        self.size = 0;
+        self.opened = Instant::now();
+
+        tracing::debug!(path = %self.path.display(), "[capture] rotated capture file");
+        Ok(())
+    }
+
+    /// Returns true if the current file has reached its size or age limit
+    fn should_rotate(&self) -> bool {
+        self.max_size.map(|max| self.size >= max).unwrap_or(false)
+            || self
+                .interval
+                .map(|interval| self.opened.elapsed() >= interval)
+                .unwrap_or(false)
+    }
+
+    /// Writes a frame to the capture file, rotating the file first if it has reached its
+    /// size or age limit
+    ///
+    /// ### Arguments
+    /// * `frame` - Captured frame
+    fn write(&mut self, frame: CapturedFrame) -> Result<(), NetworkError> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+
+        self.size += self.writer.write(frame)?;
+        Ok(())
+    }
+
+    /// Writes captured frames until all capture handles are dropped, flushing the file
+    /// whenever the queue is empty
+    ///
+    /// ### Arguments
+    /// * `rx` - Queue of frames waiting to be written
+    fn run(mut self, rx: Receiver<CapturedFrame>) {
+        while let Ok(frame) = rx.recv() {
+            if let Err(error) = self.write(frame) {
+                tracing::warn!(%error, "[capture] unable to write frame");
+            }
+
+            if rx.is_empty() {
+                self.writer.flush().ok();
+            }
+        }
+
+        self.writer.flush().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, path::PathBuf, time::Duration};
+
+    use pcap_file::pcapng::{Block, PcapNgReader};
+
+    use crate::config::capture::CaptureConfig;
+
+    use super::{rotated_path, CaptureWriter, CapturedFrame};
+
+    fn frame(port: usize, len: usize) -> CapturedFrame {
+        CapturedFrame {
+            port,
+            name: Some(format!("port-{port}")),
+            timestamp: Duration::from_secs(1),
+            orig_len: len as u32,
+            data: vec![0u8; len],
+        }
+    }
+
+    /// Returns the number of interface and packet blocks in a capture file
+    fn count_blocks(path: &PathBuf) -> (usize, usize) {
+        let mut reader = PcapNgReader::new(File::open(path).unwrap()).unwrap();
+        let (mut interfaces, mut packets) = (0, 0);
+        while let Some(block) = reader.next_block() {
+            match block.unwrap() {
+                Block::InterfaceDescription(_) => interfaces += 1,
+                Block::EnhancedPacket(_) => packets += 1,
+                _ => (),
+            }
+        }
+
+        (interfaces, packets)
+    }
+
+    #[test]
+    fn capture_rotation() {
+        let dir = std::env::temp_dir().join(format!("oathgate-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.pcapng");
+
+        let cfg = CaptureConfig {
+            max_size: Some(1),
+            files: 2,
+            ..Default::default()
+        };
+
+        let mut writer = CaptureWriter::new(path.clone(), &cfg).unwrap();
+
+        // each port is described once per file
+        writer.write(frame(0, 600_000)).unwrap();
+        writer.write(frame(1, 100)).unwrap();
+        writer.write(frame(0, 600_000)).unwrap();
+
+        // the file is over the size limit, the next frame starts a new file
+        writer.write(frame(1, 600_000)).unwrap();
+        writer.write(frame(1, 600_000)).unwrap();
+        writer.write(frame(2, 100)).unwrap();
+        drop(writer);
+
+        // only the most recent rotated file is kept
+        assert_eq!(count_blocks(&path), (1, 1));
+        assert_eq!(count_blocks(&rotated_path(&path, 1)), (1, 2));
+        assert!(!rotated_path(&path, 2).exists());
+
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
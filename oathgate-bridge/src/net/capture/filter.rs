@@ -0,0 +1,429 @@
+//! Capture filters
+//!
+//! Filters use a subset of the tcpdump (BPF) expression syntax.  An expression is made of
+//! primitives, optionally negated with `not` and combined with `and` / `or` (`and` binds
+//! tighter than `or`, and may be omitted, e.g. `tcp port 22`):
+//!
+//! * `ether [src|dst] [host] <mac>` - Source and/or destination MAC address
+//! * `[src|dst] host <ip>` - Source and/or destination IPv4 or IPv6 address
+//! * `[src|dst] net <network>` - Source and/or destination address within a network
+//! * `[src|dst] port <port>` - Source and/or destination TCP or UDP port
+//! * `vlan <id>` - 802.1Q tag of the frame
+//! * `arp`, `ip`, `ip6`, `icmp`, `icmp6`, `tcp`, `udp` - Protocol of the frame
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use oathgate_net::{
+    protocols::{NET_PROTOCOL_ICMP, NET_PROTOCOL_ICMPV6, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+    types::{EtherType, Ipv4Network, Ipv6Network, MacAddress},
+};
+use serde::{Deserialize, Serialize};
+
+/// A parsed capture filter expression
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CaptureFilter {
+    /// Original expression
+    expr: String,
+
+    /// Alternatives (joined by `or`) of terms that must all match (joined by `and`)
+    any: Vec<Vec<Term>>,
+}
+
+/// A primitive, optionally negated
+#[derive(Clone, Debug)]
+struct Term {
+    negate: bool,
+    primitive: Primitive,
+}
+
+/// Which address (or port) of a packet a primitive applies to
+#[derive(Clone, Copy, Debug)]
+enum Dir {
+    Src,
+    Dst,
+    Any,
+}
+
+#[derive(Clone, Debug)]
+enum Network {
+    V4(Ipv4Network),
+    V6(Ipv6Network),
+}
+
+#[derive(Clone, Debug)]
+enum Primitive {
+    Ether(Dir, MacAddress),
+    Net(Dir, Network),
+    Port(Dir, u16),
+    Vlan(u16),
+    Arp,
+    Ip,
+    Ip6,
+    Protocol(u8),
+}
+
+/// Headers of a frame, as far as they could be parsed
+struct Headers {
+    src: MacAddress,
+    dst: MacAddress,
+    vlan: Option<u16>,
+    ethertype: u16,
+
+    /// Source address, destination address and protocol of an IPv4 or IPv6 packet
+    ip: Option<(IpAddr, IpAddr, u8)>,
+
+    /// Source and destination port of a TCP or UDP segment
+    ports: Option<(u16, u16)>,
+}
+
+impl CaptureFilter {
+    /// Returns true if an ethernet frame matches this filter
+    ///
+    /// ### Arguments
+    /// * `pkt` - Ethernet frame (header and payload)
+    pub fn matches(&self, pkt: &[u8]) -> bool {
+        let Some(hdrs) = Headers::parse(pkt) else {
+            return false;
+        };
+
+        self.any
+            .iter()
+            .any(|all| all.iter().all(|term| term.matches(&hdrs)))
+    }
+}
+
+impl Term {
+    fn matches(&self, hdrs: &Headers) -> bool {
+        self.primitive.matches(hdrs) != self.negate
+    }
+}
+
+impl Dir {
+    /// Returns true if the source and/or destination value satisfies a predicate
+    fn check<T, F: Fn(T) -> bool>(&self, src: T, dst: T, f: F) -> bool {
+        match self {
+            Self::Src => f(src),
+            Self::Dst => f(dst),
+            Self::Any => f(src) || f(dst),
+        }
+    }
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self, ip) {
+            (Self::V4(net), IpAddr::V4(ip)) => net.contains(ip),
+            (Self::V6(net), IpAddr::V6(ip)) => net.contains(ip),
+            _ => false,
+        }
+    }
+}
+
+impl Primitive {
+    fn matches(&self, hdrs: &Headers) -> bool {
+        match self {
+            Self::Ether(dir, mac) => dir.check(hdrs.src, hdrs.dst, |addr| addr == *mac),
+            Self::Net(dir, net) => hdrs
+                .ip
+                .map(|(src, dst, _)| dir.check(src, dst, |ip| net.contains(ip)))
+                .unwrap_or(false),
+            Self::Port(dir, port) => hdrs
+                .ports
+                .map(|(src, dst)| dir.check(src, dst, |p| p == *port))
+                .unwrap_or(false),
+            Self::Vlan(vlan) => hdrs.vlan == Some(*vlan),
+            Self::Arp => hdrs.ethertype == EtherType::ARP as u16,
+            Self::Ip => hdrs.ethertype == EtherType::IPv4 as u16,
+            Self::Ip6 => hdrs.ethertype == EtherType::IPv6 as u16,
+            Self::Protocol(proto) => hdrs.ip.map(|(_, _, p)| p == *proto).unwrap_or(false),
+        }
+    }
+}
+
+impl Headers {
+    /// Parses the ethernet, ip and transport headers of a frame.  Returns None if the frame
+    /// is too short to contain an ethernet header
+    ///
+    /// ### Arguments
+    /// * `pkt` - Ethernet frame (header and payload)
+    fn parse(pkt: &[u8]) -> Option<Self> {
+        let u16_at = |buf: &[u8], idx: usize| {
+            buf.get(idx..idx + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        };
+
+        let dst = MacAddress::parse(pkt.get(0..6)?).ok()?;
+        let src = MacAddress::parse(pkt.get(6..12)?).ok()?;
+        let mut ethertype = u16_at(pkt, 12)?;
+        let mut vlan = None;
+        let mut offset = 14;
+
+        if ethertype == EtherType::Vlan as u16 {
+            vlan = Some(u16_at(pkt, 14)? & 0x0FFF);
+            ethertype = u16_at(pkt, 16)?;
+            offset = 18;
+        }
+
+        let payload = &pkt[offset.min(pkt.len())..];
+        let (ip, transport) = match ethertype {
+            t if t == EtherType::IPv4 as u16 && payload.len() >= 20 => {
+                let ihl = usize::from(payload[0] & 0x0F) * 4;
+                let fragment = u16_at(payload, 6).unwrap_or(0) & 0x1FFF;
+                let src = Ipv4Addr::new(payload[12], payload[13], payload[14], payload[15]);
+                let dst = Ipv4Addr::new(payload[16], payload[17], payload[18], payload[19]);
+                let transport = match fragment {
+                    0 => payload.get(ihl..),
+                    _ => None,
+                };
+
+                (Some((src.into(), dst.into(), payload[9])), transport)
+            }
+            t if t == EtherType::IPv6 as u16 && payload.len() >= 40 => {
+                let mut src = [0u8; 16];
+                let mut dst = [0u8; 16];
+                src.copy_from_slice(&payload[8..24]);
+                dst.copy_from_slice(&payload[24..40]);
+                let (src, dst) = (Ipv6Addr::from(src), Ipv6Addr::from(dst));
+
+                // extension headers are not followed
+                (
+                    Some((src.into(), dst.into(), payload[6])),
+                    payload.get(40..),
+                )
+            }
+            _ => (None, None),
+        };
+
+        let ports = match (ip, transport) {
+            (Some((_, _, NET_PROTOCOL_TCP | NET_PROTOCOL_UDP)), Some(transport)) => {
+                u16_at(transport, 0).zip(u16_at(transport, 2))
+            }
+            _ => None,
+        };
+
+        Some(Self {
+            src,
+            dst,
+            vlan,
+            ethertype,
+            ip,
+            ports,
+        })
+    }
+}
+
+/// Parses the value following a keyword
+fn parse_value<'a, T: FromStr, I: Iterator<Item = &'a str>>(
+    tokens: &mut std::iter::Peekable<I>,
+    keyword: &str,
+) -> Result<T, String> {
+    let value = tokens
+        .next()
+        .ok_or_else(|| format!("missing value after `{keyword}`"))?;
+
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for `{keyword}`: {value}"))
+}
+
+/// Parses a network (or a single address) in CIDR notation
+fn parse_network(value: &str) -> Result<Network, String> {
+    let invalid = || format!("invalid address: {value}");
+    let (ip, bits) = match value.split_once('/') {
+        Some((ip, bits)) => (ip, Some(bits.parse::<u8>().map_err(|_| invalid())?)),
+        None => (value, None),
+    };
+
+    match ip.parse::<IpAddr>().map_err(|_| invalid())? {
+        IpAddr::V4(ip) => match bits.unwrap_or(32) {
+            bits @ 0..=32 => Ok(Network::V4(Ipv4Network::new(ip, bits))),
+            _ => Err(invalid()),
+        },
+        IpAddr::V6(ip) => match bits.unwrap_or(128) {
+            bits @ 0..=128 => Ok(Network::V6(Ipv6Network::new(ip, bits))),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+impl FromStr for CaptureFilter {
+    type Err = String;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let mut tokens = expr.split_whitespace().peekable();
+        let mut any = vec![Vec::new()];
+        let mut negate = false;
+
+        // set when an operator is waiting for its right-hand side
+        let mut pending = false;
+
+        while let Some(token) = tokens.next() {
+            let primitive = match token {
+                "or" | "||" if !negate && any.last().is_some_and(|all| !all.is_empty()) => {
+                    any.push(Vec::new());
+                    pending = true;
+                    continue;
+                }
+                "and" | "&&" if !negate && any.last().is_some_and(|all| !all.is_empty()) => {
+                    pending = true;
+                    continue;
+                }
+                "not" | "!" => {
+                    negate = !negate;
+                    continue;
+                }
+                "ether" => {
+                    let dir = match tokens.peek() {
+                        Some(&"src") => Dir::Src,
+                        Some(&"dst") => Dir::Dst,
+                        _ => Dir::Any,
+                    };
+
+                    if !matches!(dir, Dir::Any) {
+                        tokens.next();
+                    }
+
+                    if tokens.peek() == Some(&"host") {
+                        tokens.next();
+                    }
+
+                    Primitive::Ether(dir, parse_value(&mut tokens, "ether")?)
+                }
+                "src" | "dst" | "host" | "net" | "port" => {
+                    let (dir, keyword) = match token {
+                        "src" => (Dir::Src, tokens.next()),
+                        "dst" => (Dir::Dst, tokens.next()),
+                        _ => (Dir::Any, Some(token)),
+                    };
+
+                    match keyword {
+                        Some(keyword @ ("host" | "net")) => {
+                            let value = tokens
+                                .next()
+                                .ok_or_else(|| format!("missing value after `{keyword}`"))?;
+                            Primitive::Net(dir, parse_network(value)?)
+                        }
+                        Some("port") => Primitive::Port(dir, parse_value(&mut tokens, "port")?),
+                        _ => return Err(format!("expected host, net or port after `{token}`")),
+                    }
+                }
+                "vlan" => Primitive::Vlan(parse_value(&mut tokens, "vlan")?),
+                "arp" => Primitive::Arp,
+                "ip" => Primitive::Ip,
+                "ip6" => Primitive::Ip6,
+                "icmp" => Primitive::Protocol(NET_PROTOCOL_ICMP),
+                "icmp6" => Primitive::Protocol(NET_PROTOCOL_ICMPV6),
+                "tcp" => Primitive::Protocol(NET_PROTOCOL_TCP),
+                "udp" => Primitive::Protocol(NET_PROTOCOL_UDP),
+                token => return Err(format!("unexpected token in filter: {token}")),
+            };
+
+            if let Some(all) = any.last_mut() {
+                all.push(Term { negate, primitive });
+            }
+
+            negate = false;
+            pending = false;
+        }
+
+        if negate || pending || any.iter().any(|all| all.is_empty()) {
+            return Err(format!("incomplete filter: {expr}"));
+        }
+
+        Ok(Self {
+            expr: expr.trim().to_owned(),
+            any,
+        })
+    }
+}
+
+impl TryFrom<String> for CaptureFilter {
+    type Error = String;
+
+    fn try_from(expr: String) -> Result<Self, Self::Error> {
+        expr.parse()
+    }
+}
+
+impl From<CaptureFilter> for String {
+    fn from(filter: CaptureFilter) -> Self {
+        filter.expr
+    }
+}
+
+impl Display for CaptureFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use oathgate_net::{
+        protocols::NET_PROTOCOL_TCP,
+        types::{EtherType, MacAddress},
+        EthernetFrame, Ipv4Packet,
+    };
+
+    use super::CaptureFilter;
+
+    fn tcp(src: Ipv4Addr, dst: Ipv4Addr, dport: u16, vlan: Option<u16>) -> Vec<u8> {
+        let mut payload = [0u8; 20];
+        payload[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        payload[2..4].copy_from_slice(&dport.to_be_bytes());
+        payload[12] = 0x50;
+
+        let ipv4 = Ipv4Packet::new(src, dst, NET_PROTOCOL_TCP, &payload);
+        let mac = MacAddress::parse(&[0x52, 0x54, 0x00, 0x00, 0x00, 0x01]).unwrap();
+        let mut pkt = EthernetFrame::new(mac, MacAddress::broadcast(), EtherType::IPv4)
+            .with_vlan(vlan)
+            .to_bytes();
+        pkt.extend_from_slice(&ipv4.into_bytes());
+        pkt
+    }
+
+    #[test]
+    fn capture_filter_match() {
+        let vm = Ipv4Addr::new(10, 67, 213, 50);
+        let remote = Ipv4Addr::new(198, 51, 100, 7);
+        let ssh = tcp(vm, remote, 22, None);
+        let https = tcp(vm, remote, 443, Some(20));
+
+        let filter: CaptureFilter = "tcp port 22 and host 10.67.213.50".parse().unwrap();
+        assert!(filter.matches(&ssh));
+        assert!(!filter.matches(&https));
+
+        let filter: CaptureFilter = "dst net 198.51.100.0/24 and not port 22".parse().unwrap();
+        assert!(!filter.matches(&ssh));
+        assert!(filter.matches(&https));
+
+        let filter: CaptureFilter = "vlan 20 or ether src 52:54:00:00:00:01".parse().unwrap();
+        assert!(filter.matches(&ssh));
+        assert!(filter.matches(&https));
+
+        let filter: CaptureFilter = "udp or arp".parse().unwrap();
+        assert!(!filter.matches(&ssh));
+    }
+
+    #[test]
+    fn capture_filter_invalid() {
+        for expr in [
+            "",
+            "tcp and",
+            "port",
+            "host 10.0.0.1/40",
+            "not",
+            "or tcp",
+            "bogus",
+        ] {
+            assert!(expr.parse::<CaptureFilter>().is_err(), "{expr}");
+        }
+    }
+}
@@ -1,97 +1,383 @@
 //! Simple DHCP server
 
 use std::{
-    collections::{HashMap, VecDeque},
-    net::Ipv4Addr,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::File,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use dhcproto::{v4, Decodable, Decoder, Encodable, Encoder};
+use ipnet::Ipv4Net;
 use oathgate_net::{
     types::{Ipv4Network, MacAddress},
     ProtocolError,
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::config::dhcp::DhcpConfig;
+use crate::config::{
+    dhcp::{DhcpConfig, DhcpReservation, DhcpRoute},
+    dns::DnsConfig,
+};
+
+use super::{
+    dns::HostTable,
+    router::handler::{PortHandler, ServiceCounters},
+    NetworkError,
+};
+
+/// Amount of time an offered address is reserved for a client
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Amount of time a declined address (i.e., in use by another host) is withheld from the pool
+const DECLINE_TIMEOUT: Duration = Duration::from_secs(600);
 
-use super::router::handler::PortHandler;
+/// Largest reply that fits in a single ethernet frame (1500 bytes, less the ip/udp headers)
+const MAX_MESSAGE_SZ: usize = 1472;
+
+/// State of an address handed out by the server
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LeaseState {
+    /// Address was offered in response to a DISCOVER
+    Offered,
+
+    /// Address was acknowledged and is in use by the client
+    Bound,
+
+    /// Client reported the address is in use by another host
+    Declined,
+}
+
+#[derive(Debug)]
+struct Lease {
+    /// Client that holds the lease
+    mac: MacAddress,
+
+    /// State of the lease
+    state: LeaseState,
+
+    /// Time the lease expires and the address returns to the pool
+    expires: Instant,
+
+    /// Hostname provided by the client
+    hostname: Option<String>,
+}
+
+/// A bound lease, as persisted to the lease file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DhcpLease {
+    /// Address leased to the client
+    pub ip: Ipv4Addr,
+
+    /// Client that holds the lease
+    pub mac: MacAddress,
+
+    /// Hostname provided by the client
+    pub hostname: Option<String>,
+
+    /// Time the lease expires, in seconds since the unix epoch
+    pub expires: u64,
+}
+
+/// Number of messages received from and sent to clients, by type
+#[derive(Debug, Default)]
+struct DhcpStats {
+    discover: u64,
+    offer: u64,
+    request: u64,
+    ack: u64,
+    nak: u64,
+    release: u64,
+    decline: u64,
+    inform: u64,
+
+    /// Messages that could not be decoded or answered (i.e., address space exhausted)
+    errors: u64,
+}
 
 #[derive(Debug)]
 pub struct DhcpServer {
     network: Ipv4Network,
     lease_time: u32,
 
+    /// First and last address of the pool
+    start: Ipv4Addr,
+    end: Ipv4Addr,
+
     available: VecDeque<Ipv4Addr>,
-    leased: HashMap<Ipv4Addr, MacAddress>,
+    leases: HashMap<Ipv4Addr, Lease>,
+
+    /// Static address assignments, keyed by the client's mac address
+    reservations: HashMap<MacAddress, DhcpReservation>,
+
+    /// Additional options sent to clients (ntp, mtu, routes, etc.)
+    options: Vec<v4::DhcpOption>,
+
+    /// Nameservers advertised to clients
+    dns: Vec<Ipv4Addr>,
+
+    /// Domain name advertised to clients
+    domain: Option<String>,
+
+    /// Hostnames of clients, resolvable by the DNS server
+    hosts: HostTable,
+
+    /// File to persist bound leases to (if any)
+    lease_file: Option<PathBuf>,
+
+    /// True if bound leases have changed since they were last persisted
+    dirty: bool,
+
+    /// Messages handled by the server
+    stats: DhcpStats,
 }
 
 impl DhcpServer {
-    pub fn new(network: Ipv4Network, cfg: DhcpConfig) -> Self {
+    /// Creates a new DHCP server
+    ///
+    /// ### Arguments
+    /// * `network` - Address (and subnet) of the router
+    /// * `cfg` - DHCP configuration
+    /// * `dns` - DNS configuration, determines the nameservers advertised to clients
+    /// * `hosts` - Table to register client hostnames in
+    pub fn new(
+        network: Ipv4Network,
+        cfg: DhcpConfig,
+        dns: &DnsConfig,
+        hosts: HostTable,
+    ) -> Result<Self, NetworkError> {
         if !network.contains(cfg.start) || !network.contains(cfg.end) {
-            // TODO: return error
+            return Err(NetworkError::Generic(Cow::Owned(format!(
+                "dhcp range ({} - {}) is outside of the router subnet ({network})",
+                cfg.start, cfg.end
+            ))));
         }
 
-        let mut next_avail = Ipv4Network::new(cfg.start, network.subnet_mask_bits());
-        tracing::debug!("[dhcp] created server: {network:?}");
+        if cfg.end < cfg.start {
+            return Err(NetworkError::Generic(Cow::Owned(format!(
+                "dhcp range end ({}) is before start ({})",
+                cfg.end, cfg.start
+            ))));
+        }
+
+        let options = Self::build_options(network, &cfg)?;
 
-        // generate list of available IPs
-        let mut available = VecDeque::new();
-        loop {
-            available.push_back(next_avail.ip());
-            match next_avail.next() {
-                None => break,
-                Some(nxt) if nxt.ip() > cfg.end => break,
-                Some(nxt) => next_avail = nxt,
+        let mut reservations = HashMap::new();
+        for reservation in cfg.reservations {
+            let ip = reservation.ip;
+            if !network.contains(ip)
+                || ip == network.ip()
+                || ip == network.network()
+                || ip == network.broadcast()
+            {
+                return Err(NetworkError::Generic(Cow::Owned(format!(
+                    "dhcp reservation ({ip}) is not a valid host address in {network}"
+                ))));
             }
+
+            if reservations.values().any(|r: &DhcpReservation| r.ip == ip) {
+                return Err(NetworkError::Generic(Cow::Owned(format!(
+                    "dhcp reservation ({ip}) is assigned to multiple clients"
+                ))));
+            }
+
+            reservations.insert(reservation.mac, reservation);
+        }
+
+        tracing::debug!("[dhcp] created server: {network:?}");
+
+        // generate list of available IPs, skipping the router and reserved addresses
+        let available = (u32::from(cfg.start)..=u32::from(cfg.end))
+            .map(Ipv4Addr::from)
+            .filter(|ip| {
+                *ip != network.ip() && *ip != network.network() && *ip != network.broadcast()
+            })
+            .filter(|ip| !reservations.values().any(|r| r.ip == *ip))
+            .collect();
+
+        // advertise the router when it serves dns, otherwise send clients upstream
+        let (mut dns_servers, mut domain) = match dns.enabled {
+            true => (vec![network.ip()], dns.domain.clone()),
+            false => (dns.upstream.clone(), None),
+        };
+
+        if !cfg.dns.is_empty() {
+            dns_servers = cfg.dns;
+        }
+
+        if cfg.domain.is_some() {
+            domain = cfg.domain;
         }
 
-        Self {
+        let server = Self {
             network,
-            lease_time: 86400, // 1 day
+            lease_time: cfg.lease_time,
+            start: cfg.start,
+            end: cfg.end,
             available,
-            leased: HashMap::new(),
+            leases: HashMap::new(),
+            reservations,
+            options,
+            dns: dns_servers,
+            domain,
+            hosts,
+            lease_file: None,
+            dirty: false,
+            stats: DhcpStats::default(),
+        };
+
+        // an ack carries every configured option, so it is the largest reply sent to clients
+        let ack = server.build_message(
+            &v4::Message::default(),
+            Some(server.end),
+            v4::MessageType::Ack,
+        );
+        let len = ack
+            .to_vec()
+            .map_err(|e| NetworkError::Generic(Cow::Owned(format!("invalid dhcp options: {e}"))))?
+            .len();
+
+        if len > MAX_MESSAGE_SZ {
+            return Err(NetworkError::Generic(Cow::Owned(format!(
+                "dhcp options are too large, replies would be {len} bytes (max {MAX_MESSAGE_SZ})"
+            ))));
         }
+
+        Ok(server)
     }
 
-    pub fn lease_ip(&mut self, msg: &v4::Message) -> Option<Ipv4Network> {
-        let client_mac = MacAddress::parse(msg.chaddr()).unwrap();
+    /// Persists bound leases to a file, restoring any unexpired leases already saved in it
+    ///
+    /// ### Arguments
+    /// * `path` - Path to the lease file
+    pub fn persist<P: Into<PathBuf>>(&mut self, path: P) -> Result<(), NetworkError> {
+        let path = path.into();
+        let now = Instant::now();
+        let unix_now = unix_now();
 
-        let ip = match self.get_requested_ip(msg) {
-            Some(ria) => match self.leased.get(&ria) {
-                None => {
-                    self.available.retain(|ip| *ip != ria);
-                    Some(ria)
-                }
-                Some(mac) if *mac == client_mac => {
-                    self.available.retain(|ip| *ip != ria);
-                    Some(ria)
-                }
-                _ => self.available.pop_front(),
-            },
-            None => self.available.pop_front(),
-        };
+        for lease in DhcpLease::load(&path)? {
+            if lease.expires <= unix_now || !self.is_available_for(lease.ip, lease.mac) {
+                continue;
+            }
 
-        match ip {
-            Some(ip) => {
-                self.leased.insert(ip, client_mac);
-                Some(Ipv4Network::new(ip, self.network.subnet_mask_bits()))
+            tracing::debug!(ip = %lease.ip, mac = %lease.mac, "[dhcp] restored lease");
+            let expires = now + Duration::from_secs(lease.expires - unix_now);
+            self.assign(lease.ip, lease.mac, LeaseState::Bound, expires);
+            let hostname = lease
+                .hostname
+                .filter(|name| self.hosts.insert(name, lease.ip));
+            if let Some(l) = self.leases.get_mut(&lease.ip) {
+                l.hostname = hostname;
             }
-            None => {
-                // no ips available
-                None
+        }
+
+        self.lease_file = Some(path);
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Returns all bound leases
+    pub fn leases(&self) -> Vec<DhcpLease> {
+        let now = Instant::now();
+        let unix_now = unix_now();
+
+        self.leases
+            .iter()
+            .filter(|(_, lease)| lease.state == LeaseState::Bound)
+            .map(|(ip, lease)| DhcpLease {
+                ip: *ip,
+                mac: lease.mac,
+                hostname: lease.hostname.clone(),
+                expires: unix_now + lease.expires.saturating_duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+
+    /// Writes bound leases to the lease file (if configured)
+    fn save_leases(&mut self) {
+        self.dirty = false;
+        if let Some(ref path) = self.lease_file {
+            if let Err(error) = DhcpLease::save(path, &self.leases()) {
+                tracing::warn!(?error, path = %path.display(), "[dhcp] unable to save leases");
             }
         }
     }
 
-    fn get_requested_ip(&self, msg: &v4::Message) -> Option<Ipv4Addr> {
-        match msg.opts().get(v4::OptionCode::RequestedIpAddress) {
-            Some(v4::DhcpOption::RequestedIpAddress(ip)) => Some(*ip),
-            _ => None,
+    /// Handles a decoded DHCP message, returning the response to send (if any)
+    ///
+    /// ### Arguments
+    /// * `msg` - Message received from a client
+    /// * `now` - Current time
+    fn handle_message(
+        &mut self,
+        msg: v4::Message,
+        now: Instant,
+    ) -> Result<Option<v4::Message>, ProtocolError> {
+        self.expire(now);
+
+        let mac = MacAddress::parse(msg.chaddr())?;
+        let ty = msg
+            .opts()
+            .msg_type()
+            .ok_or_else(|| ProtocolError::Other("dhcp missing msg type".into()))?;
+
+        self.stats.record(ty);
+        let res = match ty {
+            v4::MessageType::Discover => self.handle_discover(msg, mac, now).map(Some),
+            v4::MessageType::Request => Ok(self.handle_request(msg, mac, now)),
+            v4::MessageType::Release => {
+                self.handle_release(msg, mac);
+                Ok(None)
+            }
+            v4::MessageType::Decline => {
+                self.handle_decline(msg, mac, now);
+                Ok(None)
+            }
+            v4::MessageType::Inform => Ok(Some(self.handle_inform(msg))),
+            v4::MessageType::Offer => {
+                tracing::debug!("DHCP-OFFER: should not occur, sent by server");
+                Ok(None)
+            }
+            v4::MessageType::Ack => {
+                tracing::debug!("DHCP-ACKNOWLEDGE: should not occur, sent by server");
+                Ok(None)
+            }
+            ty => {
+                tracing::debug!(?ty, "[dhcp] unsupported message type");
+                Ok(None)
+            }
+        };
+
+        if self.dirty {
+            self.save_leases();
         }
+
+        res
     }
 
-    pub fn handle_discover(&mut self, msg: v4::Message) -> Result<v4::Message, ProtocolError> {
+    pub fn handle_discover(
+        &mut self,
+        msg: v4::Message,
+        mac: MacAddress,
+        now: Instant,
+    ) -> Result<v4::Message, ProtocolError> {
         tracing::trace!("[dhcp] handling discover message");
-        let ip = match self.lease_ip(&msg) {
+
+        // prefer a reserved address, the client's current address, then the address it asked for
+        let reserved = self.reservations.get(&mac).map(|r| r.ip);
+        let ip = match self.lease_for(mac) {
+            _ if reserved.is_some() => reserved.filter(|ip| self.is_available_for(*ip, mac)),
+            Some(ip) => Some(ip),
+            None => match self.get_requested_ip(&msg) {
+                Some(ria) if self.is_available_for(ria, mac) => Some(ria),
+                _ => self.available.front().copied(),
+            },
+        };
+
+        let ip = match ip {
             Some(ip) => ip,
             None => {
                 tracing::warn!("dhcp ip address space exhausted");
@@ -99,29 +385,311 @@ impl DhcpServer {
             }
         };
 
-        let msg = self.build_message(&msg, ip, v4::MessageType::Offer);
-        Ok(msg)
+        if self.leases.get(&ip).map(|l| l.state) != Some(LeaseState::Bound) {
+            self.assign(ip, mac, LeaseState::Offered, now + OFFER_TIMEOUT);
+        }
+
+        Ok(self.build_message(&msg, Some(ip), v4::MessageType::Offer))
     }
 
-    pub fn handle_request(&mut self, msg: v4::Message) -> Result<v4::Message, ProtocolError> {
+    pub fn handle_request(
+        &mut self,
+        msg: v4::Message,
+        mac: MacAddress,
+        now: Instant,
+    ) -> Option<v4::Message> {
         tracing::trace!("[dhcp] handling request message");
 
-        let ip = match self.lease_ip(&msg) {
-            Some(ip) => ip,
+        let ip = match self.get_server_id(&msg) {
+            // SELECTING: client accepted an offer from another server
+            Some(sid) if sid != self.network.ip() => {
+                tracing::trace!(%sid, "[dhcp] client selected another server");
+                self.release_offers(mac);
+                return None;
+            }
+
+            // SELECTING: client accepted our offer
+            Some(_) => self.get_requested_ip(&msg),
+
+            // INIT-REBOOT (requested ip) or RENEWING / REBINDING (ciaddr)
+            None => self
+                .get_requested_ip(&msg)
+                .or_else(|| Some(msg.ciaddr()).filter(|ip| !ip.is_unspecified())),
+        };
+
+        let ip = match ip {
+            Some(ip) if self.is_available_for(ip, mac) => ip,
+            Some(ip) => {
+                tracing::debug!(%ip, %mac, "[dhcp] requested address not available, sending nak");
+                return Some(self.build_nak(&msg));
+            }
             None => {
-                tracing::warn!("dhcp ip address space exhausted");
-                return Err(ProtocolError::Other("address space exhausted".into()));
+                tracing::debug!(%mac, "[dhcp] request missing address, sending nak");
+                return Some(self.build_nak(&msg));
+            }
+        };
+
+        // a client only holds a single address
+        let stale = self
+            .leases
+            .iter()
+            .filter(|(lip, lease)| **lip != ip && lease.mac == mac)
+            .map(|(lip, _)| *lip)
+            .collect::<Vec<_>>();
+        for lip in stale {
+            self.free(lip);
+        }
+
+        let expires = now + Duration::from_secs(u64::from(self.lease_time));
+        self.assign(ip, mac, LeaseState::Bound, expires);
+
+        // a reserved hostname takes precedence over the one provided by the client
+        let hostname = match self.reservations.get(&mac).and_then(|r| r.hostname.clone()) {
+            Some(name) => Some(name),
+            None => match msg.opts().get(v4::OptionCode::Hostname) {
+                Some(v4::DhcpOption::Hostname(name)) => Some(name.clone()),
+                _ => None,
+            },
+        };
+
+        if let Some(name) = hostname {
+            tracing::trace!(%name, %ip, "[dhcp] registering hostname");
+            if self.hosts.insert(&name, ip) {
+                if let Some(lease) = self.leases.get_mut(&ip) {
+                    lease.hostname = Some(name);
+                }
+            }
+        }
+
+        Some(self.build_message(&msg, Some(ip), v4::MessageType::Ack))
+    }
+
+    /// Returns a client's address to the pool
+    ///
+    /// ### Arguments
+    /// * `msg` - RELEASE message
+    /// * `mac` - Client's mac address
+    fn handle_release(&mut self, msg: v4::Message, mac: MacAddress) {
+        let ip = msg.ciaddr();
+        match self.leases.get(&ip) {
+            Some(lease) if lease.mac == mac => {
+                tracing::debug!(%ip, %mac, "[dhcp] client released address");
+                self.free(ip);
+            }
+            _ => tracing::debug!(%ip, %mac, "[dhcp] release for unknown lease"),
+        }
+    }
+
+    /// Withholds an address a client reported is in use by another host
+    ///
+    /// ### Arguments
+    /// * `msg` - DECLINE message
+    /// * `mac` - Client's mac address
+    /// * `now` - Current time
+    fn handle_decline(&mut self, msg: v4::Message, mac: MacAddress, now: Instant) {
+        let ip = match self.get_requested_ip(&msg) {
+            Some(ip) => ip,
+            None => return,
+        };
+
+        match self.leases.get_mut(&ip) {
+            Some(lease) if lease.mac == mac => {
+                tracing::warn!(%ip, %mac, "[dhcp] client declined address, address in use");
+                self.dirty |= lease.state == LeaseState::Bound;
+                lease.state = LeaseState::Declined;
+                lease.expires = now + DECLINE_TIMEOUT;
+                self.hosts.remove(ip);
+            }
+            _ => tracing::debug!(%ip, %mac, "[dhcp] decline for unknown lease"),
+        }
+    }
+
+    /// Responds to a client (with an externally configured address) requesting parameters
+    ///
+    /// ### Arguments
+    /// * `msg` - INFORM message
+    fn handle_inform(&mut self, msg: v4::Message) -> v4::Message {
+        tracing::trace!("[dhcp] handling inform message");
+        self.build_message(&msg, None, v4::MessageType::Ack)
+    }
+
+    /// Returns expired leases to the pool
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires <= now)
+            .map(|(ip, _)| *ip)
+            .collect::<Vec<_>>();
+
+        for ip in expired {
+            tracing::debug!(%ip, "[dhcp] lease expired");
+            self.free(ip);
+        }
+    }
+
+    /// Assigns an address to a client, removing it from the pool
+    fn assign(&mut self, ip: Ipv4Addr, mac: MacAddress, state: LeaseState, expires: Instant) {
+        self.available.retain(|aip| *aip != ip);
+        self.dirty |= state == LeaseState::Bound;
+
+        // keep the hostname when a lease is renewed
+        let hostname = self
+            .leases
+            .remove(&ip)
+            .filter(|lease| lease.mac == mac)
+            .and_then(|lease| lease.hostname);
+
+        self.leases.insert(
+            ip,
+            Lease {
+                mac,
+                state,
+                expires,
+                hostname,
+            },
+        );
+    }
+
+    /// Removes a lease, returning the address to the end of the pool
+    fn free(&mut self, ip: Ipv4Addr) {
+        if let Some(lease) = self.leases.remove(&ip) {
+            if lease.state == LeaseState::Bound {
+                self.hosts.remove(ip);
+                self.dirty = true;
+            }
+
+            // reserved addresses never return to the pool
+            if !self.reservations.values().any(|r| r.ip == ip) {
+                self.available.push_back(ip);
             }
+        }
+    }
+
+    /// Returns outstanding offers made to a client to the pool
+    fn release_offers(&mut self, mac: MacAddress) {
+        let offers = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.mac == mac && lease.state == LeaseState::Offered)
+            .map(|(ip, _)| *ip)
+            .collect::<Vec<_>>();
+
+        for ip in offers {
+            self.free(ip);
+        }
+    }
+
+    /// Returns the address currently offered or bound to a client
+    fn lease_for(&self, mac: MacAddress) -> Option<Ipv4Addr> {
+        self.leases
+            .iter()
+            .find(|(_, lease)| lease.mac == mac && lease.state != LeaseState::Declined)
+            .map(|(ip, _)| *ip)
+    }
+
+    /// Returns true if an address is in the pool (or reserved for the client) and not leased
+    /// to a different client
+    fn is_available_for(&self, ip: Ipv4Addr, mac: MacAddress) -> bool {
+        let reserved = match self.reservations.get(&mac) {
+            Some(reservation) if reservation.ip != ip => return false,
+            Some(_) => true,
+            None if self.reservations.values().any(|r| r.ip == ip) => return false,
+            None => false,
         };
 
-        let msg = self.build_message(&msg, ip, v4::MessageType::Ack);
-        Ok(msg)
+        if !reserved && (ip < self.start || ip > self.end || ip == self.network.ip()) {
+            return false;
+        }
+
+        match self.leases.get(&ip) {
+            None => reserved || self.available.contains(&ip),
+            Some(lease) => lease.mac == mac && lease.state != LeaseState::Declined,
+        }
+    }
+
+    /// Builds the additional options configured for the server
+    ///
+    /// ### Arguments
+    /// * `network` - Address (and subnet) of the router
+    /// * `cfg` - DHCP configuration
+    fn build_options(
+        network: Ipv4Network,
+        cfg: &DhcpConfig,
+    ) -> Result<Vec<v4::DhcpOption>, NetworkError> {
+        use v4::{DhcpOption, OptionCode, UnknownOption};
+
+        let mut options = Vec::new();
+
+        if let Some(mtu) = cfg.mtu {
+            options.push(DhcpOption::InterfaceMtu(mtu));
+        }
+
+        if !cfg.ntp.is_empty() {
+            options.push(DhcpOption::NtpServers(cfg.ntp.clone()));
+        }
+
+        if !cfg.search.is_empty() {
+            let names = cfg
+                .search
+                .iter()
+                .map(|name| {
+                    name.parse::<dhcproto::Name>().map_err(|e| {
+                        NetworkError::Generic(Cow::Owned(format!(
+                            "invalid dhcp search domain ({name}): {e}"
+                        )))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            options.push(DhcpOption::DomainSearch(names));
+        }
+
+        if !cfg.routes.is_empty() {
+            let routes = classless_routes(network, &cfg.routes);
+            options.push(DhcpOption::ClasslessStaticRoute(routes));
+        }
+
+        // raw options are added last so they replace any of the options above
+        for raw in &cfg.options {
+            let data = raw
+                .bytes()
+                .map_err(|e| NetworkError::Generic(Cow::Owned(e)))?;
+            options.push(DhcpOption::Unknown(UnknownOption::new(
+                OptionCode::from(raw.code),
+                data,
+            )));
+        }
+
+        Ok(options)
+    }
+
+    fn get_requested_ip(&self, msg: &v4::Message) -> Option<Ipv4Addr> {
+        match msg.opts().get(v4::OptionCode::RequestedIpAddress) {
+            Some(v4::DhcpOption::RequestedIpAddress(ip)) => Some(*ip),
+            _ => None,
+        }
+    }
+
+    fn get_server_id(&self, msg: &v4::Message) -> Option<Ipv4Addr> {
+        match msg.opts().get(v4::OptionCode::ServerIdentifier) {
+            Some(v4::DhcpOption::ServerIdentifier(ip)) => Some(*ip),
+            _ => None,
+        }
     }
 
+    /// Builds a reply to a client
+    ///
+    /// ### Arguments
+    /// * `msg` - Message received from the client
+    /// * `ip` - Address leased to the client, or None if replying to an INFORM
+    /// * `ty` - Type of reply
     fn build_message(
         &self,
         msg: &v4::Message,
-        ip: Ipv4Network,
+        ip: Option<Ipv4Addr>,
         ty: v4::MessageType,
     ) -> v4::Message {
         use v4::DhcpOption;
@@ -131,7 +699,7 @@ impl DhcpServer {
         rmsg.set_opcode(dhcproto::v4::Opcode::BootReply);
         rmsg.set_htype(msg.htype());
         rmsg.set_xid(msg.xid());
-        rmsg.set_yiaddr(ip.ip());
+        rmsg.set_yiaddr(ip.unwrap_or(Ipv4Addr::UNSPECIFIED));
         if v4::MessageType::Ack == ty {
             rmsg.set_ciaddr(msg.ciaddr());
         }
@@ -139,8 +707,14 @@ impl DhcpServer {
         rmsg.set_giaddr(msg.giaddr());
         rmsg.set_chaddr(msg.chaddr());
         rmsg.opts_mut().insert(DhcpOption::MessageType(ty));
-        rmsg.opts_mut()
-            .insert(DhcpOption::AddressLeaseTime(self.lease_time));
+        if ip.is_some() {
+            rmsg.opts_mut()
+                .insert(DhcpOption::AddressLeaseTime(self.lease_time));
+            rmsg.opts_mut()
+                .insert(DhcpOption::Renewal(self.lease_time / 2));
+            rmsg.opts_mut()
+                .insert(DhcpOption::Rebinding(self.lease_time / 8 * 7));
+        }
         rmsg.opts_mut()
             .insert(DhcpOption::ServerIdentifier(self.network.ip()));
         rmsg.opts_mut()
@@ -149,54 +723,654 @@ impl DhcpServer {
             .insert(DhcpOption::BroadcastAddr(self.network.broadcast()));
         rmsg.opts_mut()
             .insert(DhcpOption::Router(vec![self.network.ip()]));
+        if !self.dns.is_empty() {
+            rmsg.opts_mut()
+                .insert(DhcpOption::DomainNameServer(self.dns.clone()));
+        }
+        if let Some(ref domain) = self.domain {
+            rmsg.opts_mut()
+                .insert(DhcpOption::DomainName(domain.clone()));
+        }
+        for opt in &self.options {
+            // raw options are keyed by their numeric code, so drop any known option they replace
+            let code = u8::from(v4::OptionCode::from(opt));
+            rmsg.opts_mut().remove(v4::OptionCode::from(code));
+            rmsg.opts_mut().insert(opt.clone());
+        }
+
+        rmsg
+    }
+
+    /// Builds a negative acknowledgement, informing the client its address is invalid
+    ///
+    /// ### Arguments
+    /// * `msg` - Message received from the client
+    fn build_nak(&self, msg: &v4::Message) -> v4::Message {
+        use v4::DhcpOption;
+
+        let mut rmsg = v4::Message::default();
+        rmsg.set_flags(msg.flags().set_broadcast());
+        rmsg.set_opcode(dhcproto::v4::Opcode::BootReply);
+        rmsg.set_htype(msg.htype());
+        rmsg.set_xid(msg.xid());
+        rmsg.set_giaddr(msg.giaddr());
+        rmsg.set_chaddr(msg.chaddr());
         rmsg.opts_mut()
-            .insert(DhcpOption::DomainNameServer(vec![[1, 1, 1, 1].into()]));
+            .insert(DhcpOption::MessageType(v4::MessageType::Nak));
+        rmsg.opts_mut()
+            .insert(DhcpOption::ServerIdentifier(self.network.ip()));
 
         rmsg
     }
 }
 
+impl DhcpLease {
+    /// Loads leases from a lease file, returning no leases if the file does not exist
+    ///
+    /// ### Arguments
+    /// * `path` - Path to the lease file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, NetworkError> {
+        read_leases(path)
+    }
+
+    /// Atomically replaces the contents of a lease file
+    ///
+    /// ### Arguments
+    /// * `path` - Path to the lease file
+    /// * `leases` - Leases to save
+    pub fn save<P: AsRef<Path>>(path: P, leases: &[Self]) -> Result<(), NetworkError> {
+        write_leases(path, leases)
+    }
+
+    /// Returns true if this lease has not expired
+    pub fn is_active(&self) -> bool {
+        self.expires > unix_now()
+    }
+}
+
+/// Converts routes into the entries of a classless static route option (RFC 3442)
+///
+/// Clients ignore the router option when this option is present, so a default route via the
+/// router is added unless one is configured
+///
+/// ### Arguments
+/// * `network` - Address (and subnet) of the router
+/// * `routes` - Configured routes
+fn classless_routes(network: Ipv4Network, routes: &[DhcpRoute]) -> Vec<(Ipv4Net, Ipv4Addr)> {
+    let mut entries = routes
+        .iter()
+        .filter_map(|r| {
+            Ipv4Net::new(r.network.network(), r.network.subnet_mask_bits())
+                .ok()
+                .map(|net| (net, r.gateway))
+        })
+        .collect::<Vec<_>>();
+
+    if !entries.iter().any(|(net, _)| net.prefix_len() == 0) {
+        entries.push((Ipv4Net::default(), network.ip()));
+    }
+
+    entries
+}
+
+/// Reads the leases stored in a lease file, returning no leases if the file does not exist
+///
+/// ### Arguments
+/// * `path` - Path to the lease file
+pub(super) fn read_leases<T: DeserializeOwned, P: AsRef<Path>>(
+    path: P,
+) -> Result<Vec<T>, NetworkError> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+
+    let leases = serde_yaml::from_reader(f)
+        .map_err(|e| NetworkError::Generic(Cow::Owned(format!("invalid lease file: {e}"))))?;
+    Ok(leases)
+}
+
+/// Atomically replaces the contents of a lease file
+///
+/// ### Arguments
+/// * `path` - Path to the lease file
+/// * `leases` - Leases to save
+pub(super) fn write_leases<T: Serialize, P: AsRef<Path>>(
+    path: P,
+    leases: &[T],
+) -> Result<(), NetworkError> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let f = File::create(&tmp)?;
+    serde_yaml::to_writer(f, leases)
+        .map_err(|e| NetworkError::Generic(Cow::Owned(format!("unable to write leases: {e}"))))?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Returns the current time, in seconds since the unix epoch
+pub(super) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl DhcpStats {
+    /// Counts a message received from, or sent to, a client
+    ///
+    /// ### Arguments
+    /// * `ty` - Type of the message
+    fn record(&mut self, ty: v4::MessageType) {
+        let counter = match ty {
+            v4::MessageType::Discover => &mut self.discover,
+            v4::MessageType::Offer => &mut self.offer,
+            v4::MessageType::Request => &mut self.request,
+            v4::MessageType::Ack => &mut self.ack,
+            v4::MessageType::Nak => &mut self.nak,
+            v4::MessageType::Release => &mut self.release,
+            v4::MessageType::Decline => &mut self.decline,
+            v4::MessageType::Inform => &mut self.inform,
+            _ => return,
+        };
+
+        *counter += 1;
+    }
+}
+
 impl PortHandler for DhcpServer {
     fn port(&self) -> u16 {
         67
     }
 
-    fn handle_port(&mut self, data: &[u8], buf: &mut [u8]) -> Result<usize, ProtocolError> {
+    fn handle_port(
+        &mut self,
+        src: SocketAddr,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        if src.is_ipv6() {
+            return Ok(0);
+        }
+
         tracing::trace!("[dhcp] got packet");
-        let msg = v4::Message::decode(&mut Decoder::new(data))
-            .map_err(|e| ProtocolError::Other(e.to_string()))?;
+        let rmsg = match v4::Message::decode(&mut Decoder::new(data))
+            .map_err(|e| ProtocolError::Other(e.to_string()))
+            .and_then(|msg| self.handle_message(msg, Instant::now()))
+        {
+            Ok(Some(rmsg)) => rmsg,
+            Ok(None) => return Ok(0),
+            Err(error) => {
+                self.stats.errors += 1;
+                return Err(error);
+            }
+        };
+
+        if let Some(ty) = rmsg.opts().msg_type() {
+            self.stats.record(ty);
+        }
 
         let mut vbuf = Vec::with_capacity(256);
         let mut encoder = Encoder::new(&mut vbuf);
+        rmsg.encode(&mut encoder)
+            .map_err(|e| ProtocolError::Other(e.to_string()))?;
 
-        let ops = msg.opts();
-        match ops
-            .msg_type()
-            .ok_or_else(|| ProtocolError::Other("dhcp missing msg type".into()))?
-        {
-            v4::MessageType::Discover => {
-                let rmsg = self.handle_discover(msg)?;
-                rmsg.encode(&mut encoder)
-                    .map_err(|e| ProtocolError::Other(e.to_string()))?;
-            }
-            v4::MessageType::Request => {
-                let rmsg = self.handle_request(msg)?;
-                rmsg.encode(&mut encoder)
-                    .map_err(|e| ProtocolError::Other(e.to_string()))?;
-            }
-            v4::MessageType::Offer => {
-                tracing::debug!("DHCP-OFFER: should not occur, sent by server")
-            }
-            v4::MessageType::Ack => {
-                tracing::debug!("DHCP-ACKNOWLEDGE: should not occur, sent by server")
-            }
-            v4::MessageType::Release => (),
-            v4::MessageType::Decline => (),
-            _ => (),
+        let len = vbuf.len();
+        if len > buf.len() {
+            self.stats.errors += 1;
+            return Err(ProtocolError::NotEnoughData(buf.len(), len));
         }
 
-        let len = vbuf.len();
         buf[0..len].copy_from_slice(&vbuf);
         Ok(len)
     }
+
+    fn tick(&mut self, now: Instant) {
+        // leases also expire when a message is received, but hostnames of expired leases
+        // must stop resolving on a quiet network
+        self.expire(now);
+        if self.dirty {
+            self.save_leases();
+        }
+    }
+
+    fn counters(&self) -> Option<ServiceCounters> {
+        let bound = self
+            .leases
+            .values()
+            .filter(|lease| lease.state == LeaseState::Bound)
+            .count();
+
+        let counters = BTreeMap::from([
+            (String::from("discover"), self.stats.discover),
+            (String::from("offer"), self.stats.offer),
+            (String::from("request"), self.stats.request),
+            (String::from("ack"), self.stats.ack),
+            (String::from("nak"), self.stats.nak),
+            (String::from("release"), self.stats.release),
+            (String::from("decline"), self.stats.decline),
+            (String::from("inform"), self.stats.inform),
+            (String::from("errors"), self.stats.errors),
+        ]);
+
+        let gauges = BTreeMap::from([
+            (String::from("leases"), bound as u64),
+            (String::from("available"), self.available.len() as u64),
+        ]);
+
+        Some(ServiceCounters {
+            service: String::from("dhcp"),
+            counters,
+            gauges,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use dhcproto::{v4, Encodable};
+    use oathgate_net::ProtocolError;
+
+    use crate::{
+        config::{
+            dhcp::{DhcpConfig, DhcpRawOption},
+            dns::DnsConfig,
+        },
+        net::{dns::HostTable, router::handler::PortHandler},
+    };
+
+    use super::DhcpServer;
+
+    const MAC_A: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0a];
+    const MAC_B: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x0b];
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn server() -> DhcpServer {
+        let cfg = "10.0.0.100-10.0.0.101".parse::<DhcpConfig>().unwrap();
+        DhcpServer::new(
+            "10.0.0.1/24".parse().unwrap(),
+            cfg,
+            &DnsConfig::default(),
+            HostTable::default(),
+        )
+        .unwrap()
+    }
+
+    fn message(
+        mac: &[u8],
+        ty: v4::MessageType,
+        ciaddr: Ipv4Addr,
+        opts: &[v4::DhcpOption],
+    ) -> v4::Message {
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        let mut msg = v4::Message::new(ciaddr, unspecified, unspecified, unspecified, mac);
+        msg.opts_mut().insert(v4::DhcpOption::MessageType(ty));
+        for opt in opts {
+            msg.opts_mut().insert(opt.clone());
+        }
+        msg
+    }
+
+    /// Runs a discover/request exchange, returning the type and address of the final reply
+    fn bind(
+        server: &mut DhcpServer,
+        mac: &[u8],
+        now: Instant,
+    ) -> (Option<v4::MessageType>, Ipv4Addr) {
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        let discover = message(mac, v4::MessageType::Discover, unspecified, &[]);
+        let offer = server.handle_message(discover, now).unwrap().unwrap();
+
+        let request = message(
+            mac,
+            v4::MessageType::Request,
+            unspecified,
+            &[
+                v4::DhcpOption::ServerIdentifier(ROUTER),
+                v4::DhcpOption::RequestedIpAddress(offer.yiaddr()),
+                v4::DhcpOption::Hostname(String::from("shard")),
+            ],
+        );
+        let ack = server.handle_message(request, now).unwrap().unwrap();
+        (ack.opts().msg_type(), ack.yiaddr())
+    }
+
+    #[test]
+    fn dhcp_range_outside_subnet() {
+        let cfg = "10.0.1.100-10.0.1.200".parse::<DhcpConfig>().unwrap();
+        let res = DhcpServer::new(
+            "10.0.0.1/24".parse().unwrap(),
+            cfg,
+            &DnsConfig::default(),
+            HostTable::default(),
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn dhcp_discover_request_ack() {
+        let mut server = server();
+        let (ty, ip) = bind(&mut server, &MAC_A, Instant::now());
+        assert_eq!(ty, Some(v4::MessageType::Ack));
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 100));
+    }
+
+    #[test]
+    fn dhcp_request_leased_address_nak() {
+        let mut server = server();
+        let now = Instant::now();
+        let (_, ip) = bind(&mut server, &MAC_A, now);
+
+        let request = message(
+            &MAC_B,
+            v4::MessageType::Request,
+            Ipv4Addr::UNSPECIFIED,
+            &[v4::DhcpOption::RequestedIpAddress(ip)],
+        );
+        let reply = server.handle_message(request, now).unwrap().unwrap();
+        assert_eq!(reply.opts().msg_type(), Some(v4::MessageType::Nak));
+
+        // outside of the pool
+        let request = message(
+            &MAC_B,
+            v4::MessageType::Request,
+            Ipv4Addr::UNSPECIFIED,
+            &[v4::DhcpOption::RequestedIpAddress(Ipv4Addr::new(
+                10, 0, 0, 50,
+            ))],
+        );
+        let reply = server.handle_message(request, now).unwrap().unwrap();
+        assert_eq!(reply.opts().msg_type(), Some(v4::MessageType::Nak));
+    }
+
+    #[test]
+    fn dhcp_release_returns_address() {
+        let mut server = server();
+        let now = Instant::now();
+        let (_, ip_a) = bind(&mut server, &MAC_A, now);
+        let (_, ip_b) = bind(&mut server, &MAC_B, now);
+        assert_ne!(ip_a, ip_b);
+        assert!(server.available.is_empty());
+
+        let release = message(&MAC_A, v4::MessageType::Release, ip_a, &[]);
+        assert!(server.handle_message(release, now).unwrap().is_none());
+        assert_eq!(server.available.front(), Some(&ip_a));
+    }
+
+    #[test]
+    fn dhcp_lease_expires() {
+        let mut server = server();
+        let now = Instant::now();
+        let (_, ip) = bind(&mut server, &MAC_A, now);
+
+        // renewing keeps the lease
+        let renew = message(&MAC_A, v4::MessageType::Request, ip, &[]);
+        let reply = server.handle_message(renew, now).unwrap().unwrap();
+        assert_eq!(reply.opts().msg_type(), Some(v4::MessageType::Ack));
+
+        let later = now + Duration::from_secs(86401);
+        let request = message(
+            &MAC_B,
+            v4::MessageType::Request,
+            Ipv4Addr::UNSPECIFIED,
+            &[v4::DhcpOption::RequestedIpAddress(ip)],
+        );
+        let reply = server.handle_message(request, later).unwrap().unwrap();
+        assert_eq!(reply.opts().msg_type(), Some(v4::MessageType::Ack));
+        assert_eq!(reply.yiaddr(), ip);
+    }
+
+    #[test]
+    fn dhcp_leases_persisted() {
+        let path = std::env::temp_dir().join(format!("oathgate-{}.leases", rand::random::<u32>()));
+
+        let mut server = server();
+        server.persist(&path).unwrap();
+        let (_, ip) = bind(&mut server, &MAC_A, Instant::now());
+
+        let hosts = HostTable::default();
+        let mut restored = DhcpServer::new(
+            "10.0.0.1/24".parse().unwrap(),
+            "10.0.0.100-10.0.0.101".parse::<DhcpConfig>().unwrap(),
+            &DnsConfig::default(),
+            hosts.clone(),
+        )
+        .unwrap();
+        restored.persist(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let leases = restored.leases();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].ip, ip);
+        assert_eq!(leases[0].hostname.as_deref(), Some("shard"));
+        assert_eq!(hosts.lookup("shard"), Some(ip));
+        assert!(!restored.available.contains(&ip));
+    }
+
+    #[test]
+    fn dhcp_lease_expires_on_tick() {
+        let hosts = HostTable::default();
+        let mut server = DhcpServer::new(
+            "10.0.0.1/24".parse().unwrap(),
+            "10.0.0.100-10.0.0.101".parse::<DhcpConfig>().unwrap(),
+            &DnsConfig::default(),
+            hosts.clone(),
+        )
+        .unwrap();
+
+        let now = Instant::now();
+        let (_, ip) = bind(&mut server, &MAC_A, now);
+        assert_eq!(hosts.lookup("shard"), Some(ip));
+
+        server.tick(now + Duration::from_secs(60));
+        assert_eq!(hosts.lookup("shard"), Some(ip));
+
+        // no message is received, the router's tick expires the lease
+        server.tick(now + Duration::from_secs(86401));
+        assert_eq!(hosts.lookup("shard"), None);
+        assert!(server.leases().is_empty());
+        assert!(server.available.contains(&ip));
+    }
+
+    #[test]
+    fn dhcp_inform_ack() {
+        let mut server = server();
+        let inform = message(
+            &MAC_A,
+            v4::MessageType::Inform,
+            Ipv4Addr::new(10, 0, 0, 20),
+            &[],
+        );
+        let reply = server
+            .handle_message(inform, Instant::now())
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.opts().msg_type(), Some(v4::MessageType::Ack));
+        assert_eq!(reply.yiaddr(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(reply.ciaddr(), Ipv4Addr::new(10, 0, 0, 20));
+        assert!(reply.opts().get(v4::OptionCode::AddressLeaseTime).is_none());
+        assert_eq!(server.available.len(), 2);
+    }
+
+    #[test]
+    fn dhcp_reservation_assigned() {
+        let input = r#"
+start: 10.0.0.100
+end: 10.0.0.101
+reservations:
+  - mac: 52:54:00:00:00:0a
+    ip: 10.0.0.10
+    hostname: db
+"#;
+        let hosts = HostTable::default();
+        let mut server = DhcpServer::new(
+            "10.0.0.1/24".parse().unwrap(),
+            serde_yaml::from_str(input).unwrap(),
+            &DnsConfig::default(),
+            hosts.clone(),
+        )
+        .unwrap();
+
+        let now = Instant::now();
+        let (ty, ip) = bind(&mut server, &MAC_A, now);
+        assert_eq!(ty, Some(v4::MessageType::Ack));
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 10));
+        assert_eq!(hosts.lookup("db"), Some(ip));
+        assert_eq!(hosts.lookup("shard"), None);
+
+        // reserved addresses are never handed to other clients, even when released
+        let release = message(&MAC_A, v4::MessageType::Release, ip, &[]);
+        server.handle_message(release, now).unwrap();
+        assert!(!server.available.contains(&ip));
+
+        let request = message(
+            &MAC_B,
+            v4::MessageType::Request,
+            Ipv4Addr::UNSPECIFIED,
+            &[v4::DhcpOption::RequestedIpAddress(ip)],
+        );
+        let reply = server.handle_message(request, now).unwrap().unwrap();
+        assert_eq!(reply.opts().msg_type(), Some(v4::MessageType::Nak));
+    }
+
+    #[test]
+    fn dhcp_custom_options() {
+        let input = r#"
+start: 10.0.0.100
+end: 10.0.0.101
+lease_time: 600
+dns: [9.9.9.9]
+mtu: 1400
+routes:
+  - network: 192.168.0.0/16
+    gateway: 10.0.0.2
+options:
+  - code: 252
+    data: 68747470
+"#;
+        let mut server = DhcpServer::new(
+            "10.0.0.1/24".parse().unwrap(),
+            serde_yaml::from_str(input).unwrap(),
+            &DnsConfig::default(),
+            HostTable::default(),
+        )
+        .unwrap();
+
+        let discover = message(
+            &MAC_A,
+            v4::MessageType::Discover,
+            Ipv4Addr::UNSPECIFIED,
+            &[],
+        );
+        let offer = server
+            .handle_message(discover, Instant::now())
+            .unwrap()
+            .unwrap();
+
+        let opts = offer.opts();
+        assert_eq!(
+            opts.get(v4::OptionCode::AddressLeaseTime),
+            Some(&v4::DhcpOption::AddressLeaseTime(600))
+        );
+        assert_eq!(
+            opts.get(v4::OptionCode::DomainNameServer),
+            Some(&v4::DhcpOption::DomainNameServer(vec![Ipv4Addr::new(
+                9, 9, 9, 9
+            )]))
+        );
+        assert_eq!(
+            opts.get(v4::OptionCode::InterfaceMtu),
+            Some(&v4::DhcpOption::InterfaceMtu(1400))
+        );
+
+        // configured route followed by the default route via the router
+        match opts.get(v4::OptionCode::ClasslessStaticRoute) {
+            Some(v4::DhcpOption::ClasslessStaticRoute(routes)) => {
+                assert_eq!(routes.len(), 2);
+                assert_eq!(routes[0].0.prefix_len(), 16);
+                assert_eq!(routes[0].1, Ipv4Addr::new(10, 0, 0, 2));
+                assert_eq!(routes[1].0.prefix_len(), 0);
+                assert_eq!(routes[1].1, ROUTER);
+            }
+            opt => panic!("unexpected classless static route option: {opt:?}"),
+        }
+
+        match opts.get(v4::OptionCode::from(252)) {
+            Some(v4::DhcpOption::Unknown(opt)) => assert_eq!(opt.data(), b"http"),
+            opt => panic!("unexpected raw option: {opt:?}"),
+        }
+    }
+
+    #[test]
+    fn dhcp_counters() {
+        let mut server = server();
+        let src = "0.0.0.0:68".parse().unwrap();
+        let mut buf = [0u8; 1500];
+
+        let discover = message(
+            &MAC_A,
+            v4::MessageType::Discover,
+            Ipv4Addr::UNSPECIFIED,
+            &[],
+        );
+        let data = discover.to_vec().unwrap();
+        assert!(server.handle_port(src, &data, &mut buf).unwrap() > 0);
+        assert!(server.handle_port(src, &[0u8; 4], &mut buf).is_err());
+
+        let stats = server.counters().unwrap();
+        assert_eq!(stats.service, "dhcp");
+        assert_eq!(stats.counters["discover"], 1);
+        assert_eq!(stats.counters["offer"], 1);
+        assert_eq!(stats.counters["ack"], 0);
+        assert_eq!(stats.counters["errors"], 1);
+        assert_eq!(stats.gauges["leases"], 0);
+        assert_eq!(stats.gauges["available"], 1);
+    }
+
+    #[test]
+    fn dhcp_rejects_oversize_options() {
+        let mut cfg = "10.0.0.100-10.0.0.101".parse::<DhcpConfig>().unwrap();
+        cfg.options = (224..=229)
+            .map(|code| DhcpRawOption {
+                code,
+                data: "ab".repeat(250),
+            })
+            .collect();
+
+        let res = DhcpServer::new(
+            "10.0.0.1/24".parse().unwrap(),
+            cfg,
+            &DnsConfig::default(),
+            HostTable::default(),
+        );
+        let error = res.expect_err("oversize options must be rejected");
+        assert!(error.to_string().contains("too large"), "{error}");
+    }
+
+    #[test]
+    fn dhcp_reply_larger_than_buffer() {
+        let mut server = server();
+        let src = "0.0.0.0:68".parse().unwrap();
+        let mut buf = [0u8; 64];
+
+        let discover = message(
+            &MAC_A,
+            v4::MessageType::Discover,
+            Ipv4Addr::UNSPECIFIED,
+            &[],
+        );
+        let data = discover.to_vec().unwrap();
+        assert!(matches!(
+            server.handle_port(src, &data, &mut buf),
+            Err(ProtocolError::NotEnoughData(64, _))
+        ));
+    }
 }
@@ -0,0 +1,753 @@
+//! DHCPv6 server (stateful address assignment)
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    net::{Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use dhcproto::{v6, Decodable, Decoder, Encodable, Encoder};
+use oathgate_net::{types::Ipv6Network, ProtocolError};
+use serde::{Deserialize, Serialize};
+
+use crate::config::dhcp6::Dhcp6Config;
+
+use super::{
+    dhcp::{read_leases, unix_now, write_leases},
+    router::handler::PortHandler,
+    NetworkError,
+};
+
+/// Well-known port of a DHCPv6 server
+const DHCP6_SERVER_PORT: u16 = 547;
+
+/// Amount of time an advertised address is reserved for a client
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Amount of time a declined address (i.e., in use by another host) is withheld from the pool
+const DECLINE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// State of an address handed out by the server
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LeaseState {
+    /// Address was advertised in response to a SOLICIT
+    Offered,
+
+    /// Address was committed and is in use by the client
+    Bound,
+
+    /// Client reported the address is in use by another host
+    Declined,
+}
+
+#[derive(Debug)]
+struct Lease {
+    /// DUID of the client that holds the lease
+    duid: Vec<u8>,
+
+    /// Identity association (IA_NA) the address belongs to
+    iaid: u32,
+
+    /// State of the lease
+    state: LeaseState,
+
+    /// Time the lease expires and the address returns to the pool
+    expires: Instant,
+}
+
+/// A bound lease, as persisted to the lease file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Dhcp6Lease {
+    /// Address leased to the client
+    pub ip: Ipv6Addr,
+
+    /// DUID of the client that holds the lease, as a hex string
+    pub duid: String,
+
+    /// Identity association (IA_NA) the address belongs to
+    pub iaid: u32,
+
+    /// Time the lease expires, in seconds since the unix epoch
+    pub expires: u64,
+}
+
+#[derive(Debug)]
+pub struct Dhcp6Server {
+    network: Ipv6Network,
+    lease_time: u32,
+
+    /// First and last address of the pool
+    start: Ipv6Addr,
+    end: Ipv6Addr,
+
+    /// Server identifier (DUID) sent to clients
+    duid: Vec<u8>,
+
+    leases: HashMap<Ipv6Addr, Lease>,
+
+    /// Nameservers advertised to clients
+    dns: Vec<Ipv6Addr>,
+
+    /// True to commit leases immediately when requested by a client
+    rapid_commit: bool,
+
+    /// File to persist bound leases to (if any)
+    lease_file: Option<PathBuf>,
+
+    /// True if bound leases have changed since they were last persisted
+    dirty: bool,
+}
+
+impl Dhcp6Server {
+    /// Creates a new DHCPv6 server
+    ///
+    /// ### Arguments
+    /// * `network` - Address (and prefix) of the router
+    /// * `cfg` - DHCPv6 configuration
+    pub fn new(network: Ipv6Network, cfg: Dhcp6Config) -> Result<Self, NetworkError> {
+        if !network.contains(cfg.start) || !network.contains(cfg.end) {
+            return Err(NetworkError::Generic(Cow::Owned(format!(
+                "dhcp6 range ({} - {}) is outside of the router prefix ({network})",
+                cfg.start, cfg.end
+            ))));
+        }
+
+        if cfg.end < cfg.start {
+            return Err(NetworkError::Generic(Cow::Borrowed(
+                "dhcp6 end address is before start address",
+            )));
+        }
+
+        // derived from the prefix so clients can renew their leases after a restart
+        let duid = v6::duid::Duid::uuid(&network.network().octets());
+
+        Ok(Self {
+            network,
+            lease_time: cfg.lease_time,
+            start: cfg.start,
+            end: cfg.end,
+            duid: duid.as_ref().to_vec(),
+            leases: HashMap::new(),
+            dns: cfg.dns,
+            rapid_commit: cfg.rapid_commit,
+            lease_file: None,
+            dirty: false,
+        })
+    }
+
+    /// Restores unexpired leases from a lease file and saves bound leases to it as they change
+    ///
+    /// ### Arguments
+    /// * `path` - Path to the lease file
+    pub fn persist<P: Into<PathBuf>>(&mut self, path: P) -> Result<(), NetworkError> {
+        let path = path.into();
+        let now = Instant::now();
+        let unix_now = unix_now();
+
+        for lease in Dhcp6Lease::load(&path)? {
+            let Some(duid) = decode_hex(&lease.duid) else {
+                continue;
+            };
+
+            if lease.expires <= unix_now || !self.is_available(lease.ip) {
+                continue;
+            }
+
+            tracing::debug!(ip = %lease.ip, duid = %lease.duid, "[dhcp6] restored lease");
+            let expires = now + Duration::from_secs(lease.expires - unix_now);
+            self.leases.insert(
+                lease.ip,
+                Lease {
+                    duid,
+                    iaid: lease.iaid,
+                    state: LeaseState::Bound,
+                    expires,
+                },
+            );
+        }
+
+        self.lease_file = Some(path);
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Returns all bound leases
+    pub fn leases(&self) -> Vec<Dhcp6Lease> {
+        let now = Instant::now();
+        let unix_now = unix_now();
+
+        self.leases
+            .iter()
+            .filter(|(_, lease)| lease.state == LeaseState::Bound)
+            .map(|(ip, lease)| Dhcp6Lease {
+                ip: *ip,
+                duid: encode_hex(&lease.duid),
+                iaid: lease.iaid,
+                expires: unix_now + lease.expires.saturating_duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+
+    /// Writes bound leases to the lease file (if configured)
+    fn save_leases(&mut self) {
+        self.dirty = false;
+        if let Some(ref path) = self.lease_file {
+            if let Err(error) = Dhcp6Lease::save(path, &self.leases()) {
+                tracing::warn!(?error, path = %path.display(), "[dhcp6] unable to save leases");
+            }
+        }
+    }
+
+    /// Handles a decoded DHCPv6 message, returning the response to send (if any)
+    ///
+    /// ### Arguments
+    /// * `msg` - Message received from a client
+    /// * `now` - Current time
+    fn handle_message(
+        &mut self,
+        msg: v6::Message,
+        now: Instant,
+    ) -> Result<Option<v6::Message>, ProtocolError> {
+        use v6::MessageType as Ty;
+
+        self.expire(now);
+
+        let ty = msg.msg_type();
+        let client = match msg.opts().get(v6::OptionCode::ClientId) {
+            Some(v6::DhcpOption::ClientId(duid)) => duid.clone(),
+            _ if ty == Ty::InformationRequest => Vec::new(),
+            _ => {
+                return Err(ProtocolError::Other(String::from(
+                    "dhcp6 message missing client id",
+                )))
+            }
+        };
+
+        // messages addressed to a specific server are ignored unless they are for us
+        let server = match msg.opts().get(v6::OptionCode::ServerId) {
+            Some(v6::DhcpOption::ServerId(duid)) => Some(duid.as_slice()),
+            _ => None,
+        };
+
+        let for_us = match ty {
+            Ty::Request | Ty::Renew | Ty::Release | Ty::Decline => server == Some(&self.duid),
+            Ty::Solicit | Ty::Rebind | Ty::Confirm => server.is_none(),
+            _ => server.map(|duid| duid == self.duid).unwrap_or(true),
+        };
+
+        if !for_us {
+            tracing::trace!(?ty, "[dhcp6] message for another server, ignoring");
+            return Ok(None);
+        }
+
+        let res = match ty {
+            Ty::Solicit => Some(self.handle_solicit(&msg, &client, now)),
+            Ty::Request | Ty::Renew | Ty::Rebind => {
+                let mut rmsg = self.reply(&msg, Ty::Reply, &client);
+                self.assign_all(&msg, &mut rmsg, &client, LeaseState::Bound, now);
+                Some(rmsg)
+            }
+            Ty::Release => Some(self.handle_release(&msg, &client)),
+            Ty::Decline => Some(self.handle_decline(&msg, &client, now)),
+            Ty::Confirm => self.handle_confirm(&msg, &client),
+            Ty::InformationRequest => Some(self.reply(&msg, Ty::Reply, &client)),
+            ty => {
+                tracing::debug!(?ty, "[dhcp6] unhandled message type");
+                None
+            }
+        };
+
+        if self.dirty {
+            self.save_leases();
+        }
+
+        Ok(res)
+    }
+
+    /// Advertises addresses to a client, or commits them if rapid commit is in use
+    fn handle_solicit(&mut self, msg: &v6::Message, client: &[u8], now: Instant) -> v6::Message {
+        let rapid = self.rapid_commit && msg.opts().get(v6::OptionCode::RapidCommit).is_some();
+
+        let (ty, state) = match rapid {
+            true => (v6::MessageType::Reply, LeaseState::Bound),
+            false => (v6::MessageType::Advertise, LeaseState::Offered),
+        };
+
+        let mut rmsg = self.reply(msg, ty, client);
+        if rapid {
+            rmsg.opts_mut().insert(v6::DhcpOption::RapidCommit);
+        }
+
+        if self.assign_all(msg, &mut rmsg, client, state, now) == 0 {
+            rmsg.opts_mut()
+                .insert(status(v6::Status::NoAddrsAvail, "no addresses requested"));
+        }
+
+        rmsg
+    }
+
+    /// Returns addresses released by a client to the pool
+    fn handle_release(&mut self, msg: &v6::Message, client: &[u8]) -> v6::Message {
+        for (iaid, ip) in addresses(msg) {
+            let owned = self
+                .leases
+                .get(&ip)
+                .map(|lease| lease.duid == client && lease.iaid == iaid)
+                .unwrap_or(false);
+
+            if owned {
+                tracing::debug!(%ip, "[dhcp6] lease released");
+                self.leases.remove(&ip);
+                self.dirty = true;
+            }
+        }
+
+        let mut rmsg = self.reply(msg, v6::MessageType::Reply, client);
+        rmsg.opts_mut()
+            .insert(status(v6::Status::Success, "released"));
+        rmsg
+    }
+
+    /// Withholds addresses a client reports are in use by another host
+    fn handle_decline(&mut self, msg: &v6::Message, client: &[u8], now: Instant) -> v6::Message {
+        for (_, ip) in addresses(msg) {
+            if let Some(lease) = self.leases.get_mut(&ip).filter(|l| l.duid == client) {
+                tracing::warn!(%ip, "[dhcp6] address declined, withholding from pool");
+                self.dirty |= lease.state == LeaseState::Bound;
+                lease.state = LeaseState::Declined;
+                lease.expires = now + DECLINE_TIMEOUT;
+            }
+        }
+
+        let mut rmsg = self.reply(msg, v6::MessageType::Reply, client);
+        rmsg.opts_mut()
+            .insert(status(v6::Status::Success, "declined"));
+        rmsg
+    }
+
+    /// Confirms whether the addresses of a client (i.e., after moving links) are on-link
+    fn handle_confirm(&self, msg: &v6::Message, client: &[u8]) -> Option<v6::Message> {
+        let addrs = addresses(msg);
+        if addrs.is_empty() {
+            return None;
+        }
+
+        let opt = match addrs.iter().all(|(_, ip)| self.network.contains(*ip)) {
+            true => status(v6::Status::Success, "all addresses on-link"),
+            false => status(v6::Status::NotOnLink, "addresses not on-link"),
+        };
+
+        let mut rmsg = self.reply(msg, v6::MessageType::Reply, client);
+        rmsg.opts_mut().insert(opt);
+        Some(rmsg)
+    }
+
+    /// Builds a response to a message containing the server / client identifiers and
+    /// configuration options
+    fn reply(&self, msg: &v6::Message, ty: v6::MessageType, client: &[u8]) -> v6::Message {
+        let mut rmsg = v6::Message::new_with_id(ty, msg.xid());
+        let opts = rmsg.opts_mut();
+        opts.insert(v6::DhcpOption::ServerId(self.duid.clone()));
+
+        if !client.is_empty() {
+            opts.insert(v6::DhcpOption::ClientId(client.to_vec()));
+        }
+
+        if !self.dns.is_empty() {
+            opts.insert(v6::DhcpOption::DomainNameServers(self.dns.clone()));
+        }
+
+        rmsg
+    }
+
+    /// Assigns an address to each identity association (IA_NA) in a message, adding them to
+    /// the response and returning the number of identity associations
+    fn assign_all(
+        &mut self,
+        msg: &v6::Message,
+        rmsg: &mut v6::Message,
+        client: &[u8],
+        state: LeaseState,
+        now: Instant,
+    ) -> usize {
+        // `DhcpOptions::get_all` can index past the end of the options, so filter by hand
+        let ianas = msg
+            .opts()
+            .iter()
+            .filter_map(|opt| match opt {
+                v6::DhcpOption::IANA(iana) => Some(iana),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for iana in &ianas {
+            let requested = match iana.opts.get(v6::OptionCode::IAAddr) {
+                Some(v6::DhcpOption::IAAddr(addr)) => Some(addr.addr),
+                _ => None,
+            };
+
+            let opts = match self.allocate(client, iana.id, requested) {
+                Some(ip) => {
+                    self.assign(ip, client, iana.id, state, now);
+                    tracing::debug!(%ip, iaid = iana.id, ?state, "[dhcp6] assigned address");
+
+                    vec![v6::DhcpOption::IAAddr(v6::IAAddr {
+                        addr: ip,
+                        preferred_life: self.lease_time,
+                        valid_life: self.lease_time,
+                        opts: v6::DhcpOptions::new(),
+                    })]
+                }
+                None => {
+                    tracing::warn!("[dhcp6] address pool exhausted");
+                    vec![status(v6::Status::NoAddrsAvail, "address pool exhausted")]
+                }
+            };
+
+            rmsg.opts_mut().insert(v6::DhcpOption::IANA(v6::IANA {
+                id: iana.id,
+                t1: self.lease_time / 2,
+                t2: self.lease_time / 5 * 4,
+                opts: opts.into_iter().collect(),
+            }));
+        }
+
+        ianas.len()
+    }
+
+    /// Selects an address for an identity association: the address it already holds, the
+    /// address it requested (if free) or the first free address in the pool
+    fn allocate(&self, client: &[u8], iaid: u32, requested: Option<Ipv6Addr>) -> Option<Ipv6Addr> {
+        let held = self
+            .leases
+            .iter()
+            .find(|(_, l)| l.duid == client && l.iaid == iaid && l.state != LeaseState::Declined)
+            .map(|(ip, _)| *ip);
+
+        held.or(requested.filter(|ip| self.is_available(*ip)))
+            .or_else(|| {
+                (u128::from(self.start)..=u128::from(self.end))
+                    .map(Ipv6Addr::from)
+                    .find(|ip| self.is_available(*ip))
+            })
+    }
+
+    /// Returns true if an address is in the pool and not leased to any client
+    fn is_available(&self, ip: Ipv6Addr) -> bool {
+        ip >= self.start
+            && ip <= self.end
+            && ip != self.network.ip()
+            && !self.leases.contains_key(&ip)
+    }
+
+    /// Assigns an address to a client.  A bound lease is not downgraded by a new advertisement
+    fn assign(&mut self, ip: Ipv6Addr, client: &[u8], iaid: u32, state: LeaseState, now: Instant) {
+        if state == LeaseState::Offered
+            && self.leases.get(&ip).map(|l| l.state) == Some(LeaseState::Bound)
+        {
+            return;
+        }
+
+        let expires = match state {
+            LeaseState::Offered => now + OFFER_TIMEOUT,
+            _ => now + Duration::from_secs(u64::from(self.lease_time)),
+        };
+
+        self.dirty |= state == LeaseState::Bound;
+        self.leases.insert(
+            ip,
+            Lease {
+                duid: client.to_vec(),
+                iaid,
+                state,
+                expires,
+            },
+        );
+    }
+
+    /// Returns expired leases to the pool
+    fn expire(&mut self, now: Instant) {
+        self.leases.retain(|ip, lease| {
+            if lease.expires > now {
+                return true;
+            }
+
+            tracing::debug!(%ip, "[dhcp6] lease expired");
+            self.dirty |= lease.state == LeaseState::Bound;
+            false
+        });
+    }
+}
+
+impl Dhcp6Lease {
+    /// Loads leases from a lease file, returning no leases if the file does not exist
+    ///
+    /// ### Arguments
+    /// * `path` - Path to the lease file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, NetworkError> {
+        read_leases(path)
+    }
+
+    /// Atomically replaces the contents of a lease file
+    ///
+    /// ### Arguments
+    /// * `path` - Path to the lease file
+    /// * `leases` - Leases to save
+    pub fn save<P: AsRef<Path>>(path: P, leases: &[Self]) -> Result<(), NetworkError> {
+        write_leases(path, leases)
+    }
+
+    /// Returns true if this lease has not expired
+    pub fn is_active(&self) -> bool {
+        self.expires > unix_now()
+    }
+}
+
+/// Returns the (identity association, address) pairs contained in a message
+fn addresses(msg: &v6::Message) -> Vec<(u32, Ipv6Addr)> {
+    msg.opts()
+        .iter()
+        .filter_map(|opt| match opt {
+            v6::DhcpOption::IANA(iana) => Some(iana),
+            _ => None,
+        })
+        .flat_map(|iana| {
+            iana.opts.iter().filter_map(|opt| match opt {
+                v6::DhcpOption::IAAddr(addr) => Some((iana.id, addr.addr)),
+                _ => None,
+            })
+        })
+        .collect()
+}
+
+/// Builds a status code option
+fn status(status: v6::Status, msg: &str) -> v6::DhcpOption {
+    v6::DhcpOption::StatusCode(v6::StatusCode {
+        status,
+        msg: String::from(msg),
+    })
+}
+
+/// Encodes bytes as a lowercase hex string
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decodes a hex string, returning None if the string is not valid hex
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl PortHandler for Dhcp6Server {
+    fn port(&self) -> u16 {
+        DHCP6_SERVER_PORT
+    }
+
+    fn handle_port(
+        &mut self,
+        src: SocketAddr,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        if src.is_ipv4() {
+            return Ok(0);
+        }
+
+        tracing::trace!("[dhcp6] got packet");
+        let msg = v6::Message::decode(&mut Decoder::new(data))
+            .map_err(|e| ProtocolError::Other(e.to_string()))?;
+
+        let rmsg = match self.handle_message(msg, Instant::now())? {
+            Some(rmsg) => rmsg,
+            None => return Ok(0),
+        };
+
+        let mut vbuf = Vec::with_capacity(256);
+        let mut encoder = Encoder::new(&mut vbuf);
+        rmsg.encode(&mut encoder)
+            .map_err(|e| ProtocolError::Other(e.to_string()))?;
+
+        // a client requesting many addresses can grow the reply past the buffer
+        let len = vbuf.len();
+        if len > buf.len() {
+            return Err(ProtocolError::NotEnoughData(buf.len(), len));
+        }
+
+        buf[0..len].copy_from_slice(&vbuf);
+        Ok(len)
+    }
+
+    fn tick(&mut self, now: Instant) {
+        self.expire(now);
+        if self.dirty {
+            self.save_leases();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv6Addr, time::Instant};
+
+    use dhcproto::{v6, Encodable};
+    use oathgate_net::ProtocolError;
+
+    use crate::{config::dhcp6::Dhcp6Config, net::router::handler::PortHandler};
+
+    use super::Dhcp6Server;
+
+    const CLIENT_A: [u8; 10] = [0, 3, 0, 1, 0x52, 0x54, 0x00, 0x00, 0x00, 0x0a];
+    const CLIENT_B: [u8; 10] = [0, 3, 0, 1, 0x52, 0x54, 0x00, 0x00, 0x00, 0x0b];
+
+    fn config(rapid_commit: bool) -> Dhcp6Config {
+        Dhcp6Config {
+            start: "fd00::100".parse().unwrap(),
+            end: "fd00::101".parse().unwrap(),
+            lease_time: 3600,
+            dns: vec!["fd00::1".parse().unwrap()],
+            rapid_commit,
+        }
+    }
+
+    fn server(rapid_commit: bool) -> Dhcp6Server {
+        Dhcp6Server::new("fd00::1/64".parse().unwrap(), config(rapid_commit)).unwrap()
+    }
+
+    fn message(client: &[u8], ty: v6::MessageType, opts: &[v6::DhcpOption]) -> v6::Message {
+        let mut msg = v6::Message::new_with_id(ty, [0x01, 0x02, 0x03]);
+        msg.opts_mut()
+            .insert(v6::DhcpOption::ClientId(client.to_vec()));
+        msg.opts_mut().insert(v6::DhcpOption::IANA(v6::IANA {
+            id: 1,
+            t1: 0,
+            t2: 0,
+            opts: v6::DhcpOptions::new(),
+        }));
+        for opt in opts {
+            msg.opts_mut().insert(opt.clone());
+        }
+        msg
+    }
+
+    /// Returns the address assigned in the first identity association of a message
+    fn assigned(msg: &v6::Message) -> Option<Ipv6Addr> {
+        match msg.opts().get(v6::OptionCode::IANA) {
+            Some(v6::DhcpOption::IANA(iana)) => match iana.opts.get(v6::OptionCode::IAAddr) {
+                Some(v6::DhcpOption::IAAddr(addr)) => Some(addr.addr),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Runs a solicit/request exchange, returning the final reply
+    fn bind(server: &mut Dhcp6Server, client: &[u8], now: Instant) -> v6::Message {
+        let solicit = message(client, v6::MessageType::Solicit, &[]);
+        let advertise = server.handle_message(solicit, now).unwrap().unwrap();
+        assert_eq!(advertise.msg_type(), v6::MessageType::Advertise);
+
+        let server_id = advertise.opts().get(v6::OptionCode::ServerId).unwrap();
+        let request = message(
+            client,
+            v6::MessageType::Request,
+            std::slice::from_ref(server_id),
+        );
+        server.handle_message(request, now).unwrap().unwrap()
+    }
+
+    #[test]
+    fn dhcp6_range_outside_prefix() {
+        let res = Dhcp6Server::new("fd01::1/64".parse().unwrap(), config(true));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn dhcp6_solicit_request_reply() {
+        let mut server = server(false);
+        let now = Instant::now();
+
+        let reply = bind(&mut server, &CLIENT_A, now);
+        assert_eq!(reply.msg_type(), v6::MessageType::Reply);
+        assert_eq!(assigned(&reply), "fd00::100".parse().ok());
+        assert!(matches!(
+            reply.opts().get(v6::OptionCode::DomainNameServers),
+            Some(v6::DhcpOption::DomainNameServers(dns)) if dns.len() == 1
+        ));
+
+        // a second client receives the next address
+        let reply = bind(&mut server, &CLIENT_B, now);
+        assert_eq!(assigned(&reply), "fd00::101".parse().ok());
+        assert_eq!(server.leases().len(), 2);
+    }
+
+    #[test]
+    fn dhcp6_rapid_commit() {
+        let solicit = message(
+            &CLIENT_A,
+            v6::MessageType::Solicit,
+            &[v6::DhcpOption::RapidCommit],
+        );
+
+        // only committed if enabled on the server
+        let mut server = server(false);
+        let rmsg = server
+            .handle_message(solicit.clone(), Instant::now())
+            .unwrap()
+            .unwrap();
+        assert_eq!(rmsg.msg_type(), v6::MessageType::Advertise);
+        assert!(server.leases().is_empty());
+
+        let mut server = self::server(true);
+        let rmsg = server
+            .handle_message(solicit, Instant::now())
+            .unwrap()
+            .unwrap();
+        assert_eq!(rmsg.msg_type(), v6::MessageType::Reply);
+        assert!(rmsg.opts().get(v6::OptionCode::RapidCommit).is_some());
+        assert_eq!(server.leases().len(), 1);
+    }
+
+    #[test]
+    fn dhcp6_leases_persisted() {
+        let path = std::env::temp_dir().join(format!("oathgate-{}.leases6", rand::random::<u32>()));
+
+        let mut server = server(false);
+        server.persist(&path).unwrap();
+        let ip = assigned(&bind(&mut server, &CLIENT_A, Instant::now()));
+
+        let mut restored = self::server(false);
+        restored.persist(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let leases = restored.leases();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(Some(leases[0].ip), ip);
+
+        // the client keeps its address when it renews
+        let reply = bind(&mut restored, &CLIENT_A, Instant::now());
+        assert_eq!(assigned(&reply), ip);
+    }
+
+    #[test]
+    fn dhcp6_reply_larger_than_buffer() {
+        let mut server = server(false);
+        let src = "[fe80::1]:546".parse().unwrap();
+        let mut buf = [0u8; 32];
+
+        let solicit = message(&CLIENT_A, v6::MessageType::Solicit, &[]);
+        let data = solicit.to_vec().unwrap();
+        assert!(matches!(
+            server.handle_port(src, &data, &mut buf),
+            Err(ProtocolError::NotEnoughData(32, _))
+        ));
+    }
+}
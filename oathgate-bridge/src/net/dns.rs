@@ -0,0 +1,740 @@
+//! Simple DNS server / forwarder
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use oathgate_net::{
+    protocols::{
+        dns::{
+            DnsQuery, DnsRecord, DNS_CLASS_IN, DNS_PORT, DNS_RCODE_FORMERR, DNS_RCODE_NOERROR,
+            DNS_RCODE_NOTIMP, DNS_RCODE_NXDOMAIN, DNS_RCODE_REFUSED, DNS_RCODE_SERVFAIL, DNS_TY_A,
+            DNS_TY_PTR,
+        },
+        NET_PROTOCOL_UDP, UDP_HDR_SZ,
+    },
+    types::Ipv4Network,
+    Ipv4Packet, ProtocolError,
+};
+use parking_lot::RwLock;
+
+use crate::config::dns::DnsConfig;
+
+use super::router::handler::PortHandler;
+
+/// Amount of time to wait for an upstream server to respond to a forwarded query before
+/// retrying it with the next server
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum number of forwarded queries waiting on a response
+const MAX_PENDING_QUERIES: usize = 1024;
+
+/// Suffix of reverse (PTR) lookups for ipv4 addresses
+const REVERSE_SUFFIX: &str = ".in-addr.arpa";
+
+/// Hostnames registered by DHCP clients, shared between the DHCP and DNS servers
+#[derive(Clone, Debug, Default)]
+pub struct HostTable {
+    hosts: Arc<RwLock<HashMap<String, Ipv4Addr>>>,
+}
+
+#[derive(Debug)]
+pub struct DnsServer {
+    /// Address (and subnet) of the router
+    network: Ipv4Network,
+
+    /// Servers to forward non-local queries to
+    upstream: Vec<Ipv4Addr>,
+
+    /// Local domain (if any)
+    domain: Option<String>,
+
+    /// Time to live of local answers, in seconds
+    ttl: u32,
+
+    /// Static hostname to address mappings
+    statics: HashMap<String, Ipv4Addr>,
+
+    /// Hostnames registered via DHCP
+    hosts: HostTable,
+
+    /// Queries forwarded upstream, keyed by the transaction id used upstream
+    pending: HashMap<u16, PendingQuery>,
+
+    /// Packets waiting to be routed
+    outbound: Vec<Ipv4Packet>,
+}
+
+/// A query forwarded to an upstream server
+#[derive(Debug)]
+struct PendingQuery {
+    /// Client that sent the query
+    client: SocketAddrV4,
+
+    /// Transaction id used by the client
+    id: u16,
+
+    /// Query as forwarded upstream (with the upstream transaction id)
+    data: Vec<u8>,
+
+    /// Index of the upstream server the query was last forwarded to
+    upstream: usize,
+
+    /// Time the query was forwarded
+    sent: Instant,
+}
+
+impl HostTable {
+    /// Registers a hostname, replacing any previous name of the address.  Returns false if
+    /// the name is already registered to another address, which keeps the name until its
+    /// lease ends
+    ///
+    /// ### Arguments
+    /// * `name` - Hostname provided by the client
+    /// * `ip` - Address leased to the client
+    pub fn insert(&self, name: &str, ip: Ipv4Addr) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if name.is_empty() {
+            return false;
+        }
+
+        let mut hosts = self.hosts.write();
+        if let Some(holder) = hosts.get(&name).filter(|addr| **addr != ip) {
+            tracing::warn!(%name, %ip, %holder, "[dns] hostname already registered, ignoring");
+            return false;
+        }
+
+        hosts.retain(|_, addr| *addr != ip);
+        hosts.insert(name, ip);
+        true
+    }
+
+    /// Removes the hostname associated with an address
+    ///
+    /// ### Arguments
+    /// * `ip` - Address that is no longer leased
+    pub fn remove(&self, ip: Ipv4Addr) {
+        self.hosts.write().retain(|_, addr| *addr != ip);
+    }
+
+    /// Returns the address registered for a hostname
+    ///
+    /// ### Arguments
+    /// * `name` - Lowercase hostname
+    pub fn lookup(&self, name: &str) -> Option<Ipv4Addr> {
+        self.hosts.read().get(name).copied()
+    }
+
+    /// Returns the hostname registered for an address
+    ///
+    /// ### Arguments
+    /// * `ip` - Address to lookup
+    pub fn reverse(&self, ip: Ipv4Addr) -> Option<String> {
+        self.hosts
+            .read()
+            .iter()
+            .find(|(_, addr)| **addr == ip)
+            .map(|(name, _)| name.clone())
+    }
+}
+
+impl DnsServer {
+    /// Creates a new DNS server listening on the router's address
+    ///
+    /// ### Arguments
+    /// * `network` - Address (and subnet) of the router
+    /// * `cfg` - DNS configuration
+    /// * `hosts` - Hostnames registered via DHCP
+    pub fn new(network: Ipv4Network, cfg: DnsConfig, hosts: HostTable) -> Self {
+        let statics = cfg
+            .hosts
+            .into_iter()
+            .map(|(name, ip)| (name.trim_end_matches('.').to_ascii_lowercase(), ip))
+            .collect();
+
+        let domain = cfg
+            .domain
+            .map(|d| d.trim_matches('.').to_ascii_lowercase())
+            .filter(|d| !d.is_empty());
+
+        tracing::debug!(?domain, upstream = ?cfg.upstream, "[dns] created server");
+
+        Self {
+            network,
+            upstream: cfg.upstream,
+            domain,
+            ttl: cfg.ttl,
+            statics,
+            hosts,
+            pending: HashMap::new(),
+            outbound: Vec::new(),
+        }
+    }
+
+    /// Handles a query sent by a client on the LAN, returning the size of the response or
+    /// zero if the query was forwarded upstream
+    ///
+    /// ### Arguments
+    /// * `src` - Client that sent the query
+    /// * `data` - DNS message
+    /// * `buf` - Buffer to write response into
+    fn handle_query(
+        &mut self,
+        src: SocketAddrV4,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        let query = DnsQuery::parse(data)?;
+        tracing::trace!(name = %query.name, qtype = query.qtype, "[dns] handling query");
+
+        if query.opcode() != 0 {
+            return query.reply(data, DNS_RCODE_NOTIMP, 0, &[], buf);
+        }
+
+        if query.qclass != DNS_CLASS_IN {
+            return self.forward(src, &query, data, buf);
+        }
+
+        if let Some(ip) = parse_reverse(&query.name) {
+            return match self.reverse(ip) {
+                Some(name) if query.qtype == DNS_TY_PTR => query.reply(
+                    data,
+                    DNS_RCODE_NOERROR,
+                    self.ttl,
+                    &[DnsRecord::Ptr(name)],
+                    buf,
+                ),
+                Some(_) => query.reply(data, DNS_RCODE_NOERROR, self.ttl, &[], buf),
+                None if self.network.contains(ip) => {
+                    query.reply(data, DNS_RCODE_NXDOMAIN, self.ttl, &[], buf)
+                }
+                None => self.forward(src, &query, data, buf),
+            };
+        }
+
+        match self.lookup(&query.name) {
+            Some(ip) if query.qtype == DNS_TY_A => {
+                query.reply(data, DNS_RCODE_NOERROR, self.ttl, &[DnsRecord::A(ip)], buf)
+            }
+            Some(_) => query.reply(data, DNS_RCODE_NOERROR, self.ttl, &[], buf),
+            None if self.is_local_name(&query.name) => {
+                query.reply(data, DNS_RCODE_NXDOMAIN, self.ttl, &[], buf)
+            }
+            None => self.forward(src, &query, data, buf),
+        }
+    }
+
+    /// Handles a response from an upstream server, queueing it to be sent to the client
+    /// that sent the original query
+    ///
+    /// ### Arguments
+    /// * `src` - Server that sent the response
+    /// * `data` - DNS message
+    fn handle_response(&mut self, src: SocketAddrV4, data: &[u8]) -> Result<(), ProtocolError> {
+        if src.port() != DNS_PORT || !self.upstream.contains(src.ip()) {
+            tracing::debug!(%src, "[dns] response from unknown server, dropping");
+            return Ok(());
+        }
+
+        if data.len() < 2 {
+            return Err(ProtocolError::NotEnoughData(data.len(), 2));
+        }
+
+        let id = u16::from_be_bytes([data[0], data[1]]);
+        let pending = match self.pending.remove(&id) {
+            Some(pending) => pending,
+            None => {
+                tracing::debug!(id, "[dns] no pending query for response, dropping");
+                return Ok(());
+            }
+        };
+
+        let mut rdata = data.to_vec();
+        rdata[0..2].copy_from_slice(&pending.id.to_be_bytes());
+
+        let pkt = udp_packet(
+            SocketAddrV4::new(self.network.ip(), DNS_PORT),
+            pending.client,
+            &rdata,
+        );
+        self.outbound.push(pkt);
+
+        Ok(())
+    }
+
+    /// Forwards a query to an upstream server, replying with SERVFAIL if the query can't
+    /// be forwarded
+    ///
+    /// ### Arguments
+    /// * `src` - Client that sent the query
+    /// * `query` - Parsed query
+    /// * `data` - DNS message
+    /// * `buf` - Buffer to write a response into (if the query can't be forwarded)
+    fn forward(
+        &mut self,
+        src: SocketAddrV4,
+        query: &DnsQuery,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        if self.upstream.is_empty() {
+            return query.reply(data, DNS_RCODE_REFUSED, 0, &[], buf);
+        }
+
+        let now = Instant::now();
+        if self.pending.len() >= MAX_PENDING_QUERIES {
+            self.expire(now);
+        }
+
+        if self.pending.len() >= MAX_PENDING_QUERIES {
+            tracing::warn!("[dns] too many pending queries, unable to forward");
+            return query.reply(data, DNS_RCODE_SERVFAIL, 0, &[], buf);
+        }
+
+        // use a random transaction id upstream to avoid collisions between clients
+        let mut id = rand::random::<u16>();
+        while self.pending.contains_key(&id) {
+            id = rand::random::<u16>();
+        }
+
+        let mut fdata = data.to_vec();
+        fdata[0..2].copy_from_slice(&id.to_be_bytes());
+
+        tracing::trace!(name = %query.name, upstream = %self.upstream[0], "[dns] forwarding query");
+        self.send_upstream(0, &fdata);
+        self.pending.insert(
+            id,
+            PendingQuery {
+                client: src,
+                id: query.id,
+                data: fdata,
+                upstream: 0,
+                sent: now,
+            },
+        );
+
+        Ok(0)
+    }
+
+    /// Queues a forwarded query to be sent to an upstream server
+    ///
+    /// ### Arguments
+    /// * `upstream` - Index of the upstream server
+    /// * `data` - DNS message (with the upstream transaction id)
+    fn send_upstream(&mut self, upstream: usize, data: &[u8]) {
+        let pkt = udp_packet(
+            SocketAddrV4::new(self.network.ip(), DNS_PORT),
+            SocketAddrV4::new(self.upstream[upstream], DNS_PORT),
+            data,
+        );
+        self.outbound.push(pkt);
+    }
+
+    /// Retries forwarded queries that timed out with the next upstream server, replying with
+    /// SERVFAIL once every server has been tried
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.sent) >= FORWARD_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            let Some(mut pending) = self.pending.remove(&id) else {
+                continue;
+            };
+
+            pending.upstream += 1;
+            if pending.upstream < self.upstream.len() {
+                tracing::debug!(
+                    id,
+                    upstream = %self.upstream[pending.upstream],
+                    "[dns] upstream timed out, retrying query"
+                );
+                self.send_upstream(pending.upstream, &pending.data);
+                pending.sent = now;
+                self.pending.insert(id, pending);
+                continue;
+            }
+
+            tracing::debug!(id, "[dns] no upstream responded, failing query");
+            let mut buf = [0u8; 512];
+            let sz = DnsQuery::parse(&pending.data)
+                .and_then(|query| query.reply(&pending.data, DNS_RCODE_SERVFAIL, 0, &[], &mut buf));
+
+            match sz {
+                Ok(sz) => {
+                    buf[0..2].copy_from_slice(&pending.id.to_be_bytes());
+                    let pkt = udp_packet(
+                        SocketAddrV4::new(self.network.ip(), DNS_PORT),
+                        pending.client,
+                        &buf[..sz],
+                    );
+                    self.outbound.push(pkt);
+                }
+                Err(error) => tracing::debug!(?error, id, "[dns] unable to build SERVFAIL"),
+            }
+        }
+    }
+
+    /// Returns the address of a local host (static or DHCP-registered)
+    ///
+    /// ### Arguments
+    /// * `name` - Lowercase name being queried
+    fn lookup(&self, name: &str) -> Option<Ipv4Addr> {
+        if let Some(ip) = self.statics.get(name) {
+            return Some(*ip);
+        }
+
+        let short = self.strip_domain(name)?;
+        self.statics
+            .get(short)
+            .copied()
+            .or_else(|| self.hosts.lookup(short))
+    }
+
+    /// Returns the name of a local host
+    ///
+    /// ### Arguments
+    /// * `ip` - Address of the host
+    fn reverse(&self, ip: Ipv4Addr) -> Option<String> {
+        let name = self
+            .statics
+            .iter()
+            .find(|(_, addr)| **addr == ip)
+            .map(|(name, _)| name.clone())
+            .or_else(|| self.hosts.reverse(ip))?;
+
+        match &self.domain {
+            Some(domain) if !name.contains('.') => Some(format!("{name}.{domain}")),
+            _ => Some(name),
+        }
+    }
+
+    /// Returns the short (single label) hostname of a name in the local domain
+    ///
+    /// ### Arguments
+    /// * `name` - Lowercase name being queried
+    fn strip_domain<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let short = match &self.domain {
+            Some(domain) => name
+                .strip_suffix(domain.as_str())
+                .and_then(|n| n.strip_suffix('.'))
+                .unwrap_or(name),
+            None => name,
+        };
+
+        match short.contains('.') {
+            true => None,
+            false => Some(short),
+        }
+    }
+
+    /// Returns true if a name belongs to the local network and must not be forwarded
+    ///
+    /// ### Arguments
+    /// * `name` - Lowercase name being queried
+    fn is_local_name(&self, name: &str) -> bool {
+        if !name.contains('.') {
+            return true;
+        }
+
+        match &self.domain {
+            Some(domain) => name == domain || name.ends_with(&format!(".{domain}")),
+            None => false,
+        }
+    }
+}
+
+impl PortHandler for DnsServer {
+    fn port(&self) -> u16 {
+        DNS_PORT
+    }
+
+    fn handle_port(
+        &mut self,
+        src: SocketAddr,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        let SocketAddr::V4(src) = src else {
+            tracing::trace!("[dns] ipv6 clients are not supported, ignoring message");
+            return Ok(0);
+        };
+
+        match DnsQuery::parse(data) {
+            Ok(query) if query.is_response() => {
+                self.handle_response(src, data)?;
+                Ok(0)
+            }
+            Ok(_) => self.handle_query(src, data, buf),
+            Err(error) if data.len() >= 12 && data[2] & 0x80 == 0 => {
+                // malformed query, let the client know
+                tracing::debug!(?error, "[dns] unable to parse query");
+                let mut rdata = data[..12].to_vec();
+                rdata[2] = 0x80 | (data[2] & 0x01);
+                rdata[3] = DNS_RCODE_FORMERR;
+                rdata[4..12].copy_from_slice(&[0u8; 8]);
+                buf[..12].copy_from_slice(&rdata);
+                Ok(12)
+            }
+            Err(error) if data.len() >= 2 => {
+                // responses without a question (i.e., some errors) still need to be relayed
+                tracing::trace!(?error, "[dns] unparsable response");
+                self.handle_response(src, data)?;
+                Ok(0)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn outbound(&mut self) -> Vec<Ipv4Packet> {
+        std::mem::take(&mut self.outbound)
+    }
+
+    fn tick(&mut self, now: Instant) {
+        self.expire(now);
+    }
+}
+
+/// Parses the address of a reverse lookup name (i.e., `5.0.0.10.in-addr.arpa`)
+///
+/// ### Arguments
+/// * `name` - Lowercase name being queried
+fn parse_reverse(name: &str) -> Option<Ipv4Addr> {
+    let name = name.strip_suffix(REVERSE_SUFFIX)?;
+    let mut octets = [0u8; 4];
+    let mut parts = name.split('.');
+    for octet in octets.iter_mut().rev() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+
+    match parts.next() {
+        Some(_) => None,
+        None => Some(Ipv4Addr::from(octets)),
+    }
+}
+
+/// Builds an ipv4 packet containing a udp datagram
+///
+/// ### Arguments
+/// * `src` - Source address and port
+/// * `dst` - Destination address and port
+/// * `data` - UDP payload
+fn udp_packet(src: SocketAddrV4, dst: SocketAddrV4, data: &[u8]) -> Ipv4Packet {
+    let len = (data.len() + UDP_HDR_SZ) as u16;
+    let mut payload = vec![0u8; usize::from(len)];
+    payload[0..2].copy_from_slice(&src.port().to_be_bytes());
+    payload[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    payload[4..6].copy_from_slice(&len.to_be_bytes());
+    payload[UDP_HDR_SZ..].copy_from_slice(data);
+
+    Ipv4Packet::new(*src.ip(), *dst.ip(), NET_PROTOCOL_UDP, &payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddrV4},
+        time::Instant,
+    };
+
+    use crate::{config::dns::DnsConfig, net::router::handler::PortHandler};
+
+    use super::{parse_reverse, DnsServer, HostTable, FORWARD_TIMEOUT};
+
+    const CLIENT: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 100), 40000);
+
+    fn server() -> (DnsServer, HostTable) {
+        let cfg = DnsConfig {
+            enabled: true,
+            upstream: vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(8, 8, 8, 8)],
+            domain: Some(String::from("oathgate")),
+            ttl: 60,
+            hosts: HashMap::from([(String::from("db"), Ipv4Addr::new(10, 0, 0, 10))]),
+        };
+
+        let hosts = HostTable::default();
+        let server = DnsServer::new("10.0.0.1/24".parse().unwrap(), cfg, hosts.clone());
+        (server, hosts)
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut data = vec![0xAB, 0xCD, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            data.push(label.len() as u8);
+            data.extend_from_slice(label.as_bytes());
+        }
+        data.push(0);
+        data.extend_from_slice(&qtype.to_be_bytes());
+        data.extend_from_slice(&[0x00, 0x01]);
+        data
+    }
+
+    #[test]
+    fn dns_parse_reverse_name() {
+        assert_eq!(
+            parse_reverse("5.0.0.10.in-addr.arpa"),
+            Some(Ipv4Addr::new(10, 0, 0, 5))
+        );
+        assert_eq!(parse_reverse("0.10.in-addr.arpa"), None);
+        assert_eq!(parse_reverse("example.com"), None);
+    }
+
+    #[test]
+    fn dns_host_table_keeps_first_holder() {
+        let hosts = HostTable::default();
+        let (web, other) = (Ipv4Addr::new(10, 0, 0, 100), Ipv4Addr::new(10, 0, 0, 101));
+
+        assert!(hosts.insert("web", web));
+        assert!(!hosts.insert("Web.", other));
+        assert_eq!(hosts.lookup("web"), Some(web));
+        assert_eq!(hosts.reverse(other), None);
+
+        // renaming an address releases its previous name
+        assert!(hosts.insert("www", web));
+        assert!(hosts.insert("web", other));
+        assert_eq!(hosts.lookup("web"), Some(other));
+
+        // a name is available again once its lease ends
+        hosts.remove(other);
+        assert!(hosts.insert("web", web));
+        assert_eq!(hosts.lookup("www"), None);
+    }
+
+    #[test]
+    fn dns_answers_local_hosts() {
+        let (mut server, hosts) = server();
+        hosts.insert("Web", Ipv4Addr::new(10, 0, 0, 100));
+
+        let mut buf = [0u8; 512];
+        for name in ["web", "web.oathgate", "db.oathgate"] {
+            let sz = server
+                .handle_port(CLIENT.into(), &query(name, 1), &mut buf)
+                .unwrap();
+            assert_eq!(buf[3] & 0x0F, 0, "{name}");
+            assert_eq!(&buf[6..8], &[0x00, 0x01], "{name}");
+            assert!(sz > 12);
+        }
+
+        // reverse lookup includes the local domain
+        let sz = server
+            .handle_port(
+                CLIENT.into(),
+                &query("100.0.0.10.in-addr.arpa", 12),
+                &mut buf,
+            )
+            .unwrap();
+        assert!(buf[..sz].ends_with(b"\x03web\x08oathgate\x00"));
+
+        // unknown local names are not forwarded
+        server
+            .handle_port(CLIENT.into(), &query("missing.oathgate", 1), &mut buf)
+            .unwrap();
+        assert_eq!(buf[3] & 0x0F, 3);
+        assert!(server.outbound().is_empty());
+    }
+
+    #[test]
+    fn dns_forwards_unknown_names() {
+        let (mut server, _) = server();
+
+        let mut buf = [0u8; 512];
+        let sz = server
+            .handle_port(CLIENT.into(), &query("example.com", 1), &mut buf)
+            .unwrap();
+        assert_eq!(sz, 0);
+
+        let fwd = server.outbound();
+        assert_eq!(fwd.len(), 1);
+        assert_eq!(fwd[0].src(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(fwd[0].dest(), Ipv4Addr::new(1, 1, 1, 1));
+
+        // upstream response is relayed to the client with the original id
+        let mut response = fwd[0].payload()[8..].to_vec();
+        response[2] |= 0x80;
+        let upstream = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+        let sz = server
+            .handle_port(upstream.into(), &response, &mut buf)
+            .unwrap();
+        assert_eq!(sz, 0);
+
+        let relayed = server.outbound();
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].dest(), *CLIENT.ip());
+        assert_eq!(&relayed[0].payload()[2..4], &CLIENT.port().to_be_bytes());
+        assert_eq!(&relayed[0].payload()[8..10], &[0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn dns_retries_next_upstream() {
+        let (mut server, _) = server();
+
+        let mut buf = [0u8; 512];
+        server
+            .handle_port(CLIENT.into(), &query("example.com", 1), &mut buf)
+            .unwrap();
+        let fwd = server.outbound();
+        assert_eq!(fwd[0].dest(), Ipv4Addr::new(1, 1, 1, 1));
+
+        // nothing is retried until the first server times out
+        let now = Instant::now();
+        server.tick(now);
+        assert!(server.outbound().is_empty());
+
+        server.tick(now + FORWARD_TIMEOUT);
+        let retry = server.outbound();
+        assert_eq!(retry.len(), 1);
+        assert_eq!(retry[0].dest(), Ipv4Addr::new(8, 8, 8, 8));
+        assert_eq!(retry[0].payload()[8..], fwd[0].payload()[8..]);
+
+        // the second server's response is relayed to the client
+        let mut response = retry[0].payload()[8..].to_vec();
+        response[2] |= 0x80;
+        let upstream = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+        server
+            .handle_port(upstream.into(), &response, &mut buf)
+            .unwrap();
+
+        let relayed = server.outbound();
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].dest(), *CLIENT.ip());
+        assert_eq!(&relayed[0].payload()[8..10], &[0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn dns_fails_query_when_no_upstream_responds() {
+        let (mut server, _) = server();
+
+        let mut buf = [0u8; 512];
+        server
+            .handle_port(CLIENT.into(), &query("example.com", 1), &mut buf)
+            .unwrap();
+        server.outbound();
+
+        let now = Instant::now();
+        server.tick(now + FORWARD_TIMEOUT);
+        assert_eq!(server.outbound().len(), 1);
+
+        // every server was tried, the client gets a SERVFAIL
+        server.tick(now + FORWARD_TIMEOUT * 2);
+        let failed = server.outbound();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].dest(), *CLIENT.ip());
+
+        let msg = &failed[0].payload()[8..];
+        assert_eq!(&msg[0..2], &[0xAB, 0xCD]);
+        assert_eq!(msg[3] & 0x0F, 2);
+
+        server.tick(now + FORWARD_TIMEOUT * 3);
+        assert!(server.outbound().is_empty());
+    }
+}
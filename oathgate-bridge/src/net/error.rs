@@ -2,7 +2,7 @@
 
 use std::borrow::Cow;
 
-use oathgate_net::ProtocolError;
+use oathgate_net::{nat::NatError, ProtocolError};
 
 /// Collection of errors that may occur during routing/switching packets
 #[derive(Debug, thiserror::Error)]
@@ -19,6 +19,9 @@ pub enum NetworkError {
     #[error("protocol failed: {0}")]
     Protocol(#[from] ProtocolError),
 
+    #[error("nat: {0}")]
+    Nat(#[from] NatError),
+
     #[error("pcap: {0}")]
     Pcap(#[from] pcap_file::PcapError),
 
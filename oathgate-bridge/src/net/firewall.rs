@@ -0,0 +1,384 @@
+//! Stateful packet filter applied to traffic forwarded by the router and switch
+
+use std::sync::Arc;
+
+use oathgate_net::{
+    conntrack::{ConnState, ConnTrack},
+    nat::NatConfig,
+    protocols::{NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+    Ipv4Packet,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::firewall::{Action, Direction, FirewallConfig, FirewallPolicy, RuleConfig};
+
+/// Where a packet entered (or leaves) the router
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Zone {
+    /// A host on the LAN
+    Lan,
+
+    /// A WAN connection
+    Wan,
+
+    /// The router itself (e.g., forwarded dns queries)
+    Local,
+}
+
+/// Packet filter shared by the router and switch
+#[derive(Clone)]
+pub struct Firewall {
+    inner: Arc<Mutex<FirewallState>>,
+}
+
+struct FirewallState {
+    /// Default action of each direction
+    policy: FirewallPolicy,
+
+    /// Rules, evaluated in order
+    rules: Vec<Rule>,
+
+    /// Tracks connections accepted by the firewall
+    conntrack: ConnTrack,
+}
+
+/// A rule and the number of packets it has matched
+struct Rule {
+    cfg: RuleConfig,
+    packets: u64,
+    bytes: u64,
+}
+
+/// Hit counters of a firewall rule
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RuleCounters {
+    /// Name of the rule, or its position in the configuration if unnamed
+    pub name: String,
+
+    /// Action taken by the rule
+    pub action: Action,
+
+    /// Number of packets that matched the rule
+    pub packets: u64,
+
+    /// Number of bytes (ip header + payload) that matched the rule
+    pub bytes: u64,
+}
+
+impl Direction {
+    /// Returns the direction of a packet travelling between two zones, or None if the packet
+    /// is not subject to filtering (i.e., it was sent by the router)
+    ///
+    /// ### Arguments
+    /// * `from` - Zone the packet was received from
+    /// * `to` - Zone the packet is being sent to
+    pub fn between(from: Zone, to: Zone) -> Option<Self> {
+        match (from, to) {
+            (Zone::Lan, Zone::Wan) => Some(Self::LanToWan),
+            (Zone::Wan, Zone::Lan) => Some(Self::WanToLan),
+            (Zone::Lan, Zone::Lan) => Some(Self::LanToLan),
+            (Zone::Wan, Zone::Wan) => Some(Self::WanToWan),
+            (Zone::Local, _) | (_, Zone::Local) => None,
+        }
+    }
+}
+
+impl FirewallPolicy {
+    /// Returns the default action of a direction
+    ///
+    /// ### Arguments
+    /// * `direction` - Direction the packet is travelling
+    fn action(&self, direction: Direction) -> Action {
+        match direction {
+            Direction::LanToWan => self.lan_to_wan,
+            Direction::WanToLan => self.wan_to_lan,
+            Direction::LanToLan => self.lan_to_lan,
+            Direction::WanToWan => self.wan_to_wan,
+        }
+    }
+}
+
+impl Rule {
+    /// Creates the rules of a firewall, with all counters set to zero
+    ///
+    /// ### Arguments
+    /// * `rules` - Rule configurations, in order
+    fn from_config(rules: Vec<RuleConfig>) -> Vec<Self> {
+        rules
+            .into_iter()
+            .map(|cfg| Rule {
+                cfg,
+                packets: 0,
+                bytes: 0,
+            })
+            .collect()
+    }
+
+    /// Returns true if a packet matches all fields configured on this rule
+    ///
+    /// ### Arguments
+    /// * `direction` - Direction the packet is travelling
+    /// * `pkt` - Packet to match
+    /// * `state` - State of the connection the packet belongs to
+    fn matches(&self, direction: Direction, pkt: &Ipv4Packet, state: ConnState) -> bool {
+        let cfg = &self.cfg;
+        let protocol = pkt.protocol();
+        let payload = match pkt.fragment_offset() {
+            0 => pkt.payload(),
+            _ => &[],
+        };
+
+        let ports = match protocol {
+            NET_PROTOCOL_TCP | NET_PROTOCOL_UDP if payload.len() >= 4 => Some((
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+            )),
+            _ => None,
+        };
+
+        let icmp_type = match protocol {
+            NET_PROTOCOL_ICMP => payload.first().copied(),
+            _ => None,
+        };
+
+        cfg.direction.map(|d| d == direction).unwrap_or(true)
+            && cfg.src.map(|src| src.contains(pkt.src())).unwrap_or(true)
+            && cfg.dst.map(|dst| dst.contains(pkt.dest())).unwrap_or(true)
+            && cfg.protocol.map(|p| p.number() == protocol).unwrap_or(true)
+            && cfg
+                .src_port
+                .map(|r| ports.map(|(sport, _)| r.contains(sport)).unwrap_or(false))
+                .unwrap_or(true)
+            && cfg
+                .dst_port
+                .map(|r| ports.map(|(_, dport)| r.contains(dport)).unwrap_or(false))
+                .unwrap_or(true)
+            && cfg
+                .icmp_type
+                .map(|ty| icmp_type == Some(ty))
+                .unwrap_or(true)
+            && (cfg.state.is_empty() || cfg.state.contains(&state))
+    }
+}
+
+impl Firewall {
+    /// Creates a new firewall from its configuration
+    ///
+    /// ### Arguments
+    /// * `cfg` - Default policies and rules
+    /// * `nat` - Connection tracking timeouts and size (shared with the NAT table)
+    pub fn new(cfg: FirewallConfig, nat: NatConfig) -> Self {
+        let state = FirewallState {
+            policy: cfg.policy,
+            rules: Rule::from_config(cfg.rules),
+            conntrack: ConnTrack::new(nat),
+        };
+
+        Self {
+            inner: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Replaces the policies and rules of the firewall, resetting the rule counters.
+    /// Tracked connections are kept
+    ///
+    /// ### Arguments
+    /// * `cfg` - New default policies and rules
+    pub fn reload(&self, cfg: FirewallConfig) {
+        let mut fw = self.inner.lock();
+        fw.policy = cfg.policy;
+        fw.rules = Rule::from_config(cfg.rules);
+        tracing::debug!(rules = fw.rules.len(), "[firewall] reloaded rules");
+    }
+
+    /// Returns the action to take on a packet, based on the first matching rule or the
+    /// direction's default policy.  Accepted packets that open a new connection are tracked
+    ///
+    /// ### Arguments
+    /// * `direction` - Direction the packet is travelling
+    /// * `pkt` - Packet to filter
+    pub fn filter(&self, direction: Direction, pkt: &Ipv4Packet) -> Action {
+        let mut fw = self.inner.lock();
+        let state = fw.conntrack.state(pkt);
+
+        let action = match fw
+            .rules
+            .iter_mut()
+            .find(|rule| rule.matches(direction, pkt, state))
+        {
+            Some(rule) => {
+                rule.packets += 1;
+                rule.bytes += u64::from(pkt.len());
+                rule.cfg.action
+            }
+            None => fw.policy.action(direction),
+        };
+
+        if action == Action::Accept && state == ConnState::New {
+            fw.conntrack.insert(pkt);
+        }
+
+        tracing::trace!(
+            ?direction,
+            ?state,
+            ?action,
+            src = %pkt.src(),
+            dst = %pkt.dest(),
+            "[firewall] filtered packet"
+        );
+
+        action
+    }
+
+    /// Returns the hit counters of each rule, in order
+    pub fn counters(&self) -> Vec<RuleCounters> {
+        let fw = self.inner.lock();
+        fw.rules
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| RuleCounters {
+                name: rule
+                    .cfg
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("rule-{idx}")),
+                action: rule.cfg.action,
+                packets: rule.packets,
+                bytes: rule.bytes,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use oathgate_net::{nat::NatConfig, protocols::NET_PROTOCOL_UDP, Ipv4Packet};
+
+    use crate::config::firewall::{Action, Direction, FirewallConfig};
+
+    use super::Firewall;
+
+    const VM: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 101);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    fn udp(src: Ipv4Addr, sport: u16, dst: Ipv4Addr, dport: u16) -> Ipv4Packet {
+        let mut payload = [0u8; 12];
+        payload[0..2].copy_from_slice(&sport.to_be_bytes());
+        payload[2..4].copy_from_slice(&dport.to_be_bytes());
+        payload[4..6].copy_from_slice(&12u16.to_be_bytes());
+        Ipv4Packet::new(src, dst, NET_PROTOCOL_UDP, &payload)
+    }
+
+    fn firewall(cfg: &str) -> Firewall {
+        let cfg: FirewallConfig = serde_yaml::from_str(cfg).unwrap();
+        Firewall::new(cfg, NatConfig::default())
+    }
+
+    #[test]
+    fn firewall_stateful_return_traffic() {
+        let fw = firewall(
+            r#"
+policy:
+  wan_to_lan: drop
+rules:
+  - name: established
+    direction: wan_to_lan
+    state: [established, related]
+    action: accept
+"#,
+        );
+
+        // unsolicited inbound traffic is dropped
+        let inbound = udp(REMOTE, 53, VM, 5000);
+        assert_eq!(fw.filter(Direction::WanToLan, &inbound), Action::Drop);
+
+        // ...until the lan host opens the connection
+        let outbound = udp(VM, 5000, REMOTE, 53);
+        assert_eq!(fw.filter(Direction::LanToWan, &outbound), Action::Accept);
+        assert_eq!(fw.filter(Direction::WanToLan, &inbound), Action::Accept);
+
+        let counters = fw.counters();
+        assert_eq!(counters[0].name, "established");
+        assert_eq!(counters[0].packets, 1);
+        assert_eq!(counters[0].bytes, u64::from(inbound.len()));
+    }
+
+    #[test]
+    fn firewall_reload_keeps_connections() {
+        let fw = firewall(
+            r#"
+policy:
+  wan_to_lan: drop
+rules:
+  - name: established
+    direction: wan_to_lan
+    state: [established, related]
+    action: accept
+"#,
+        );
+
+        let outbound = udp(VM, 5000, REMOTE, 53);
+        let inbound = udp(REMOTE, 53, VM, 5000);
+        assert_eq!(fw.filter(Direction::LanToWan, &outbound), Action::Accept);
+
+        let cfg: FirewallConfig = serde_yaml::from_str(
+            r#"
+policy:
+  lan_to_wan: drop
+  wan_to_lan: drop
+rules:
+  - name: return
+    direction: wan_to_lan
+    state: [established]
+    action: accept
+"#,
+        )
+        .unwrap();
+        fw.reload(cfg);
+
+        // connections opened before the reload are still tracked
+        assert_eq!(fw.filter(Direction::WanToLan, &inbound), Action::Accept);
+        assert_eq!(
+            fw.filter(Direction::LanToWan, &udp(VM, 5001, REMOTE, 53)),
+            Action::Drop
+        );
+
+        let counters = fw.counters();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].name, "return");
+        assert_eq!(counters[0].packets, 1);
+    }
+
+    #[test]
+    fn firewall_first_match_wins() {
+        let fw = firewall(
+            r#"
+rules:
+  - direction: lan_to_wan
+    dst: 198.51.100.0/24
+    protocol: udp
+    dst_port: 50-60
+    action: reject
+  - protocol: udp
+    action: drop
+"#,
+        );
+
+        assert_eq!(
+            fw.filter(Direction::LanToWan, &udp(VM, 5000, REMOTE, 53)),
+            Action::Reject
+        );
+        assert_eq!(
+            fw.filter(Direction::LanToWan, &udp(VM, 5000, REMOTE, 123)),
+            Action::Drop
+        );
+
+        let counters = fw.counters();
+        assert_eq!(counters[0].name, "rule-0");
+        assert_eq!(counters[0].packets, 1);
+        assert_eq!(counters[1].packets, 1);
+    }
+}
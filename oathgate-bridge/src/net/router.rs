@@ -1,60 +1,324 @@
 //! Simple L3 Router
 
 pub mod handler;
+mod neighbor;
+pub mod table;
 
-use std::{collections::HashMap, net::IpAddr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
-use flume::{Receiver, Sender};
+use flume::{Receiver, RecvTimeoutError, Sender};
 use oathgate_net::{
-    protocols::ArpPacket,
-    types::{EtherType, Ipv4Network, MacAddress},
-    EthernetFrame, EthernetPacket, Ipv4Packet, ProtocolError, Switch, SwitchPort,
+    nat::{FlowKey, NatStats, TcpState},
+    protocols::{
+        icmp::{self, DestinationUnreachableCode, TimeExceededCode},
+        icmpv6::{
+            self, NdpPacket, RouterAdvert, ICMPV6_TY_ECHO_REQUEST, ICMPV6_TY_NEIGHBOR_ADVERT,
+            ICMPV6_TY_NEIGHBOR_SOLICIT, ICMPV6_TY_ROUTER_SOLICIT, IPV6_ALL_NODES, NDP_HOP_LIMIT,
+            NDP_NA_FLAG_OVERRIDE, NDP_NA_FLAG_ROUTER, NDP_NA_FLAG_SOLICITED,
+        },
+        ArpPacket, IcmpPacket, NET_PROTOCOL_ICMP, NET_PROTOCOL_ICMPV6,
+    },
+    stats::{InterfaceCounters, InterfaceStats},
+    types::{EtherType, Ipv4Network, Ipv6Network, MacAddress},
+    EthernetFrame, EthernetPacket, Ipv4Packet, Ipv6Packet, ProtocolError, Switch, SwitchPort,
 };
+use serde::{Deserialize, Serialize};
 
 pub use crate::net::{
-    switch::VirtioSwitch,
-    wan::{Wan, WanHandle},
+    switch::{PortMode, VirtioSwitch, DEFAULT_VLAN},
+    wan::{SharedNat, Wan, WanHandle},
 };
 
-use self::handler::ProtocolHandler;
+use self::{
+    handler::{ProtocolHandler, ServiceCounters},
+    neighbor::{NeighborCache, RESOLVE_INTERVAL},
+    table::{NextHop, RoutingTable},
+};
 
-use super::NetworkError;
+use super::{
+    firewall::{Firewall, Zone},
+    NetworkError,
+};
+use crate::config::firewall::{Action, Direction};
 
 const IPV4_HDR_SZ: usize = 20;
 
+/// Interval between unsolicited router advertisements
+const RA_INTERVAL: Duration = Duration::from_secs(200);
+
+/// Lifetime of the router (as a default router) advertised to hosts, in seconds
+const RA_ROUTER_LIFETIME: u16 = 1800;
+
+/// MTU advertised to hosts
+const RA_MTU: u32 = 1500;
+
+/// Time to wait for the router thread to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time to wait for the router thread (and its WANs) to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub enum RouterMsg {
     FromLan(EthernetPacket),
     FromWan4(Ipv4Packet),
+    FromWan6(Ipv6Packet),
+    Request(RouterRequest),
+
+    /// Stops the router (and its WANs), sending back the state handed to its replacement
+    Shutdown(Sender<Handover>),
+}
+
+/// Requests to inspect or modify the state owned by the router thread
+pub enum RouterRequest {
+    /// Returns the neighbors learned via ARP (ipv4) and NDP (ipv6)
+    Neighbors(Sender<Vec<NeighborInfo>>),
+
+    /// Returns the state of each WAN
+    Wans(Sender<Vec<WanInfo>>),
+
+    /// Returns the entries of each WAN's NAT table
+    Nat(Sender<Vec<NatInfo>>),
+
+    /// Returns the traffic counters of each WAN and network
+    Counters(Sender<RouterCounters>),
+
+    /// Forgets all learned neighbors
+    FlushNeighbors,
+
+    /// Removes all entries from each WAN's NAT table
+    FlushNat,
+}
+
+/// A neighbor learned by the router
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NeighborInfo {
+    /// Address of the neighbor
+    pub ip: IpAddr,
+
+    /// MAC address the address resolved to
+    pub mac: MacAddress,
+
+    /// Seconds until the mapping expires
+    pub expires: u64,
+}
+
+/// State of a WAN the router forwards packets to
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WanInfo {
+    /// Name of the WAN
+    pub name: String,
+
+    /// True if the WAN started successfully
+    pub running: bool,
+
+    /// Number of entries in the WAN's NAT table, None if the WAN does not masquerade
+    pub nat_entries: Option<usize>,
+}
+
+/// A flow tracked by a WAN's NAT table
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NatInfo {
+    /// Name of the WAN
+    pub wan: String,
+
+    /// Original flow (as seen on the LAN side)
+    pub flow: FlowKey,
+
+    /// Source port (or ICMP identifier) used on the WAN side
+    pub external_port: u16,
+
+    /// Tracked state of TCP connections, None for other protocols
+    pub tcp: Option<TcpState>,
+
+    /// True if the flow was opened from the WAN through a port forward
+    pub forwarded: bool,
+
+    /// Seconds since a packet last matched the flow
+    pub idle: u64,
+}
+
+/// Traffic counters kept by the router
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RouterCounters {
+    /// Counters of each WAN
+    pub wans: Vec<WanCounters>,
+
+    /// Counters of each network served by the router
+    pub lans: Vec<LanCounters>,
+}
+
+/// Traffic counters of a WAN
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WanCounters {
+    /// Name of the WAN
+    pub name: String,
+
+    /// Packets received from (rx) and written to (tx) the WAN
+    pub counters: InterfaceCounters,
+
+    /// Statistics of the WAN's NAT table, None if the WAN does not masquerade
+    pub nat: Option<NatStats>,
+}
+
+/// Traffic counters of a network served by the router
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LanCounters {
+    /// VLAN the network is attached to
+    pub vlan: u16,
+
+    /// Packets addressed to (rx) and replies sent by (tx) each protocol handler
+    pub protocols: Vec<ProtocolCounters>,
+
+    /// Counters kept by the services (i.e., dhcp) running on the network
+    pub services: Vec<ServiceCounters>,
+}
+
+/// Traffic counters of a protocol handler
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProtocolCounters {
+    /// IP protocol number handled
+    pub protocol: u8,
+
+    /// Packets addressed to (rx) and replies sent by (tx) the handler
+    pub counters: InterfaceCounters,
 }
 
 pub enum RouterAction {
     ToLan(EtherType, IpAddr, Vec<u8>),
-    ToWan(Ipv4Packet),
+    ToWan(usize, Ipv4Packet),
+    ToWan6(usize, Ipv6Packet),
     Drop(Vec<u8>),
 }
 
 #[derive(Clone)]
 pub struct RouterHandle {
     tx: Sender<RouterMsg>,
+
+    /// Receiving end of the router's channel, handed to each router replacing the last
+    rx: Receiver<RouterMsg>,
+
+    /// Link to the router of the WAN owning this handle, if any
+    wan: Option<WanLink>,
+}
+
+/// Link between a WAN and the router it forwards packets to
+#[derive(Clone)]
+struct WanLink {
+    /// Packets received from (rx) and written to (tx) the WAN
+    stats: Arc<InterfaceStats>,
+
+    /// Set when the router shuts down, telling the WAN to stop
+    stopped: Arc<AtomicBool>,
+}
+
+/// State of a router that was shut down, handed to the router replacing it
+pub struct Handover {
+    /// Switch port the router is connected to
+    port: usize,
+
+    /// MAC address of the router
+    mac: MacAddress,
+
+    /// Neighbors learned by the router
+    arp: NeighborCache,
+}
+
+/// A router that failed to start in place of a router that was shut down.  The state handed
+/// over is returned so another router can take its place
+pub struct RespawnError {
+    pub error: NetworkError,
+    pub handover: Handover,
 }
 
 pub struct Router {
-    arp: HashMap<IpAddr, MacAddress>,
+    /// Neighbor cache, populated by ARP (ipv4) and NDP (ipv6)
+    arp: NeighborCache,
     switch: VirtioSwitch,
     port: usize,
-    wan: Option<Box<dyn WanHandle>>,
+    wans: Vec<Uplink>,
+    routes: RoutingTable,
+    firewall: Option<Firewall>,
     mac: MacAddress,
+
+    /// Networks served by the router, the primary network (on the default vlan) first
+    lans: Vec<Lan>,
+    network6: Option<Ipv6Network>,
+    link_local: Ipv6Addr,
+    managed: bool,
+    next_ra: Instant,
+    next_tick: Instant,
+
+    /// Set when the router shuts down, telling its WANs to stop
+    stopped: Arc<AtomicBool>,
+}
+
+/// A network served by the router, attached to one VLAN of the switch
+pub struct Lan {
+    /// VLAN the network is attached to
+    vlan: u16,
+
+    /// Address and subnet of the router on this network
     network: Ipv4Network,
-    ip4_handlers: HashMap<u8, Box<dyn ProtocolHandler>>,
+
+    /// True if traffic routed between this network and other networks is dropped
+    isolated: bool,
+
+    /// Mapping of protocol numbers to a handler to run when a packet addressed to the
+    /// router on this network is received
+    handlers: HashMap<u8, Box<dyn ProtocolHandler>>,
+
+    /// Counters of each protocol handler, keyed by protocol number
+    counters: HashMap<u8, InterfaceCounters>,
 }
 
 pub struct RouterBuilder {
-    /// Mapping of ipv4 protocol numbers to a handler to run when a packet
-    /// matching the protocol is received
-    ip4_handlers: HashMap<u8, Box<dyn ProtocolHandler>>,
+    /// Mapping of protocol numbers (ipv4 protocol / ipv6 next header) to a handler to run
+    /// when a packet matching the protocol is received on the primary network
+    handlers: HashMap<u8, Box<dyn ProtocolHandler>>,
+
+    /// Additional networks, each on its own VLAN
+    vlans: Vec<Lan>,
+
+    /// Wide Area Network (WAN) connections, referenced by index in the routing table
+    wans: Vec<(String, Box<dyn Wan>)>,
+
+    /// Routes for packets not destined for the LAN
+    routes: RoutingTable,
+
+    /// Filters forwarded packets, if enabled
+    firewall: Option<Firewall>,
+
+    /// Router address and prefix advertised to hosts, if ipv6 is enabled
+    network6: Option<Ipv6Network>,
 
-    /// Wide Area Network (WAN) connection
-    wan: Option<Box<dyn Wan>>,
+    /// True if hosts should obtain addresses via DHCPv6
+    managed: bool,
+
+    /// True if the router fails to start when a WAN fails to start
+    require_wans: bool,
+}
+
+/// A WAN the router forwards packets to
+struct Uplink {
+    name: String,
+
+    /// Handle to write packets to the WAN, or None if it failed to start
+    handle: Option<Box<dyn WanHandle>>,
+
+    /// Packets received from (rx) and written to (tx) the WAN
+    stats: Arc<InterfaceStats>,
+
+    /// Thread running the WAN, None if it failed to start
+    thread: Option<JoinHandle<()>>,
 }
 
 impl<T> From<flume::SendError<T>> for NetworkError {
@@ -65,136 +329,571 @@ impl<T> From<flume::SendError<T>> for NetworkError {
 
 #[allow(dead_code)]
 impl RouterBuilder {
-    pub fn wan(mut self, wan: Option<Box<dyn Wan>>) -> Self {
-        self.wan = wan;
+    /// Adds a WAN connection.  WANs are referenced by the routing table in the order they
+    /// are added
+    ///
+    /// ### Arguments
+    /// * `name` - Name of the WAN
+    /// * `wan` - WAN connection
+    pub fn wan<S: Into<String>>(mut self, name: S, wan: Box<dyn Wan>) -> Self {
+        self.wans.push((name.into(), wan));
+        self
+    }
+
+    /// Sets the routing table used for packets not destined for the LAN
+    ///
+    /// ### Arguments
+    /// * `routes` - Static routes and policy rules
+    pub fn routes(mut self, routes: RoutingTable) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    pub fn ipv6(mut self, network: Option<Ipv6Network>) -> Self {
+        self.network6 = network;
+        self
+    }
+
+    /// Filters packets forwarded by the router
+    ///
+    /// ### Arguments
+    /// * `firewall` - Firewall to apply, or None to forward all packets
+    pub fn firewall(mut self, firewall: Option<Firewall>) -> Self {
+        self.firewall = firewall;
+        self
+    }
+
+    /// Advertises that addresses (and other configuration) are available via DHCPv6
+    ///
+    /// ### Arguments
+    /// * `managed` - True if a DHCPv6 server is running
+    pub fn managed(mut self, managed: bool) -> Self {
+        self.managed = managed;
         self
     }
 
     pub fn register_proto_handler<P: ProtocolHandler + 'static>(mut self, handler: P) -> Self {
         let proto = handler.protocol();
-        self.ip4_handlers.insert(proto, Box::new(handler));
+        self.handlers.insert(proto, Box::new(handler));
+        self
+    }
+
+    /// Fails to start the router when any of its WANs fails to start, instead of running
+    /// without the WAN
+    ///
+    /// ### Arguments
+    /// * `required` - True if every WAN must start
+    pub fn require_wans(mut self, required: bool) -> Self {
+        self.require_wans = required;
+        self
+    }
+
+    /// Adds a network, served by the router on its own VLAN
+    ///
+    /// ### Arguments
+    /// * `lan` - Network (and its handlers) to serve
+    pub fn vlan(mut self, lan: Lan) -> Self {
+        self.vlans.push(lan);
         self
     }
 
-    /// Create the router, spawning a new thread to run the core logic
+    /// Create the router, spawning a new thread to run the core logic.  Returns a handle used
+    /// to inspect the router while it runs
     ///
     /// ### Arguments
-    /// * `network` - Network address and subnet mask
-    pub fn spawn(self, network: Ipv4Network, switch: VirtioSwitch) -> std::io::Result<()> {
+    /// * `network` - Network address and subnet mask of the primary network
+    /// * `switch` - Switch the router is connected to
+    pub fn spawn(
+        self,
+        network: Ipv4Network,
+        switch: VirtioSwitch,
+    ) -> Result<RouterHandle, NetworkError> {
         let (tx, rx) = flume::unbounded();
+        let handle = RouterHandle { tx, rx, wan: None };
+        let port = switch.with_mode(self.port_mode()).connect(handle.clone());
 
-        let handle = RouterHandle { tx };
-        let port = switch.connect(handle.clone());
+        let handover = Handover {
+            port,
+            mac: MacAddress::generate(),
+            arp: NeighborCache::default(),
+        };
 
-        let wan = self.wan.and_then(|wan| match wan.spawn(handle) {
-            Ok(handle) => Some(handle),
-            Err(error) => {
-                tracing::warn!(?error, "unable to start wan");
-                None
-            }
-        });
+        self.respawn(network, switch, &handle, handover)
+            .map_err(|error| error.error)?;
+        Ok(handle)
+    }
+
+    /// Create the router in place of a router that was shut down, spawning a new thread to
+    /// run the core logic.  The new router keeps the switch port, MAC address and neighbors of
+    /// the router it replaces and receives the messages sent to the existing handle
+    ///
+    /// If the router fails to start (see `require_wans`), the WANs already started are
+    /// stopped and the handover is returned with the error
+    ///
+    /// ### Arguments
+    /// * `network` - Network address and subnet mask of the primary network
+    /// * `switch` - Switch the router is connected to
+    /// * `handle` - Handle of the router being replaced
+    /// * `handover` - State returned by the router being replaced when it shut down
+    pub fn respawn(
+        self,
+        network: Ipv4Network,
+        switch: VirtioSwitch,
+        handle: &RouterHandle,
+        handover: Handover,
+    ) -> Result<(), Box<RespawnError>> {
+        let mode = self.port_mode();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut wans = Vec::new();
+        for (name, wan) in self.wans {
+            let stats = Arc::new(InterfaceStats::default());
+            let link = WanLink {
+                stats: Arc::clone(&stats),
+                stopped: Arc::clone(&stopped),
+            };
+
+            let (wan_handle, thread) = match wan.spawn(handle.with_wan(link)) {
+                Ok((wan_handle, thread)) => (Some(wan_handle), Some(thread)),
+                Err(error) if self.require_wans => {
+                    stop_wans(&stopped, wans);
+                    let error = format!("unable to start wan `{name}`: {error}");
+                    return Err(Box::new(RespawnError {
+                        error: NetworkError::Generic(error.into()),
+                        handover,
+                    }));
+                }
+                Err(error) => {
+                    tracing::warn!(?error, %name, "unable to start wan");
+                    (None, None)
+                }
+            };
+
+            wans.push(Uplink {
+                name,
+                handle: wan_handle,
+                stats,
+                thread,
+            });
+        }
+
+        let mut lans = vec![Lan {
+            vlan: DEFAULT_VLAN,
+            network,
+            isolated: false,
+            handlers: self.handlers,
+            counters: HashMap::new(),
+        }];
+        lans.extend(self.vlans);
+
+        let (port, mac) = (handover.port, handover.mac);
+        switch.set_port_mode(port, mode);
 
         let router = Router {
-            arp: HashMap::new(),
+            arp: handover.arp,
             switch,
             port,
-            wan,
-            mac: MacAddress::generate(),
-            network,
-            ip4_handlers: self.ip4_handlers,
+            wans,
+            routes: self.routes,
+            firewall: self.firewall,
+            mac,
+            lans,
+            network6: self.network6,
+            link_local: mac.link_local(),
+            managed: self.managed,
+            next_ra: Instant::now(),
+            next_tick: Instant::now() + RESOLVE_INTERVAL,
+            stopped: Arc::clone(&stopped),
         };
 
-        std::thread::Builder::new()
+        let rx = handle.rx.clone();
+        if let Err(error) = std::thread::Builder::new()
             .name(String::from("router"))
-            .spawn(move || router.run(rx))?;
+            .spawn(move || router.run(rx))
+        {
+            // the router (and its wans' handles) were dropped, neighbors will be relearned
+            stopped.store(true, Ordering::Relaxed);
+            return Err(Box::new(RespawnError {
+                error: error.into(),
+                handover: Handover {
+                    port,
+                    mac,
+                    arp: NeighborCache::default(),
+                },
+            }));
+        }
 
         Ok(())
     }
+
+    /// Returns the VLAN membership of the router's switch port.  The router is a member of
+    /// every vlan it serves, receiving tagged frames for all but the primary network
+    fn port_mode(&self) -> PortMode {
+        PortMode::Trunk {
+            native: Some(DEFAULT_VLAN),
+            allowed: self.vlans.iter().map(|lan| lan.vlan).collect(),
+        }
+    }
+}
+
+/// Stops WANs, waiting for their threads to exit (i.e., closing their devices and sockets)
+///
+/// ### Arguments
+/// * `stopped` - Stop flag shared with the WANs
+/// * `wans` - WANs to stop
+fn stop_wans(stopped: &AtomicBool, wans: Vec<Uplink>) {
+    stopped.store(true, Ordering::Relaxed);
+
+    for wan in wans {
+        drop(wan.handle);
+        if let Some(thread) = wan.thread {
+            if thread.join().is_err() {
+                tracing::warn!(wan = %wan.name, "[router] wan thread panicked");
+            }
+        }
+    }
+}
+
+impl Lan {
+    /// Creates a network served by the router on a VLAN
+    ///
+    /// ### Arguments
+    /// * `vlan` - VLAN the network is attached to
+    /// * `network` - Address and subnet of the router on the network
+    pub fn new(vlan: u16, network: Ipv4Network) -> Self {
+        Self {
+            vlan,
+            network,
+            isolated: false,
+            handlers: HashMap::new(),
+            counters: HashMap::new(),
+        }
+    }
+
+    /// Drops traffic routed between this network and the router's other networks
+    ///
+    /// ### Arguments
+    /// * `isolated` - True to isolate the network
+    pub fn isolated(mut self, isolated: bool) -> Self {
+        self.isolated = isolated;
+        self
+    }
+
+    pub fn register_proto_handler<P: ProtocolHandler + 'static>(mut self, handler: P) -> Self {
+        let proto = handler.protocol();
+        self.handlers.insert(proto, Box::new(handler));
+        self
+    }
 }
 
 impl Router {
     pub fn builder() -> RouterBuilder {
         RouterBuilder {
-            ip4_handlers: HashMap::new(),
-            wan: None,
+            handlers: HashMap::new(),
+            vlans: Vec::new(),
+            wans: Vec::new(),
+            routes: RoutingTable::default(),
+            firewall: None,
+            network6: None,
+            managed: false,
+            require_wans: false,
         }
     }
 
     pub fn run(mut self, rx: Receiver<RouterMsg>) {
+        // let hosts with a stale cache entry (i.e., from a previous run) know where we are
+        for (lan, network) in self.networks().into_iter().enumerate() {
+            self.arp_request(lan, network.ip());
+        }
+
         loop {
-            match rx.recv() {
+            match rx.recv_deadline(self.next_ra.min(self.next_tick)) {
                 Ok(RouterMsg::FromLan(pkt)) => match self.route(pkt) {
                     Ok(_) => (),
                     Err(error) => tracing::warn!(?error, "unable to route lan packet"),
                 },
                 Ok(RouterMsg::FromWan4(pkt)) => {
                     if let Err(error) = self
-                        .route_ip4(pkt)
-                        .and_then(|action| self.handle_action(action, None))
+                        .route_ip4(pkt, Zone::Wan, 0)
+                        .and_then(|action| self.handle_action(action, 0, None))
                     {
                         tracing::warn!(?error, "unable to route wan packet");
                     }
                 }
+                Ok(RouterMsg::FromWan6(pkt)) => {
+                    if let Err(error) = self
+                        .route_ip6(pkt)
+                        .and_then(|action| self.handle_action(action, 0, None))
+                    {
+                        tracing::warn!(?error, "unable to route wan packet");
+                    }
+                }
+                Ok(RouterMsg::Request(request)) => self.handle_request(request),
+                Ok(RouterMsg::Shutdown(tx)) => {
+                    tx.send(self.shutdown()).ok();
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
                 Err(error) => {
                     tracing::error!(?error, "unable to receive packet");
                     break;
                 }
             }
+
+            if Instant::now() >= self.next_tick {
+                self.next_tick = Instant::now() + RESOLVE_INTERVAL;
+                self.tick();
+            }
+
+            if Instant::now() >= self.next_ra {
+                self.next_ra = Instant::now() + RA_INTERVAL;
+                if let Err(error) = self.advertise() {
+                    tracing::warn!(?error, "[router] unable to send router advertisement");
+                }
+            }
         }
 
         tracing::info!("router died");
     }
 
+    /// Stops the router's WANs, waiting for their threads to exit (i.e., closing their
+    /// devices and sockets), and returns the state handed to the router replacing this one
+    fn shutdown(mut self) -> Handover {
+        stop_wans(&self.stopped, std::mem::take(&mut self.wans));
+
+        tracing::info!("[router] stopped");
+        Handover {
+            port: self.port,
+            mac: self.mac,
+            arp: self.arp,
+        }
+    }
+
+    /// Answers a request to inspect (or modify) the router's state.  Requests whose sender
+    /// has gone away (e.g., timed out) are ignored
+    ///
+    /// ### Arguments
+    /// * `request` - Request to answer
+    fn handle_request(&mut self, request: RouterRequest) {
+        match request {
+            RouterRequest::Neighbors(tx) => {
+                let neighbors = self
+                    .arp
+                    .entries()
+                    .map(|(ip, mac, expires)| NeighborInfo {
+                        ip,
+                        mac,
+                        expires: expires.as_secs(),
+                    })
+                    .collect();
+
+                tx.send(neighbors).ok();
+            }
+            RouterRequest::Wans(tx) => {
+                let wans = self
+                    .wans
+                    .iter()
+                    .map(|wan| WanInfo {
+                        name: wan.name.clone(),
+                        running: wan.handle.is_some(),
+                        nat_entries: wan.nat().map(|nat| nat.lock().len()),
+                    })
+                    .collect();
+
+                tx.send(wans).ok();
+            }
+            RouterRequest::Nat(tx) => {
+                let mut entries = Vec::new();
+                for wan in &self.wans {
+                    let Some(nat) = wan.nat() else {
+                        continue;
+                    };
+
+                    entries.extend(nat.lock().entries().map(|entry| NatInfo {
+                        wan: wan.name.clone(),
+                        flow: entry.flow,
+                        external_port: entry.external_port,
+                        tcp: entry.tcp,
+                        forwarded: entry.forwarded,
+                        idle: entry.idle().as_secs(),
+                    }));
+                }
+
+                tx.send(entries).ok();
+            }
+            RouterRequest::Counters(tx) => {
+                let wans = self
+                    .wans
+                    .iter()
+                    .map(|wan| WanCounters {
+                        name: wan.name.clone(),
+                        counters: wan.stats.load(),
+                        nat: wan.nat().map(|nat| nat.lock().stats()),
+                    })
+                    .collect();
+
+                let lans = self.lans.iter().map(Lan::counters).collect();
+                tx.send(RouterCounters { wans, lans }).ok();
+            }
+            RouterRequest::FlushNeighbors => {
+                self.arp.clear();
+                tracing::debug!("[router] neighbor cache flushed");
+            }
+            RouterRequest::FlushNat => {
+                for nat in self.wans.iter().filter_map(Uplink::nat) {
+                    nat.lock().clear();
+                }
+                tracing::debug!("[router] nat tables flushed");
+            }
+        }
+    }
+
     /// Routes a packet based on it's packet type
     ///
     /// ### Arguments
     /// * `ethertype` - What type of data is contained in the packet
     /// * `pkt` - Packet data (based on ethertype)
     fn route(&mut self, pkt: EthernetPacket) -> Result<(), ProtocolError> {
-        let action = match pkt.frame.ethertype {
-            EtherType::ARP => self.handle_arp(pkt.payload),
+        let tag = pkt.frame.vlan;
+        let Some(lan) = self.lan_by_vlan(tag) else {
+            tracing::trace!(?tag, "[router] not serving vlan, dropping packet");
+            return Ok(());
+        };
+
+        let (src, action) = match pkt.frame.ethertype {
+            EtherType::ARP => {
+                let action = self.handle_arp(lan, pkt.payload)?;
+                return self.handle_action(action, lan, Some(pkt.frame.src));
+            }
             EtherType::IPv4 => {
-                let pkt = Ipv4Packet::parse(pkt.payload)?;
-                self.route_ip4(pkt)
+                let ipv4 = Ipv4Packet::parse(pkt.payload)?;
+                (
+                    IpAddr::V4(ipv4.src()),
+                    self.route_ip4(ipv4, Zone::Lan, lan)?,
+                )
             }
-            EtherType::IPv6 => self.route_ip6(pkt.payload),
-        }?;
+            EtherType::IPv6 if lan == 0 => {
+                let ipv6 = Ipv6Packet::parse(pkt.payload)?;
+                (IpAddr::V6(ipv6.src()), self.route_ip6(ipv6)?)
+            }
+            EtherType::IPv6 | EtherType::Vlan => {
+                tracing::trace!(?tag, "[router] ipv6 not served on vlan, dropping packet");
+                return Ok(());
+            }
+        };
+
+        // only replies go back to the sender, forwarded packets are resolved via arp / ndp
+        let dst = match action {
+            RouterAction::ToLan(_, dst, _) if dst == src => Some(pkt.frame.src),
+            _ => None,
+        };
 
-        self.handle_action(action, Some(pkt.frame.src))
+        self.handle_action(action, lan, dst)
     }
 
+    /// Sends a packet to its destination
+    ///
+    /// ### Arguments
+    /// * `action` - Where to send the packet
+    /// * `lan` - Network the packet is sent on if it cannot be determined from its
+    ///   destination address (i.e., replies to broadcasts)
+    /// * `dst` - MAC address of the destination, if known
     fn handle_action(
         &mut self,
         action: RouterAction,
+        lan: usize,
         dst: Option<MacAddress>,
     ) -> Result<(), ProtocolError> {
         match action {
             RouterAction::ToLan(ethertype, dst_ip, pkt) => {
-                let dst = dst.or_else(|| self.arp.get(&dst_ip).copied());
+                let lan = self.egress_lan(dst_ip, lan);
+                let dst = match dst_ip {
+                    IpAddr::V6(ip) if ip.is_multicast() => Some(MacAddress::ipv6_multicast(ip)),
+                    _ => dst.or_else(|| self.arp.get(&dst_ip)),
+                };
 
                 match dst {
-                    Some(dst) => self.write_to_switch(dst, ethertype, pkt),
+                    Some(dst) => self.write_to_switch(lan, dst, ethertype, pkt),
                     None => {
-                        tracing::warn!(ip = ?dst_ip, "[router] mac not found in arp cache, dropping packet")
+                        tracing::debug!(ip = %dst_ip, "[router] neighbor unknown, queueing packet");
+                        if self.arp.queue(dst_ip, ethertype, pkt) {
+                            self.resolve(dst_ip);
+                        }
                     }
                 }
             }
-            RouterAction::ToWan(pkt) => match self.forward_packet(pkt) {
+            RouterAction::ToWan(wan, pkt) => match self.forward_packet(wan, pkt) {
                 Ok(_) => tracing::trace!("[router] forwarded packet"),
                 Err(error) => tracing::warn!(?error, "[router] unable to forward packet"),
             },
+            RouterAction::ToWan6(wan, pkt) => match self.forward_packet6(wan, pkt) {
+                Ok(_) => tracing::trace!("[router] forwarded ipv6 packet"),
+                Err(error) => tracing::warn!(?error, "[router] unable to forward ipv6 packet"),
+            },
             RouterAction::Drop(_pkt) => tracing::debug!("[router] dropping packet"),
         }
 
         Ok(())
     }
 
+    /// Returns the address and subnet of the router on each network it serves
+    fn networks(&self) -> Vec<Ipv4Network> {
+        self.lans.iter().map(|lan| lan.network).collect()
+    }
+
+    /// Returns the network (index) an address belongs to, or None if it is not on any
+    /// network served by the router
+    ///
+    /// ### Arguments
+    /// * `ip` - Address to lookup
+    fn lan_of(&self, ip: Ipv4Addr) -> Option<usize> {
+        self.lans.iter().position(|lan| lan.network.contains(ip))
+    }
+
+    /// Returns the network (index) attached to a VLAN.  Untagged frames belong to the
+    /// primary network
+    ///
+    /// ### Arguments
+    /// * `tag` - VLAN identifier of a received frame, if tagged
+    fn lan_by_vlan(&self, tag: Option<u16>) -> Option<usize> {
+        match tag {
+            None => Some(0),
+            Some(vlan) => self.lans.iter().position(|lan| lan.vlan == vlan),
+        }
+    }
+
+    /// Returns the network (index) a packet for a host should be sent on
+    ///
+    /// ### Arguments
+    /// * `ip` - Address of the host
+    /// * `fallback` - Network to use if the host is not on a network served by the router
+    fn egress_lan(&self, ip: IpAddr, fallback: usize) -> usize {
+        match ip {
+            IpAddr::V4(ip) => self.lan_of(ip).unwrap_or(fallback),
+            // ipv6 is only served on the primary network
+            IpAddr::V6(_) => 0,
+        }
+    }
+
+    /// Returns true if traffic from a network to a host on another network must be dropped
+    ///
+    /// ### Arguments
+    /// * `from` - Network (index) the packet was received on
+    /// * `dst` - Address of the host on the lan the packet is routed to
+    fn is_isolated(&self, from: usize, dst: Ipv4Addr) -> bool {
+        match self.lan_of(dst) {
+            Some(to) if to != from => self.lans[from].isolated || self.lans[to].isolated,
+            _ => false,
+        }
+    }
+
     /// Returns true if a packet is destined for this local device or if
     /// the it is a broadcast packet
     fn is_local<A: Into<IpAddr>>(&self, dst: A) -> bool {
         match dst.into() {
-            IpAddr::V4(ip) => self.network == ip,
-            IpAddr::V6(_ip) => false,
+            IpAddr::V4(ip) => self.lans.iter().any(|lan| lan.network == ip),
+            IpAddr::V6(ip) => {
+                ip == self.link_local || self.network6.map(|n| n == ip).unwrap_or(false)
+            }
         }
     }
 
@@ -206,18 +905,46 @@ impl Router {
         }
     }
 
-    fn handle_arp(&mut self, pkt: Vec<u8>) -> Result<RouterAction, ProtocolError> {
+    /// Handles an arp packet received on one of the router's networks
+    ///
+    /// ### Arguments
+    /// * `lan` - Network (index) the packet was received on
+    /// * `pkt` - Arp packet
+    fn handle_arp(&mut self, lan: usize, pkt: Vec<u8>) -> Result<RouterAction, ProtocolError> {
         tracing::trace!("handling arp packet");
         let mut arp = ArpPacket::parse(&pkt)?;
+        let network = self.lans[lan].network;
 
-        tracing::trace!(
-            "[router] associating mac to ip: {:?} -> {}",
-            arp.spa,
-            arp.sha
-        );
-        self.arp.insert(arp.spa, arp.sha);
+        if self.is_local(arp.spa) {
+            // another host is claiming our address, defend it
+            tracing::warn!(ip = ?arp.spa, mac = %arp.sha, "[router] address conflict detected");
+            self.arp_request(lan, network.ip());
+            return Ok(RouterAction::Drop(pkt));
+        }
 
-        if self.is_local(arp.tpa) || self.is_global_broadcast(arp.tpa) {
+        // probes (duplicate address detection) have an unspecified sender address, while
+        // gratuitous arps (sender == target) only update the cache and are never answered
+        let on_link = match arp.spa {
+            IpAddr::V4(ip) => !ip.is_unspecified() && network.contains(ip),
+            IpAddr::V6(_) => false,
+        };
+
+        if on_link {
+            tracing::trace!(
+                "[router] associating mac to ip: {:?} -> {}",
+                arp.spa,
+                arp.sha
+            );
+            self.learn(arp.spa, arp.sha);
+        }
+
+        // only requests (operation 1) are answered, replies were consumed by the cache above
+        if arp.operation != 1 || arp.spa == arp.tpa {
+            return Ok(RouterAction::Drop(pkt));
+        }
+
+        let is_target = matches!(arp.tpa, IpAddr::V4(ip) if network == ip);
+        if is_target || self.is_global_broadcast(arp.tpa) {
             // responsd with router's mac
             let mut rpkt = vec![0u8; arp.size()];
             arp.to_reply(self.mac);
@@ -229,46 +956,644 @@ impl Router {
         }
     }
 
-    fn forward_packet(&mut self, pkt: Ipv4Packet) -> Result<(), NetworkError> {
-        if let Some(ref wan) = self.wan {
-            if let Err(error) = wan.write(pkt) {
-                tracing::warn!(?error, "unable to write to wan, dropping packet");
+    fn forward_packet(&mut self, wan: usize, pkt: Ipv4Packet) -> Result<(), NetworkError> {
+        match self.wans.get(wan) {
+            Some(Uplink {
+                name,
+                handle: Some(handle),
+                stats,
+                ..
+            }) => {
+                let len = pkt.as_bytes().len();
+                match handle.write(pkt) {
+                    Ok(_) => stats.tx.record(len),
+                    Err(error) => {
+                        stats.tx.record_error();
+                        tracing::warn!(?error, wan = %name, "unable to write to wan, dropping packet");
+                    }
+                }
             }
-        } else {
-            tracing::warn!("[router] no wan device, dropping packet");
+            Some(Uplink {
+                name,
+                handle: None,
+                stats,
+                ..
+            }) => {
+                // route_ip4 answers with network unreachable when the wan is not running
+                stats.tx.record_drop();
+                tracing::warn!(wan = %name, "[router] wan not running, dropping packet");
+            }
+            None => tracing::warn!(wan, "[router] no wan device, dropping packet"),
         }
         Ok(())
     }
 
-    /// Routes an IPv4 packet to the appropriate destination
-    fn route_ip4(&mut self, pkt: Ipv4Packet) -> Result<RouterAction, ProtocolError> {
-        match self.network.contains(pkt.dest()) || pkt.dest().is_broadcast() {
-            true => match self.is_local(pkt.dest()) || pkt.dest().is_broadcast() {
-                true => Ok(self.handle_local_ipv4(pkt)),
-                false => {
-                    let dst = pkt.dest();
-                    Ok(RouterAction::ToLan(
-                        EtherType::IPv4,
-                        IpAddr::V4(dst),
-                        pkt.into_bytes(),
-                    ))
+    fn forward_packet6(&mut self, wan: usize, pkt: Ipv6Packet) -> Result<(), NetworkError> {
+        let Some(uplink) = self.wans.get(wan) else {
+            tracing::debug!(wan, "[router] no wan device, dropping ipv6 packet");
+            return Ok(());
+        };
+
+        match uplink.handle.as_ref() {
+            Some(handle) => {
+                let len = pkt.as_bytes().len();
+                match handle.write_ipv6(pkt) {
+                    Ok(_) => uplink.stats.tx.record(len),
+                    Err(error) => {
+                        uplink.stats.tx.record_error();
+                        tracing::warn!(?error, "unable to write to wan, dropping packet");
+                    }
                 }
-            },
-            false => Ok(RouterAction::ToWan(pkt)),
+            }
+            None => {
+                uplink.stats.tx.record_drop();
+                tracing::debug!(wan, "[router] wan not running, dropping ipv6 packet");
+            }
         }
+        Ok(())
     }
 
-    fn route_ip6(&self, pkt: Vec<u8>) -> Result<RouterAction, ProtocolError> {
-        tracing::debug!("ipv6 not supported, dropping packet");
-        Ok(RouterAction::Drop(pkt))
+    /// Returns the mtu of a wan, or None if the wan is not running
+    ///
+    /// ### Arguments
+    /// * `wan` - Index of the wan
+    fn wan_mtu(&self, wan: usize) -> Option<u16> {
+        self.wans
+            .get(wan)
+            .and_then(|uplink| uplink.handle.as_ref())
+            .map(|handle| handle.mtu())
     }
 
-    fn handle_local_ipv4(&mut self, pkt: Ipv4Packet) -> RouterAction {
-        let mut rpkt = vec![0u8; 1560];
-
-        match self.ip4_handlers.get_mut(&pkt.protocol()) {
+    /// Routes an IPv4 packet to the appropriate destination
+    ///
+    /// Forwarded packets have their ttl decremented, and the sender is notified (via ICMP) if
+    /// the packet cannot be delivered
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet to route
+    /// * `from` - Where the packet was received from
+    /// * `lan` - Network (index) the packet was received on, if received from the lan
+    fn route_ip4(
+        &mut self,
+        mut pkt: Ipv4Packet,
+        from: Zone,
+        lan: usize,
+    ) -> Result<RouterAction, ProtocolError> {
+        let dst = pkt.dest();
+        let on_link = self.lan_of(dst).is_some();
+
+        if self.is_local(dst) || dst.is_broadcast() {
+            // broadcasts are handled by the network they were received on
+            let lan = self
+                .lans
+                .iter()
+                .position(|lan| lan.network == dst)
+                .unwrap_or(lan);
+
+            return Ok(self.handle_local_ipv4(pkt, lan));
+        }
+
+        if pkt.ttl() <= 1 {
+            tracing::debug!(%dst, "[router] ttl exceeded, dropping packet");
+            let icmp = IcmpPacket::time_exceeded(TimeExceededCode::TtlExceeded, &pkt);
+            return Ok(self.icmp_reply(pkt, icmp));
+        }
+
+        pkt.decrement_ttl();
+
+        let hop = match on_link {
+            true => NextHop::Lan(dst),
+            false => match self
+                .routes
+                .lookup_forward(&pkt, |wan, pkt| self.wans[wan].is_forwarded(pkt))
+                .or_else(|| self.routes.lookup(pkt.src(), dst))
+            {
+                Some(hop) => hop,
+                None => {
+                    tracing::debug!(%dst, "[router] no route to host, network unreachable");
+                    let icmp = IcmpPacket::unreachable(
+                        DestinationUnreachableCode::NetworkUnreachable,
+                        &pkt,
+                    );
+                    return Ok(self.icmp_reply(pkt, icmp));
+                }
+            },
+        };
+
+        let to = match hop {
+            NextHop::Lan(_) => Zone::Lan,
+            NextHop::Wan(_) => Zone::Wan,
+        };
+
+        if let NextHop::Lan(ip) = hop {
+            if from == Zone::Lan && self.is_isolated(lan, ip) {
+                tracing::debug!(src = %pkt.src(), %dst, "[router] network isolated, dropping packet");
+                let icmp =
+                    IcmpPacket::unreachable(DestinationUnreachableCode::CommAdminProhibited, &pkt);
+                return Ok(self.icmp_reply(pkt, icmp));
+            }
+        }
+
+        if let Some(action) = self.filter(from, to, &pkt) {
+            return Ok(action);
+        }
+
+        let wan = match hop {
+            NextHop::Lan(ip) => {
+                return Ok(RouterAction::ToLan(
+                    EtherType::IPv4,
+                    IpAddr::V4(ip),
+                    pkt.into_bytes(),
+                ))
+            }
+            NextHop::Wan(wan) => wan,
+        };
+
+        match self.wan_mtu(wan) {
+            None => {
+                tracing::debug!(%dst, "[router] wan not running, network unreachable");
+                let icmp =
+                    IcmpPacket::unreachable(DestinationUnreachableCode::NetworkUnreachable, &pkt);
+                Ok(self.icmp_reply(pkt, icmp))
+            }
+            Some(mtu) if pkt.len() > mtu && pkt.dont_fragment() => {
+                tracing::debug!(%dst, len = pkt.len(), mtu, "[router] packet exceeds wan mtu");
+                let icmp = IcmpPacket::fragmentation_needed(mtu, &pkt);
+                Ok(self.icmp_reply(pkt, icmp))
+            }
+            Some(_) => Ok(RouterAction::ToWan(wan, pkt)),
+        }
+    }
+
+    /// Runs a forwarded packet through the firewall (if enabled), returning the action to take
+    /// if the packet was not accepted
+    ///
+    /// ### Arguments
+    /// * `from` - Where the packet was received from
+    /// * `to` - Where the packet is being sent
+    /// * `pkt` - Packet being forwarded
+    fn filter(&self, from: Zone, to: Zone, pkt: &Ipv4Packet) -> Option<RouterAction> {
+        let firewall = self.firewall.as_ref()?;
+        let direction = Direction::between(from, to)?;
+
+        match firewall.filter(direction, pkt) {
+            Action::Accept => None,
+            Action::Drop => Some(RouterAction::Drop(Vec::new())),
+            Action::Reject => {
+                let icmp =
+                    IcmpPacket::unreachable(DestinationUnreachableCode::CommAdminProhibited, pkt);
+                Some(
+                    self.icmp_error(pkt, icmp)
+                        .unwrap_or(RouterAction::Drop(Vec::new())),
+                )
+            }
+        }
+    }
+
+    /// Sends an ICMP error to the sender of a packet that could not be delivered, or drops the
+    /// packet if an error is not permitted
+    fn icmp_reply(&self, pkt: Ipv4Packet, icmp: IcmpPacket) -> RouterAction {
+        match self.icmp_error(&pkt, icmp) {
+            Some(action) => action,
+            None => RouterAction::Drop(pkt.into_bytes()),
+        }
+    }
+
+    /// Builds an ICMP error message addressed to the sender of a packet
+    ///
+    /// Errors are only sent to hosts on the LAN, and never in response to another ICMP error,
+    /// a non-initial fragment or a broadcast / multicast packet (RFC 1812, section 4.3.2.7)
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet that could not be delivered
+    /// * `icmp` - Error message to send
+    fn icmp_error(&self, pkt: &Ipv4Packet, icmp: IcmpPacket) -> Option<RouterAction> {
+        let (src, dst) = (pkt.src(), pkt.dest());
+        let network = self.lans[self.lan_of(src)?].network;
+
+        let is_icmp_error = pkt.protocol() == NET_PROTOCOL_ICMP
+            && pkt
+                .payload()
+                .first()
+                .map(|ty| icmp::is_error(*ty))
+                .unwrap_or(true);
+
+        if self.is_local(src)
+            || src == network.network()
+            || src == network.broadcast()
+            || dst.is_broadcast()
+            || dst.is_multicast()
+            || self.lans.iter().any(|lan| lan.network.broadcast() == dst)
+            || pkt.fragment_offset() != 0
+            || is_icmp_error
+        {
+            return None;
+        }
+
+        let mut buf = vec![0u8; icmp.size()];
+        icmp.as_bytes(&mut buf);
+
+        let reply = Ipv4Packet::new(network.ip(), src, NET_PROTOCOL_ICMP, &buf);
+        Some(RouterAction::ToLan(
+            EtherType::IPv4,
+            IpAddr::V4(src),
+            reply.into_bytes(),
+        ))
+    }
+
+    /// Routes an IPv6 packet to the appropriate destination
+    fn route_ip6(&mut self, mut pkt: Ipv6Packet) -> Result<RouterAction, ProtocolError> {
+        let Some(network) = self.network6 else {
+            tracing::trace!("[router] ipv6 not enabled, dropping packet");
+            return Ok(RouterAction::Drop(pkt.into_bytes()));
+        };
+
+        let dst = pkt.dest();
+        if self.is_local(dst) || dst.is_multicast() {
+            return Ok(self.handle_local_ipv6(pkt));
+        }
+
+        // link-local traffic is never forwarded
+        if dst.is_unicast_link_local() || pkt.src().is_unicast_link_local() {
+            return Ok(RouterAction::Drop(pkt.into_bytes()));
+        }
+
+        if pkt.hop_limit() <= 1 {
+            tracing::debug!(%dst, "[router] hop limit exceeded, dropping packet");
+            return Ok(self.time_exceeded6(pkt));
+        }
+
+        pkt.decrement_hop_limit();
+
+        match (network.contains(dst), network.contains(pkt.src())) {
+            (true, _) => Ok(RouterAction::ToLan(
+                EtherType::IPv6,
+                IpAddr::V6(dst),
+                pkt.into_bytes(),
+            )),
+            // the routing table only holds ipv4 routes, ipv6 follows the default route
+            (false, true) => match self.routes.default_wan() {
+                Some(wan) => Ok(RouterAction::ToWan6(wan, pkt)),
+                None => {
+                    tracing::debug!(%dst, "[router] no default route, dropping packet");
+                    Ok(RouterAction::Drop(pkt.into_bytes()))
+                }
+            },
+            (false, false) => {
+                tracing::debug!(src = %pkt.src(), %dst, "[router] not routable, dropping packet");
+                Ok(RouterAction::Drop(pkt.into_bytes()))
+            }
+        }
+    }
+
+    /// Notifies a host on the LAN that a packet it sent exceeded its hop limit (unless the
+    /// packet was itself an ICMPv6 error)
+    fn time_exceeded6(&self, pkt: Ipv6Packet) -> RouterAction {
+        let src = pkt.src();
+        let is_icmp_error = pkt.next_header() == NET_PROTOCOL_ICMPV6
+            && pkt
+                .payload()
+                .first()
+                .map(|ty| icmpv6::is_error(*ty))
+                .unwrap_or(true);
+
+        match self.network6 {
+            Some(network) if network.contains(src) && !self.is_local(src) && !is_icmp_error => {
+                let icmp = icmpv6::time_exceeded(pkt.as_bytes());
+                let reply = Ipv6Packet::new(network.ip(), src, NET_PROTOCOL_ICMPV6, &icmp);
+                RouterAction::ToLan(EtherType::IPv6, IpAddr::V6(src), reply.into_bytes())
+            }
+            _ => RouterAction::Drop(pkt.into_bytes()),
+        }
+    }
+
+    /// Handles an IPv6 packet addressed to the router (or a multicast group)
+    fn handle_local_ipv6(&mut self, pkt: Ipv6Packet) -> RouterAction {
+        if pkt.next_header() != NET_PROTOCOL_ICMPV6 {
+            return self.handle_local_protocol6(pkt);
+        }
+
+        let res = match pkt.payload().first().copied() {
+            Some(ICMPV6_TY_ECHO_REQUEST) => self.handle_echo6(&pkt),
+            Some(
+                ICMPV6_TY_ROUTER_SOLICIT | ICMPV6_TY_NEIGHBOR_SOLICIT | ICMPV6_TY_NEIGHBOR_ADVERT,
+            ) => self.handle_ndp(&pkt),
+            _ => Ok(None),
+        };
+
+        match res {
+            Ok(Some(action)) => action,
+            Ok(None) => RouterAction::Drop(pkt.into_bytes()),
+            Err(error) => {
+                tracing::warn!(?error, "[router] unable to handle icmpv6 packet");
+                RouterAction::Drop(pkt.into_bytes())
+            }
+        }
+    }
+
+    /// Replies to an ICMPv6 echo request
+    fn handle_echo6(&self, pkt: &Ipv6Packet) -> Result<Option<RouterAction>, ProtocolError> {
+        if pkt.src().is_unspecified() {
+            return Ok(None);
+        }
+
+        let reply = icmpv6::echo_reply(pkt.payload())?;
+        let reply = Ipv6Packet::new(self.reply_src(pkt), pkt.src(), NET_PROTOCOL_ICMPV6, &reply);
+
+        Ok(Some(RouterAction::ToLan(
+            EtherType::IPv6,
+            IpAddr::V6(pkt.src()),
+            reply.into_bytes(),
+        )))
+    }
+
+    /// Passes an IPv6 packet addressed to the router to the handler registered for its
+    /// next header (i.e., udp), replying to the sender
+    fn handle_local_protocol6(&mut self, pkt: Ipv6Packet) -> RouterAction {
+        let mut rpkt = vec![0u8; 1500];
+        let src = self.reply_src(&pkt);
+
+        let lan = &mut self.lans[0];
+        let Some(handler) = lan.handlers.get_mut(&pkt.next_header()) else {
+            return RouterAction::Drop(pkt.into_bytes());
+        };
+
+        let counters = lan.counters.entry(pkt.next_header()).or_default();
+        counters.rx.record(pkt.as_bytes().len());
+
+        match handler.handle_protocol6(&pkt, &mut rpkt) {
+            Ok(0) => RouterAction::Drop(Vec::new()),
+            Ok(sz) => {
+                let reply = Ipv6Packet::new(src, pkt.src(), pkt.next_header(), &rpkt[..sz]);
+                counters.tx.record(reply.as_bytes().len());
+                RouterAction::ToLan(EtherType::IPv6, IpAddr::V6(pkt.src()), reply.into_bytes())
+            }
+            Err(error) => {
+                counters.rx.record_error();
+                tracing::warn!(
+                    ?error,
+                    next_header = pkt.next_header(),
+                    "[router] unable to handle ipv6 packet"
+                );
+                RouterAction::Drop(Vec::new())
+            }
+        }
+    }
+
+    /// Returns the address the router uses as the source of a reply to a packet, which is
+    /// the packet's destination unless it was sent to a multicast group
+    fn reply_src(&self, pkt: &Ipv6Packet) -> Ipv6Addr {
+        match (pkt.dest().is_multicast(), self.network6) {
+            (false, _) => pkt.dest(),
+            (true, Some(network)) if network.contains(pkt.src()) => network.ip(),
+            (true, _) => self.link_local,
+        }
+    }
+
+    /// Handles neighbor discovery messages (router / neighbor solicitations and neighbor
+    /// advertisements), learning the link-layer address of the sender
+    fn handle_ndp(&mut self, pkt: &Ipv6Packet) -> Result<Option<RouterAction>, ProtocolError> {
+        if pkt.hop_limit() != NDP_HOP_LIMIT {
+            tracing::debug!("[router] ndp message with invalid hop limit, dropping");
+            return Ok(None);
+        }
+
+        let src = pkt.src();
+        let ndp = NdpPacket::parse(pkt.payload())?;
+
+        if let Some(mac) = ndp.link_addr() {
+            let ip = match ndp {
+                NdpPacket::NeighborAdvert { target, .. } => target,
+                _ => src,
+            };
+
+            if !ip.is_unspecified() {
+                tracing::trace!("[router] associating mac to ip: {ip} -> {mac}");
+                self.learn(IpAddr::V6(ip), mac);
+            }
+        }
+
+        match ndp {
+            NdpPacket::RouterSolicit { .. } => {
+                let dst = match src.is_unspecified() {
+                    true => IPV6_ALL_NODES,
+                    false => src,
+                };
+
+                self.router_advert(dst).map(Some)
+            }
+            NdpPacket::NeighborSolicit { target, .. } if self.is_local(target) => {
+                // duplicate address detection probes are answered to all nodes
+                let (dst, flags) = match src.is_unspecified() {
+                    true => (IPV6_ALL_NODES, NDP_NA_FLAG_ROUTER | NDP_NA_FLAG_OVERRIDE),
+                    false => (
+                        src,
+                        NDP_NA_FLAG_ROUTER | NDP_NA_FLAG_SOLICITED | NDP_NA_FLAG_OVERRIDE,
+                    ),
+                };
+
+                let na = icmpv6::neighbor_advert(target, self.mac, flags);
+                let mut reply = Ipv6Packet::new(target, dst, NET_PROTOCOL_ICMPV6, &na);
+                reply.set_hop_limit(NDP_HOP_LIMIT);
+
+                Ok(Some(RouterAction::ToLan(
+                    EtherType::IPv6,
+                    IpAddr::V6(dst),
+                    reply.into_bytes(),
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Builds a router advertisement for the configured prefix
+    ///
+    /// ### Arguments
+    /// * `dst` - Destination of the advertisement (a host or all nodes)
+    fn router_advert(&self, dst: Ipv6Addr) -> Result<RouterAction, ProtocolError> {
+        let prefix = self.network6.ok_or_else(|| {
+            ProtocolError::Other(String::from("ipv6 not enabled, no prefix to advertise"))
+        })?;
+
+        // only act as a default router if there is somewhere to send the traffic
+        let lifetime = match self.routes.default_wan() {
+            Some(_) => RA_ROUTER_LIFETIME,
+            None => 0,
+        };
+
+        let ra = RouterAdvert {
+            mac: self.mac,
+            prefix,
+            lifetime,
+            mtu: RA_MTU,
+            managed: self.managed,
+        };
+
+        let mut pkt = Ipv6Packet::new(self.link_local, dst, NET_PROTOCOL_ICMPV6, &ra.to_bytes());
+        pkt.set_hop_limit(NDP_HOP_LIMIT);
+
+        Ok(RouterAction::ToLan(
+            EtherType::IPv6,
+            IpAddr::V6(dst),
+            pkt.into_bytes(),
+        ))
+    }
+
+    /// Sends an unsolicited router advertisement to all nodes (if ipv6 is enabled)
+    fn advertise(&mut self) -> Result<(), ProtocolError> {
+        if self.network6.is_none() {
+            return Ok(());
+        }
+
+        tracing::trace!("[router] sending router advertisement");
+        let action = self.router_advert(IPV6_ALL_NODES)?;
+        self.handle_action(action, 0, None)
+    }
+
+    /// Updates the neighbor cache and sends any packets that were waiting on the host to be
+    /// resolved
+    ///
+    /// ### Arguments
+    /// * `ip` - Address of the host
+    /// * `mac` - MAC address of the host
+    fn learn(&mut self, ip: IpAddr, mac: MacAddress) {
+        let lan = self.egress_lan(ip, 0);
+        for (ethertype, pkt) in self.arp.learn(ip, mac) {
+            self.write_to_switch(lan, mac, ethertype, pkt);
+        }
+    }
+
+    /// Sends an arp request (ipv4) or neighbor solicitation (ipv6) for a host on the lan
+    ///
+    /// ### Arguments
+    /// * `ip` - Address of the host
+    fn resolve(&self, ip: IpAddr) {
+        match ip {
+            IpAddr::V4(ip) => match self.lan_of(ip) {
+                Some(lan) => self.arp_request(lan, ip),
+                None => tracing::debug!(%ip, "[router] host not on a lan, unable to resolve"),
+            },
+            IpAddr::V6(ip) => self.solicit(ip),
+        }
+    }
+
+    /// Ticks the services of each network (routing any packets they generate), retries
+    /// outstanding address resolutions, ages out stale neighbors and notifies the senders of
+    /// packets for hosts that never responded
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let mut outbound = Vec::new();
+        for (idx, lan) in self.lans.iter_mut().enumerate() {
+            for handler in lan.handlers.values_mut() {
+                handler.tick(now);
+                outbound.extend(handler.outbound().into_iter().map(|pkt| (idx, pkt)));
+            }
+        }
+
+        // route any packets the handlers generated (i.e., retried dns queries)
+        for (lan, pkt) in outbound {
+            if let Err(error) = self
+                .route_ip4(pkt, Zone::Local, lan)
+                .and_then(|action| self.handle_action(action, lan, None))
+            {
+                tracing::warn!(?error, "[router] unable to route handler packet");
+            }
+        }
+
+        let tick = self.arp.tick(now);
+
+        for ip in tick.retry {
+            self.resolve(ip);
+        }
+
+        for (_, (ethertype, pkt)) in tick.unreachable {
+            if ethertype != EtherType::IPv4 {
+                continue;
+            }
+
+            let Ok(pkt) = Ipv4Packet::parse(pkt) else {
+                continue;
+            };
+
+            let icmp = IcmpPacket::unreachable(DestinationUnreachableCode::HostUnreachable, &pkt);
+            if let Some(action) = self.icmp_error(&pkt, icmp) {
+                if let Err(error) = self.handle_action(action, 0, None) {
+                    tracing::warn!(?error, "[router] unable to send host unreachable");
+                }
+            }
+        }
+    }
+
+    /// Broadcasts an arp request for an address on the lan.  Requesting our own address
+    /// sends a gratuitous arp, announcing the router's mac address
+    ///
+    /// ### Arguments
+    /// * `lan` - Network (index) to send the request on
+    /// * `target` - Address to resolve
+    fn arp_request(&self, lan: usize, target: Ipv4Addr) {
+        let arp = ArpPacket::request(self.mac, self.lans[lan].network.ip(), target);
+        let mut pkt = vec![0u8; arp.size()];
+        arp.as_bytes(&mut pkt);
+        self.write_to_switch(lan, MacAddress::broadcast(), EtherType::ARP, pkt);
+    }
+
+    /// Sends a neighbor solicitation to resolve the link-layer address of a host
+    ///
+    /// ### Arguments
+    /// * `target` - Address of the host
+    fn solicit(&self, target: Ipv6Addr) {
+        let src = match self.network6 {
+            Some(network) if network.contains(target) => network.ip(),
+            _ => self.link_local,
+        };
+
+        let dst = icmpv6::solicited_node(target);
+        let ns = icmpv6::neighbor_solicit(target, self.mac);
+        let mut pkt = Ipv6Packet::new(src, dst, NET_PROTOCOL_ICMPV6, &ns);
+        pkt.set_hop_limit(NDP_HOP_LIMIT);
+
+        self.write_to_switch(
+            0,
+            MacAddress::ipv6_multicast(dst),
+            EtherType::IPv6,
+            pkt.into_bytes(),
+        );
+    }
+
+    /// Passes an IPv4 packet addressed to the router to the handler registered for its
+    /// protocol on the network the packet was addressed to
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet addressed to the router
+    /// * `lan` - Network (index) handling the packet
+    fn handle_local_ipv4(&mut self, pkt: Ipv4Packet, lan: usize) -> RouterAction {
+        let mut rpkt = vec![0u8; 1560];
+        let protocol = pkt.protocol();
+
+        match self.lans[lan].handlers.get_mut(&protocol) {
             Some(ref mut handler) => {
-                match handler.handle_protocol(&pkt, &mut rpkt[IPV4_HDR_SZ..]) {
+                let res = handler.handle_protocol(&pkt, &mut rpkt[IPV4_HDR_SZ..]);
+                let outbound = handler.outbound();
+
+                let counters = self.lans[lan].counters.entry(protocol).or_default();
+                counters.rx.record(pkt.as_bytes().len());
+                match res {
+                    Ok(0) => (),
+                    Ok(sz) => counters.tx.record(IPV4_HDR_SZ + sz),
+                    Err(_) => counters.rx.record_error(),
+                }
+
+                // route any packets the handler generated (i.e., forwarded dns queries)
+                for opkt in outbound {
+                    if let Err(error) = self
+                        .route_ip4(opkt, Zone::Local, lan)
+                        .and_then(|action| self.handle_action(action, lan, None))
+                    {
+                        tracing::warn!(?error, "[router] unable to route handler packet");
+                    }
+                }
+
+                match res {
                     Ok(0) => RouterAction::Drop(Vec::new()),
                     Ok(sz) => {
                         rpkt.truncate(IPV4_HDR_SZ + sz);
@@ -293,14 +1618,27 @@ impl Router {
         }
     }
 
-    fn write_to_switch(&self, dst: MacAddress, ethertype: EtherType, mut pkt: Vec<u8>) {
-        let mut data = Vec::with_capacity(12 + pkt.len());
-        data.extend_from_slice(&dst.as_bytes());
-        data.extend_from_slice(&self.mac.as_bytes());
-        data.extend_from_slice(&ethertype.as_u16().to_be_bytes());
+    /// Writes a packet to the switch, tagged with the VLAN of the network it is sent on
+    ///
+    /// ### Arguments
+    /// * `lan` - Network (index) to send the packet on
+    /// * `dst` - MAC address of the destination
+    /// * `ethertype` - Type of the packet
+    /// * `pkt` - Packet to send
+    fn write_to_switch(&self, lan: usize, dst: MacAddress, ethertype: EtherType, mut pkt: Vec<u8>) {
+        // frames on the primary network are untagged (the native vlan of the router's port)
+        let vlan = match lan {
+            0 => None,
+            _ => Some(self.lans[lan].vlan),
+        };
+
+        let frame = EthernetFrame::new(self.mac, dst, ethertype).with_vlan(vlan);
+        let hdr = frame.header_len();
+
+        let mut data = frame.to_bytes();
         data.append(&mut pkt);
 
-        tracing::trace!("[router] write to switch: {:02x?}", &data[14..34]);
+        tracing::trace!("[router] write to switch: {:02x?}", &data[hdr..(hdr + 20)]);
 
         if let Err(error) = self.switch.process(self.port, data) {
             tracing::warn!(?error, "unable to write to switch");
@@ -308,13 +1646,153 @@ impl Router {
     }
 }
 
+impl Lan {
+    /// Returns the counters of the network's protocol handlers and services
+    fn counters(&self) -> LanCounters {
+        let mut protocols = self
+            .counters
+            .iter()
+            .map(|(protocol, counters)| ProtocolCounters {
+                protocol: *protocol,
+                counters: *counters,
+            })
+            .collect::<Vec<_>>();
+        protocols.sort_by_key(|p| p.protocol);
+
+        let mut handlers = self.handlers.iter().collect::<Vec<_>>();
+        handlers.sort_by_key(|(protocol, _)| **protocol);
+
+        LanCounters {
+            vlan: self.vlan,
+            protocols,
+            services: handlers
+                .into_iter()
+                .flat_map(|(_, handler)| handler.services())
+                .collect(),
+        }
+    }
+}
+
+impl Uplink {
+    /// Returns the NAT table of the WAN, or None if the WAN is not running or does not
+    /// masquerade packets
+    fn nat(&self) -> Option<SharedNat> {
+        self.handle.as_ref().and_then(|handle| handle.nat())
+    }
+
+    /// Returns true if a packet sent by a LAN device belongs to a flow opened through one of
+    /// the WAN's port forwards
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet sent by a LAN device
+    fn is_forwarded(&self, pkt: &Ipv4Packet) -> bool {
+        self.handle
+            .as_ref()
+            .map(|handle| handle.is_forwarded(pkt))
+            .unwrap_or(false)
+    }
+}
+
 impl RouterHandle {
+    /// Returns a handle for a WAN to route packets with, counting every packet routed
+    /// through it as received from the WAN
+    ///
+    /// ### Arguments
+    /// * `link` - Counters of the WAN and the router's stop flag
+    fn with_wan(&self, link: WanLink) -> Self {
+        Self {
+            wan: Some(link),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a handle not connected to any router, along with the channel receiving the
+    /// messages sent through it
+    #[cfg(test)]
+    pub(crate) fn detached() -> (Self, Receiver<RouterMsg>) {
+        let (tx, rx) = flume::unbounded();
+        let handle = Self {
+            tx,
+            rx: rx.clone(),
+            wan: None,
+        };
+        (handle, rx)
+    }
+
     pub fn route_ipv4(&self, pkt: Ipv4Packet) {
+        if let Some(link) = &self.wan {
+            link.stats.rx.record(pkt.as_bytes().len());
+        }
         self.tx.send(RouterMsg::FromWan4(pkt)).ok();
     }
 
-    pub fn route_ipv6(&self, _pkt: Vec<u8>) {
-        tracing::warn!("[router] no ipv6 support");
+    pub fn route_ipv6(&self, pkt: Ipv6Packet) {
+        if let Some(link) = &self.wan {
+            link.stats.rx.record(pkt.as_bytes().len());
+        }
+        self.tx.send(RouterMsg::FromWan6(pkt)).ok();
+    }
+
+    /// Returns true if the router this WAN handle was given by has shut down, in which case
+    /// the WAN should stop
+    pub fn is_stopped(&self) -> bool {
+        self.wan
+            .as_ref()
+            .is_some_and(|link| link.stopped.load(Ordering::Relaxed))
+    }
+
+    /// Stops the router and its WANs, waiting for them to exit.  Packets and requests sent
+    /// to the router wait to be handled by the router replacing it (see
+    /// `RouterBuilder::respawn`)
+    pub fn shutdown(&self) -> Result<Handover, NetworkError> {
+        let (tx, rx) = flume::bounded(1);
+        self.tx.send(RouterMsg::Shutdown(tx))?;
+        rx.recv_timeout(SHUTDOWN_TIMEOUT)
+            .map_err(|_| NetworkError::Generic("router did not stop".into()))
+    }
+
+    /// Returns the neighbors learned by the router
+    pub fn neighbors(&self) -> Result<Vec<NeighborInfo>, NetworkError> {
+        self.request(RouterRequest::Neighbors)
+    }
+
+    /// Returns the state of each WAN
+    pub fn wans(&self) -> Result<Vec<WanInfo>, NetworkError> {
+        self.request(RouterRequest::Wans)
+    }
+
+    /// Returns the entries of each WAN's NAT table
+    pub fn nat(&self) -> Result<Vec<NatInfo>, NetworkError> {
+        self.request(RouterRequest::Nat)
+    }
+
+    /// Returns the traffic counters of each WAN and network
+    pub fn counters(&self) -> Result<RouterCounters, NetworkError> {
+        self.request(RouterRequest::Counters)
+    }
+
+    /// Forgets all neighbors learned by the router
+    pub fn flush_neighbors(&self) -> Result<(), NetworkError> {
+        self.tx
+            .send(RouterMsg::Request(RouterRequest::FlushNeighbors))?;
+        Ok(())
+    }
+
+    /// Removes all entries from each WAN's NAT table
+    pub fn flush_nat(&self) -> Result<(), NetworkError> {
+        self.tx.send(RouterMsg::Request(RouterRequest::FlushNat))?;
+        Ok(())
+    }
+
+    /// Sends a request to the router thread, waiting for its answer
+    ///
+    /// ### Arguments
+    /// * `request` - Creates the request from the sender the answer is written to
+    fn request<T>(&self, request: fn(Sender<T>) -> RouterRequest) -> Result<T, NetworkError> {
+        let (tx, rx) = flume::bounded(1);
+        self.tx.send(RouterMsg::Request(request(tx)))?;
+        rx.recv_timeout(REQUEST_TIMEOUT)
+            .map_err(|_| NetworkError::Generic("router did not respond".into()))
     }
 }
 
@@ -324,3 +1802,137 @@ impl SwitchPort for RouterHandle {
         self.tx.send(RouterMsg::FromLan(pkt)).ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
+
+    use oathgate_net::{
+        protocols::ArpPacket,
+        types::{EtherType, MacAddress},
+        EthernetFrame, Switch, SwitchPort,
+    };
+    use parking_lot::Mutex;
+
+    use crate::net::{
+        switch::{PortMode, VirtioSwitch},
+        wan::{Wan, WanHandle},
+        NetworkError,
+    };
+
+    use super::{Lan, RespawnError, Router, RouterHandle};
+
+    /// A host that records the frames it receives
+    #[derive(Clone, Default)]
+    struct Host {
+        frames: Arc<Mutex<Vec<EthernetFrame>>>,
+    }
+
+    impl SwitchPort for Host {
+        fn enqueue(&self, frame: EthernetFrame, _pkt: Vec<u8>) {
+            self.frames.lock().push(frame);
+        }
+    }
+
+    /// A WAN that always fails to start
+    struct BrokenWan;
+
+    impl Wan for BrokenWan {
+        fn as_wan_handle(&self) -> Result<Box<dyn WanHandle>, NetworkError> {
+            Err(NetworkError::Generic("device busy".into()))
+        }
+
+        fn add_forward(
+            &mut self,
+            _fwd: oathgate_net::nat::PortForward,
+            _listen: IpAddr,
+        ) -> Result<(), NetworkError> {
+            Ok(())
+        }
+
+        fn run(self: Box<Self>, _router: RouterHandle) -> Result<(), NetworkError> {
+            Ok(())
+        }
+    }
+
+    /// Sends an arp request for the router's address from a host
+    fn arp_request(switch: &VirtioSwitch, port: usize, mac: MacAddress, ip: Ipv4Addr) {
+        let arp = ArpPacket::request(mac, ip, Ipv4Addr::new(10, 67, 213, 1));
+        let mut pkt = EthernetFrame::new(mac, MacAddress::broadcast(), EtherType::ARP).to_bytes();
+        let offset = pkt.len();
+        pkt.resize(offset + arp.size(), 0);
+        arp.as_bytes(&mut pkt[offset..]);
+        switch.process(port, pkt).unwrap();
+    }
+
+    #[test]
+    fn router_shutdown_respawn() {
+        let switch = VirtioSwitch::default();
+        let host = Host::default();
+        let port = switch.with_name("host").connect(host.clone());
+
+        let network = "10.67.213.1/24".parse().unwrap();
+        let handle = Router::builder().spawn(network, switch.clone()).unwrap();
+
+        let (host_mac, host_ip) = (MacAddress::generate(), Ipv4Addr::new(10, 67, 213, 50));
+        arp_request(&switch, port, host_mac, host_ip);
+
+        // requests are answered in order, so the arp request has been handled
+        assert_eq!(handle.neighbors().unwrap()[0].mac, host_mac);
+        let mac = host.frames.lock()[0].src;
+        let router_port = switch
+            .ports()
+            .into_iter()
+            .find(|p| p.name.is_none())
+            .unwrap()
+            .port;
+
+        let handover = handle.shutdown().unwrap();
+        assert_eq!(handover.port, router_port);
+        assert_eq!(handover.mac, mac);
+        assert_eq!(handover.arp.get(&IpAddr::V4(host_ip)), Some(host_mac));
+
+        // a router that fails to start hands the state back
+        let result = Router::builder()
+            .wan("broken", Box::new(BrokenWan))
+            .require_wans(true)
+            .respawn(network, switch.clone(), &handle, handover);
+
+        let handover = match result.map_err(|error| *error) {
+            Err(RespawnError { error, handover }) => {
+                assert!(error.to_string().contains("broken"));
+                handover
+            }
+            Ok(()) => panic!("router started without its wan"),
+        };
+        assert_eq!(handover.port, router_port);
+        assert_eq!(handover.mac, mac);
+
+        // the replacement keeps the port, mac and neighbors, and joins the new vlan
+        host.frames.lock().clear();
+        Router::builder()
+            .vlan(Lan::new(20, "10.67.20.1/24".parse().unwrap()))
+            .respawn(network, switch.clone(), &handle, handover)
+            .map_err(|error| error.error)
+            .unwrap();
+
+        assert_eq!(handle.neighbors().unwrap()[0].ip, IpAddr::V4(host_ip));
+        assert_eq!(host.frames.lock()[0].src, mac);
+
+        let ports = switch.ports();
+        assert_eq!(ports.len(), 2);
+        let router = ports.iter().find(|p| p.port == router_port).unwrap();
+        assert_eq!(
+            router.mode,
+            PortMode::Trunk {
+                native: Some(1),
+                allowed: vec![20],
+            }
+        );
+
+        handle.shutdown().unwrap();
+    }
+}
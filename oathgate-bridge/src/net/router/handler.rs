@@ -3,18 +3,87 @@
 mod icmp;
 mod udp;
 
-use oathgate_net::{Ipv4Packet, ProtocolError};
+use std::{collections::BTreeMap, net::SocketAddr, time::Instant};
+
+use oathgate_net::{Ipv4Packet, Ipv6Packet, ProtocolError};
+use serde::{Deserialize, Serialize};
 
 pub use self::{icmp::IcmpHandler, udp::UdpHandler};
 
+/// Counters kept by a service (i.e., the dhcp server) running on the router
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServiceCounters {
+    /// Name of the service
+    pub service: String,
+
+    /// Value of each counter (i.e., messages handled), keyed by name
+    pub counters: BTreeMap<String, u64>,
+
+    /// Value of each gauge (i.e., active leases), keyed by name
+    pub gauges: BTreeMap<String, u64>,
+}
+
 pub trait ProtocolHandler: Send + Sync {
     fn protocol(&self) -> u8;
 
     fn handle_protocol(&mut self, pkt: &Ipv4Packet, buf: &mut [u8])
         -> Result<usize, ProtocolError>;
+
+    /// Handles an IPv6 packet addressed to the router, writing the reply payload (if any)
+    /// into `buf`.  Handlers that do not support IPv6 ignore the packet
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet received by the router
+    /// * `buf` - Buffer to write the reply payload into
+    fn handle_protocol6(
+        &mut self,
+        _pkt: &Ipv6Packet,
+        _buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        Ok(0)
+    }
+
+    /// Returns packets generated by this handler that are not replies to the sender
+    /// (i.e., queries forwarded to another server), which the router will route
+    fn outbound(&mut self) -> Vec<Ipv4Packet> {
+        Vec::new()
+    }
+
+    /// Returns the counters of the services run by this handler
+    fn services(&self) -> Vec<ServiceCounters> {
+        Vec::new()
+    }
+
+    /// Runs periodic work (i.e., expiring leases), called by the router about once a second
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    fn tick(&mut self, _now: Instant) {}
 }
 
 pub trait PortHandler: Send + Sync {
     fn port(&self) -> u16;
-    fn handle_port(&mut self, data: &[u8], buf: &mut [u8]) -> Result<usize, ProtocolError>;
+
+    fn handle_port(
+        &mut self,
+        src: SocketAddr,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError>;
+
+    /// Returns packets generated by this handler that are not replies to the sender
+    fn outbound(&mut self) -> Vec<Ipv4Packet> {
+        Vec::new()
+    }
+
+    /// Returns the counters kept by this handler, or None if it does not keep any
+    fn counters(&self) -> Option<ServiceCounters> {
+        None
+    }
+
+    /// Runs periodic work (i.e., expiring leases), called by the router about once a second
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    fn tick(&mut self, _now: Instant) {}
 }
@@ -1,13 +1,17 @@
 //! ICMP Protocol Handler
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
 
 use oathgate_net::{
     protocols::{NET_PROTOCOL_UDP, UDP_HDR_SZ},
-    Ipv4Packet, ProtocolError,
+    Ipv4Packet, Ipv6Packet, ProtocolError,
 };
 
-use super::{PortHandler, ProtocolHandler};
+use super::{PortHandler, ProtocolHandler, ServiceCounters};
 
 #[derive(Default)]
 pub struct UdpHandler {
@@ -22,20 +26,20 @@ impl UdpHandler {
     pub fn register_port_handler<P: PortHandler + 'static>(&mut self, handler: P) {
         self.handlers.insert(handler.port(), Box::new(handler));
     }
-}
-
-impl ProtocolHandler for UdpHandler {
-    fn protocol(&self) -> u8 {
-        NET_PROTOCOL_UDP
-    }
 
-    fn handle_protocol(
+    /// Passes a datagram to the handler registered for its destination port, writing the
+    /// udp header and reply (if any) into `buf` and returning the size of the reply
+    ///
+    /// ### Arguments
+    /// * `src` - Address of the sender
+    /// * `payload` - UDP header and data
+    /// * `buf` - Buffer to write the reply into
+    fn dispatch(
         &mut self,
-        pkt: &Ipv4Packet,
+        src: IpAddr,
+        payload: &[u8],
         buf: &mut [u8],
     ) -> Result<usize, ProtocolError> {
-        let payload = pkt.payload();
-
         if payload.len() < UDP_HDR_SZ {
             return Err(ProtocolError::NotEnoughData(payload.len(), UDP_HDR_SZ))?;
         }
@@ -44,7 +48,12 @@ impl ProtocolHandler for UdpHandler {
         let dst_port = u16::from_be_bytes([payload[2], payload[3]]);
 
         if let Some(handler) = self.handlers.get_mut(&dst_port) {
-            let len = handler.handle_port(&payload[8..], &mut buf[8..])?;
+            let src = SocketAddr::new(src, src_port);
+            let len = handler.handle_port(src, &payload[8..], &mut buf[8..])?;
+            if len == 0 {
+                return Ok(0);
+            }
+
             let len = len + 8;
 
             buf[0..2].copy_from_slice(&dst_port.to_be_bytes());
@@ -57,3 +66,47 @@ impl ProtocolHandler for UdpHandler {
         }
     }
 }
+
+impl ProtocolHandler for UdpHandler {
+    fn protocol(&self) -> u8 {
+        NET_PROTOCOL_UDP
+    }
+
+    fn handle_protocol(
+        &mut self,
+        pkt: &Ipv4Packet,
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        self.dispatch(IpAddr::V4(pkt.src()), pkt.payload(), buf)
+    }
+
+    fn handle_protocol6(
+        &mut self,
+        pkt: &Ipv6Packet,
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        self.dispatch(IpAddr::V6(pkt.src()), pkt.payload(), buf)
+    }
+
+    fn outbound(&mut self) -> Vec<Ipv4Packet> {
+        self.handlers
+            .values_mut()
+            .flat_map(|handler| handler.outbound())
+            .collect()
+    }
+
+    fn services(&self) -> Vec<ServiceCounters> {
+        let mut ports = self.handlers.iter().collect::<Vec<_>>();
+        ports.sort_by_key(|(port, _)| **port);
+        ports
+            .into_iter()
+            .filter_map(|(_, handler)| handler.counters())
+            .collect()
+    }
+
+    fn tick(&mut self, now: Instant) {
+        for handler in self.handlers.values_mut() {
+            handler.tick(now);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Neighbor cache (ARP / NDP) with a queue for packets waiting on address resolution
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use oathgate_net::types::{EtherType, MacAddress};
+
+/// Interval between resolution attempts (arp requests / neighbor solicitations)
+pub const RESOLVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of resolution attempts before dropping packets waiting on a host
+const MAX_RESOLVE_ATTEMPTS: u8 = 3;
+
+/// Maximum number of packets queued per host while waiting for it to be resolved
+const MAX_PENDING_PACKETS: usize = 16;
+
+/// Time a learned mapping remains valid without being refreshed
+const NEIGHBOR_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A packet waiting on its destination's mac address
+pub type PendingPacket = (EtherType, Vec<u8>);
+
+/// Maps ip addresses on the lan to their mac address
+#[derive(Default)]
+pub struct NeighborCache {
+    /// Learned mappings
+    entries: HashMap<IpAddr, Neighbor>,
+
+    /// Packets waiting for a host's mac address to be resolved
+    pending: HashMap<IpAddr, PendingResolve>,
+}
+
+/// A learned ip to mac mapping
+struct Neighbor {
+    mac: MacAddress,
+    expires: Instant,
+}
+
+/// Packets queued while address resolution is outstanding
+struct PendingResolve {
+    /// Packets to send once the host is resolved
+    packets: VecDeque<PendingPacket>,
+
+    /// Time the last resolution attempt was made
+    sent: Instant,
+
+    /// Number of resolution attempts made
+    attempts: u8,
+}
+
+/// Result of aging the neighbor cache
+#[derive(Default)]
+pub struct NeighborTick {
+    /// Hosts that should be solicited again
+    pub retry: Vec<IpAddr>,
+
+    /// Packets for hosts that never responded
+    pub unreachable: Vec<(IpAddr, PendingPacket)>,
+}
+
+impl NeighborCache {
+    /// Returns the mac address associated with an ip address, if known
+    ///
+    /// ### Arguments
+    /// * `ip` - Address to lookup
+    pub fn get(&self, ip: &IpAddr) -> Option<MacAddress> {
+        self.entries.get(ip).map(|n| n.mac)
+    }
+
+    /// Returns each learned mapping along with the time remaining before it expires
+    pub fn entries(&self) -> impl Iterator<Item = (IpAddr, MacAddress, Duration)> + '_ {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .map(move |(ip, n)| (*ip, n.mac, n.expires.saturating_duration_since(now)))
+    }
+
+    /// Removes all learned mappings.  Packets waiting on address resolution are kept
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Associates an ip address with a mac address, returning any packets that were
+    /// waiting for the address to be resolved
+    ///
+    /// ### Arguments
+    /// * `ip` - IP address of the host
+    /// * `mac` - MAC address of the host
+    pub fn learn(&mut self, ip: IpAddr, mac: MacAddress) -> Vec<PendingPacket> {
+        self.entries.insert(
+            ip,
+            Neighbor {
+                mac,
+                expires: Instant::now() + NEIGHBOR_TIMEOUT,
+            },
+        );
+
+        self.pending
+            .remove(&ip)
+            .map(|pending| pending.packets.into())
+            .unwrap_or_default()
+    }
+
+    /// Queues a packet until its destination is resolved, returning true if a resolution
+    /// request should be sent (i.e., this is the first packet queued for the host)
+    ///
+    /// ### Arguments
+    /// * `ip` - Destination (unresolved) ip address
+    /// * `ethertype` - Type of packet queued
+    /// * `pkt` - Packet to send once resolved
+    pub fn queue(&mut self, ip: IpAddr, ethertype: EtherType, pkt: Vec<u8>) -> bool {
+        let pending = self.pending.entry(ip).or_insert_with(|| PendingResolve {
+            packets: VecDeque::new(),
+            sent: Instant::now(),
+            attempts: 0,
+        });
+
+        if pending.packets.len() >= MAX_PENDING_PACKETS {
+            pending.packets.pop_front();
+        }
+        pending.packets.push_back((ethertype, pkt));
+
+        if pending.attempts == 0 {
+            pending.attempts = 1;
+            return true;
+        }
+
+        false
+    }
+
+    /// Removes expired mappings and returns hosts to retry along with the packets of any
+    /// hosts that could not be resolved
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    pub fn tick(&mut self, now: Instant) -> NeighborTick {
+        self.entries.retain(|ip, neighbor| {
+            let keep = neighbor.expires > now;
+            if !keep {
+                tracing::trace!("[router] neighbor {ip} expired");
+            }
+            keep
+        });
+
+        let mut tick = NeighborTick::default();
+        self.pending.retain(|ip, pending| {
+            if now.duration_since(pending.sent) < RESOLVE_INTERVAL {
+                return true;
+            }
+
+            if pending.attempts >= MAX_RESOLVE_ATTEMPTS {
+                tracing::warn!(
+                    "[router] unable to resolve {ip}, dropping {} packets",
+                    pending.packets.len()
+                );
+                tick.unreachable
+                    .extend(pending.packets.drain(..).map(|pkt| (*ip, pkt)));
+                return false;
+            }
+
+            pending.attempts += 1;
+            pending.sent = now;
+            tick.retry.push(*ip);
+            true
+        });
+
+        tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Instant,
+    };
+
+    use oathgate_net::types::{EtherType, MacAddress};
+
+    use super::{NeighborCache, MAX_PENDING_PACKETS, NEIGHBOR_TIMEOUT, RESOLVE_INTERVAL};
+
+    const HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 10, 10, 50));
+
+    #[test]
+    fn neighbor_queue_flushed_on_learn() {
+        let mut cache = NeighborCache::default();
+        let mac = MacAddress::generate();
+
+        assert!(cache.queue(HOST, EtherType::IPv4, vec![1]));
+        assert!(!cache.queue(HOST, EtherType::IPv4, vec![2]));
+        assert!(cache.get(&HOST).is_none());
+
+        let flushed = cache.learn(HOST, mac);
+        assert_eq!(flushed.len(), 2);
+        assert_eq!(flushed[0].1, vec![1]);
+        assert_eq!(cache.get(&HOST), Some(mac));
+
+        // nothing left waiting
+        assert!(cache.learn(HOST, mac).is_empty());
+    }
+
+    #[test]
+    fn neighbor_queue_bounded() {
+        let mut cache = NeighborCache::default();
+        for i in 0..(MAX_PENDING_PACKETS + 4) {
+            cache.queue(HOST, EtherType::IPv4, vec![i as u8]);
+        }
+
+        let flushed = cache.learn(HOST, MacAddress::generate());
+        assert_eq!(flushed.len(), MAX_PENDING_PACKETS);
+        assert_eq!(flushed[0].1, vec![4]);
+    }
+
+    #[test]
+    fn neighbor_unresolved_after_retries() {
+        let mut cache = NeighborCache::default();
+        cache.queue(HOST, EtherType::IPv4, vec![1]);
+
+        let mut now = Instant::now();
+        for _ in 0..2 {
+            now += RESOLVE_INTERVAL;
+            let tick = cache.tick(now);
+            assert_eq!(tick.retry, vec![HOST]);
+            assert!(tick.unreachable.is_empty());
+        }
+
+        now += RESOLVE_INTERVAL;
+        let tick = cache.tick(now);
+        assert!(tick.retry.is_empty());
+        assert_eq!(tick.unreachable.len(), 1);
+        assert_eq!(tick.unreachable[0].0, HOST);
+    }
+
+    #[test]
+    fn neighbor_expires() {
+        let mut cache = NeighborCache::default();
+        cache.learn(HOST, MacAddress::generate());
+
+        cache.tick(Instant::now());
+        assert!(cache.get(&HOST).is_some());
+
+        cache.tick(Instant::now() + NEIGHBOR_TIMEOUT);
+        assert!(cache.get(&HOST).is_none());
+    }
+}
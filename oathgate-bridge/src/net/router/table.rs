@@ -0,0 +1,217 @@
+//! Routing table (static routes and source-based policy rules)
+
+use std::net::Ipv4Addr;
+
+use oathgate_net::{nat::PortForward, types::Ipv4Network, Ipv4Packet};
+
+/// Where a packet should be sent next
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NextHop {
+    /// A WAN, identified by its index in the router's list of WANs
+    Wan(usize),
+
+    /// A gateway on the LAN
+    Lan(Ipv4Addr),
+}
+
+/// A route to a destination prefix
+struct Route {
+    prefix: Ipv4Network,
+    hop: NextHop,
+}
+
+/// Sends all (non-local) traffic from a source prefix to a WAN
+struct Policy {
+    source: Ipv4Network,
+    wan: usize,
+}
+
+/// Selects the next hop for packets that are not destined for the LAN
+#[derive(Default)]
+pub struct RoutingTable {
+    /// Routes, sorted from the longest to shortest prefix
+    routes: Vec<Route>,
+
+    /// Policy rules, evaluated in order before any routes
+    policies: Vec<Policy>,
+
+    /// Port forwards and their WAN.  Replies of flows opened through a forward are sent back
+    /// through its WAN before policy rules are evaluated
+    forwards: Vec<(PortForward, usize)>,
+}
+
+impl RoutingTable {
+    /// Adds a static route to the table.  A `0.0.0.0/0` prefix is the default route
+    ///
+    /// ### Arguments
+    /// * `prefix` - Destination network
+    /// * `hop` - Next hop for packets destined for `prefix`
+    pub fn add_route(&mut self, prefix: Ipv4Network, hop: NextHop) {
+        let bits = prefix.subnet_mask_bits();
+        let idx = self
+            .routes
+            .iter()
+            .position(|r| r.prefix.subnet_mask_bits() < bits)
+            .unwrap_or(self.routes.len());
+
+        self.routes.insert(idx, Route { prefix, hop });
+    }
+
+    /// Adds a policy rule, sending all traffic from a source network to a WAN regardless
+    /// of the destination's route
+    ///
+    /// ### Arguments
+    /// * `source` - Source network
+    /// * `wan` - Index of the WAN
+    pub fn add_policy(&mut self, source: Ipv4Network, wan: usize) {
+        self.policies.push(Policy { source, wan });
+    }
+
+    /// Adds a port forward, ensuring replies sent by the LAN device leave through the WAN
+    /// the forward is configured on
+    ///
+    /// ### Arguments
+    /// * `fwd` - Port forward
+    /// * `wan` - Index of the WAN
+    pub fn add_forward(&mut self, fwd: PortForward, wan: usize) {
+        self.forwards.push((fwd, wan));
+    }
+
+    /// Returns the WAN of the port forward that opened a packet's flow, if any
+    ///
+    /// Only the WAN knows which flows were opened through its forwards (a device may use the
+    /// forwarded port for unrelated outbound connections), so each candidate WAN is asked to
+    /// confirm the flow
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet sent by a LAN device
+    /// * `is_forwarded` - Returns true if a WAN opened the packet's flow through a forward
+    pub fn lookup_forward<F>(&self, pkt: &Ipv4Packet, is_forwarded: F) -> Option<NextHop>
+    where
+        F: Fn(usize, &Ipv4Packet) -> bool,
+    {
+        self.forwards
+            .iter()
+            .filter(|(fwd, _)| fwd.protocol == pkt.protocol() && fwd.to == pkt.src())
+            .find(|(_, wan)| is_forwarded(*wan, pkt))
+            .map(|(_, wan)| NextHop::Wan(*wan))
+    }
+
+    /// Returns the next hop for a packet, or None if there is no route to the destination
+    ///
+    /// ### Arguments
+    /// * `src` - Source address of the packet
+    /// * `dst` - Destination address of the packet
+    pub fn lookup(&self, src: Ipv4Addr, dst: Ipv4Addr) -> Option<NextHop> {
+        if let Some(policy) = self.policies.iter().find(|p| p.source.contains(src)) {
+            return Some(NextHop::Wan(policy.wan));
+        }
+
+        self.routes
+            .iter()
+            .find(|r| r.prefix.contains(dst))
+            .map(|r| r.hop)
+    }
+
+    /// Returns the WAN used by the default route, if one is configured
+    pub fn default_wan(&self) -> Option<usize> {
+        self.routes
+            .iter()
+            .filter(|r| r.prefix.subnet_mask_bits() == 0)
+            .find_map(|r| match r.hop {
+                NextHop::Wan(wan) => Some(wan),
+                NextHop::Lan(_) => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use oathgate_net::{nat::PortForward, protocols::NET_PROTOCOL_TCP, Ipv4Packet};
+
+    use super::{NextHop, RoutingTable};
+
+    const HOST: Ipv4Addr = Ipv4Addr::new(10, 10, 10, 50);
+
+    fn table() -> RoutingTable {
+        let mut table = RoutingTable::default();
+        table.add_route("0.0.0.0/0".parse().unwrap(), NextHop::Wan(0));
+        table.add_route("172.16.0.0/12".parse().unwrap(), NextHop::Wan(1));
+        table.add_route(
+            "172.16.5.0/24".parse().unwrap(),
+            NextHop::Lan(Ipv4Addr::new(10, 10, 10, 2)),
+        );
+        table
+    }
+
+    #[test]
+    fn route_longest_prefix_match() {
+        let table = table();
+
+        assert_eq!(
+            table.lookup(HOST, Ipv4Addr::new(1, 1, 1, 1)),
+            Some(NextHop::Wan(0))
+        );
+        assert_eq!(
+            table.lookup(HOST, Ipv4Addr::new(172, 20, 0, 1)),
+            Some(NextHop::Wan(1))
+        );
+        assert_eq!(
+            table.lookup(HOST, Ipv4Addr::new(172, 16, 5, 9)),
+            Some(NextHop::Lan(Ipv4Addr::new(10, 10, 10, 2)))
+        );
+        assert_eq!(table.default_wan(), Some(0));
+    }
+
+    #[test]
+    fn route_no_default() {
+        let mut table = RoutingTable::default();
+        table.add_route("172.16.0.0/12".parse().unwrap(), NextHop::Wan(1));
+
+        assert_eq!(table.lookup(HOST, Ipv4Addr::new(1, 1, 1, 1)), None);
+        assert_eq!(table.default_wan(), None);
+    }
+
+    #[test]
+    fn route_policy_overrides_routes() {
+        let mut table = table();
+        table.add_policy("10.10.10.128/25".parse().unwrap(), 2);
+
+        let dst = Ipv4Addr::new(172, 20, 0, 1);
+        assert_eq!(table.lookup(HOST, dst), Some(NextHop::Wan(1)));
+        assert_eq!(
+            table.lookup(Ipv4Addr::new(10, 10, 10, 200), dst),
+            Some(NextHop::Wan(2))
+        );
+    }
+
+    #[test]
+    fn route_forward_replies() {
+        let mut table = table();
+        let fwd = PortForward {
+            protocol: NET_PROTOCOL_TCP,
+            port: 2222,
+            to: HOST,
+            to_port: 22,
+        };
+        table.add_forward(fwd, 1);
+
+        let segment = |sport: u16| {
+            let mut payload = [0u8; 20];
+            payload[0..2].copy_from_slice(&sport.to_be_bytes());
+            payload[12] = 0x50;
+            Ipv4Packet::new(HOST, Ipv4Addr::new(1, 1, 1, 1), NET_PROTOCOL_TCP, &payload)
+        };
+
+        // only flows the wan opened through the forward are sent back through it
+        let opened = |wan: usize, pkt: &Ipv4Packet| wan == 1 && pkt.payload()[0..2] == [0, 22];
+        assert_eq!(
+            table.lookup_forward(&segment(22), opened),
+            Some(NextHop::Wan(1))
+        );
+        assert_eq!(table.lookup_forward(&segment(23), opened), None);
+        assert_eq!(table.lookup_forward(&segment(22), |_, _| false), None);
+    }
+}
@@ -1,74 +1,642 @@
 //! Simple network switch
 
 use std::{
-    borrow::Cow, collections::HashMap, fs::File, path::PathBuf, sync::Arc, time::UNIX_EPOCH,
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use flume::Sender;
 use parking_lot::RwLock;
-use pcap_file::pcap::{PcapPacket, PcapWriter};
+use serde::{Deserialize, Serialize};
 
-use oathgate_net::{types::MacAddress, EthernetFrame, ProtocolError, Switch, SwitchPort};
+use oathgate_net::{
+    protocols::{
+        icmp::{self, DestinationUnreachableCode},
+        IcmpPacket, NET_PROTOCOL_ICMP,
+    },
+    stats::{Counters, InterfaceCounters, InterfaceStats},
+    types::{EtherType, Ipv4Network, MacAddress},
+    EthernetFrame, Ipv4Packet, ProtocolError, Switch, SwitchPort,
+};
+
+use crate::config::{
+    firewall::{Action, Direction},
+    mirror::MirrorConfig,
+    vlan::PortConfig,
+};
+
+use super::{
+    capture::{Capture, Taps},
+    firewall::Firewall,
+    ETHERNET_HDR_SZ,
+};
 
-use super::{NetworkError, ETHERNET_HDR_SZ};
+/// VLAN of the bridge's main socket and the router's primary network
+pub const DEFAULT_VLAN: u16 = 1;
+
+/// Time a learned MAC address remains associated with a port without being seen
+pub const DEFAULT_MAC_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Minimum interval between refreshes of a MAC address seen again on the same port
+const MAC_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimum interval between sweeps for expired MAC addresses
+const MAC_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Clone, Default)]
 pub struct VirtioSwitch {
-    /// Handles to devices connected to switch ports
-    ports: Arc<RwLock<Vec<Box<dyn SwitchPort>>>>,
+    /// Handles to devices connected to switch ports, None if the port is free
+    ports: Arc<RwLock<Vec<Option<Port>>>>,
+
+    /// Map of VLAN and MacAddress to switch ports
+    cache: Arc<RwLock<MacTable>>,
+
+    /// VLAN membership of ports connected through this handle
+    mode: PortMode,
+
+    /// Name of ports connected through this handle, used to select ports in mirror sessions
+    name: Option<String>,
+
+    /// Mirror sessions, copying traffic to monitor ports
+    mirrors: Arc<RwLock<Vec<MirrorConfig>>>,
+
+    /// Captures frames received by the switch, if enabled
+    capture: Option<Capture>,
+
+    /// Live captures streaming frames received by the switch to control socket clients
+    taps: Taps,
+
+    /// Filters traffic between hosts on the LAN, if enabled
+    filter: Arc<RwLock<Option<LanFilter>>>,
+}
+
+/// VLAN membership of a switch port
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortMode {
+    /// Member of a single VLAN, frames are sent and received untagged
+    Access(u16),
+
+    /// Member of multiple VLANs, frames are sent and received with an 802.1Q tag, except
+    /// for frames on the native VLAN (if any), which are untagged
+    Trunk {
+        native: Option<u16>,
+        allowed: Vec<u16>,
+    },
+
+    /// Member of no VLAN, the port only receives frames copied by mirror sessions
+    Monitor,
+}
+
+/// A device connected to a switch port
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PortInfo {
+    /// Switch port the device is connected to
+    pub port: usize,
+
+    /// Name of the vhost socket the device connected through, None for internal devices
+    /// (e.g., the router)
+    pub name: Option<String>,
+
+    /// VLAN membership of the port
+    pub mode: PortMode,
+
+    /// Number of MAC addresses learned on the port
+    pub macs: usize,
+
+    /// Frames received from (rx) and sent to (tx) the device by the switch
+    pub counters: InterfaceCounters,
+
+    /// Counters of each of the device's queues (e.g., virtqueues), if the device keeps any
+    pub queues: Vec<Counters>,
+}
+
+/// A MAC address learned by the switch
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MacInfo {
+    /// VLAN the address was seen on
+    pub vlan: u16,
+
+    /// Learned MAC address
+    pub mac: MacAddress,
+
+    /// Port the address was last seen on
+    pub port: usize,
+
+    /// Seconds since the address was last seen
+    pub age: u64,
+}
+
+/// A device connected to the switch
+struct Port {
+    dev: Box<dyn SwitchPort>,
+    mode: PortMode,
+    name: Option<String>,
+    stats: InterfaceStats,
+}
+
+/// MAC addresses learned by the switch
+struct MacTable {
+    /// Map of VLAN and MacAddress to the port the address was last seen on
+    entries: HashMap<(u16, MacAddress), MacEntry>,
 
-    /// Map of MacAddress to switch ports
-    cache: Arc<RwLock<HashMap<MacAddress, usize>>>,
+    /// Time an address remains associated with a port without being seen
+    timeout: Duration,
 
-    /// Pcap logger, if configured
-    logger: PcapLogger,
+    /// Last time expired addresses were removed
+    last_sweep: Instant,
 }
 
-#[derive(Clone, Debug, Default)]
-struct PcapLogger {
-    tx: Option<Sender<Vec<u8>>>,
+/// A learned MAC address
+struct MacEntry {
+    port: usize,
+    last_seen: Instant,
+}
+
+#[derive(Clone)]
+struct LanFilter {
+    /// Address and subnet of the router on each network
+    networks: Vec<Ipv4Network>,
+
+    firewall: Firewall,
 }
 
 impl VirtioSwitch {
     /// Creates a new, empty switch with no ports connected
     ///
     /// ### Arguments
-    /// * `pcap` - Path to pcap file on disk, or None to disable pcap
-    pub fn new(pcap: Option<PathBuf>) -> Result<Self, NetworkError> {
-        let logger = PcapLogger::new(pcap)?;
-        Ok(Self {
-            logger,
+    /// * `capture` - Capture receiving all frames processed by the switch, or None to
+    ///   disable capture
+    pub fn new(capture: Option<Capture>) -> Self {
+        Self {
+            capture,
             ..Default::default()
-        })
+        }
+    }
+
+    /// Returns a handle to this switch that connects new devices with the specified VLAN
+    /// membership (devices are connected as access ports of the default VLAN otherwise)
+    ///
+    /// ### Arguments
+    /// * `mode` - VLAN membership of ports connected through the returned handle
+    pub fn with_mode(&self, mode: PortMode) -> Self {
+        Self {
+            mode,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a handle to this switch that connects new devices under the specified name,
+    /// allowing them to be selected as sources or destinations of mirror sessions
+    ///
+    /// ### Arguments
+    /// * `name` - Name of ports connected through the returned handle
+    pub fn with_name<S: Into<String>>(&self, name: S) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..self.clone()
+        }
+    }
+
+    /// Sets the mirror sessions of this switch, replacing any existing sessions
+    ///
+    /// ### Arguments
+    /// * `mirrors` - Mirror sessions
+    pub fn set_mirrors(&self, mirrors: Vec<MirrorConfig>) {
+        *self.mirrors.write() = mirrors;
+    }
+
+    /// Changes the VLAN membership of the device connected to a port
+    ///
+    /// ### Arguments
+    /// * `port` - Port the device is connected to
+    /// * `mode` - New VLAN membership of the port
+    pub fn set_port_mode(&self, port: usize, mode: PortMode) {
+        if let Some(Some(dev)) = self.ports.write().get_mut(port) {
+            dev.mode = mode;
+        }
+    }
+
+    /// Sets the time a learned MAC address remains associated with a port without being seen
+    ///
+    /// ### Arguments
+    /// * `timeout` - Aging time of learned addresses
+    pub fn set_mac_timeout(&self, timeout: Duration) {
+        self.cache.write().timeout = timeout;
+    }
+
+    /// Returns the devices connected to the switch, ordered by port
+    pub fn ports(&self) -> Vec<PortInfo> {
+        let cache = self.cache.read();
+        self.ports
+            .read()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, port)| port.as_ref().map(|port| (idx, port)))
+            .map(|(idx, port)| PortInfo {
+                port: idx,
+                name: port.name.clone(),
+                mode: port.mode.clone(),
+                macs: cache.entries.values().filter(|e| e.port == idx).count(),
+                counters: port.stats.load(),
+                queues: port.dev.queue_counters(),
+            })
+            .collect()
+    }
+
+    /// Returns the MAC addresses learned by the switch that have not aged out
+    pub fn mac_table(&self) -> Vec<MacInfo> {
+        let now = Instant::now();
+        let cache = self.cache.read();
+        cache
+            .entries
+            .iter()
+            .map(|((vlan, mac), entry)| (*vlan, *mac, entry, now.duration_since(entry.last_seen)))
+            .filter(|(_, _, _, age)| *age < cache.timeout)
+            .map(|(vlan, mac, entry, age)| MacInfo {
+                vlan,
+                mac,
+                port: entry.port,
+                age: age.as_secs(),
+            })
+            .collect()
+    }
+
+    /// Forgets all learned MAC addresses, flooding frames until the addresses are learned again
+    pub fn flush_macs(&self) {
+        self.cache.write().entries.clear();
+        tracing::debug!("[switch] mac table flushed");
+    }
+
+    /// Closes the connection of the device connected to a port.  The port is freed once the
+    /// device disconnects.  Returns false if no device is connected to the port or the device
+    /// cannot be disconnected (e.g., the router)
+    ///
+    /// ### Arguments
+    /// * `port` - Port the device is connected to
+    pub fn kick(&self, port: usize) -> bool {
+        self.ports
+            .read()
+            .get(port)
+            .and_then(Option::as_ref)
+            .map(|port| port.dev.close())
+            .unwrap_or(false)
+    }
+
+    /// Returns the live captures of this switch, used to attach new captures while the switch
+    /// is running
+    pub fn taps(&self) -> Taps {
+        self.taps.clone()
+    }
+
+    /// Applies a firewall to (unicast ipv4) traffic exchanged directly between hosts on the
+    /// LAN.  Traffic to or through the router is filtered by the router
+    ///
+    /// ### Arguments
+    /// * `networks` - Address and subnet of the router on each network (VLAN)
+    /// * `firewall` - Firewall to apply
+    pub fn set_firewall(&self, networks: Vec<Ipv4Network>, firewall: Option<Firewall>) {
+        *self.filter.write() = firewall.map(|firewall| LanFilter { networks, firewall });
+    }
+
+    /// Runs a unicast frame through the firewall (if enabled), returning the payload if it
+    /// should be delivered
+    ///
+    /// ### Arguments
+    /// * `port` - Port the frame was received on
+    /// * `vlan` - VLAN the frame belongs to
+    /// * `frame` - Ethernet frame header
+    /// * `pkt` - Ethernet frame payload
+    fn filter(
+        &self,
+        port: usize,
+        vlan: u16,
+        frame: &EthernetFrame,
+        pkt: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, ProtocolError> {
+        let filter = self.filter.read();
+        let filter = match *filter {
+            Some(ref filter) if frame.ethertype == EtherType::IPv4 => filter,
+            _ => return Ok(Some(pkt)),
+        };
+
+        let ipv4 = Ipv4Packet::parse(pkt)?;
+        let (src, dst) = (ipv4.src(), ipv4.dest());
+        let on_lan = filter
+            .networks
+            .iter()
+            .any(|lan| lan.contains(src) && lan.contains(dst) && *lan != src && *lan != dst);
+
+        if !on_lan {
+            return Ok(Some(ipv4.into_bytes()));
+        }
+
+        match filter.firewall.filter(Direction::LanToLan, &ipv4) {
+            Action::Accept => Ok(Some(ipv4.into_bytes())),
+            Action::Drop => Ok(None),
+            Action::Reject => {
+                self.reject(port, vlan, frame, &ipv4);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Notifies the sender of a rejected packet (on behalf of the destination host)
+    ///
+    /// ### Arguments
+    /// * `port` - Port the packet was received on
+    /// * `vlan` - VLAN the packet was received on
+    /// * `frame` - Ethernet frame header of the rejected packet
+    /// * `pkt` - Rejected packet
+    fn reject(&self, port: usize, vlan: u16, frame: &EthernetFrame, pkt: &Ipv4Packet) {
+        let is_icmp_error = pkt.protocol() == NET_PROTOCOL_ICMP
+            && pkt
+                .payload()
+                .first()
+                .map(|ty| icmp::is_error(*ty))
+                .unwrap_or(true);
+
+        if pkt.fragment_offset() != 0 || is_icmp_error {
+            return;
+        }
+
+        let icmp = IcmpPacket::unreachable(DestinationUnreachableCode::CommAdminProhibited, pkt);
+        let mut buf = vec![0u8; icmp.size()];
+        icmp.as_bytes(&mut buf);
+
+        let reply = Ipv4Packet::new(pkt.dest(), pkt.src(), NET_PROTOCOL_ICMP, &buf);
+        let frame = EthernetFrame::new(frame.dst, frame.src, EtherType::IPv4);
+        if let Some(Some(dev)) = self.ports.read().get(port) {
+            dev.send(vlan, frame, reply.into_bytes());
+        }
+    }
+
+    /// Copies a frame to the destination port of each mirror session matching the frame.
+    /// Copies are tagged with the frame's VLAN, unless it belongs to the default VLAN
+    ///
+    /// ### Arguments
+    /// * `ports` - Ports connected to the switch
+    /// * `ingress` - Port the frame was received on
+    /// * `egress` - Ports the frame is sent to
+    /// * `vlan` - VLAN the frame belongs to
+    /// * `frame` - Ethernet frame header
+    /// * `pkt` - Ethernet frame payload
+    fn mirror(
+        &self,
+        ports: &[Option<Port>],
+        ingress: usize,
+        egress: &[usize],
+        vlan: u16,
+        frame: EthernetFrame,
+        pkt: &[u8],
+    ) {
+        let mirrors = self.mirrors.read();
+        if mirrors.is_empty() {
+            return;
+        }
+
+        let name = |idx: usize| {
+            ports
+                .get(idx)
+                .and_then(Option::as_ref)
+                .and_then(|dev| dev.name.as_deref())
+        };
+
+        let mut targets = Vec::new();
+        for session in mirrors.iter() {
+            let is_source = |port: Option<&str>| {
+                port.map(|port| session.ports.iter().any(|name| name == port))
+                    .unwrap_or(false)
+            };
+
+            let matches = session.vlans.contains(&vlan)
+                || (session.direction.ingress() && is_source(name(ingress)))
+                || (session.direction.egress() && egress.iter().any(|idx| is_source(name(*idx))));
+
+            if !matches {
+                continue;
+            }
+
+            for idx in 0..ports.len() {
+                if name(idx) == Some(session.destination.as_str()) && !targets.contains(&idx) {
+                    targets.push(idx);
+                }
+            }
+        }
+
+        let tag = (vlan != DEFAULT_VLAN).then_some(vlan);
+        for idx in targets {
+            if let Some(Some(dev)) = ports.get(idx) {
+                tracing::trace!(port = idx, vlan, "[switch] mirroring frame");
+                dev.transmit(frame.with_vlan(tag), pkt.to_vec());
+            }
+        }
+    }
+
+    /// Runs a function on the device connected to a port, returning None if no device is
+    /// connected to the port
+    ///
+    /// ### Arguments
+    /// * `port` - Switch port number
+    /// * `f` - Function to run
+    fn with_port<T, F: FnOnce(&Port) -> T>(&self, port: usize, f: F) -> Option<T> {
+        self.ports.read().get(port).and_then(Option::as_ref).map(f)
     }
 
     /// Maps a switch port to a MAC address for later retrieval
     ///
     /// ### Arguments
     /// * `port` - Switch port number
+    /// * `vlan` - VLAN the MAC address was seen on
     /// * `mac` - MAC address to associate with port
-    fn associate_port(&self, port: usize, mac: MacAddress) {
-        let mut cache = self.cache.write();
+    fn associate_port(&self, port: usize, vlan: u16, mac: MacAddress) {
+        self.cache.write().learn_at(port, vlan, mac, Instant::now());
+    }
 
-        // associate MAC address of source with port
-        match cache.insert(mac, port) {
-            Some(old_port) if port == old_port => { /* do nothing, no port change */ }
-            Some(old_port) => {
-                tracing::trace!(
-                    port,
-                    old_port,
-                    "[switch] associating mac ({}) with new port",
-                    mac
-                )
+    /// Returns the switch port associated with a MAC address on a VLAN, or None if no port
+    /// was found (or the address has aged out)
+    fn get_port(&self, vlan: u16, mac: MacAddress) -> Option<usize> {
+        self.cache.read().get_at(vlan, mac, Instant::now())
+    }
+}
+
+impl Default for MacTable {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            timeout: DEFAULT_MAC_TIMEOUT,
+            last_sweep: Instant::now(),
+        }
+    }
+}
+
+impl MacTable {
+    /// Returns the port a MAC address was last seen on, or None if the address is unknown or
+    /// has aged out
+    ///
+    /// ### Arguments
+    /// * `vlan` - VLAN to lookup the address on
+    /// * `mac` - MAC address to lookup
+    /// * `now` - Current time
+    fn get_at(&self, vlan: u16, mac: MacAddress, now: Instant) -> Option<usize> {
+        self.entries
+            .get(&(vlan, mac))
+            .filter(|entry| now.duration_since(entry.last_seen) < self.timeout)
+            .map(|entry| entry.port)
+    }
+
+    /// Returns true if a MAC address was recently seen on a port, meaning the entry does not
+    /// need to be refreshed
+    ///
+    /// ### Arguments
+    /// * `port` - Port the address was seen on
+    /// * `vlan` - VLAN the address was seen on
+    /// * `mac` - MAC address
+    /// * `now` - Current time
+    fn is_fresh(&self, port: usize, vlan: u16, mac: MacAddress, now: Instant) -> bool {
+        self.entries
+            .get(&(vlan, mac))
+            .map(|entry| {
+                entry.port == port && now.duration_since(entry.last_seen) < MAC_REFRESH_INTERVAL
+            })
+            .unwrap_or(false)
+    }
+
+    /// Associates a MAC address with the port it was seen on, removing expired addresses
+    /// periodically
+    ///
+    /// ### Arguments
+    /// * `port` - Port the address was seen on
+    /// * `vlan` - VLAN the address was seen on
+    /// * `mac` - MAC address
+    /// * `now` - Current time
+    fn learn_at(&mut self, port: usize, vlan: u16, mac: MacAddress, now: Instant) {
+        if now.duration_since(self.last_sweep) >= MAC_SWEEP_INTERVAL {
+            self.expire_at(now);
+        }
+
+        let entry = MacEntry {
+            port,
+            last_seen: now,
+        };
+
+        match self.entries.insert((vlan, mac), entry) {
+            Some(old) if old.port == port => { /* do nothing, no port change */ }
+            Some(old) => tracing::trace!(
+                port,
+                old_port = old.port,
+                vlan,
+                "[switch] associating mac ({}) with new port",
+                mac
+            ),
+            None => tracing::trace!(
+                vlan,
+                "[switch] associating mac ({}) with port {}",
+                mac,
+                port
+            ),
+        }
+    }
+
+    /// Forgets all MAC addresses learned on a port
+    ///
+    /// ### Arguments
+    /// * `port` - Port that was disconnected
+    fn remove_port(&mut self, port: usize) {
+        self.entries.retain(|_, entry| entry.port != port);
+    }
+
+    /// Removes all MAC addresses that have not been seen within the timeout
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    fn expire_at(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.entries
+            .retain(|_, entry| now.duration_since(entry.last_seen) < timeout);
+
+        self.last_sweep = now;
+    }
+}
+
+impl Default for PortMode {
+    fn default() -> Self {
+        Self::Access(DEFAULT_VLAN)
+    }
+}
+
+impl From<&PortConfig> for PortMode {
+    fn from(cfg: &PortConfig) -> Self {
+        match cfg {
+            PortConfig::Access { vlan } => Self::Access(*vlan),
+            PortConfig::Trunk { native, vlans } => Self::Trunk {
+                native: *native,
+                allowed: vlans.clone(),
+            },
+            PortConfig::Monitor => Self::Monitor,
+        }
+    }
+}
+
+impl PortMode {
+    /// Returns the VLAN a frame received on a port with this mode belongs to, or None if the
+    /// port is not a member of the frame's VLAN.  Priority-tagged frames (VLAN 0) are treated
+    /// as untagged
+    ///
+    /// ### Arguments
+    /// * `tag` - VLAN identifier of the frame's 802.1Q tag, if tagged
+    fn ingress(&self, tag: Option<u16>) -> Option<u16> {
+        let tag = tag.filter(|vlan| *vlan != 0);
+        match (self, tag) {
+            (Self::Access(vlan), None) => Some(*vlan),
+            (Self::Access(vlan), Some(tag)) if *vlan == tag => Some(tag),
+            (Self::Trunk { native, .. }, None) => *native,
+            (Self::Trunk { native, allowed }, Some(tag))
+                if *native == Some(tag) || allowed.contains(&tag) =>
+            {
+                Some(tag)
             }
-            None => tracing::trace!("[switch] associating mac ({}) with port {}", mac, port),
+            _ => None,
         }
     }
 
-    /// Returns the switch port associated with a MAC address, or None if no port was found
-    fn get_port(&self, mac: MacAddress) -> Option<usize> {
-        let cache = self.cache.read();
-        cache.get(&mac).map(|port| *port)
+    /// Returns the tag to send a frame with on a port with this mode (`Some(None)` if the
+    /// frame is sent untagged), or None if the port is not a member of the VLAN
+    ///
+    /// ### Arguments
+    /// * `vlan` - VLAN the frame belongs to
+    fn egress(&self, vlan: u16) -> Option<Option<u16>> {
+        match self {
+            Self::Access(id) if *id == vlan => Some(None),
+            Self::Trunk { native, .. } if *native == Some(vlan) => Some(None),
+            Self::Trunk { allowed, .. } if allowed.contains(&vlan) => Some(Some(vlan)),
+            _ => None,
+        }
+    }
+}
+
+impl Port {
+    /// Sends a frame to the device if the port is a member of the frame's VLAN, adding or
+    /// removing the 802.1Q tag as required
+    ///
+    /// ### Arguments
+    /// * `vlan` - VLAN the frame belongs to
+    /// * `frame` - Ethernet frame header
+    /// * `pkt` - Ethernet frame payload
+    fn send(&self, vlan: u16, frame: EthernetFrame, pkt: Vec<u8>) {
+        if let Some(tag) = self.mode.egress(vlan) {
+            self.transmit(frame.with_vlan(tag), pkt);
+        }
+    }
+
+    /// Sends a frame to the device as-is, counting it
+    ///
+    /// ### Arguments
+    /// * `frame` - Ethernet frame header
+    /// * `pkt` - Ethernet frame payload
+    fn transmit(&self, frame: EthernetFrame, pkt: Vec<u8>) {
+        self.stats.tx.record(ETHERNET_HDR_SZ + pkt.len());
+        self.dev.enqueue(frame, pkt);
     }
 }
 
@@ -78,49 +646,158 @@ impl Switch for VirtioSwitch {
     /// ### Arguments
     /// * `port` - Device to connect to this switch
     fn connect<P: SwitchPort + 'static>(&self, port: P) -> usize {
+        let port = Port {
+            dev: Box::new(port),
+            mode: self.mode.clone(),
+            name: self.name.clone(),
+            stats: InterfaceStats::default(),
+        };
+
+        // reuse the first port freed by a disconnected device
         let mut ports = self.ports.write();
-        let idx = ports.len();
-        ports.push(Box::new(port));
+        let idx = match ports.iter().position(Option::is_none) {
+            Some(idx) => {
+                ports[idx] = Some(port);
+                idx
+            }
+            None => {
+                ports.push(Some(port));
+                ports.len() - 1
+            }
+        };
 
+        tracing::debug!(port = idx, mode = ?self.mode, name = ?self.name, "[switch] device connected");
         idx
     }
 
+    /// Disconnects the device connected to a port, forgetting the MAC addresses learned on
+    /// the port.  The port is reused by the next device to connect
+    ///
+    /// ### Arguments
+    /// * `port` - Port the device is connected to
+    fn disconnect(&self, port: usize) {
+        if let Some(slot) = self.ports.write().get_mut(port) {
+            *slot = None;
+        }
+
+        self.cache.write().remove_port(port);
+        tracing::debug!(port, "[switch] device disconnected");
+    }
+
     /// Processes a packet through the switch, sending it to the desired port
-    /// or flooding it to all ports if the mac is not known
+    /// or flooding it to all ports if the mac is not known (or has aged out).
+    /// Packets are only delivered to ports that are members of the packet's VLAN,
+    /// and copied to the monitor ports of matching mirror sessions
     ///
     /// ### Arguments
     /// * `port` - Port id this packet was sent from
     /// * `pkt` - Ethernet Framed packet (Layer 2)
     fn process(&self, port: usize, mut pkt: Vec<u8>) -> Result<(), ProtocolError> {
         if pkt.len() < ETHERNET_HDR_SZ {
+            self.with_port(port, |dev| dev.stats.rx.record_error());
             return Err(ProtocolError::NotEnoughData(pkt.len(), ETHERNET_HDR_SZ));
         }
 
-        self.logger.log_packet(&pkt);
+        let len = pkt.len();
+
+        if self.capture.is_some() || !self.taps.is_empty() {
+            let ports = self.ports.read();
+            let name = ports
+                .get(port)
+                .and_then(Option::as_ref)
+                .and_then(|dev| dev.name.as_deref());
+
+            if let Some(ref capture) = self.capture {
+                capture.log(port, name, &pkt);
+            }
+
+            self.taps.log(port, name, &pkt);
+        }
 
         let frame = EthernetFrame::extract(&mut pkt)?;
 
+        let ingress = self.with_port(port, |dev| {
+            dev.stats.rx.record(len);
+            let vlan = dev.mode.ingress(frame.vlan);
+            if vlan.is_none() {
+                dev.stats.rx.record_drop();
+            }
+            vlan
+        });
+
+        let Some(vlan) = ingress.flatten() else {
+            tracing::trace!(port, tag = ?frame.vlan, "[switch] port not a member of vlan, dropping frame");
+            return Ok(());
+        };
+
         // update our cached mac address / port cache mapping if needed for the source port
-        match self.get_port(frame.src) {
-            Some(p) if p == port => { /* do nothing, no need to update cache */ }
-            Some(_) | None => self.associate_port(port, frame.src),
+        let fresh = self
+            .cache
+            .read()
+            .is_fresh(port, vlan, frame.src, Instant::now());
+
+        if !fresh {
+            self.associate_port(port, vlan, frame.src);
+        }
+
+        let is_unicast = !(frame.dst.is_broadcast() || frame.dst.is_multicast());
+        if is_unicast {
+            pkt = match self.filter(port, vlan, &frame, pkt)? {
+                Some(pkt) => pkt,
+                None => {
+                    self.with_port(port, |dev| dev.stats.rx.record_drop());
+                    return Ok(());
+                }
+            };
         }
 
         // write packet to destination port
         let ports = self.ports.read();
-        if frame.dst.is_broadcast() {
-            // write to all ports (but originator)
-            tracing::trace!(?frame, "[switch] got broadcast message");
-            for (_, dev) in ports.iter().enumerate().filter(|(idx, _)| *idx != port) {
-                dev.enqueue(frame, pkt.clone());
+        let dst = match is_unicast {
+            true => self.get_port(vlan, frame.dst),
+            false => None,
+        };
+
+        let egress = match dst {
+            Some(dst) if dst == port => {
+                tracing::trace!(
+                    port,
+                    vlan,
+                    "[switch] destination on ingress port, dropping frame"
+                );
+                Vec::new()
             }
-        } else {
-            match self.get_port(frame.dst) {
-                Some(port) => match ports.get(port) {
-                    Some(dev) => dev.enqueue(frame, pkt),
-                    None => tracing::warn!(port, "[switch] device not connected to port!"),
-                },
-                None => tracing::warn!("[switch] mac ({}) not associated with port", frame.dst),
+            Some(dst) => vec![dst],
+            None => {
+                // write to all ports in the vlan (but originator)
+                match is_unicast {
+                    true => tracing::trace!(?frame, vlan, "[switch] unknown unicast, flooding"),
+                    false => {
+                        tracing::trace!(?frame, vlan, "[switch] got broadcast/multicast message")
+                    }
+                }
+
+                ports
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, dev)| {
+                        *idx != port
+                            && dev
+                                .as_ref()
+                                .map(|dev| dev.mode.egress(vlan).is_some())
+                                .unwrap_or(false)
+                    })
+                    .map(|(idx, _)| idx)
+                    .collect()
+            }
+        };
+
+        self.mirror(&ports, port, &egress, vlan, frame, &pkt);
+
+        for idx in egress {
+            match ports.get(idx).and_then(Option::as_ref) {
+                Some(dev) => dev.send(vlan, frame, pkt.clone()),
+                None => tracing::warn!(port = idx, "[switch] device not connected to port!"),
             }
         }
 
@@ -128,43 +805,310 @@ impl Switch for VirtioSwitch {
     }
 }
 
-impl PcapLogger {
-    pub fn new(path: Option<PathBuf>) -> Result<Self, NetworkError> {
-        match path {
-            Some(path) => {
-                let tx = Self::spawn(path)?;
-                Ok(Self { tx: Some(tx) })
-            }
-            None => Ok(Self { tx: None }),
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use oathgate_net::{
+        types::{EtherType, MacAddress},
+        EthernetFrame, Switch, SwitchPort,
+    };
+    use parking_lot::Mutex;
+
+    use crate::config::mirror::MirrorConfig;
+
+    use super::{MacTable, PortMode, VirtioSwitch};
+
+    /// A port that records the frames it receives
+    #[derive(Clone, Default)]
+    struct Recorder {
+        frames: Arc<Mutex<Vec<EthernetFrame>>>,
+    }
+
+    impl SwitchPort for Recorder {
+        fn enqueue(&self, frame: EthernetFrame, _pkt: Vec<u8>) {
+            self.frames.lock().push(frame);
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<EthernetFrame> {
+            std::mem::take(&mut *self.frames.lock())
         }
     }
 
-    fn spawn(path: PathBuf) -> Result<Sender<Vec<u8>>, NetworkError> {
-        let file = File::options().create(true).write(true).open(path)?;
+    fn frame(src: MacAddress, dst: MacAddress, vlan: Option<u16>) -> Vec<u8> {
+        let mut pkt = EthernetFrame::new(src, dst, EtherType::ARP)
+            .with_vlan(vlan)
+            .to_bytes();
+        pkt.extend_from_slice(&[0u8; 28]);
+        pkt
+    }
 
-        let mut writer = PcapWriter::new(file)?;
-        let (tx, rx) = flume::unbounded::<Vec<u8>>();
+    #[test]
+    fn switch_vlan_isolation() {
+        let switch = VirtioSwitch::default();
+        let (a, b, c, trunk) = (
+            Recorder::default(),
+            Recorder::default(),
+            Recorder::default(),
+            Recorder::default(),
+        );
 
-        std::thread::Builder::new()
-            .name(String::from("pcap-logger"))
-            .spawn(move || {
-                while let Ok(pkt) = rx.recv() {
-                    writer
-                        .write_packet(&PcapPacket {
-                            timestamp: UNIX_EPOCH.elapsed().unwrap(),
-                            orig_len: pkt.len() as u32,
-                            data: Cow::Borrowed(&pkt),
-                        })
-                        .ok();
-                }
-            })?;
+        let pa = switch.with_mode(PortMode::Access(10)).connect(a.clone());
+        let pb = switch.with_mode(PortMode::Access(10)).connect(b.clone());
+        let pc = switch.with_mode(PortMode::Access(20)).connect(c.clone());
+        let pt = switch
+            .with_mode(PortMode::Trunk {
+                native: Some(10),
+                allowed: vec![20],
+            })
+            .connect(trunk.clone());
+
+        let (mac_a, mac_c, mac_t) = (
+            MacAddress::generate(),
+            MacAddress::generate(),
+            MacAddress::generate(),
+        );
+
+        // broadcasts only reach ports in the same vlan, tagged on the trunk (except native)
+        switch
+            .process(pc, frame(mac_c, MacAddress::broadcast(), None))
+            .unwrap();
+        assert!(a.take().is_empty());
+        assert!(b.take().is_empty());
+        assert_eq!(trunk.take()[0].vlan, Some(20));
 
-        Ok(tx)
+        switch
+            .process(pa, frame(mac_a, MacAddress::broadcast(), None))
+            .unwrap();
+        assert_eq!(b.take().len(), 1);
+        assert!(c.take().is_empty());
+        assert_eq!(trunk.take()[0].vlan, None);
+
+        // tagged frames from the trunk are untagged on access ports
+        switch.process(pt, frame(mac_t, mac_c, Some(20))).unwrap();
+        let frames = c.take();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].vlan, None);
+
+        // mac addresses are learned per vlan, unknown addresses are flooded within the vlan
+        switch.process(pt, frame(mac_t, mac_a, Some(20))).unwrap();
+        assert!(a.take().is_empty());
+        assert_eq!(c.take().len(), 1);
+
+        // frames for vlans a port is not a member of are dropped
+        switch.process(pb, frame(mac_a, mac_c, Some(20))).unwrap();
+        assert!(c.take().is_empty());
     }
 
-    fn log_packet(&self, pkt: &[u8]) {
-        if let Some(ref tx) = self.tx {
-            tx.send(pkt.to_vec()).ok();
-        }
+    #[test]
+    fn switch_flood_unknown_unicast() {
+        let switch = VirtioSwitch::default();
+        let (a, b, c) = (
+            Recorder::default(),
+            Recorder::default(),
+            Recorder::default(),
+        );
+
+        let pa = switch.connect(a.clone());
+        let pb = switch.connect(b.clone());
+        switch.connect(c.clone());
+
+        let (mac_a, mac_b) = (MacAddress::generate(), MacAddress::generate());
+
+        // unknown destination is flooded
+        switch.process(pa, frame(mac_a, mac_b, None)).unwrap();
+        assert!(a.take().is_empty());
+        assert_eq!(b.take().len(), 1);
+        assert_eq!(c.take().len(), 1);
+
+        // ...until it is learned
+        switch.process(pb, frame(mac_b, mac_a, None)).unwrap();
+        assert_eq!(a.take().len(), 1);
+        assert!(c.take().is_empty());
+    }
+
+    #[test]
+    fn switch_disconnect_reuses_port() {
+        let switch = VirtioSwitch::default();
+        let (a, b, c) = (
+            Recorder::default(),
+            Recorder::default(),
+            Recorder::default(),
+        );
+
+        let pa = switch.connect(a.clone());
+        let pb = switch.connect(b.clone());
+        let (mac_a, mac_b) = (MacAddress::generate(), MacAddress::generate());
+        switch.process(pb, frame(mac_b, mac_a, None)).unwrap();
+        a.take();
+
+        switch.disconnect(pb);
+        assert_eq!(switch.connect(c.clone()), pb);
+
+        // the address learned on the old port is forgotten, so the frame is flooded
+        switch.process(pa, frame(mac_a, mac_b, None)).unwrap();
+        assert!(b.take().is_empty());
+        assert_eq!(c.take().len(), 1);
+    }
+
+    #[test]
+    fn switch_set_port_mode() {
+        let switch = VirtioSwitch::default();
+        let (a, trunk) = (Recorder::default(), Recorder::default());
+
+        let pa = switch.with_mode(PortMode::Access(20)).connect(a.clone());
+        let pt = switch
+            .with_mode(PortMode::Trunk {
+                native: Some(1),
+                allowed: vec![],
+            })
+            .connect(trunk.clone());
+
+        let mac_a = MacAddress::generate();
+        switch
+            .process(pa, frame(mac_a, MacAddress::broadcast(), None))
+            .unwrap();
+        assert!(trunk.take().is_empty());
+
+        // the device stays connected to the same port, now a member of vlan 20
+        switch.set_port_mode(
+            pt,
+            PortMode::Trunk {
+                native: Some(1),
+                allowed: vec![20],
+            },
+        );
+        switch
+            .process(pa, frame(mac_a, MacAddress::broadcast(), None))
+            .unwrap();
+        assert_eq!(trunk.take()[0].vlan, Some(20));
+    }
+
+    #[test]
+    fn switch_mirror() {
+        let switch = VirtioSwitch::default();
+        let (a, b, c, ids) = (
+            Recorder::default(),
+            Recorder::default(),
+            Recorder::default(),
+            Recorder::default(),
+        );
+
+        let pa = switch.with_name("a").connect(a.clone());
+        let pb = switch.with_name("b").connect(b.clone());
+        let pc = switch
+            .with_mode(PortMode::Access(20))
+            .with_name("c")
+            .connect(c.clone());
+        let pids = switch
+            .with_mode(PortMode::Monitor)
+            .with_name("ids")
+            .connect(ids.clone());
+
+        let mirrors: Vec<MirrorConfig> = serde_yaml::from_str(
+            r#"
+- destination: ids
+  ports: [a]
+  direction: ingress
+- destination: ids
+  vlans: [20]
+"#,
+        )
+        .unwrap();
+        switch.set_mirrors(mirrors);
+
+        let (mac_a, mac_b, mac_c) = (
+            MacAddress::generate(),
+            MacAddress::generate(),
+            MacAddress::generate(),
+        );
+
+        // monitor ports receive no regular traffic
+        switch.process(pa, frame(mac_a, mac_b, None)).unwrap();
+        assert_eq!(b.take().len(), 1);
+        assert_eq!(ids.take().len(), 1);
+
+        // traffic sent to a is not mirrored (ingress only)
+        switch.process(pb, frame(mac_b, mac_a, None)).unwrap();
+        assert_eq!(a.take().len(), 1);
+        assert!(ids.take().is_empty());
+
+        // mirrored vlans are copied tagged
+        switch
+            .process(pc, frame(mac_c, MacAddress::broadcast(), None))
+            .unwrap();
+        assert_eq!(ids.take()[0].vlan, Some(20));
+
+        // frames sent by monitor ports are dropped
+        switch
+            .process(pids, frame(mac_b, MacAddress::broadcast(), None))
+            .unwrap();
+        assert!(a.take().is_empty());
+        assert!(b.take().is_empty());
+        assert!(c.take().is_empty());
+    }
+
+    #[test]
+    fn switch_inspect() {
+        let switch = VirtioSwitch::default();
+        let (a, b) = (Recorder::default(), Recorder::default());
+
+        let pa = switch.with_name("a").connect(a.clone());
+        let pb = switch.with_mode(PortMode::Access(20)).connect(b.clone());
+        let (mac_a, mac_b) = (MacAddress::generate(), MacAddress::generate());
+        switch.process(pa, frame(mac_a, mac_b, None)).unwrap();
+        switch.process(pb, frame(mac_b, mac_a, None)).unwrap();
+
+        let ports = switch.ports();
+        assert_eq!(ports.len(), 2);
+        assert_eq!(ports[0].name.as_deref(), Some("a"));
+        assert_eq!(ports[0].macs, 1);
+        assert_eq!(ports[1].mode, PortMode::Access(20));
+        assert_eq!(ports[0].counters.rx.packets, 1);
+        assert_eq!(ports[0].counters.tx.packets, 0);
+        assert_eq!(ports[1].counters.rx.packets, 1);
+
+        // frames tagged with a vlan the port is not a member of are dropped
+        switch.process(pa, frame(mac_a, mac_b, Some(30))).ok();
+        assert_eq!(switch.ports()[0].counters.rx.packets, 2);
+        assert_eq!(switch.ports()[0].counters.rx.dropped, 1);
+
+        let macs = switch.mac_table();
+        assert!(macs
+            .iter()
+            .any(|entry| entry.mac == mac_b && entry.vlan == 20 && entry.port == pb));
+
+        // devices that cannot be disconnected are not kicked
+        assert!(!switch.kick(pa));
+        assert!(!switch.kick(10));
+
+        switch.flush_macs();
+        assert!(switch.mac_table().is_empty());
+        assert_eq!(switch.ports()[0].macs, 0);
+    }
+
+    #[test]
+    fn mac_table_aging() {
+        let now = Instant::now();
+        let mut table = MacTable {
+            timeout: Duration::from_secs(30),
+            ..Default::default()
+        };
+
+        let mac = MacAddress::generate();
+        table.learn_at(3, 1, mac, now);
+        assert_eq!(table.get_at(1, mac, now), Some(3));
+        assert_eq!(table.get_at(2, mac, now), None);
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(table.get_at(1, mac, later), None);
+
+        table.expire_at(later);
+        assert!(table.entries.is_empty());
     }
 }
@@ -1,42 +1,101 @@
 //! Various WAN providers
 
+mod netlink;
 mod tap;
 mod udp;
+mod user;
 mod wireguard;
 
-use oathgate_net::Ipv4Packet;
+use std::{net::IpAddr, sync::Arc, thread::JoinHandle};
+
+use oathgate_net::{
+    nat::{NatTable, PortForward},
+    Ipv4Packet, Ipv6Packet,
+};
+use parking_lot::Mutex;
 
 pub use self::{
     tap::TunTap,
     udp::UdpDevice,
+    user::{UserConfig, UserNet},
     wireguard::{WgConfig, WgDevice},
 };
 
 use super::{router::RouterHandle, NetworkError};
 
+/// NAT table of a WAN, shared between the WAN's thread and its handle
+pub type SharedNat = Arc<Mutex<NatTable>>;
+
 pub trait Wan: Send + Sync
 where
     Self: 'static,
 {
     fn as_wan_handle(&self) -> Result<Box<dyn WanHandle>, NetworkError>;
 
+    /// Forwards traffic arriving on a port to a LAN device
+    ///
+    /// WANs with an address of their own forward the port on that address; other WANs accept
+    /// the traffic on a host socket
+    ///
+    /// ### Arguments
+    /// * `fwd` - Port to forward and the LAN device to forward it to
+    /// * `listen` - Host address to listen on, for WANs without an address
+    fn add_forward(&mut self, fwd: PortForward, listen: IpAddr) -> Result<(), NetworkError>;
+
+    /// Runs the WAN until the router shuts down (see `RouterHandle::is_stopped`)
     fn run(self: Box<Self>, router: RouterHandle) -> Result<(), NetworkError>;
 
-    fn spawn(self: Box<Self>, router: RouterHandle) -> Result<Box<dyn WanHandle>, NetworkError> {
+    /// Runs the WAN on its own thread, returning a handle to write packets to the WAN and
+    /// the thread running it
+    fn spawn(
+        self: Box<Self>,
+        router: RouterHandle,
+    ) -> Result<(Box<dyn WanHandle>, JoinHandle<()>), NetworkError> {
         let handle = self.as_wan_handle()?;
 
-        std::thread::Builder::new()
+        let thread = std::thread::Builder::new()
             .name(String::from("wan-thread"))
             .spawn(move || match self.run(router) {
                 Ok(_) => tracing::trace!("wan thread exited successfully"),
                 Err(error) => tracing::warn!(?error, "unable to run wan thread"),
             })?;
 
-        Ok(handle)
+        Ok((handle, thread))
     }
 }
 
 pub trait WanHandle: Send + Sync {
     /// Writes a packet to the upstream device
     fn write(&self, pkt: Ipv4Packet) -> Result<(), NetworkError>;
+
+    /// Returns the largest ipv4 packet that can be written to the upstream device
+    fn mtu(&self) -> u16 {
+        1500
+    }
+
+    /// Writes an ipv6 packet to the upstream device
+    ///
+    /// IPv6 packets are routed (not masqueraded), so this is only supported by WANs whose
+    /// peer routes the LAN's prefix back to the bridge
+    fn write_ipv6(&self, _pkt: Ipv6Packet) -> Result<(), NetworkError> {
+        tracing::trace!("[wan] ipv6 not supported, dropping packet");
+        Ok(())
+    }
+
+    /// Returns the NAT table masquerading packets written to the upstream device, or None if
+    /// the WAN does not masquerade packets
+    fn nat(&self) -> Option<SharedNat> {
+        None
+    }
+
+    /// Returns true if a packet sent by a LAN device belongs to a flow opened through one of
+    /// this WAN's port forwards, and so must leave through this WAN
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet sent by a LAN device
+    fn is_forwarded(&self, pkt: &Ipv4Packet) -> bool {
+        self.nat()
+            .map(|nat| nat.lock().is_forwarded(pkt))
+            .unwrap_or(false)
+    }
 }
@@ -0,0 +1,206 @@
+//! Minimal rtnetlink client used to configure host network interfaces
+
+use std::{
+    borrow::Cow,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, OwnedFd},
+};
+
+use nix::{
+    errno::Errno,
+    libc::{
+        AF_INET, IFA_ADDRESS, IFA_LOCAL, IFF_UP, NLMSG_ERROR, NLM_F_ACK, NLM_F_CREATE,
+        NLM_F_REPLACE, NLM_F_REQUEST, RTA_DST, RTA_OIF, RTM_NEWADDR, RTM_NEWLINK, RTM_NEWROUTE,
+        RTN_UNICAST, RTPROT_BOOT, RT_SCOPE_LINK, RT_SCOPE_UNIVERSE, RT_TABLE_MAIN,
+    },
+    sys::socket::{
+        bind, recv, sendto, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol,
+        SockType,
+    },
+};
+use oathgate_net::types::Ipv4Network;
+
+use crate::net::NetworkError;
+
+/// Size of a netlink message header (struct nlmsghdr)
+const NLMSG_HDR_SZ: usize = 16;
+
+/// Size of a buffer used to receive acknowledgements
+const NLMSG_ACK_BUF_SZ: usize = 1024;
+
+/// A connection to the kernel's routing (rtnetlink) subsystem
+pub struct Netlink {
+    /// Netlink socket
+    sock: OwnedFd,
+
+    /// Sequence number of the next message
+    seq: u32,
+}
+
+/// A netlink message being built
+struct NetlinkMessage {
+    buf: Vec<u8>,
+}
+
+impl Netlink {
+    /// Opens a new rtnetlink socket
+    ///
+    /// Note: Modifying interfaces requires administration privileges or CAP_NET_ADMIN
+    pub fn connect() -> Result<Self, NetworkError> {
+        let sock = socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkRoute,
+        )?;
+
+        bind(sock.as_raw_fd(), &NetlinkAddr::new(0, 0))?;
+
+        Ok(Self { sock, seq: 1 })
+    }
+
+    /// Sets an interface's state to up
+    ///
+    /// ### Arguments
+    /// * `idx` - Index of the interface
+    pub fn set_link_up(&mut self, idx: u32) -> Result<(), NetworkError> {
+        // struct ifinfomsg
+        let mut msg = NetlinkMessage::new(RTM_NEWLINK, 0);
+        msg.push(&[AF_INET as u8, 0]); // family, padding
+        msg.push(&0u16.to_ne_bytes()); // type
+        msg.push(&idx.to_ne_bytes()); // index
+        msg.push(&(IFF_UP as u32).to_ne_bytes()); // flags
+        msg.push(&(IFF_UP as u32).to_ne_bytes()); // change mask
+
+        self.send(msg)
+    }
+
+    /// Assigns an ipv4 address to an interface
+    ///
+    /// ### Arguments
+    /// * `idx` - Index of the interface
+    /// * `addr` - Address (and prefix) to assign
+    pub fn add_address(&mut self, idx: u32, addr: Ipv4Network) -> Result<(), NetworkError> {
+        // struct ifaddrmsg
+        let mut msg = NetlinkMessage::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE);
+        msg.push(&[AF_INET as u8, addr.subnet_mask_bits(), 0, RT_SCOPE_UNIVERSE]);
+        msg.push(&idx.to_ne_bytes());
+        msg.push_attr(IFA_LOCAL, &addr.ip().octets());
+        msg.push_attr(IFA_ADDRESS, &addr.ip().octets());
+
+        self.send(msg)
+    }
+
+    /// Adds a route to a network via an interface
+    ///
+    /// ### Arguments
+    /// * `idx` - Index of the interface
+    /// * `dst` - Destination network
+    pub fn add_route(&mut self, idx: u32, dst: Ipv4Network) -> Result<(), NetworkError> {
+        let network: Ipv4Addr = dst.network();
+
+        // struct rtmsg
+        let mut msg = NetlinkMessage::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE);
+        msg.push(&[AF_INET as u8, dst.subnet_mask_bits(), 0, 0]); // family, dst/src len, tos
+        msg.push(&[RT_TABLE_MAIN, RTPROT_BOOT, RT_SCOPE_LINK, RTN_UNICAST]);
+        msg.push(&0u32.to_ne_bytes()); // flags
+        msg.push_attr(RTA_DST, &network.octets());
+        msg.push_attr(RTA_OIF, &idx.to_ne_bytes());
+
+        self.send(msg)
+    }
+
+    /// Sends a message and waits for the kernel to acknowledge it
+    ///
+    /// ### Arguments
+    /// * `msg` - Message to send
+    fn send(&mut self, msg: NetlinkMessage) -> Result<(), NetworkError> {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+
+        let pkt = msg.finish(seq);
+        sendto(
+            self.sock.as_raw_fd(),
+            &pkt,
+            &NetlinkAddr::new(0, 0),
+            MsgFlags::empty(),
+        )?;
+
+        let mut buf = [0u8; NLMSG_ACK_BUF_SZ];
+        loop {
+            let sz = recv(self.sock.as_raw_fd(), &mut buf, MsgFlags::empty())?;
+            let mut offset = 0;
+            while offset + NLMSG_HDR_SZ <= sz {
+                let hdr = &buf[offset..];
+                let len = u32::from_ne_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]) as usize;
+                let ty = u16::from_ne_bytes([hdr[4], hdr[5]]);
+                let rseq = u32::from_ne_bytes([hdr[8], hdr[9], hdr[10], hdr[11]]);
+
+                if len < NLMSG_HDR_SZ {
+                    return Err(NetworkError::Generic(Cow::Borrowed(
+                        "malformed netlink message",
+                    )));
+                }
+
+                if rseq == seq && i32::from(ty) == NLMSG_ERROR && len >= NLMSG_HDR_SZ + 4 {
+                    let code = i32::from_ne_bytes([hdr[16], hdr[17], hdr[18], hdr[19]]);
+                    return match code {
+                        0 => Ok(()),
+                        code => Err(Errno::from_raw(-code).into()),
+                    };
+                }
+
+                offset += (len + 3) & !3;
+            }
+        }
+    }
+}
+
+impl NetlinkMessage {
+    /// Creates a new request message
+    ///
+    /// ### Arguments
+    /// * `ty` - Message type (i.e., RTM_NEWADDR)
+    /// * `flags` - Additional flags (request and ack are always set)
+    fn new(ty: u16, flags: i32) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&[0u8; 4]); // length, set when finished
+        buf.extend_from_slice(&ty.to_ne_bytes());
+        buf.extend_from_slice(&((flags | NLM_F_REQUEST | NLM_F_ACK) as u16).to_ne_bytes());
+        buf.extend_from_slice(&[0u8; 8]); // sequence and port id
+
+        Self { buf }
+    }
+
+    /// Appends raw bytes to the message
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Appends a route attribute (struct rtattr) to the message
+    ///
+    /// ### Arguments
+    /// * `ty` - Attribute type
+    /// * `data` - Attribute value
+    fn push_attr(&mut self, ty: u16, data: &[u8]) {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+
+        // attributes are aligned on 4-byte boundaries
+        let padded = (self.buf.len() + 3) & !3;
+        self.buf.resize(padded, 0);
+    }
+
+    /// Sets the length and sequence number, returning the bytes to send
+    ///
+    /// ### Arguments
+    /// * `seq` - Sequence number of this message
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
@@ -1,25 +1,37 @@
-//! an upstream tap device
+//! an upstream tap/tun device
 
 use std::{
     borrow::Cow,
+    collections::{HashMap, VecDeque},
     fmt::Debug,
     fs::File,
-    io::{self, IoSlice, Read, Write},
-    os::fd::AsRawFd,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use crate::net::{router::RouterHandle, NetworkError};
+use crate::{
+    config::{TapConfig, TunConfig},
+    net::{router::RouterHandle, NetworkError},
+};
 
 use flume::{Receiver, Sender};
 use mio::{unix::SourceFd, Events, Interest, Poll, Token, Waker};
 use nix::{
-    libc::{IFF_NO_PI, IFF_TAP, IFF_TUN, IFNAMSIZ, SIOCGIFHWADDR},
+    libc::{IFF_NO_PI, IFF_TAP, IFF_TUN, IFNAMSIZ, O_NONBLOCK, SIOCGIFHWADDR},
     net::if_::if_nametoindex,
 };
-use oathgate_net::{types::MacAddress, Ipv4Packet};
+use oathgate_net::{
+    nat::{NatConfig, NatTable, PortForward},
+    protocols::{ArpPacket, IcmpPacket, NET_PROTOCOL_ICMP},
+    types::{EtherType, Ipv4Network, MacAddress},
+    EthernetFrame, Ipv4Header, Ipv4Packet,
+};
+use parking_lot::Mutex;
 
-use super::{Wan, WanHandle};
+use super::{netlink::Netlink, SharedNat, Wan, WanHandle};
 
 /// Maximum number of events mio can processes at one time
 const MAX_EVENTS_CAPACITY: usize = 10;
@@ -28,6 +40,28 @@ const MAX_EVENTS_CAPACITY: usize = 10;
 const TOKEN_READ: Token = Token(0);
 const TOKEN_WRITE: Token = Token(1);
 
+/// Interval used to expire nat entries and retry arp requests
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of arp requests sent before dropping packets waiting on a next hop
+const MAX_ARP_ATTEMPTS: u8 = 3;
+
+/// Maximum number of packets queued while waiting for a next hop to be resolved
+const MAX_PENDING_PACKETS: usize = 16;
+
+/// Default MTU of a tap device
+const DEFAULT_MTU: u16 = 1500;
+
+/// Type of device, determines what layer packets are exchanged at
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    /// Layer 2, ethernet frames
+    Tap,
+
+    /// Layer 3, raw ipv4 packets
+    Tun,
+}
+
 pub struct TunTap {
     /// Name of the tun device
     name: String,
@@ -47,13 +81,59 @@ pub struct TunTap {
     /// Channel used to receive packets to send out this device
     rx: Option<Receiver<Ipv4Packet>>,
 
-    /// Mac Address of the device
+    /// Addressing, neighbors and NAT state of the network on the other side of the device
+    link: Link,
+}
+
+/// State of the network on the other side of a tap/tun device.  Frames (or packets) to send
+/// are queued instead of written to the device, keeping address resolution and translation
+/// independent of the file descriptor
+struct Link {
+    /// Type of device (tap or tun)
+    mode: Mode,
+
+    /// Mac Address used by the bridge on the tap network
     mac: MacAddress,
+
+    /// Address (and subnet) used by the bridge on the tap network
+    ipv4: Ipv4Network,
+
+    /// Next hop for destinations outside of the tap network
+    gateway: Option<Ipv4Addr>,
+
+    /// Maximum size of an ipv4 packet sent/received over the device
+    mtu: u16,
+
+    /// Maps ipv4 addresses on the tap network to their mac address
+    arp: HashMap<Ipv4Addr, MacAddress>,
+
+    /// Packets waiting for the next hop's mac address to be resolved
+    pending: HashMap<Ipv4Addr, PendingArp>,
+
+    /// Maps & tracks outbound connections
+    nat: SharedNat,
+
+    /// Frames (tap) or packets (tun) waiting to be written to the device
+    out: VecDeque<Vec<u8>>,
 }
 
 pub struct TunTapHandle {
     tx: Sender<Ipv4Packet>,
     waker: Arc<Waker>,
+    mtu: u16,
+    nat: SharedNat,
+}
+
+/// Packets queued while an arp request is outstanding
+struct PendingArp {
+    /// Packets to send once the next hop is resolved
+    packets: VecDeque<Ipv4Packet>,
+
+    /// Time the last arp request was sent
+    sent: Instant,
+
+    /// Number of arp requests sent
+    attempts: u8,
 }
 
 // ifreq is 40 bytes long
@@ -69,19 +149,66 @@ impl TunTap {
     /// Creates a new tap device
     ///
     /// Note: This requires administration privileges or CAP_NET_ADMIN
-    pub fn create_tap(name: String) -> Result<Self, NetworkError> {
-        Self::create(name, IFF_TAP)
+    ///
+    /// ### Arguments
+    /// * `cfg` - Tap device configuration
+    /// * `nat` - NAT configuration used to masquerade outbound traffic
+    pub fn create_tap(cfg: TapConfig, nat: NatConfig) -> Result<Self, NetworkError> {
+        let mut tap = Self::create(cfg.device, Mode::Tap, cfg.ipv4, nat)?;
+        tap.link.gateway = cfg.gateway;
+        tap.link.mtu = cfg.mtu;
+        Ok(tap)
     }
 
-    /// Creates a new tun device
+    /// Creates a new tun device, optionally assigning an address and routes to the
+    /// host's side of the device
     ///
     /// Note: This requires administration privileges or CAP_NET_ADMIN
-    #[allow(dead_code)]
-    pub fn create_tun(name: String) -> Result<Self, NetworkError> {
-        Self::create(name, IFF_TUN)
+    ///
+    /// ### Arguments
+    /// * `cfg` - Tun device configuration
+    /// * `nat` - NAT configuration used to masquerade outbound traffic
+    pub fn create_tun(cfg: TunConfig, nat: NatConfig) -> Result<Self, NetworkError> {
+        let mut tun = Self::create(cfg.device, Mode::Tun, cfg.ipv4, nat)?;
+        tun.link.mtu = cfg.mtu;
+
+        let mut netlink = Netlink::connect()?;
+        if let Some(address) = cfg.address {
+            tracing::debug!(device = %tun.name, %address, "[tun] assigning address");
+            netlink.add_address(tun.idx, address)?;
+        }
+
+        netlink.set_link_up(tun.idx)?;
+
+        for route in cfg.routes {
+            tracing::debug!(device = %tun.name, %route, "[tun] adding route");
+            netlink.add_route(tun.idx, route)?;
+        }
+
+        Ok(tun)
     }
 
-    fn create(name: String, flags: i32) -> Result<Self, NetworkError> {
+    /// Returns an error if a device name is too long to be given to the kernel
+    ///
+    /// ### Arguments
+    /// * `name` - Name of the tap/tun device
+    pub fn validate_name(name: &str) -> Result<(), NetworkError> {
+        let len = name.len();
+        if len > IFNAMSIZ {
+            return Err(NetworkError::Generic(Cow::Owned(format!(
+                "device name ({name}) is too long, max length is {IFNAMSIZ}, provided length {len}",
+            ))));
+        }
+
+        Ok(())
+    }
+
+    fn create(
+        name: String,
+        mode: Mode,
+        ipv4: Ipv4Network,
+        nat: NatConfig,
+    ) -> Result<Self, NetworkError> {
         // #define TUNSETIFF _IOW('T', 202, int)
         nix::ioctl_write_int!(tunsetiff, b'T', 202);
 
@@ -91,22 +218,23 @@ impl TunTap {
         // #define SIOCGIFHWADDR 0x8927
         nix::ioctl_read_bad!(siocgifhwaddr, SIOCGIFHWADDR, nix::libc::ifreq);
 
-        let len = name.len();
-        if len > IFNAMSIZ {
-            return Err(NetworkError::Generic(Cow::Owned(format!(
-                "device name ({name}) is too long, max length is {IFNAMSIZ}, provided length {len}",
-            ))))?;
-        }
+        Self::validate_name(&name)?;
 
+        let len = name.len();
         let mut ifreq = IfReqCreateTun::default();
         let len = std::cmp::min(IFNAMSIZ, len);
         ifreq.ifrn_name[0..len].copy_from_slice(&name.as_bytes()[0..len]);
+        let flags = match mode {
+            Mode::Tap => IFF_TAP,
+            Mode::Tun => IFF_TUN,
+        };
         ifreq.ifru_flags = (flags | IFF_NO_PI) as u16;
 
         // Create TAP via ioctls
         let fd = File::options()
             .read(true)
             .write(true)
+            .custom_flags(O_NONBLOCK)
             .open("/dev/net/tun")?;
 
         unsafe {
@@ -119,11 +247,6 @@ impl TunTap {
         let poll = Poll::new()?;
         let (tx, rx) = flume::unbounded();
 
-        let mac = match flags {
-            IFF_TAP => MacAddress::from_interface(&name)?,
-            _ => MacAddress::generate(),
-        };
-
         Ok(Self {
             name,
             fd,
@@ -131,25 +254,313 @@ impl TunTap {
             poll,
             tx,
             rx: Some(rx),
-            mac,
+            link: Link::new(mode, ipv4, nat),
         })
     }
 
-    fn read_from_device(&mut self) -> io::Result<()> {
-        let mut buf = [0u8; 1024];
-        let sz = self.fd.read(&mut buf)?;
-        tracing::trace!("[tap] read {sz} bytes");
+    /// Reads all available frames from the device and routes them to the LAN
+    ///
+    /// ### Arguments
+    /// * `buf` - Buffer to read frames into
+    /// * `router` - Handle to the router
+    fn read_from_device(&mut self, buf: &mut [u8], router: &RouterHandle) -> io::Result<()> {
+        loop {
+            let sz = match self.fd.read(buf) {
+                Ok(0) => break,
+                Ok(sz) => sz,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            };
+
+            tracing::trace!("[tap] read {sz} bytes");
+            let pkt = buf[..sz].to_vec();
+            let res = match self.link.mode {
+                Mode::Tap => self.link.handle_frame(pkt, router),
+                Mode::Tun => self.link.handle_ipv4(pkt, router),
+            };
+
+            if let Err(error) = res {
+                tracing::debug!(?error, "[tap] unable to handle packet");
+            }
+        }
+
+        // arp replies
+        self.flush();
+
         Ok(())
     }
 
-    fn write_to_device(&mut self, pkt: Ipv4Packet) -> io::Result<()> {
-        let iovs = [IoSlice::new(pkt.as_bytes())];
-        let sz = self.fd.write_vectored(&iovs)?;
+    /// Writes all frames (or packets) queued by the link to the device
+    fn flush(&mut self) {
+        while let Some(pkt) = self.link.out.pop_front() {
+            match self.fd.write(&pkt) {
+                Ok(sz) => tracing::trace!("[tap] wrote {sz} bytes"),
+                Err(error) => tracing::warn!(?error, "[tap] unable to write to device"),
+            }
+        }
+    }
+}
+
+impl Link {
+    /// Creates the state of a network with no known neighbors
+    ///
+    /// ### Arguments
+    /// * `mode` - Type of device (tap or tun)
+    /// * `ipv4` - Address (and subnet) used by the bridge on the network
+    /// * `nat` - NAT configuration used to masquerade outbound traffic
+    fn new(mode: Mode, ipv4: Ipv4Network, nat: NatConfig) -> Self {
+        // the host owns the mac address of the tap interface, so use our own
+        let mac = MacAddress::generate();
+
+        Self {
+            mode,
+            mac,
+            ipv4,
+            gateway: None,
+            mtu: DEFAULT_MTU,
+            arp: HashMap::new(),
+            pending: HashMap::new(),
+            nat: Arc::new(Mutex::new(NatTable::new(nat))),
+            out: VecDeque::new(),
+        }
+    }
+
+    /// Handles an ethernet frame received from the device
+    ///
+    /// ### Arguments
+    /// * `pkt` - Ethernet frame, including header
+    /// * `router` - Handle to the router
+    fn handle_frame(
+        &mut self,
+        mut pkt: Vec<u8>,
+        router: &RouterHandle,
+    ) -> Result<(), NetworkError> {
+        let frame = EthernetFrame::extract(&mut pkt)?;
+        if frame.dst != self.mac && !frame.dst.is_broadcast() {
+            // not for us
+            return Ok(());
+        }
+
+        if frame.vlan.is_some() {
+            tracing::trace!("[tap] vlan tagged frame, dropping packet");
+            return Ok(());
+        }
+
+        match frame.ethertype {
+            EtherType::ARP => self.handle_arp(&pkt)?,
+            EtherType::IPv4 => self.handle_ipv4(pkt, router)?,
+            EtherType::IPv6 => tracing::trace!("[tap] ipv6 not supported, dropping packet"),
+            EtherType::Vlan => tracing::trace!("[tap] stacked vlan tags, dropping packet"),
+        }
+
+        Ok(())
+    }
+
+    /// Handles an ipv4 packet received from the device, undoing the NAT translation and
+    /// routing it to the LAN
+    ///
+    /// ### Arguments
+    /// * `pkt` - IPv4 packet, including header
+    /// * `router` - Handle to the router
+    fn handle_ipv4(&mut self, mut pkt: Vec<u8>, router: &RouterHandle) -> Result<(), NetworkError> {
+        if pkt.first().map(|b| b >> 4) != Some(4) {
+            tracing::trace!("[tap] not an ipv4 packet, dropping packet");
+            return Ok(());
+        }
+
+        // strip any ethernet padding
+        let hdr = Ipv4Header::extract_from_slice(&pkt)?;
+        pkt.truncate(usize::from(hdr.length));
+
+        let mut pkt = Ipv4Packet::parse(pkt)?;
+        if pkt.dest() != self.ipv4.ip() {
+            return Ok(());
+        }
+
+        let translated = self.nat.lock().translate_inbound(&mut pkt)?;
+        match translated {
+            true => router.route_ipv4(pkt),
+            false => tracing::trace!(
+                src = %pkt.src(),
+                protocol = pkt.protocol(),
+                "[tap] no nat entry found, dropping packet"
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Handles an ARP packet received from the device, learning the sender's mac address and
+    /// replying to requests for the bridge's address
+    ///
+    /// ### Arguments
+    /// * `pkt` - ARP packet
+    fn handle_arp(&mut self, pkt: &[u8]) -> Result<(), NetworkError> {
+        let mut arp = ArpPacket::parse(pkt)?;
+        let (spa, tpa) = match (arp.spa, arp.tpa) {
+            (IpAddr::V4(spa), IpAddr::V4(tpa)) => (spa, tpa),
+            _ => return Ok(()),
+        };
+
+        if self.ipv4.contains(spa) && !spa.is_unspecified() {
+            tracing::trace!("[tap] associating mac to ip: {spa} -> {}", arp.sha);
+            self.arp.insert(spa, arp.sha);
+            self.flush_pending(spa, arp.sha);
+        }
+
+        if arp.operation == 1 && tpa == self.ipv4.ip() {
+            let mut rpkt = vec![0u8; arp.size()];
+            arp.to_reply(self.mac);
+            arp.as_bytes(&mut rpkt);
+            self.write_frame(arp.tha, EtherType::ARP, &rpkt);
+        }
+
+        Ok(())
+    }
+
+    /// Masquerades a packet received from the router and queues it for the next hop, sending
+    /// an ARP request if the next hop has not been resolved.  Packets larger than the mtu are
+    /// fragmented, or answered with an ICMP fragmentation needed message if they must not be
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet to send out the device
+    /// * `router` - Handle to the router
+    fn send(&mut self, mut pkt: Ipv4Packet, router: &RouterHandle) -> Result<(), NetworkError> {
+        if pkt.len() > self.mtu && pkt.dont_fragment() {
+            tracing::debug!(dst = %pkt.dest(), len = pkt.len(), mtu = self.mtu, "[tap] packet exceeds mtu");
+            let icmp = IcmpPacket::fragmentation_needed(self.mtu, &pkt);
+            let mut buf = vec![0u8; icmp.size()];
+            icmp.as_bytes(&mut buf);
+            router.route_ipv4(Ipv4Packet::new(
+                self.ipv4.ip(),
+                pkt.src(),
+                NET_PROTOCOL_ICMP,
+                &buf,
+            ));
+            return Ok(());
+        }
+
+        // translate before fragmenting, only the first fragment holds the ports
+        self.nat
+            .lock()
+            .translate_outbound(&mut pkt, self.ipv4.ip())?;
+
+        let next_hop = match self.gateway {
+            Some(gateway) if !self.ipv4.contains(pkt.dest()) => gateway,
+            _ => pkt.dest(),
+        };
 
-        tracing::trace!("[tap] wrote {sz} bytes");
+        for pkt in pkt.fragment(self.mtu) {
+            self.send_to(next_hop, pkt);
+        }
 
         Ok(())
     }
+
+    /// Queues a packet for a next hop, holding it until the next hop is resolved (tap only)
+    ///
+    /// ### Arguments
+    /// * `next_hop` - Address of the next hop on the tap network
+    /// * `pkt` - Packet to send
+    fn send_to(&mut self, next_hop: Ipv4Addr, pkt: Ipv4Packet) {
+        if self.mode == Mode::Tun {
+            self.out.push_back(pkt.into_bytes());
+            return;
+        }
+
+        if let Some(mac) = self.arp.get(&next_hop).copied() {
+            self.write_frame(mac, EtherType::IPv4, pkt.as_bytes());
+            return;
+        }
+
+        let pending = self.pending.entry(next_hop).or_insert_with(|| PendingArp {
+            packets: VecDeque::new(),
+            sent: Instant::now(),
+            attempts: 0,
+        });
+
+        if pending.packets.len() >= MAX_PENDING_PACKETS {
+            pending.packets.pop_front();
+        }
+        pending.packets.push_back(pkt);
+
+        if pending.attempts == 0 {
+            pending.attempts = 1;
+            self.send_arp_request(next_hop);
+        }
+    }
+
+    /// Broadcasts an ARP request for an address on the tap network
+    ///
+    /// ### Arguments
+    /// * `ip` - IPv4 address to resolve
+    fn send_arp_request(&mut self, ip: Ipv4Addr) {
+        tracing::trace!("[tap] sending arp request for {ip}");
+        let arp = ArpPacket::request(self.mac, self.ipv4.ip(), ip);
+        let mut pkt = vec![0u8; arp.size()];
+        arp.as_bytes(&mut pkt);
+        self.write_frame(MacAddress::broadcast(), EtherType::ARP, &pkt);
+    }
+
+    /// Sends all packets waiting on an ip address to be resolved
+    ///
+    /// ### Arguments
+    /// * `ip` - Resolved IPv4 address
+    /// * `mac` - MAC address of `ip`
+    fn flush_pending(&mut self, ip: Ipv4Addr, mac: MacAddress) {
+        if let Some(pending) = self.pending.remove(&ip) {
+            for pkt in pending.packets {
+                self.write_frame(mac, EtherType::IPv4, pkt.as_bytes());
+            }
+        }
+    }
+
+    /// Retries outstanding ARP requests, dropping packets for next hops that never
+    /// responded, and removes expired NAT entries
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    fn tick(&mut self, now: Instant) {
+        let mut retry = Vec::new();
+        self.pending.retain(|ip, pending| {
+            if now.duration_since(pending.sent) < TICK_INTERVAL {
+                return true;
+            }
+
+            if pending.attempts >= MAX_ARP_ATTEMPTS {
+                tracing::warn!(
+                    "[tap] unable to resolve {ip}, dropping {} packets",
+                    pending.packets.len()
+                );
+                return false;
+            }
+
+            pending.attempts += 1;
+            pending.sent = now;
+            retry.push(*ip);
+            true
+        });
+
+        for ip in retry {
+            self.send_arp_request(ip);
+        }
+
+        self.nat.lock().expire();
+    }
+
+    /// Queues an ethernet frame to be written to the device
+    ///
+    /// ### Arguments
+    /// * `dst` - Destination MAC address
+    /// * `ethertype` - Type of the payload
+    /// * `payload` - Layer 3 payload
+    fn write_frame(&mut self, dst: MacAddress, ethertype: EtherType, payload: &[u8]) {
+        let hdr = EthernetFrame::new(self.mac, dst, ethertype).to_bytes();
+        let mut frame = Vec::with_capacity(hdr.len() + payload.len());
+        frame.extend_from_slice(&hdr);
+        frame.extend_from_slice(payload);
+        self.out.push_back(frame);
+    }
 }
 
 impl Debug for TunTap {
@@ -170,44 +581,72 @@ impl Wan for TunTap {
         let handle = TunTapHandle {
             tx: self.tx.clone(),
             waker: Arc::new(waker),
+            mtu: self.link.mtu,
+            nat: Arc::clone(&self.link.nat),
         };
 
         Ok(Box::new(handle))
     }
 
-    fn run(mut self: Box<Self>, _router: RouterHandle) -> Result<(), NetworkError> {
+    fn add_forward(&mut self, fwd: PortForward, _listen: IpAddr) -> Result<(), NetworkError> {
+        self.link.nat.lock().add_forward(fwd);
+        Ok(())
+    }
+
+    fn run(mut self: Box<Self>, router: RouterHandle) -> Result<(), NetworkError> {
         let mut events = Events::with_capacity(MAX_EVENTS_CAPACITY);
 
-        let rx = self.rx.take().unwrap();
-        //.ok_or_else(|| Error::General(String::from("no receiver available, already used")))?;
+        let rx = self
+            .rx
+            .take()
+            .ok_or_else(|| NetworkError::Generic("no receiver available, already used".into()))?;
+
+        // large enough for an mtu-sized packet and the ethernet header (tap only)
+        let mut buf = match self.link.mode {
+            Mode::Tap => vec![0u8; usize::from(self.link.mtu) + EthernetFrame::size()],
+            Mode::Tun => vec![0u8; usize::from(self.link.mtu)],
+        };
+        let mut last_tick = Instant::now();
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(&mut events, Some(TICK_INTERVAL))?;
 
             for event in &events {
                 match event.token() {
-                    TOKEN_READ => match self.read_from_device() {
+                    TOKEN_READ => match self.read_from_device(&mut buf, &router) {
                         Ok(_) => (),
                         Err(error) => {
-                            tracing::warn!(?error, "[upstream] unable to read from tun device")
+                            tracing::warn!(?error, "[upstream] unable to read from tap device")
                         }
                     },
                     TOKEN_WRITE => {
                         for pkt in rx.drain() {
-                            match self.write_to_device(pkt) {
+                            match self.link.send(pkt, &router) {
                                 Ok(()) => {
-                                    tracing::trace!("[upstream] wrote ipv4 packet to tun device")
+                                    tracing::trace!("[upstream] sent ipv4 packet to tap device")
                                 }
                                 Err(error) => tracing::error!(
                                     ?error,
-                                    "[upstream] unable to write to tun device"
+                                    "[upstream] unable to write to tap device"
                                 ),
                             }
                         }
+                        self.flush();
                     }
                     Token(token) => tracing::trace!(token, "[tap] unknown mio token"),
                 }
             }
+
+            if router.is_stopped() {
+                tracing::debug!("[tap] router stopped, closing device");
+                return Ok(());
+            }
+
+            if last_tick.elapsed() >= TICK_INTERVAL {
+                self.link.tick(Instant::now());
+                self.flush();
+                last_tick = Instant::now();
+            }
         }
     }
 }
@@ -218,14 +657,329 @@ impl WanHandle for TunTapHandle {
         self.waker.wake().ok();
         Ok(())
     }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    fn nat(&self) -> Option<SharedNat> {
+        Some(Arc::clone(&self.nat))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::TunTap;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Instant,
+    };
+
+    use oathgate_net::{
+        nat::NatConfig,
+        protocols::{ArpPacket, NET_PROTOCOL_ICMP, NET_PROTOCOL_UDP, UDP_HDR_SZ},
+        types::{EtherType, MacAddress},
+        EthernetFrame, Ipv4Packet,
+    };
+
+    use crate::{
+        config::{TapConfig, TunConfig},
+        net::router::{RouterHandle, RouterMsg},
+    };
+
+    use super::{Link, Mode, TunTap, MAX_ARP_ATTEMPTS, MAX_PENDING_PACKETS, TICK_INTERVAL};
+
+    const DEVICE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 100);
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(192, 168, 254, 1);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+
+    fn link() -> Link {
+        let mut link = Link::new(
+            Mode::Tap,
+            "192.168.254.2/24".parse().unwrap(),
+            NatConfig::default(),
+        );
+        link.gateway = Some(GATEWAY);
+        link
+    }
+
+    /// Builds a UDP datagram whose payload is `len` bytes of `marker`
+    fn datagram(
+        src: Ipv4Addr,
+        sport: u16,
+        dst: Ipv4Addr,
+        dport: u16,
+        marker: u8,
+        len: usize,
+    ) -> Ipv4Packet {
+        let mut payload = vec![marker; UDP_HDR_SZ + len];
+        payload[0..2].copy_from_slice(&sport.to_be_bytes());
+        payload[2..4].copy_from_slice(&dport.to_be_bytes());
+        payload[4..6].copy_from_slice(&((UDP_HDR_SZ + len) as u16).to_be_bytes());
+        payload[6..8].copy_from_slice(&[0x00, 0x00]);
+        Ipv4Packet::new(src, dst, NET_PROTOCOL_UDP, &payload)
+    }
+
+    /// Clears the don't fragment flag of a packet
+    fn fragmentable(pkt: Ipv4Packet) -> Ipv4Packet {
+        let mut data = pkt.into_bytes();
+        data[6] &= !0x40;
+        Ipv4Packet::parse(data).unwrap()
+    }
+
+    /// Builds an ethernet frame sent to the bridge by a host on the tap network
+    fn frame(link: &Link, src: MacAddress, ethertype: EtherType, payload: &[u8]) -> Vec<u8> {
+        let hdr = EthernetFrame::new(src, link.mac, ethertype).to_bytes();
+        [hdr.as_slice(), payload].concat()
+    }
+
+    /// Builds an ARP reply sent to the bridge
+    fn arp_reply(link: &Link, mac: MacAddress, ip: Ipv4Addr) -> Vec<u8> {
+        let mut arp = ArpPacket::request(mac, ip, link.ipv4.ip());
+        arp.operation = 2;
+        arp.tha = link.mac;
+
+        let mut pkt = vec![0u8; arp.size()];
+        arp.as_bytes(&mut pkt);
+        frame(link, mac, EtherType::ARP, &pkt)
+    }
+
+    /// Drains the frames queued to be written to the device
+    fn written(link: &mut Link) -> Vec<(EthernetFrame, Vec<u8>)> {
+        link.out
+            .drain(..)
+            .map(|mut pkt| (EthernetFrame::extract(&mut pkt).unwrap(), pkt))
+            .collect()
+    }
+
+    /// Asserts a frame is a broadcast ARP request for `ip`
+    fn assert_arp_request(frame: &(EthernetFrame, Vec<u8>), ip: Ipv4Addr) {
+        let (frame, pkt) = frame;
+        assert!(frame.dst.is_broadcast());
+        assert_eq!(frame.ethertype, EtherType::ARP);
+
+        let arp = ArpPacket::parse(pkt).unwrap();
+        assert_eq!(arp.operation, 1);
+        assert_eq!(arp.tpa, IpAddr::V4(ip));
+    }
 
     #[test]
+    #[ignore = "requires CAP_NET_ADMIN"]
     fn open_tap() {
-        TunTap::create_tap("oathgate1".into()).expect("unable to open tap");
+        let cfg = TapConfig {
+            device: "oathgate1".into(),
+            ipv4: "192.168.254.2/24".parse().unwrap(),
+            gateway: None,
+            mtu: 1500,
+        };
+
+        TunTap::create_tap(cfg, NatConfig::default()).expect("unable to open tap");
+    }
+
+    #[test]
+    #[ignore = "requires CAP_NET_ADMIN"]
+    fn open_tun() {
+        let cfg = TunConfig {
+            device: "oathgate-tun1".into(),
+            ipv4: "192.168.253.2/30".parse().unwrap(),
+            address: Some("192.168.253.1/30".parse().unwrap()),
+            routes: Vec::new(),
+            mtu: 1500,
+        };
+
+        TunTap::create_tun(cfg, NatConfig::default()).expect("unable to open tun");
+    }
+
+    #[test]
+    fn tap_resolves_gateway() {
+        let (router, _rx) = RouterHandle::detached();
+        let mut link = link();
+
+        // off-link destinations are sent to the gateway, which must be resolved first
+        let pkt = datagram(DEVICE, 40000, REMOTE, 53, 0, 16);
+        link.send(pkt, &router).unwrap();
+        let frames = written(&mut link);
+        assert_eq!(frames.len(), 1);
+        assert_arp_request(&frames[0], GATEWAY);
+
+        let mac = MacAddress::generate();
+        link.handle_frame(arp_reply(&link, mac, GATEWAY), &router)
+            .unwrap();
+        assert!(link.pending.is_empty());
+
+        let frames = written(&mut link);
+        assert_eq!(frames.len(), 1);
+        let (frame, pkt) = &frames[0];
+        assert_eq!((frame.dst, frame.ethertype), (mac, EtherType::IPv4));
+        let pkt = Ipv4Packet::parse(pkt.clone()).unwrap();
+        assert_eq!((pkt.src(), pkt.dest()), (link.ipv4.ip(), REMOTE));
+
+        // resolved next hops are used directly
+        let pkt = datagram(DEVICE, 40000, REMOTE, 53, 0, 16);
+        link.send(pkt, &router).unwrap();
+        let frames = written(&mut link);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.dst, mac);
+
+        // on-link destinations are resolved themselves
+        let host = Ipv4Addr::new(192, 168, 254, 50);
+        let pkt = datagram(DEVICE, 40000, host, 53, 0, 16);
+        link.send(pkt, &router).unwrap();
+        let frames = written(&mut link);
+        assert_eq!(frames.len(), 1);
+        assert_arp_request(&frames[0], host);
+    }
+
+    #[test]
+    fn tap_pending_queue_is_bounded() {
+        let (router, _rx) = RouterHandle::detached();
+        let mut link = link();
+
+        let extra = 4;
+        for marker in 0..(MAX_PENDING_PACKETS + extra) {
+            let pkt = datagram(DEVICE, 40000, REMOTE, 53, marker as u8, 16);
+            link.send(pkt, &router).unwrap();
+        }
+
+        // a single request while the next hop is unresolved
+        let frames = written(&mut link);
+        assert_eq!(frames.len(), 1);
+        assert_arp_request(&frames[0], GATEWAY);
+
+        // the oldest packets were dropped
+        let mac = MacAddress::generate();
+        link.handle_frame(arp_reply(&link, mac, GATEWAY), &router)
+            .unwrap();
+        let frames = written(&mut link);
+        assert_eq!(frames.len(), MAX_PENDING_PACKETS);
+        for (idx, (_, pkt)) in frames.iter().enumerate() {
+            let pkt = Ipv4Packet::parse(pkt.clone()).unwrap();
+            assert_eq!(pkt.payload()[UDP_HDR_SZ], (idx + extra) as u8);
+        }
+    }
+
+    #[test]
+    fn tap_gives_up_after_max_arp_attempts() {
+        let (router, _rx) = RouterHandle::detached();
+        let mut link = link();
+
+        let pkt = datagram(DEVICE, 40000, REMOTE, 53, 0, 16);
+        link.send(pkt, &router).unwrap();
+        let start = Instant::now();
+        assert_eq!(written(&mut link).len(), 1);
+
+        // no retry until the request times out
+        link.tick(start);
+        assert!(written(&mut link).is_empty());
+
+        for attempt in 1..MAX_ARP_ATTEMPTS {
+            link.tick(start + TICK_INTERVAL * u32::from(attempt));
+            let frames = written(&mut link);
+            assert_eq!(frames.len(), 1);
+            assert_arp_request(&frames[0], GATEWAY);
+        }
+
+        link.tick(start + TICK_INTERVAL * u32::from(MAX_ARP_ATTEMPTS));
+        assert!(written(&mut link).is_empty());
+        assert!(link.pending.is_empty());
+    }
+
+    #[test]
+    fn tap_replies_to_arp_requests() {
+        let (router, _rx) = RouterHandle::detached();
+        let mut link = link();
+
+        let (mac, ip) = (MacAddress::generate(), Ipv4Addr::new(192, 168, 254, 50));
+        let arp = ArpPacket::request(mac, ip, link.ipv4.ip());
+        let mut pkt = vec![0u8; arp.size()];
+        arp.as_bytes(&mut pkt);
+        let mut pkt = frame(&link, mac, EtherType::ARP, &pkt);
+        pkt[0..6].copy_from_slice(MacAddress::broadcast().as_bytes());
+        link.handle_frame(pkt, &router).unwrap();
+
+        let frames = written(&mut link);
+        assert_eq!(frames.len(), 1);
+        let (frame, pkt) = &frames[0];
+        assert_eq!(frame.dst, mac);
+
+        let arp = ArpPacket::parse(pkt).unwrap();
+        assert_eq!(arp.operation, 2);
+        assert_eq!((arp.sha, arp.spa), (link.mac, IpAddr::V4(link.ipv4.ip())));
+        assert_eq!(link.arp.get(&ip), Some(&mac));
+    }
+
+    #[test]
+    fn tap_translates_inbound_replies() {
+        let (router, rx) = RouterHandle::detached();
+        let mut link = link();
+
+        let mac = MacAddress::generate();
+        link.handle_frame(arp_reply(&link, mac, GATEWAY), &router)
+            .unwrap();
+
+        let pkt = datagram(DEVICE, 40000, REMOTE, 53, 0, 16);
+        link.send(pkt, &router).unwrap();
+        let frames = written(&mut link);
+        let pkt = Ipv4Packet::parse(frames[0].1.clone()).unwrap();
+        let port = u16::from_be_bytes([pkt.payload()[0], pkt.payload()[1]]);
+
+        // replies to the external address and port are routed back to the device
+        let reply = datagram(REMOTE, 53, link.ipv4.ip(), port, 0, 16);
+        let pkt = frame(&link, mac, EtherType::IPv4, reply.as_bytes());
+        link.handle_frame(pkt, &router).unwrap();
+
+        let Ok(RouterMsg::FromWan4(pkt)) = rx.try_recv() else {
+            panic!("reply not routed");
+        };
+        assert_eq!((pkt.src(), pkt.dest()), (REMOTE, DEVICE));
+        assert_eq!(
+            u16::from_be_bytes([pkt.payload()[2], pkt.payload()[3]]),
+            40000
+        );
+
+        // unsolicited traffic is dropped
+        let other = datagram(REMOTE, 53, link.ipv4.ip(), port.wrapping_add(1), 0, 16);
+        let pkt = frame(&link, mac, EtherType::IPv4, other.as_bytes());
+        link.handle_frame(pkt, &router).unwrap();
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    fn tap_fragments_packets_exceeding_mtu() {
+        let (router, rx) = RouterHandle::detached();
+        let mut link = link();
+        link.mtu = 576;
+
+        let mac = MacAddress::generate();
+        link.handle_frame(arp_reply(&link, mac, GATEWAY), &router)
+            .unwrap();
+
+        let pkt = fragmentable(datagram(DEVICE, 40000, REMOTE, 53, 0xAA, 1000));
+        link.send(pkt, &router).unwrap();
+
+        let frames = written(&mut link);
+        assert_eq!(frames.len(), 2);
+        let mut payload = Vec::new();
+        for (frame, pkt) in frames {
+            assert_eq!(frame.dst, mac);
+            let pkt = Ipv4Packet::parse(pkt).unwrap();
+            assert!(pkt.len() <= link.mtu);
+            assert_eq!(usize::from(pkt.fragment_offset()), payload.len());
+            payload.extend_from_slice(pkt.payload());
+        }
+        assert_eq!(payload.len(), UDP_HDR_SZ + 1000);
+        assert!(payload[UDP_HDR_SZ..].iter().all(|b| *b == 0xAA));
+
+        // packets that must not be fragmented are refused with an icmp error
+        let pkt = datagram(DEVICE, 40000, REMOTE, 53, 0xAA, 1000);
+        link.send(pkt, &router).unwrap();
+        assert!(written(&mut link).is_empty());
+
+        let Ok(RouterMsg::FromWan4(pkt)) = rx.try_recv() else {
+            panic!("icmp error not routed");
+        };
+        assert_eq!((pkt.dest(), pkt.protocol()), (DEVICE, NET_PROTOCOL_ICMP));
+        let icmp = pkt.payload();
+        assert_eq!((icmp[0], icmp[1]), (3, 4));
+        assert_eq!(u16::from_be_bytes([icmp[6], icmp[7]]), 576);
     }
 }
@@ -1,33 +1,50 @@
 //! UDP upstream.  Forwards traffic to a specific UDP port
+//!
+//! Packets are routed (not masqueraded), so the UDP WAN has no address of its own.  Port
+//! forwards are accepted on host sockets by a userspace forwarder instead.
 
 use std::{
-    io::{self, IoSlice},
-    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    io::{self, ErrorKind, IoSlice},
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
     os::fd::{AsRawFd, RawFd},
+    time::Duration,
 };
 
 use nix::sys::socket::{sendmsg, MsgFlags, SockaddrIn, SockaddrIn6};
-use oathgate_net::Ipv4Packet;
+use oathgate_net::{nat::PortForward, Ipv4Packet, Ipv6Packet};
 
 use crate::net::{router::RouterHandle, NetworkError};
 
-use super::{Wan, WanHandle};
+use super::{UserNet, Wan, WanHandle};
+
+/// Longest time the WAN waits for a packet before checking if the router stopped
+const STOP_INTERVAL: Duration = Duration::from_millis(500);
 
 pub struct UdpDevice {
     sock: UdpSocket,
     dests: Vec<SocketAddr>,
+
+    /// Accepts port forwards on host sockets, created when the first forward is added
+    forwarder: Option<Box<UserNet>>,
 }
 
 pub struct UdpDeviceHandle {
     sock: RawFd,
     dests: Vec<SocketAddr>,
+
+    /// Handle to the forwarder, if any port is forwarded
+    forwarder: Option<Box<dyn WanHandle>>,
 }
 
 impl UdpDevice {
     pub fn connect<A: ToSocketAddrs>(addrs: A) -> io::Result<Self> {
         let sock = UdpSocket::bind("0.0.0.0:0")?;
         let dests = addrs.to_socket_addrs()?.collect::<Vec<_>>();
-        Ok(Self { sock, dests })
+        Ok(Self {
+            sock,
+            dests,
+            forwarder: None,
+        })
     }
 }
 
@@ -36,18 +53,62 @@ where
     Self: Sized,
 {
     fn as_wan_handle(&self) -> Result<Box<dyn WanHandle>, NetworkError> {
+        let forwarder = match self.forwarder {
+            Some(ref forwarder) => Some(forwarder.as_wan_handle()?),
+            None => None,
+        };
+
         let handle = UdpDeviceHandle {
             sock: self.sock.as_raw_fd(),
             dests: self.dests.clone(),
+            forwarder,
         };
 
         Ok(Box::new(handle))
     }
 
-    fn run(self: Box<Self>, router: RouterHandle) -> Result<(), NetworkError> {
+    fn add_forward(&mut self, fwd: PortForward, listen: IpAddr) -> Result<(), NetworkError> {
+        let forwarder = match self.forwarder {
+            Some(ref mut forwarder) => forwarder,
+            None => self.forwarder.insert(Box::new(UserNet::forwarder()?)),
+        };
+
+        forwarder.add_forward(fwd, listen)
+    }
+
+    fn run(mut self: Box<Self>, router: RouterHandle) -> Result<(), NetworkError> {
+        let forwarder = match self.forwarder.take() {
+            Some(forwarder) => {
+                let router = router.clone();
+                let thread = std::thread::Builder::new()
+                    .name(String::from("wan-forwarder"))
+                    .spawn(move || match forwarder.run(router) {
+                        Ok(_) => tracing::trace!("forwarder thread exited successfully"),
+                        Err(error) => tracing::warn!(?error, "unable to run forwarder thread"),
+                    })?;
+                Some(thread)
+            }
+            None => None,
+        };
+
+        // wake up periodically to notice the router stopping
+        self.sock.set_read_timeout(Some(STOP_INTERVAL))?;
+
         let mut buf = [0u8; 1600];
         loop {
-            let (sz, peer) = self.sock.recv_from(&mut buf)?;
+            let (sz, peer) = match self.sock.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(error)
+                    if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    if router.is_stopped() {
+                        break;
+                    }
+                    continue;
+                }
+                Err(error) => return Err(error.into()),
+            };
+
             tracing::trace!(?peer, "read {sz} bytes from peer: {:02x?}", &buf[..20],);
             let pkt = buf[0..sz].to_vec();
             match pkt[0] >> 4 {
@@ -55,16 +116,29 @@ where
                     let pkt = Ipv4Packet::parse(pkt)?;
                     router.route_ipv4(pkt)
                 }
-                6 => router.route_ipv6(pkt),
+                6 => {
+                    let pkt = Ipv6Packet::parse(pkt)?;
+                    router.route_ipv6(pkt)
+                }
                 version => tracing::warn!(version, "unknown ip version / malformed packet"),
             }
         }
+
+        if let Some(thread) = forwarder {
+            thread.join().ok();
+        }
+
+        Ok(())
     }
 }
 
-impl WanHandle for UdpDeviceHandle {
-    fn write(&self, pkt: Ipv4Packet) -> Result<(), NetworkError> {
-        let iov = [IoSlice::new(&pkt.as_bytes())];
+impl UdpDeviceHandle {
+    /// Sends a (raw) ip packet to all destinations
+    ///
+    /// ### Arguments
+    /// * `pkt` - IPv4 or IPv6 packet, including the header
+    fn send(&self, pkt: &[u8]) -> Result<(), NetworkError> {
+        let iov = [IoSlice::new(pkt)];
 
         for dest in &self.dests {
             match dest {
@@ -81,3 +155,23 @@ impl WanHandle for UdpDeviceHandle {
         Ok(())
     }
 }
+
+impl WanHandle for UdpDeviceHandle {
+    fn write(&self, pkt: Ipv4Packet) -> Result<(), NetworkError> {
+        match self.forwarder {
+            Some(ref forwarder) if forwarder.is_forwarded(&pkt) => forwarder.write(pkt),
+            _ => self.send(pkt.as_bytes()),
+        }
+    }
+
+    fn write_ipv6(&self, pkt: Ipv6Packet) -> Result<(), NetworkError> {
+        self.send(pkt.as_bytes())
+    }
+
+    fn is_forwarded(&self, pkt: &Ipv4Packet) -> bool {
+        self.forwarder
+            .as_ref()
+            .map(|forwarder| forwarder.is_forwarded(pkt))
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,1617 @@
+//! Userspace (slirp-style) upstream.  Terminates TCP and UDP flows from the LAN and
+//! re-originates them using ordinary host sockets.  Requires no privileges or kernel devices.
+//!
+//! Port forwards listen on host sockets; accepted connections (and received datagrams) are
+//! opened towards the LAN device as if they originated from the remote peer.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, Sender};
+use mio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    Events, Interest, Poll, Token, Waker,
+};
+use oathgate_net::{
+    nat::{FlowKey, PortForward},
+    protocols::{
+        tcp::{TCP_FLAG_ACK, TCP_FLAG_FIN, TCP_FLAG_PSH, TCP_FLAG_RST, TCP_FLAG_SYN},
+        TcpHeader, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP, UDP_HDR_SZ,
+    },
+    Ipv4Packet,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::net::{router::RouterHandle, NetworkError};
+
+use super::{Wan, WanHandle};
+
+/// Maximum number of events mio can processes at one time
+const MAX_EVENTS_CAPACITY: usize = 64;
+
+/// Token used to wake the poller when packets are available from the router
+const TOKEN_WAKER: Token = Token(0);
+
+/// Interval used to retransmit segments and expire idle flows
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Time to wait for an acknowledgement before retransmitting
+const TCP_RTO: Duration = Duration::from_secs(1);
+
+/// Number of retransmissions before a connection is reset
+const TCP_MAX_RETRIES: u8 = 8;
+
+/// Maximum segment size advertised to (and used with) LAN devices
+const TCP_MSS: u16 = 1460;
+
+/// Default maximum segment size if the LAN device does not provide one
+const TCP_DEFAULT_MSS: u16 = 536;
+
+/// Size of the buffer holding data received from a LAN device before it is written to the
+/// host socket.  Also used as the advertised receive window.
+const TCP_RECV_BUF_SZ: usize = 65535;
+
+/// Maximum size of a UDP datagram
+const UDP_BUF_SZ: usize = 65535;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UserConfig {
+    /// Maximum number of concurrent TCP connections and UDP flows
+    pub max_flows: usize,
+
+    /// Idle timeout, in seconds, of a TCP connection
+    pub tcp_timeout: u64,
+
+    /// Idle timeout, in seconds, of a UDP flow
+    pub udp_timeout: u64,
+
+    /// Address LAN devices see as the source of forwarded traffic from peers without a
+    /// (non-loopback) ipv4 address, such as the host itself
+    pub host_address: Ipv4Addr,
+}
+
+pub struct UserNet {
+    /// Poller to watch for events
+    poll: Poll,
+
+    /// Settings for flow limits and timeouts
+    cfg: UserConfig,
+
+    /// Channel used to send packets to this device
+    tx: Sender<Ipv4Packet>,
+
+    /// Channel used to receive packets to send out this device
+    rx: Option<Receiver<Ipv4Packet>>,
+
+    /// Active TCP connections, keyed by the flow as seen from the LAN
+    tcp: HashMap<FlowKey, TcpConn>,
+
+    /// Active UDP flows, keyed by the flow as seen from the LAN
+    udp: HashMap<FlowKey, UdpFlow>,
+
+    /// Maps mio tokens to the flow that owns the socket
+    tokens: HashMap<Token, FlowKey>,
+
+    /// Host sockets accepting traffic for port forwards
+    listeners: HashMap<Token, Listener>,
+
+    /// Maps the listener and remote address of a forwarded UDP flow to the flow
+    forwarded: HashMap<(Token, SocketAddr), FlowKey>,
+
+    /// Flows (as seen from the LAN) opened through a port forward, shared with handles so
+    /// the router can send replies back through this device
+    open_forwards: Arc<Mutex<HashSet<FlowKey>>>,
+
+    /// Set if flows opened by LAN devices are re-originated from host sockets.  If not set,
+    /// only forwarded flows are handled
+    outbound: bool,
+
+    /// Next token to assign to a socket
+    next_token: usize,
+}
+
+pub struct UserNetHandle {
+    tx: Sender<Ipv4Packet>,
+    waker: Arc<Waker>,
+    open_forwards: Arc<Mutex<HashSet<FlowKey>>>,
+}
+
+/// A host socket accepting traffic for a port forward
+struct Listener {
+    sock: ListenerSocket,
+    fwd: PortForward,
+}
+
+enum ListenerSocket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+/// State of a TCP connection as seen by the LAN device
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TcpState {
+    /// Waiting for the host socket to connect
+    Connecting,
+
+    /// SYN sent to the LAN device for a forwarded connection, waiting for a SYN-ACK
+    SynSent,
+
+    /// SYN-ACK sent to the LAN device, waiting for an ACK
+    SynReceived,
+
+    /// Data may flow in both directions
+    Established,
+}
+
+/// A TCP connection terminated in userspace
+struct TcpConn {
+    /// Host socket connected to the remote
+    sock: TcpStream,
+
+    /// Token of the host socket
+    token: Token,
+
+    /// State of the LAN-facing connection
+    state: TcpState,
+
+    /// Initial sequence number used by this side
+    iss: u32,
+
+    /// Oldest sequence number not acknowledged by the LAN device
+    snd_una: u32,
+
+    /// Next sequence number to send to the LAN device
+    snd_nxt: u32,
+
+    /// Next sequence number expected from the LAN device
+    rcv_nxt: u32,
+
+    /// Receive window advertised by the LAN device
+    peer_window: u32,
+
+    /// Maximum segment size of the LAN device
+    peer_mss: u16,
+
+    /// Data received from the LAN device, waiting to be written to the host socket
+    to_host: VecDeque<u8>,
+
+    /// Data sent to the LAN device that has not been acknowledged
+    unacked: VecDeque<u8>,
+
+    /// Set when the LAN device has closed its side of the connection
+    peer_fin: bool,
+
+    /// Set when the host socket has been shutdown for writing
+    host_shutdown: bool,
+
+    /// Set when a FIN has been sent to the LAN device
+    fin_sent: bool,
+
+    /// Set when the last advertised window was too small for a full segment
+    window_closed: bool,
+
+    /// Time of the last retransmission or acknowledgement progress
+    rto_start: Instant,
+
+    /// Number of retransmissions without progress
+    retries: u8,
+
+    /// Last time a segment was received from either side
+    last_seen: Instant,
+}
+
+/// A UDP flow re-originated from (or forwarded by) a host socket
+struct UdpFlow {
+    /// Host side of the flow
+    host: UdpHost,
+
+    /// Last time a datagram was sent or received
+    last_seen: Instant,
+}
+
+/// Host side of a UDP flow
+enum UdpHost {
+    /// Socket connected to the remote, for flows opened by a LAN device
+    Connected { sock: UdpSocket, token: Token },
+
+    /// Listener and remote address of a forwarded flow
+    Forwarded { listener: Token, peer: SocketAddr },
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        Self {
+            max_flows: 1024,
+            tcp_timeout: 7200,
+            udp_timeout: 60,
+            host_address: Ipv4Addr::new(10, 0, 2, 2),
+        }
+    }
+}
+
+impl UserNet {
+    /// Creates a new userspace network device
+    ///
+    /// ### Arguments
+    /// * `cfg` - Flow limits and timeouts
+    pub fn new(cfg: UserConfig) -> Result<Self, NetworkError> {
+        let poll = Poll::new()?;
+        let (tx, rx) = flume::unbounded();
+
+        Ok(Self {
+            poll,
+            cfg,
+            tx,
+            rx: Some(rx),
+            tcp: HashMap::new(),
+            udp: HashMap::new(),
+            tokens: HashMap::new(),
+            listeners: HashMap::new(),
+            forwarded: HashMap::new(),
+            open_forwards: Arc::new(Mutex::new(HashSet::new())),
+            outbound: true,
+            next_token: 1,
+        })
+    }
+
+    /// Creates a userspace network device that only handles forwarded traffic, for WANs that
+    /// accept port forwards on host sockets but route all other traffic themselves
+    pub fn forwarder() -> Result<Self, NetworkError> {
+        let mut net = Self::new(UserConfig::default())?;
+        net.outbound = false;
+        Ok(net)
+    }
+
+    /// Returns the number of active flows (TCP and UDP)
+    fn flows(&self) -> usize {
+        self.tcp.len() + self.udp.len()
+    }
+
+    /// Returns the next unused mio token
+    fn next_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token = self.next_token.checked_add(1).unwrap_or(1);
+        token
+    }
+
+    /// Handles a packet received from the router
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet sent by a LAN device
+    /// * `router` - Handle to the router
+    fn handle_packet(
+        &mut self,
+        pkt: Ipv4Packet,
+        router: &RouterHandle,
+    ) -> Result<(), NetworkError> {
+        if pkt.has_fragments() || pkt.fragment_offset() != 0 {
+            tracing::debug!(src = %pkt.src(), dst = %pkt.dest(), "[user] fragmented packets not supported, dropping");
+            return Ok(());
+        }
+
+        match pkt.protocol() {
+            NET_PROTOCOL_TCP => self.handle_tcp(pkt, router),
+            NET_PROTOCOL_UDP => self.handle_udp(pkt),
+            protocol => {
+                tracing::debug!(protocol, "[user] unsupported protocol, dropping packet");
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends the payload of a UDP datagram from a host socket, creating the socket if necessary
+    ///
+    /// ### Arguments
+    /// * `pkt` - UDP datagram sent by a LAN device
+    fn handle_udp(&mut self, pkt: Ipv4Packet) -> Result<(), NetworkError> {
+        let payload = pkt.payload();
+        if payload.len() < UDP_HDR_SZ {
+            return Ok(());
+        }
+
+        let key = FlowKey {
+            protocol: NET_PROTOCOL_UDP,
+            src: pkt.src(),
+            src_port: u16::from_be_bytes([payload[0], payload[1]]),
+            dst: pkt.dest(),
+            dst_port: u16::from_be_bytes([payload[2], payload[3]]),
+        };
+
+        if !self.udp.contains_key(&key) {
+            if !self.outbound {
+                tracing::trace!(?key, "[user] not a forwarded flow, dropping packet");
+                return Ok(());
+            }
+
+            if self.flows() >= self.cfg.max_flows {
+                tracing::warn!(?key, "[user] too many flows, dropping packet");
+                return Ok(());
+            }
+
+            let token = self.next_token();
+            let mut sock = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+            sock.connect(SocketAddr::V4(SocketAddrV4::new(key.dst, key.dst_port)))?;
+            self.poll
+                .registry()
+                .register(&mut sock, token, Interest::READABLE)?;
+
+            tracing::trace!(?key, "[user] new udp flow");
+            self.tokens.insert(token, key);
+            self.udp.insert(
+                key,
+                UdpFlow {
+                    host: UdpHost::Connected { sock, token },
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+
+        if let Some(flow) = self.udp.get_mut(&key) {
+            flow.last_seen = Instant::now();
+            let data = &payload[UDP_HDR_SZ..];
+            let res = match flow.host {
+                UdpHost::Connected { ref sock, .. } => sock.send(data),
+                UdpHost::Forwarded { listener, peer } => match self.listeners.get(&listener) {
+                    Some(Listener {
+                        sock: ListenerSocket::Udp(sock),
+                        ..
+                    }) => sock.send_to(data, peer),
+                    _ => Ok(0),
+                },
+            };
+
+            match res {
+                Ok(_) => (),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    tracing::debug!(?key, "[user] udp socket full, dropping datagram")
+                }
+                Err(error) => tracing::debug!(?error, ?key, "[user] unable to send datagram"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads all datagrams available on a UDP flow's host socket and routes them to the LAN
+    ///
+    /// ### Arguments
+    /// * `key` - UDP flow
+    /// * `buf` - Scratch buffer to read datagrams into
+    /// * `router` - Handle to the router
+    fn read_udp(&mut self, key: FlowKey, buf: &mut [u8], router: &RouterHandle) {
+        let Some(UdpFlow {
+            host: UdpHost::Connected { ref sock, .. },
+            ref mut last_seen,
+        }) = self.udp.get_mut(&key)
+        else {
+            return;
+        };
+
+        loop {
+            let sz = match sock.recv(&mut buf[UDP_HDR_SZ..]) {
+                Ok(sz) => sz,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    tracing::debug!(?error, ?key, "[user] unable to receive datagram");
+                    break;
+                }
+            };
+
+            *last_seen = Instant::now();
+            send_datagram(router, &key, buf, sz);
+        }
+    }
+
+    /// Handles a readiness event on a port forward's listener, accepting connections or
+    /// reading datagrams and forwarding them to the LAN device
+    ///
+    /// ### Arguments
+    /// * `token` - Token of the listener
+    /// * `buf` - Scratch buffer to read datagrams into
+    /// * `router` - Handle to the router
+    fn read_listener(
+        &mut self,
+        token: Token,
+        buf: &mut [u8],
+        router: &RouterHandle,
+    ) -> Result<(), NetworkError> {
+        loop {
+            let Some(listener) = self.listeners.get(&token) else {
+                return Ok(());
+            };
+
+            let fwd = listener.fwd;
+            match listener.sock {
+                ListenerSocket::Tcp(ref sock) => match sock.accept() {
+                    Ok((stream, peer)) => self.tcp_forward(fwd, stream, peer, router)?,
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(error) => return Err(error.into()),
+                },
+                ListenerSocket::Udp(ref sock) => match sock.recv_from(&mut buf[UDP_HDR_SZ..]) {
+                    Ok((sz, peer)) => self.udp_forward(token, fwd, peer, buf, sz, router),
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(error) => return Err(error.into()),
+                },
+            }
+        }
+    }
+
+    /// Forwards a datagram received by a port forward's listener to the LAN device, creating
+    /// a flow for the remote peer if necessary
+    ///
+    /// ### Arguments
+    /// * `listener` - Token of the listener
+    /// * `fwd` - Port forward the datagram arrived on
+    /// * `peer` - Address of the remote peer
+    /// * `buf` - Buffer holding the datagram after room for the UDP header
+    /// * `sz` - Size of the datagram
+    /// * `router` - Handle to the router
+    fn udp_forward(
+        &mut self,
+        listener: Token,
+        fwd: PortForward,
+        peer: SocketAddr,
+        buf: &mut [u8],
+        sz: usize,
+        router: &RouterHandle,
+    ) {
+        let key = match self.forwarded.get(&(listener, peer)).copied() {
+            Some(key) => key,
+            None => {
+                let key = self.forward_key(fwd, peer);
+                if self.flows() >= self.cfg.max_flows || self.udp.contains_key(&key) {
+                    tracing::debug!(?key, %peer, "[user] unable to forward udp flow");
+                    return;
+                }
+
+                tracing::trace!(?key, %peer, "[user] new forwarded udp flow");
+                self.forwarded.insert((listener, peer), key);
+                self.open_forwards.lock().insert(key);
+                self.udp.insert(
+                    key,
+                    UdpFlow {
+                        host: UdpHost::Forwarded { listener, peer },
+                        last_seen: Instant::now(),
+                    },
+                );
+                key
+            }
+        };
+
+        if let Some(flow) = self.udp.get_mut(&key) {
+            flow.last_seen = Instant::now();
+        }
+
+        send_datagram(router, &key, buf, sz);
+    }
+
+    /// Returns the flow (as seen from the LAN) of traffic forwarded from a remote peer
+    ///
+    /// ### Arguments
+    /// * `fwd` - Port forward the traffic arrived on
+    /// * `peer` - Address of the remote peer
+    fn forward_key(&self, fwd: PortForward, peer: SocketAddr) -> FlowKey {
+        let ip = match peer.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+        };
+
+        // loopback addresses are not routable from the lan
+        let remote = match ip {
+            Some(ip) if !ip.is_loopback() && !ip.is_unspecified() => ip,
+            _ => self.cfg.host_address,
+        };
+
+        FlowKey {
+            protocol: fwd.protocol,
+            src: fwd.to,
+            src_port: fwd.to_port,
+            dst: remote,
+            dst_port: peer.port(),
+        }
+    }
+
+    /// Handles a TCP segment sent by a LAN device
+    ///
+    /// ### Arguments
+    /// * `pkt` - TCP segment
+    /// * `router` - Handle to the router
+    fn handle_tcp(&mut self, pkt: Ipv4Packet, router: &RouterHandle) -> Result<(), NetworkError> {
+        let hdr = TcpHeader::parse(pkt.payload())?;
+        let data = &pkt.payload()[hdr.hdr_len..];
+
+        let key = FlowKey {
+            protocol: NET_PROTOCOL_TCP,
+            src: pkt.src(),
+            src_port: hdr.src_port,
+            dst: pkt.dest(),
+            dst_port: hdr.dst_port,
+        };
+
+        if hdr.has_flags(TCP_FLAG_RST) {
+            if self.tcp.contains_key(&key) {
+                tracing::trace!(?key, "[user] connection reset by lan");
+                self.remove_tcp(key);
+            }
+            return Ok(());
+        }
+
+        if hdr.has_flags(TCP_FLAG_SYN) && !hdr.has_flags(TCP_FLAG_ACK) {
+            return self.tcp_connect(key, &hdr, router);
+        }
+
+        let Some(conn) = self.tcp.get_mut(&key) else {
+            // unknown connection, tell the device to go away
+            if !data.is_empty() || hdr.has_flags(TCP_FLAG_FIN) || hdr.has_flags(TCP_FLAG_ACK) {
+                let (seq, ack, flags) = match hdr.has_flags(TCP_FLAG_ACK) {
+                    true => (hdr.ack, 0, TCP_FLAG_RST),
+                    false => (0, seg_end(&hdr, data), TCP_FLAG_RST | TCP_FLAG_ACK),
+                };
+                send_segment(router, &key, seq, ack, flags, 0, &[], None);
+            }
+            return Ok(());
+        };
+
+        conn.last_seen = Instant::now();
+
+        if conn.state == TcpState::SynSent {
+            // waiting for the device to accept a forwarded connection
+            if hdr.has_flags(TCP_FLAG_SYN | TCP_FLAG_ACK) && hdr.ack == conn.snd_nxt {
+                tracing::trace!(?key, "[user] forwarded connection accepted");
+                conn.rcv_nxt = hdr.seq.wrapping_add(1);
+                conn.peer_mss = std::cmp::min(hdr.mss.unwrap_or(TCP_DEFAULT_MSS), TCP_MSS);
+                conn.handle_ack(&hdr);
+                conn.send_ack(router, &key);
+                conn.pump_to_lan(router, &key);
+            }
+            return Ok(());
+        }
+
+        if hdr.has_flags(TCP_FLAG_ACK) {
+            conn.handle_ack(&hdr);
+        }
+
+        if conn.state == TcpState::Connecting {
+            // nothing can be accepted until the handshake completes
+            return Ok(());
+        }
+
+        let mut ack_needed = false;
+
+        if !data.is_empty() {
+            ack_needed = true;
+            if hdr.seq == conn.rcv_nxt && !conn.peer_fin {
+                let space = TCP_RECV_BUF_SZ - conn.to_host.len();
+                let accepted = std::cmp::min(space, data.len());
+                conn.to_host.extend(&data[..accepted]);
+                conn.rcv_nxt = conn.rcv_nxt.wrapping_add(accepted as u32);
+                conn.flush_to_host(&key);
+            }
+        }
+
+        if hdr.has_flags(TCP_FLAG_FIN) {
+            ack_needed = true;
+            if seg_end(&hdr, data).wrapping_sub(1) == conn.rcv_nxt && !conn.peer_fin {
+                tracing::trace!(?key, "[user] lan closed connection");
+                conn.peer_fin = true;
+                conn.rcv_nxt = conn.rcv_nxt.wrapping_add(1);
+                conn.flush_to_host(&key);
+            }
+        }
+
+        if ack_needed {
+            conn.send_ack(router, &key);
+        }
+
+        conn.pump_to_lan(router, &key);
+        self.reap(key);
+
+        Ok(())
+    }
+
+    /// Opens a host connection in response to a SYN from a LAN device
+    ///
+    /// ### Arguments
+    /// * `key` - TCP flow
+    /// * `hdr` - Header of the SYN segment
+    /// * `router` - Handle to the router
+    fn tcp_connect(
+        &mut self,
+        key: FlowKey,
+        hdr: &TcpHeader,
+        router: &RouterHandle,
+    ) -> Result<(), NetworkError> {
+        if let Some(conn) = self.tcp.get(&key) {
+            if conn.state == TcpState::SynReceived {
+                // our SYN-ACK was lost, send it again
+                conn.send_syn_ack(router, &key);
+            }
+            return Ok(());
+        }
+
+        let reset = |router: &RouterHandle| {
+            let ack = hdr.seq.wrapping_add(1);
+            send_segment(
+                router,
+                &key,
+                0,
+                ack,
+                TCP_FLAG_RST | TCP_FLAG_ACK,
+                0,
+                &[],
+                None,
+            );
+        };
+
+        if !self.outbound {
+            tracing::trace!(
+                ?key,
+                "[user] not a forwarded connection, refusing connection"
+            );
+            reset(router);
+            return Ok(());
+        }
+
+        if self.flows() >= self.cfg.max_flows {
+            tracing::warn!(?key, "[user] too many flows, refusing connection");
+            reset(router);
+            return Ok(());
+        }
+
+        let addr = SocketAddr::V4(SocketAddrV4::new(key.dst, key.dst_port));
+        let mut sock = match TcpStream::connect(addr) {
+            Ok(sock) => sock,
+            Err(error) => {
+                tracing::debug!(?error, ?key, "[user] unable to connect");
+                reset(router);
+                return Ok(());
+            }
+        };
+
+        let token = self.next_token();
+        self.poll
+            .registry()
+            .register(&mut sock, token, Interest::READABLE | Interest::WRITABLE)?;
+
+        let mut conn = TcpConn::new(sock, token, TcpState::Connecting);
+        conn.rcv_nxt = hdr.seq.wrapping_add(1);
+        conn.peer_window = u32::from(hdr.window);
+        conn.peer_mss = std::cmp::min(hdr.mss.unwrap_or(TCP_DEFAULT_MSS), TCP_MSS);
+
+        tracing::trace!(?key, "[user] new tcp connection");
+        self.tokens.insert(token, key);
+        self.tcp.insert(key, conn);
+
+        Ok(())
+    }
+
+    /// Opens a connection to the LAN device for a connection accepted by a port forward's
+    /// listener
+    ///
+    /// ### Arguments
+    /// * `fwd` - Port forward the connection was accepted on
+    /// * `sock` - Accepted host socket
+    /// * `peer` - Address of the remote peer
+    /// * `router` - Handle to the router
+    fn tcp_forward(
+        &mut self,
+        fwd: PortForward,
+        mut sock: TcpStream,
+        peer: SocketAddr,
+        router: &RouterHandle,
+    ) -> Result<(), NetworkError> {
+        let key = self.forward_key(fwd, peer);
+        if self.flows() >= self.cfg.max_flows || self.tcp.contains_key(&key) {
+            tracing::debug!(?key, %peer, "[user] unable to forward connection");
+            sock.shutdown(Shutdown::Both).ok();
+            return Ok(());
+        }
+
+        let token = self.next_token();
+        self.poll
+            .registry()
+            .register(&mut sock, token, Interest::READABLE | Interest::WRITABLE)?;
+
+        let mut conn = TcpConn::new(sock, token, TcpState::SynSent);
+        conn.snd_nxt = conn.iss.wrapping_add(1);
+        conn.send_syn(router, &key);
+
+        tracing::trace!(?key, %peer, "[user] new forwarded tcp connection");
+        self.tokens.insert(token, key);
+        self.open_forwards.lock().insert(key);
+        self.tcp.insert(key, conn);
+
+        Ok(())
+    }
+
+    /// Handles a readiness event on a TCP connection's host socket
+    ///
+    /// ### Arguments
+    /// * `key` - TCP flow
+    /// * `readable` - True if the socket is readable
+    /// * `writable` - True if the socket is writable
+    /// * `router` - Handle to the router
+    fn tcp_event(&mut self, key: FlowKey, readable: bool, writable: bool, router: &RouterHandle) {
+        let Some(conn) = self.tcp.get_mut(&key) else {
+            return;
+        };
+
+        if conn.state == TcpState::Connecting {
+            match (conn.sock.take_error(), conn.sock.peer_addr()) {
+                (Ok(None), Ok(_)) => {
+                    tracing::trace!(?key, "[user] connected");
+                    conn.state = TcpState::SynReceived;
+                    conn.snd_nxt = conn.iss.wrapping_add(1);
+                    conn.rto_start = Instant::now();
+                    conn.send_syn_ack(router, &key);
+                }
+                (Ok(Some(error)), _) | (Err(error), _) => {
+                    tracing::debug!(?error, ?key, "[user] unable to connect");
+                    let ack = conn.rcv_nxt;
+                    send_segment(
+                        router,
+                        &key,
+                        0,
+                        ack,
+                        TCP_FLAG_RST | TCP_FLAG_ACK,
+                        0,
+                        &[],
+                        None,
+                    );
+                    self.remove_tcp(key);
+                }
+                (Ok(None), Err(error)) if error.kind() == io::ErrorKind::NotConnected => {
+                    // still connecting
+                }
+                (Ok(None), Err(error)) => {
+                    tracing::debug!(?error, ?key, "[user] unable to connect");
+                    let ack = conn.rcv_nxt;
+                    send_segment(
+                        router,
+                        &key,
+                        0,
+                        ack,
+                        TCP_FLAG_RST | TCP_FLAG_ACK,
+                        0,
+                        &[],
+                        None,
+                    );
+                    self.remove_tcp(key);
+                }
+            }
+            return;
+        }
+
+        if writable {
+            let before = conn.to_host.len();
+            conn.flush_to_host(&key);
+
+            // let the device know buffer space is available again
+            if conn.window_closed && conn.to_host.len() < before {
+                conn.send_ack(router, &key);
+            }
+        }
+
+        if readable {
+            conn.pump_to_lan(router, &key);
+        }
+
+        self.reap(key);
+    }
+
+    /// Retransmits unacknowledged segments and removes idle flows
+    ///
+    /// ### Arguments
+    /// * `router` - Handle to the router
+    fn tick(&mut self, router: &RouterHandle) {
+        let now = Instant::now();
+        let tcp_timeout = Duration::from_secs(self.cfg.tcp_timeout);
+        let udp_timeout = Duration::from_secs(self.cfg.udp_timeout);
+
+        let mut expired = Vec::new();
+        for (key, conn) in self.tcp.iter_mut() {
+            if now.duration_since(conn.last_seen) >= tcp_timeout {
+                expired.push(*key);
+                continue;
+            }
+
+            if conn.snd_una == conn.snd_nxt || now.duration_since(conn.rto_start) < TCP_RTO {
+                continue;
+            }
+
+            if conn.retries >= TCP_MAX_RETRIES {
+                tracing::debug!(
+                    ?key,
+                    "[user] too many retransmissions, resetting connection"
+                );
+                let (seq, ack) = (conn.snd_nxt, conn.rcv_nxt);
+                send_segment(
+                    router,
+                    key,
+                    seq,
+                    ack,
+                    TCP_FLAG_RST | TCP_FLAG_ACK,
+                    0,
+                    &[],
+                    None,
+                );
+                expired.push(*key);
+                continue;
+            }
+
+            conn.retries += 1;
+            conn.rto_start = now;
+            conn.retransmit(router, key);
+        }
+
+        for key in expired {
+            self.remove_tcp(key);
+        }
+
+        let expired = self
+            .udp
+            .iter()
+            .filter(|(_, flow)| now.duration_since(flow.last_seen) >= udp_timeout)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            tracing::trace!(?key, "[user] expiring udp flow");
+            match self.udp.remove(&key).map(|flow| flow.host) {
+                Some(UdpHost::Connected { mut sock, token }) => {
+                    self.tokens.remove(&token);
+                    self.poll.registry().deregister(&mut sock).ok();
+                }
+                Some(UdpHost::Forwarded { listener, peer }) => {
+                    self.forwarded.remove(&(listener, peer));
+                    self.open_forwards.lock().remove(&key);
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Removes a TCP connection if both sides have closed and all data was acknowledged
+    ///
+    /// ### Arguments
+    /// * `key` - TCP flow
+    fn reap(&mut self, key: FlowKey) {
+        let done = match self.tcp.get(&key) {
+            Some(conn) => conn.peer_fin && conn.fin_sent && conn.snd_una == conn.snd_nxt,
+            None => false,
+        };
+
+        if done {
+            tracing::trace!(?key, "[user] connection closed");
+            self.remove_tcp(key);
+        }
+    }
+
+    /// Removes a TCP connection and closes the host socket
+    ///
+    /// ### Arguments
+    /// * `key` - TCP flow
+    fn remove_tcp(&mut self, key: FlowKey) {
+        if let Some(conn) = self.tcp.remove(&key) {
+            self.open_forwards.lock().remove(&key);
+            self.close(conn);
+        }
+    }
+
+    /// Deregisters and closes a TCP connection's host socket
+    ///
+    /// ### Arguments
+    /// * `conn` - Connection to close
+    fn close(&mut self, mut conn: TcpConn) {
+        self.tokens.remove(&conn.token);
+        self.poll.registry().deregister(&mut conn.sock).ok();
+        conn.sock.shutdown(Shutdown::Both).ok();
+    }
+}
+
+impl TcpConn {
+    /// Creates a new connection with a random initial sequence number
+    ///
+    /// ### Arguments
+    /// * `sock` - Host socket
+    /// * `token` - Token of the host socket
+    /// * `state` - Initial state of the LAN-facing connection
+    fn new(sock: TcpStream, token: Token, state: TcpState) -> Self {
+        let iss = rand::random::<u32>();
+        let now = Instant::now();
+
+        Self {
+            sock,
+            token,
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            rcv_nxt: 0,
+            peer_window: 0,
+            peer_mss: TCP_DEFAULT_MSS,
+            to_host: VecDeque::new(),
+            unacked: VecDeque::new(),
+            peer_fin: false,
+            host_shutdown: false,
+            fin_sent: false,
+            window_closed: false,
+            rto_start: now,
+            retries: 0,
+            last_seen: now,
+        }
+    }
+
+    /// Processes an acknowledgement from the LAN device
+    ///
+    /// ### Arguments
+    /// * `hdr` - Header of the received segment
+    fn handle_ack(&mut self, hdr: &TcpHeader) {
+        self.peer_window = u32::from(hdr.window);
+
+        // acknowledgement must be for data that has been sent
+        let acked = hdr.ack.wrapping_sub(self.snd_una);
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+        if acked == 0 || acked > in_flight {
+            return;
+        }
+
+        if matches!(self.state, TcpState::SynReceived | TcpState::SynSent) {
+            // the SYN consumes the only sequence number in flight
+            self.state = TcpState::Established;
+        } else {
+            // a FIN also consumes a sequence number but is not stored in the buffer
+            let data = std::cmp::min(acked as usize, self.unacked.len());
+            self.unacked.drain(..data);
+        }
+
+        self.snd_una = hdr.ack;
+        self.rto_start = Instant::now();
+        self.retries = 0;
+    }
+
+    /// Writes as much buffered data as possible to the host socket, shutting down the
+    /// write side once the LAN device has closed the connection
+    ///
+    /// ### Arguments
+    /// * `key` - TCP flow (for logging)
+    fn flush_to_host(&mut self, key: &FlowKey) {
+        while !self.to_host.is_empty() {
+            let (data, _) = self.to_host.as_slices();
+            match self.sock.write(data) {
+                Ok(0) => break,
+                Ok(sz) => {
+                    self.to_host.drain(..sz);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    tracing::debug!(?error, ?key, "[user] unable to write to host socket");
+                    self.to_host.clear();
+                    break;
+                }
+            }
+        }
+
+        if self.peer_fin && self.to_host.is_empty() && !self.host_shutdown {
+            self.sock.shutdown(Shutdown::Write).ok();
+            self.host_shutdown = true;
+        }
+    }
+
+    /// Reads from the host socket and sends the data to the LAN device, limited by the
+    /// device's receive window
+    ///
+    /// ### Arguments
+    /// * `router` - Handle to the router
+    /// * `key` - TCP flow
+    fn pump_to_lan(&mut self, router: &RouterHandle, key: &FlowKey) {
+        if self.state != TcpState::Established {
+            return;
+        }
+
+        let mut buf = [0u8; TCP_MSS as usize];
+        while !self.fin_sent {
+            // restart the retransmission timer when data is put in flight
+            let idle = self.snd_una == self.snd_nxt;
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+            let window = self.peer_window.saturating_sub(in_flight) as usize;
+            let len = std::cmp::min(window, usize::from(self.peer_mss));
+            if len == 0 {
+                break;
+            }
+
+            match self.sock.read(&mut buf[..len]) {
+                Ok(0) => {
+                    tracing::trace!(?key, "[user] remote closed connection");
+                    let flags = TCP_FLAG_FIN | TCP_FLAG_ACK;
+                    send_segment(
+                        router,
+                        key,
+                        self.snd_nxt,
+                        self.rcv_nxt,
+                        flags,
+                        self.window(),
+                        &[],
+                        None,
+                    );
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                    self.fin_sent = true;
+                }
+                Ok(sz) => {
+                    let flags = TCP_FLAG_PSH | TCP_FLAG_ACK;
+                    let window = self.window();
+                    send_segment(
+                        router,
+                        key,
+                        self.snd_nxt,
+                        self.rcv_nxt,
+                        flags,
+                        window,
+                        &buf[..sz],
+                        None,
+                    );
+                    self.unacked.extend(&buf[..sz]);
+                    self.snd_nxt = self.snd_nxt.wrapping_add(sz as u32);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => {
+                    tracing::debug!(?error, ?key, "[user] unable to read from host socket");
+                    send_segment(
+                        router,
+                        key,
+                        self.snd_nxt,
+                        self.rcv_nxt,
+                        TCP_FLAG_RST | TCP_FLAG_ACK,
+                        0,
+                        &[],
+                        None,
+                    );
+                    // treat as closed, reaped once the device acknowledges
+                    self.fin_sent = true;
+                    self.peer_fin = true;
+                    self.snd_una = self.snd_nxt;
+                    self.unacked.clear();
+                    break;
+                }
+            }
+
+            if idle {
+                self.rto_start = Instant::now();
+            }
+        }
+    }
+
+    /// Resends the oldest unacknowledged segment (or SYN-ACK / FIN)
+    ///
+    /// ### Arguments
+    /// * `router` - Handle to the router
+    /// * `key` - TCP flow
+    fn retransmit(&self, router: &RouterHandle, key: &FlowKey) {
+        match self.state {
+            TcpState::SynReceived => return self.send_syn_ack(router, key),
+            TcpState::SynSent => return self.send_syn(router, key),
+            _ => (),
+        }
+
+        let len = std::cmp::min(self.unacked.len(), usize::from(self.peer_mss));
+        if len > 0 {
+            let data = self.unacked.range(..len).copied().collect::<Vec<_>>();
+            let flags = TCP_FLAG_PSH | TCP_FLAG_ACK;
+            send_segment(
+                router,
+                key,
+                self.snd_una,
+                self.rcv_nxt,
+                flags,
+                self.window(),
+                &data,
+                None,
+            );
+        } else if self.fin_sent {
+            let flags = TCP_FLAG_FIN | TCP_FLAG_ACK;
+            let seq = self.snd_nxt.wrapping_sub(1);
+            send_segment(
+                router,
+                key,
+                seq,
+                self.rcv_nxt,
+                flags,
+                self.window(),
+                &[],
+                None,
+            );
+        }
+    }
+
+    /// Sends a SYN-ACK to the LAN device
+    ///
+    /// ### Arguments
+    /// * `router` - Handle to the router
+    /// * `key` - TCP flow
+    fn send_syn_ack(&self, router: &RouterHandle, key: &FlowKey) {
+        let flags = TCP_FLAG_SYN | TCP_FLAG_ACK;
+        send_segment(
+            router,
+            key,
+            self.iss,
+            self.rcv_nxt,
+            flags,
+            self.window(),
+            &[],
+            Some(TCP_MSS),
+        );
+    }
+
+    /// Sends a SYN to the LAN device, opening a forwarded connection
+    ///
+    /// ### Arguments
+    /// * `router` - Handle to the router
+    /// * `key` - TCP flow
+    fn send_syn(&self, router: &RouterHandle, key: &FlowKey) {
+        send_segment(
+            router,
+            key,
+            self.iss,
+            0,
+            TCP_FLAG_SYN,
+            self.window(),
+            &[],
+            Some(TCP_MSS),
+        );
+    }
+
+    /// Sends an ACK to the LAN device
+    ///
+    /// ### Arguments
+    /// * `router` - Handle to the router
+    /// * `key` - TCP flow
+    fn send_ack(&mut self, router: &RouterHandle, key: &FlowKey) {
+        let window = self.window();
+        self.window_closed = window < TCP_MSS;
+        send_segment(
+            router,
+            key,
+            self.snd_nxt,
+            self.rcv_nxt,
+            TCP_FLAG_ACK,
+            window,
+            &[],
+            None,
+        );
+    }
+
+    /// Returns the receive window to advertise to the LAN device
+    fn window(&self) -> u16 {
+        (TCP_RECV_BUF_SZ - self.to_host.len()) as u16
+    }
+}
+
+/// Returns the sequence number following a segment (accounting for SYN/FIN flags)
+///
+/// ### Arguments
+/// * `hdr` - Header of the segment
+/// * `data` - Payload of the segment
+fn seg_end(hdr: &TcpHeader, data: &[u8]) -> u32 {
+    let mut len = data.len() as u32;
+    if hdr.has_flags(TCP_FLAG_SYN) {
+        len += 1;
+    }
+
+    if hdr.has_flags(TCP_FLAG_FIN) {
+        len += 1;
+    }
+
+    hdr.seq.wrapping_add(len)
+}
+
+/// Builds a UDP datagram addressed to the LAN device and routes it
+///
+/// ### Arguments
+/// * `router` - Handle to the router
+/// * `key` - UDP flow (as seen from the LAN)
+/// * `buf` - Buffer holding the payload after room for the UDP header
+/// * `sz` - Size of the payload
+fn send_datagram(router: &RouterHandle, key: &FlowKey, buf: &mut [u8], sz: usize) {
+    let len = (sz + UDP_HDR_SZ) as u16;
+    buf[0..2].copy_from_slice(&key.dst_port.to_be_bytes());
+    buf[2..4].copy_from_slice(&key.src_port.to_be_bytes());
+    buf[4..6].copy_from_slice(&len.to_be_bytes());
+    buf[6..8].copy_from_slice(&[0x00, 0x00]);
+
+    let pkt = Ipv4Packet::new(key.dst, key.src, NET_PROTOCOL_UDP, &buf[..usize::from(len)]);
+    router.route_ipv4(pkt);
+}
+
+/// Builds a TCP segment addressed to the LAN device and routes it
+///
+/// ### Arguments
+/// * `router` - Handle to the router
+/// * `key` - TCP flow (as seen from the LAN)
+/// * `seq` - Sequence number
+/// * `ack` - Acknowledgement number
+/// * `flags` - TCP flags
+/// * `window` - Receive window to advertise
+/// * `data` - Segment payload
+/// * `mss` - Maximum segment size option (SYN segments only)
+#[allow(clippy::too_many_arguments)]
+fn send_segment(
+    router: &RouterHandle,
+    key: &FlowKey,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    data: &[u8],
+    mss: Option<u16>,
+) {
+    let mut hdr = TcpHeader::new(key.dst_port, key.src_port, seq, ack, flags, window);
+    hdr.mss = mss;
+
+    let mut segment = vec![0u8; hdr.size() + data.len()];
+    let sz = hdr.as_bytes(&mut segment);
+    segment[sz..].copy_from_slice(data);
+
+    let pkt = Ipv4Packet::new(key.dst, key.src, NET_PROTOCOL_TCP, &segment);
+    router.route_ipv4(pkt);
+}
+
+impl Wan for UserNet {
+    fn as_wan_handle(&self) -> Result<Box<dyn WanHandle>, NetworkError> {
+        let waker = Waker::new(self.poll.registry(), TOKEN_WAKER)?;
+
+        let handle = UserNetHandle {
+            tx: self.tx.clone(),
+            waker: Arc::new(waker),
+            open_forwards: Arc::clone(&self.open_forwards),
+        };
+
+        Ok(Box::new(handle))
+    }
+
+    fn add_forward(&mut self, fwd: PortForward, listen: IpAddr) -> Result<(), NetworkError> {
+        let addr = SocketAddr::new(listen, fwd.port);
+        let token = self.next_token();
+        let mut sock = match fwd.protocol {
+            NET_PROTOCOL_TCP => ListenerSocket::Tcp(TcpListener::bind(addr)?),
+            NET_PROTOCOL_UDP => ListenerSocket::Udp(UdpSocket::bind(addr)?),
+            protocol => {
+                return Err(NetworkError::Generic(
+                    format!("unable to forward protocol {protocol}").into(),
+                ))
+            }
+        };
+
+        let registry = self.poll.registry();
+        match sock {
+            ListenerSocket::Tcp(ref mut sock) => {
+                registry.register(sock, token, Interest::READABLE)?
+            }
+            ListenerSocket::Udp(ref mut sock) => {
+                registry.register(sock, token, Interest::READABLE)?
+            }
+        }
+
+        tracing::debug!(%addr, ?fwd, "[user] listening for forwarded traffic");
+        self.listeners.insert(token, Listener { sock, fwd });
+
+        Ok(())
+    }
+
+    fn run(mut self: Box<Self>, router: RouterHandle) -> Result<(), NetworkError> {
+        let mut events = Events::with_capacity(MAX_EVENTS_CAPACITY);
+
+        let rx = self
+            .rx
+            .take()
+            .ok_or_else(|| NetworkError::Generic("no receiver available, already used".into()))?;
+
+        let mut buf = vec![0u8; UDP_BUF_SZ];
+        let mut last_tick = Instant::now();
+
+        loop {
+            self.poll.poll(&mut events, Some(TICK_INTERVAL))?;
+
+            for event in &events {
+                match event.token() {
+                    TOKEN_WAKER => {
+                        for pkt in rx.drain() {
+                            if let Err(error) = self.handle_packet(pkt, &router) {
+                                tracing::debug!(?error, "[user] unable to handle packet");
+                            }
+                        }
+                    }
+                    token if self.listeners.contains_key(&token) => {
+                        if let Err(error) = self.read_listener(token, &mut buf, &router) {
+                            tracing::debug!(?error, "[user] unable to accept forwarded traffic");
+                        }
+                    }
+                    token => match self.tokens.get(&token).copied() {
+                        Some(key) if key.protocol == NET_PROTOCOL_UDP => {
+                            self.read_udp(key, &mut buf, &router)
+                        }
+                        Some(key) => {
+                            let readable = event.is_readable() || event.is_read_closed();
+                            let writable = event.is_writable() || event.is_error();
+                            self.tcp_event(key, readable, writable, &router);
+                        }
+                        None => tracing::trace!(?token, "[user] event for closed socket"),
+                    },
+                }
+            }
+
+            if router.is_stopped() {
+                tracing::debug!("[user] router stopped, closing sockets");
+                return Ok(());
+            }
+
+            if last_tick.elapsed() >= TICK_INTERVAL {
+                self.tick(&router);
+                last_tick = Instant::now();
+            }
+        }
+    }
+}
+
+impl WanHandle for UserNetHandle {
+    fn write(&self, pkt: Ipv4Packet) -> Result<(), NetworkError> {
+        self.tx.send(pkt).ok();
+        self.waker.wake().ok();
+        Ok(())
+    }
+
+    fn is_forwarded(&self, pkt: &Ipv4Packet) -> bool {
+        // fragmented packets are not supported
+        match pkt.fragment_offset() {
+            0 => FlowKey::new(pkt)
+                .map(|flow| self.open_forwards.lock().contains(&flow))
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+        time::{Duration, Instant},
+    };
+
+    use flume::Receiver;
+    use mio::Token;
+    use oathgate_net::{
+        nat::{FlowKey, PortForward},
+        protocols::{
+            tcp::{TCP_FLAG_ACK, TCP_FLAG_FIN, TCP_FLAG_PSH, TCP_FLAG_SYN},
+            TcpHeader, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP, UDP_HDR_SZ,
+        },
+        Ipv4Packet,
+    };
+
+    use crate::net::router::{RouterHandle, RouterMsg};
+
+    use super::{TcpState, UserConfig, UserNet, Wan, UDP_BUF_SZ};
+
+    const DEVICE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 100);
+    const DEVICE_PORT: u16 = 40000;
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Builds a TCP segment sent by the LAN device
+    fn segment(key: &FlowKey, seq: u32, ack: u32, flags: u8, data: &[u8]) -> Ipv4Packet {
+        let mut hdr = TcpHeader::new(key.src_port, key.dst_port, seq, ack, flags, u16::MAX);
+        if flags & TCP_FLAG_SYN != 0 {
+            hdr.mss = Some(1460);
+        }
+
+        let mut buf = vec![0u8; hdr.size() + data.len()];
+        let sz = hdr.as_bytes(&mut buf);
+        buf[sz..].copy_from_slice(data);
+        Ipv4Packet::new(key.src, key.dst, NET_PROTOCOL_TCP, &buf)
+    }
+
+    /// Returns the next segment routed to the LAN device
+    fn recv_segment(rx: &Receiver<RouterMsg>) -> (TcpHeader, Vec<u8>) {
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(RouterMsg::FromWan4(pkt)) => {
+                assert_eq!(pkt.dest(), DEVICE);
+                let hdr = TcpHeader::parse(pkt.payload()).unwrap();
+                let data = pkt.payload()[hdr.hdr_len..].to_vec();
+                (hdr, data)
+            }
+            _ => panic!("no segment routed to the device"),
+        }
+    }
+
+    #[test]
+    fn user_tcp_connection() {
+        let (router, rx) = RouterHandle::detached();
+        let mut net = UserNet::new(UserConfig::default()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let key = FlowKey {
+            protocol: NET_PROTOCOL_TCP,
+            src: DEVICE,
+            src_port: DEVICE_PORT,
+            dst: Ipv4Addr::LOCALHOST,
+            dst_port: listener.local_addr().unwrap().port(),
+        };
+
+        // SYN from the device opens a host connection
+        let iss = 1000u32;
+        net.handle_packet(segment(&key, iss, 0, TCP_FLAG_SYN, &[]), &router)
+            .unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        assert_eq!(net.tcp[&key].state, TcpState::Connecting);
+
+        // host connection established, SYN-ACK sent to the device
+        net.tcp_event(key, false, true, &router);
+        let (hdr, _) = recv_segment(&rx);
+        assert!(hdr.has_flags(TCP_FLAG_SYN | TCP_FLAG_ACK));
+        assert_eq!(hdr.ack, iss + 1);
+        assert_eq!(hdr.mss, Some(1460));
+        let mut rcv_nxt = hdr.seq.wrapping_add(1);
+
+        net.handle_packet(segment(&key, iss + 1, rcv_nxt, TCP_FLAG_ACK, &[]), &router)
+            .unwrap();
+        assert_eq!(net.tcp[&key].state, TcpState::Established);
+
+        // data from the device is acknowledged and written to the host socket
+        let flags = TCP_FLAG_PSH | TCP_FLAG_ACK;
+        net.handle_packet(segment(&key, iss + 1, rcv_nxt, flags, b"hello"), &router)
+            .unwrap();
+        let (hdr, _) = recv_segment(&rx);
+        assert!(hdr.has_flags(TCP_FLAG_ACK));
+        assert_eq!(hdr.ack, iss + 6);
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // data from the host is sent to the device, followed by a FIN once the host closes
+        stream.write_all(b"world").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let mut data = Vec::new();
+        let start = Instant::now();
+        loop {
+            assert!(start.elapsed() < TIMEOUT, "no FIN sent to the device");
+            net.tcp_event(key, true, false, &router);
+
+            let Ok(RouterMsg::FromWan4(pkt)) = rx.try_recv() else {
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            };
+
+            let hdr = TcpHeader::parse(pkt.payload()).unwrap();
+            assert_eq!(hdr.seq, rcv_nxt);
+            data.extend_from_slice(&pkt.payload()[hdr.hdr_len..]);
+            rcv_nxt = rcv_nxt.wrapping_add((pkt.payload().len() - hdr.hdr_len) as u32);
+
+            if hdr.has_flags(TCP_FLAG_FIN) {
+                rcv_nxt = rcv_nxt.wrapping_add(1);
+                break;
+            }
+        }
+        assert_eq!(data, b"world");
+
+        // device acknowledges everything and closes its side
+        let flags = TCP_FLAG_FIN | TCP_FLAG_ACK;
+        net.handle_packet(segment(&key, iss + 6, rcv_nxt, flags, &[]), &router)
+            .unwrap();
+        let (hdr, _) = recv_segment(&rx);
+        assert!(hdr.has_flags(TCP_FLAG_ACK));
+        assert_eq!(hdr.ack, iss + 7);
+
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(net.tcp.is_empty());
+        assert!(net.tokens.is_empty());
+    }
+
+    #[test]
+    fn user_udp_flow_expires() {
+        let (router, rx) = RouterHandle::detached();
+        let mut net = UserNet::new(UserConfig::default()).unwrap();
+
+        let remote = UdpSocket::bind("127.0.0.1:0").unwrap();
+        remote.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let key = FlowKey {
+            protocol: NET_PROTOCOL_UDP,
+            src: DEVICE,
+            src_port: DEVICE_PORT,
+            dst: Ipv4Addr::LOCALHOST,
+            dst_port: remote.local_addr().unwrap().port(),
+        };
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&key.src_port.to_be_bytes());
+        payload.extend_from_slice(&key.dst_port.to_be_bytes());
+        payload.extend_from_slice(&((UDP_HDR_SZ + 4) as u16).to_be_bytes());
+        payload.extend_from_slice(&[0x00, 0x00]);
+        payload.extend_from_slice(b"ping");
+        let pkt = Ipv4Packet::new(key.src, key.dst, NET_PROTOCOL_UDP, &payload);
+        net.handle_packet(pkt, &router).unwrap();
+
+        let mut buf = vec![0u8; UDP_BUF_SZ];
+        let (sz, peer): (usize, SocketAddr) = remote.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..sz], b"ping");
+
+        // replies are routed back to the device
+        remote.send_to(b"pong", peer).unwrap();
+        let start = Instant::now();
+        let pkt = loop {
+            assert!(start.elapsed() < TIMEOUT, "no reply routed to the device");
+            net.read_udp(key, &mut buf, &router);
+            match rx.try_recv() {
+                Ok(RouterMsg::FromWan4(pkt)) => break pkt,
+                _ => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        assert_eq!(pkt.src(), key.dst);
+        assert_eq!(pkt.dest(), DEVICE);
+        let payload = pkt.payload();
+        assert_eq!(u16::from_be_bytes([payload[2], payload[3]]), DEVICE_PORT);
+        assert_eq!(&payload[UDP_HDR_SZ..], b"pong");
+
+        // an active flow is kept, an idle one removed
+        net.tick(&router);
+        assert_eq!(net.udp.len(), 1);
+
+        net.udp.get_mut(&key).unwrap().last_seen = Instant::now() - Duration::from_secs(61);
+        net.tick(&router);
+        assert!(net.udp.is_empty());
+        assert!(net.tokens.is_empty());
+    }
+
+    #[test]
+    fn user_udp_forwarded_flow() {
+        let (router, rx) = RouterHandle::detached();
+        let mut net = UserNet::forwarder().unwrap();
+        let handle = net.as_wan_handle().unwrap();
+
+        let fwd = PortForward {
+            protocol: NET_PROTOCOL_UDP,
+            port: 5353,
+            to: DEVICE,
+            to_port: 53,
+        };
+        let peer = SocketAddr::from(([192, 168, 1, 10], 40000));
+
+        let mut buf = vec![0u8; UDP_BUF_SZ];
+        buf[UDP_HDR_SZ..UDP_HDR_SZ + 4].copy_from_slice(b"ping");
+        net.udp_forward(Token(1), fwd, peer, &mut buf, 4, &router);
+
+        let Ok(RouterMsg::FromWan4(pkt)) = rx.recv_timeout(TIMEOUT) else {
+            panic!("no datagram routed to the device");
+        };
+        assert_eq!(pkt.dest(), DEVICE);
+
+        // replies on the forwarded flow belong to this wan, other flows from the port do not
+        let reply = |dst: Ipv4Addr, dport: u16| {
+            let mut payload = Vec::new();
+            payload.extend_from_slice(&53u16.to_be_bytes());
+            payload.extend_from_slice(&dport.to_be_bytes());
+            payload.extend_from_slice(&(UDP_HDR_SZ as u16).to_be_bytes());
+            payload.extend_from_slice(&[0x00, 0x00]);
+            Ipv4Packet::new(DEVICE, dst, NET_PROTOCOL_UDP, &payload)
+        };
+
+        assert!(handle.is_forwarded(&reply(Ipv4Addr::new(192, 168, 1, 10), 40000)));
+        assert!(!handle.is_forwarded(&reply(Ipv4Addr::new(1, 1, 1, 1), 53)));
+
+        net.udp.values_mut().for_each(|flow| {
+            flow.last_seen = Instant::now() - Duration::from_secs(61);
+        });
+        net.tick(&router);
+        assert!(!handle.is_forwarded(&reply(Ipv4Addr::new(192, 168, 1, 10), 40000)));
+    }
+}
@@ -5,7 +5,7 @@ use std::{
     collections::HashMap,
     fmt::Debug,
     io::ErrorKind,
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     os::fd::{AsFd, AsRawFd},
     sync::Arc,
 };
@@ -21,12 +21,16 @@ use nix::sys::{
     time::TimeSpec,
     timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags},
 };
-use oathgate_net::{nat::NatTable, Ipv4Header, Ipv4Packet};
+use oathgate_net::{
+    nat::{NatConfig, NatTable, PortForward},
+    Ipv4Header, Ipv4Packet, Ipv6Packet,
+};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
 use crate::net::{router::RouterHandle, NetworkError};
 
-use super::{Wan, WanHandle};
+use super::{SharedNat, Wan, WanHandle};
 
 const TOKEN_WAKER: Token = Token(0);
 const TOKEN_UDP: Token = Token(1);
@@ -34,6 +38,10 @@ const TOKEN_TIMER: Token = Token(2);
 
 const WG_BUF_SZ: usize = 1600;
 
+/// Largest packet sent through the tunnel (the WireGuard default, leaving room for the
+/// encapsulation overhead)
+const WG_MTU: u16 = 1420;
+
 pub struct WgDevice {
     /// WireGuard tunnel (encryptor/decryptor)
     tun: Tunn,
@@ -47,6 +55,9 @@ pub struct WgDevice {
     /// Receiver for an Ipv4 packet to encrypt / route
     rx: Option<Receiver<Ipv4Packet>>,
 
+    /// Receiver for an Ipv6 packet to encrypt / route
+    rx6: Option<Receiver<Ipv6Packet>>,
+
     /// Handle used to communicate with this device
     handle: WgHandle,
 
@@ -54,7 +65,7 @@ pub struct WgDevice {
     poll: Poll,
 
     /// Maps & tracks outbound connections
-    nat: NatTable,
+    nat: SharedNat,
 
     /// Cache used to store/rebuild fragmented packets
     cache: HashMap<u16, Ipv4Packet>,
@@ -63,10 +74,12 @@ pub struct WgDevice {
 #[derive(Clone)]
 pub struct WgHandle {
     tx: Sender<Ipv4Packet>,
+    tx6: Sender<Ipv6Packet>,
     waker: Arc<Waker>,
+    nat: SharedNat,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct WgConfig {
     pub key: String,
     pub ipv4: Ipv4Addr,
@@ -84,22 +97,28 @@ impl Debug for WgConfig {
     }
 }
 
-impl WgDevice {
-    /// Creates a new WireGuard tunnel device from the supplied config
-    ///
-    /// ### Arguments
-    /// * `cfg` - WireGuard configuration
-    pub fn create(cfg: WgConfig) -> Result<Self, NetworkError> {
+impl WgConfig {
+    /// Decodes the (base64-encoded) private key of the tunnel and public key of the peer
+    pub fn keys(&self) -> Result<(StaticSecret, PublicKey), NetworkError> {
         use base64::prelude::BASE64_STANDARD;
 
         let mut key = [0u8; 32];
         let mut peer = [0u8; 32];
-        BASE64_STANDARD.decode_slice(&cfg.key, &mut key)?;
-        BASE64_STANDARD.decode_slice(&cfg.peer, &mut peer)?;
+        BASE64_STANDARD.decode_slice(&self.key, &mut key)?;
+        BASE64_STANDARD.decode_slice(&self.peer, &mut peer)?;
 
-        let key = StaticSecret::from(key);
-        let peer = PublicKey::from(peer);
+        Ok((StaticSecret::from(key), PublicKey::from(peer)))
+    }
+}
 
+impl WgDevice {
+    /// Creates a new WireGuard tunnel device from the supplied config
+    ///
+    /// ### Arguments
+    /// * `cfg` - WireGuard configuration
+    /// * `nat` - NAT configuration used to masquerade outbound traffic
+    pub fn create(cfg: WgConfig, nat: NatConfig) -> Result<Self, NetworkError> {
+        let (key, peer) = cfg.keys()?;
         let tun = Tunn::new(key, peer, None, None, 1, None)
             .map_err(|e| NetworkError::Generic(Cow::Borrowed(e)))?;
 
@@ -107,9 +126,13 @@ impl WgDevice {
         let waker = Waker::new(poll.registry(), TOKEN_WAKER)?;
 
         let (tx, rx) = flume::unbounded();
+        let (tx6, rx6) = flume::unbounded();
+        let nat = Arc::new(Mutex::new(NatTable::new(nat)));
         let handle = WgHandle {
             tx,
+            tx6,
             waker: Arc::new(waker),
+            nat: Arc::clone(&nat),
         };
 
         Ok(Self {
@@ -117,9 +140,10 @@ impl WgDevice {
             endpoint: cfg.endpoint,
             ipv4: cfg.ipv4,
             rx: Some(rx),
+            rx6: Some(rx6),
             handle,
             poll,
-            nat: NatTable::new(),
+            nat,
             cache: HashMap::new(),
         })
     }
@@ -179,29 +203,36 @@ impl WgDevice {
 
                 // undo nat'd packets
                 if let Some(mut pkt) = pkt {
-                    if let Some(orig) = self.nat.get(&pkt) {
-                        tracing::trace!(ip = ?orig, "[wg] setting original ipv4 address");
-                        pkt.unmasquerade(orig);
-                        router.route_ipv4(pkt);
-                    } else {
-                        tracing::warn!(
-                            protocol = pkt.protocol(),
-                            src = %pkt.src(),
-                            dst = %pkt.dest(),
-                            id = %pkt.id(),
-                            flags = %pkt.flags(),
-                            hdrlen = %pkt.header_length(),
-                            length = %pkt.len(),
-                            "[wg] no nat entry found",
-                        );
-
-                        tracing::trace!("packet bytes: {:02x?}", &pkt.as_bytes()[..28]);
+                    let translated = self.nat.lock().translate_inbound(&mut pkt);
+                    match translated {
+                        Ok(true) => {
+                            tracing::trace!(ip = ?pkt.dest(), "[wg] setting original ipv4 address");
+                            router.route_ipv4(pkt);
+                        }
+                        Ok(false) => {
+                            tracing::warn!(
+                                protocol = pkt.protocol(),
+                                src = %pkt.src(),
+                                dst = %pkt.dest(),
+                                id = %pkt.id(),
+                                flags = %pkt.flags(),
+                                hdrlen = %pkt.header_length(),
+                                length = %pkt.len(),
+                                "[wg] no nat entry found",
+                            );
+
+                            tracing::trace!("packet bytes: {:02x?}", &pkt.as_bytes()[..28]);
+                        }
+                        Err(error) => {
+                            tracing::warn!(?error, src = %pkt.src(), "[wg] unable to translate packet, dropping");
+                        }
                     }
                 }
             }
             TunnResult::WriteToTunnelV6(pkt, ip) => {
                 tracing::trace!(?ip, "[wg] write {} bytes to tunnel", pkt.len());
-                router.route_ipv6(pkt.to_vec());
+                let pkt = Ipv6Packet::parse(pkt.to_vec())?;
+                router.route_ipv6(pkt);
             }
         }
 
@@ -214,6 +245,11 @@ impl Wan for WgDevice {
         Ok(Box::new(self.handle.clone()))
     }
 
+    fn add_forward(&mut self, fwd: PortForward, _listen: IpAddr) -> Result<(), NetworkError> {
+        self.nat.lock().add_forward(fwd);
+        Ok(())
+    }
+
     fn run(mut self: Box<Self>, router: RouterHandle) -> Result<(), NetworkError> {
         let sock = std::net::UdpSocket::bind("0.0.0.0:0")?;
         sock.set_nonblocking(true)?;
@@ -240,6 +276,10 @@ impl Wan for WgDevice {
             NetworkError::Generic(String::from("wireguard missing receiver").into())
         })?;
 
+        let rx6 = self.rx6.take().ok_or_else(|| {
+            NetworkError::Generic(String::from("wireguard missing ipv6 receiver").into())
+        })?;
+
         // Handle packets / messages
         // from vm (aka write): rx -> tunn -> socket
         // from internet (aka read): socket -> tunn -> router
@@ -252,6 +292,12 @@ impl Wan for WgDevice {
         let mut wg_buf = [0u8; WG_BUF_SZ];
         let mut events = Events::with_capacity(10);
         while let Ok(_) = self.poll.poll(&mut events, None) {
+            // the timer wakes the loop up at least every 500 milliseconds
+            if router.is_stopped() {
+                tracing::debug!("[wg] router stopped, closing tunnel");
+                return Ok(());
+            }
+
             for event in &events {
                 match event.token() {
                     TOKEN_UDP => {
@@ -290,16 +336,29 @@ impl Wan for WgDevice {
                     TOKEN_WAKER => {
                         tracing::trace!("[wg] woke up!");
                         for mut pkt in rx.drain() {
-                            self.nat.insert(&pkt);
-                            pkt.masquerade(self.ipv4);
+                            if let Err(error) =
+                                self.nat.lock().translate_outbound(&mut pkt, self.ipv4)
+                            {
+                                tracing::warn!(?error, src = ?pkt.src(), dst = ?pkt.dest(), "[wg] unable to translate packet, dropping");
+                                continue;
+                            }
+
                             tracing::trace!(src = ?pkt.src(), dst = ?pkt.dest(), "[wg] encapsulating packet");
                             let action = self.tun.encapsulate(pkt.as_bytes(), &mut wg_buf);
                             self.handle_tun_result(action, &router, &sock)?;
                         }
+
+                        // ipv6 packets are routed, not masqueraded
+                        for pkt in rx6.drain() {
+                            tracing::trace!(src = ?pkt.src(), dst = ?pkt.dest(), "[wg] encapsulating ipv6 packet");
+                            let action = self.tun.encapsulate(pkt.as_bytes(), &mut wg_buf);
+                            self.handle_tun_result(action, &router, &sock)?;
+                        }
                     }
                     TOKEN_TIMER => {
                         tracing::trace!("[wg] updating timers");
                         timer.wait()?;
+                        self.nat.lock().expire();
                         let action = self.tun.update_timers(&mut wg_buf);
                         self.handle_tun_result(action, &router, &sock)?;
                     }
@@ -320,4 +379,18 @@ impl WanHandle for WgHandle {
         self.waker.wake()?;
         Ok(())
     }
+
+    fn mtu(&self) -> u16 {
+        WG_MTU
+    }
+
+    fn nat(&self) -> Option<SharedNat> {
+        Some(Arc::clone(&self.nat))
+    }
+
+    fn write_ipv6(&self, pkt: Ipv6Packet) -> Result<(), NetworkError> {
+        self.tx6.send(pkt)?;
+        self.waker.wake()?;
+        Ok(())
+    }
 }
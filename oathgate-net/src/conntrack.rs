@@ -0,0 +1,359 @@
+//! Connection tracking
+//!
+//! The `ConnTrack` table follows flows (identified by their 5-tuple) without translating them,
+//! allowing a firewall to match packets against the state of their connection.  Flows use the
+//! same TCP state machine and idle timeouts as the `NatTable`.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    nat::{
+        flow_ports, flow_timeout, is_icmp_error, next_tcp_state, tcp_flags, FlowKey, NatConfig,
+        QuotedPacket, TcpState,
+    },
+    protocols::{tcp::TCP_FLAG_SYN, NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+    Ipv4Packet,
+};
+
+/// Minimum interval between sweeps for expired connections when inserting new connections
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// State of the connection a packet belongs to
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnState {
+    /// First packet of a flow
+    New,
+
+    /// Packet belongs to a tracked flow (in either direction)
+    Established,
+
+    /// ICMP error about a packet of a tracked flow
+    Related,
+
+    /// Packet cannot be tracked (malformed, a non-initial fragment, or an ICMP error that
+    /// does not match a tracked flow)
+    Invalid,
+}
+
+/// A tracked connection
+struct Conn {
+    /// Tracked state of TCP connections, None for other protocols
+    tcp: Option<TcpState>,
+
+    /// Set when a FIN is sent by the side that opened the connection
+    fin_out: bool,
+
+    /// Set when a FIN is sent by the other side
+    fin_in: bool,
+
+    /// Last time a packet matched this connection
+    last_seen: Instant,
+}
+
+/// Connection tracking table
+pub struct ConnTrack {
+    /// Timeout and size settings
+    cfg: NatConfig,
+
+    /// Map of a flow (as seen from the side that opened it) to its connection
+    conns: HashMap<FlowKey, Conn>,
+
+    /// Last time expired connections were removed
+    last_sweep: Instant,
+}
+
+impl FlowKey {
+    /// Returns the key of packets flowing in the opposite direction
+    fn reverse(&self) -> Self {
+        Self {
+            protocol: self.protocol,
+            src: self.dst,
+            src_port: self.dst_port,
+            dst: self.src,
+            dst_port: self.src_port,
+        }
+    }
+
+    /// Returns the key of the flow a packet belongs to, or None if it cannot be tracked
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet to extract the 5-tuple from
+    fn from_packet(pkt: &Ipv4Packet) -> Option<Self> {
+        let protocol = pkt.protocol();
+        let (src_port, dst_port) = match protocol {
+            NET_PROTOCOL_TCP | NET_PROTOCOL_UDP | NET_PROTOCOL_ICMP => {
+                flow_ports(protocol, pkt.payload()).ok()?
+            }
+            _ => (0, 0),
+        };
+
+        Some(Self {
+            protocol,
+            src: pkt.src(),
+            src_port,
+            dst: pkt.dest(),
+            dst_port,
+        })
+    }
+}
+
+impl Default for ConnTrack {
+    fn default() -> Self {
+        Self::new(NatConfig::default())
+    }
+}
+
+impl ConnTrack {
+    /// Creates a new, empty connection tracking table
+    ///
+    /// ### Arguments
+    /// * `cfg` - Timeout and size settings
+    pub fn new(cfg: NatConfig) -> Self {
+        Self {
+            cfg,
+            conns: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Returns the number of tracked connections
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Returns true if no connections are tracked
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    /// Returns the state of the connection a packet belongs to, refreshing the connection if
+    /// it is tracked.  New connections are not tracked until `insert` is called.
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet to classify
+    pub fn state(&mut self, pkt: &Ipv4Packet) -> ConnState {
+        self.state_at(pkt, Instant::now())
+    }
+
+    /// Starts tracking the connection a packet belongs to (i.e., once a new connection has
+    /// been accepted)
+    ///
+    /// ### Arguments
+    /// * `pkt` - First packet of the connection
+    pub fn insert(&mut self, pkt: &Ipv4Packet) {
+        self.insert_at(pkt, Instant::now())
+    }
+
+    fn state_at(&mut self, pkt: &Ipv4Packet, now: Instant) -> ConnState {
+        if pkt.fragment_offset() != 0 {
+            return ConnState::Invalid;
+        }
+
+        if pkt.protocol() == NET_PROTOCOL_ICMP && is_icmp_error(pkt.payload()) {
+            let Ok(quoted) = QuotedPacket::parse(pkt.payload()) else {
+                return ConnState::Invalid;
+            };
+
+            let key = FlowKey {
+                protocol: quoted.protocol,
+                src: quoted.src,
+                src_port: quoted.src_port,
+                dst: quoted.dst,
+                dst_port: quoted.dst_port,
+            };
+
+            return match self.conns.contains_key(&key) || self.conns.contains_key(&key.reverse()) {
+                true => ConnState::Related,
+                false => ConnState::Invalid,
+            };
+        }
+
+        let Some(key) = FlowKey::from_packet(pkt) else {
+            return ConnState::Invalid;
+        };
+
+        let (key, outbound) = match self.conns.contains_key(&key) {
+            true => (key, true),
+            false => (key.reverse(), false),
+        };
+
+        let cfg = &self.cfg;
+        let Some(conn) = self.conns.get_mut(&key) else {
+            return ConnState::New;
+        };
+
+        if now.duration_since(conn.last_seen) >= flow_timeout(cfg, key.protocol, conn.tcp) {
+            self.conns.remove(&key);
+            return ConnState::New;
+        }
+
+        conn.last_seen = now;
+        if let Some(state) = conn.tcp {
+            let flags = tcp_flags(key.protocol, pkt.payload());
+            conn.tcp = Some(next_tcp_state(
+                state,
+                flags,
+                outbound,
+                &mut conn.fin_out,
+                &mut conn.fin_in,
+            ));
+        }
+
+        ConnState::Established
+    }
+
+    fn insert_at(&mut self, pkt: &Ipv4Packet, now: Instant) {
+        if pkt.fragment_offset() != 0 {
+            return;
+        }
+
+        let Some(key) = FlowKey::from_packet(pkt) else {
+            return;
+        };
+
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL
+            || self.conns.len() >= self.cfg.max_entries
+        {
+            self.expire_at(now);
+        }
+
+        if self.conns.len() >= self.cfg.max_entries {
+            tracing::warn!(
+                max = self.cfg.max_entries,
+                "[conntrack] table full, not tracking connection"
+            );
+            return;
+        }
+
+        let flags = tcp_flags(key.protocol, pkt.payload());
+        let tcp = match key.protocol {
+            NET_PROTOCOL_TCP if flags & TCP_FLAG_SYN == TCP_FLAG_SYN => Some(TcpState::SynSent),
+            NET_PROTOCOL_TCP => Some(TcpState::Established),
+            _ => None,
+        };
+
+        tracing::trace!(?key, "[conntrack] tracking connection");
+        self.conns.insert(
+            key,
+            Conn {
+                tcp,
+                fin_out: false,
+                fin_in: false,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Removes all connections that have exceeded their idle timeout
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    fn expire_at(&mut self, now: Instant) {
+        let cfg = &self.cfg;
+        self.conns.retain(|key, conn| {
+            now.duration_since(conn.last_seen) < flow_timeout(cfg, key.protocol, conn.tcp)
+        });
+
+        self.last_sweep = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        protocols::{
+            icmp::{DestinationUnreachableCode, IcmpPacket},
+            NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP,
+        },
+        Ipv4Header, Ipv4Packet,
+    };
+
+    use super::{ConnState, ConnTrack};
+
+    const VM: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 101);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    fn packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Ipv4Packet {
+        let hdr = Ipv4Header::new(src, dst, protocol, payload.len() as u16);
+        let mut data = hdr.into_bytes().to_vec();
+        data.extend_from_slice(payload);
+        Ipv4Packet::parse(data).unwrap()
+    }
+
+    fn udp(src: Ipv4Addr, sport: u16, dst: Ipv4Addr, dport: u16) -> Ipv4Packet {
+        let mut payload = [0u8; 12];
+        payload[0..2].copy_from_slice(&sport.to_be_bytes());
+        payload[2..4].copy_from_slice(&dport.to_be_bytes());
+        payload[4..6].copy_from_slice(&12u16.to_be_bytes());
+        packet(src, dst, NET_PROTOCOL_UDP, &payload)
+    }
+
+    fn tcp(src: Ipv4Addr, sport: u16, dst: Ipv4Addr, dport: u16, flags: u8) -> Ipv4Packet {
+        let mut payload = [0u8; 20];
+        payload[0..2].copy_from_slice(&sport.to_be_bytes());
+        payload[2..4].copy_from_slice(&dport.to_be_bytes());
+        payload[12] = 0x50;
+        payload[13] = flags;
+        packet(src, dst, NET_PROTOCOL_TCP, &payload)
+    }
+
+    #[test]
+    fn conntrack_new_until_inserted() {
+        let mut ct = ConnTrack::default();
+        let out = udp(VM, 5000, REMOTE, 53);
+        let reply = udp(REMOTE, 53, VM, 5000);
+
+        assert_eq!(ct.state(&out), ConnState::New);
+        assert_eq!(ct.state(&reply), ConnState::New);
+        assert!(ct.is_empty());
+
+        ct.insert(&out);
+        assert_eq!(ct.state(&out), ConnState::Established);
+        assert_eq!(ct.state(&reply), ConnState::Established);
+
+        // different port, different flow
+        assert_eq!(ct.state(&udp(REMOTE, 53, VM, 5001)), ConnState::New);
+    }
+
+    #[test]
+    fn conntrack_related_icmp_error() {
+        let mut ct = ConnTrack::default();
+        let out = udp(VM, 5000, REMOTE, 53);
+
+        let icmp = IcmpPacket::unreachable(DestinationUnreachableCode::PortUnreachable, &out);
+        let mut buf = vec![0u8; icmp.size()];
+        icmp.as_bytes(&mut buf);
+        let error = Ipv4Packet::new(REMOTE, VM, NET_PROTOCOL_ICMP, &buf);
+
+        assert_eq!(ct.state(&error), ConnState::Invalid);
+
+        ct.insert(&out);
+        assert_eq!(ct.state(&error), ConnState::Related);
+    }
+
+    #[test]
+    fn conntrack_expires() {
+        let mut ct = ConnTrack::default();
+        let now = Instant::now();
+        let syn = tcp(VM, 40000, REMOTE, 443, 0x02);
+
+        ct.insert_at(&syn, now);
+        assert_eq!(ct.state_at(&syn, now), ConnState::Established);
+
+        // unanswered syn uses the transitory timeout
+        let later = now + Duration::from_secs(121);
+        assert_eq!(ct.state_at(&syn, later), ConnState::New);
+        assert!(ct.is_empty());
+    }
+}
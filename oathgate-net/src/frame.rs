@@ -7,10 +7,21 @@ use crate::{
 
 const ETHERNET_FRAME_SIZE: usize = 14;
 
+/// Size of an IEEE 802.1Q tag (TPID + TCI)
+const VLAN_TAG_SIZE: usize = 4;
+
+/// Mask of the VLAN identifier in the tag control information (TCI)
+const VLAN_ID_MASK: u16 = 0x0FFF;
+
 #[derive(Clone, Copy, Debug)]
 pub struct EthernetFrame {
     pub dst: MacAddress,
     pub src: MacAddress,
+
+    /// VLAN identifier of the 802.1Q tag, or None if the frame is untagged
+    pub vlan: Option<u16>,
+
+    /// Type of the payload (for tagged frames, the type following the tag)
     pub ethertype: EtherType,
 }
 
@@ -22,57 +33,115 @@ pub struct EthernetPacket {
 }
 
 impl EthernetFrame {
-    /// Creates a new EthernetFrame
+    /// Creates a new (untagged) EthernetFrame
     pub fn new(src: MacAddress, dst: MacAddress, ethertype: EtherType) -> Self {
         Self {
             dst,
             src,
+            vlan: None,
             ethertype,
         }
     }
 
-    /// Extracts an EthernetFrame from a packet received over the wire.
-    /// Returns an error if not enough data is provided to build an EthernetFrame.
+    /// Extracts an EthernetFrame from a packet received over the wire, removing the 802.1Q
+    /// tag (if present).  Returns an error if not enough data is provided to build an
+    /// EthernetFrame.
     ///
     /// ### Arguments
     /// * `pkt` - Bytes to extract etherframe from from
     pub fn extract(pkt: &mut Vec<u8>) -> Result<Self, ProtocolError> {
-        if pkt.len() < 14 {
-            return Err(ProtocolError::NotEnoughData(pkt.len(), 14));
+        if pkt.len() < ETHERNET_FRAME_SIZE {
+            return Err(ProtocolError::NotEnoughData(pkt.len(), ETHERNET_FRAME_SIZE));
         }
 
-        let hdr = pkt.drain(0..14).collect::<Vec<_>>();
-        let dst = MacAddress::parse(&hdr[0..6])?;
-        let src = MacAddress::parse(&hdr[6..12])?;
-        let ethertype = EtherType::try_from(&hdr[12..14])?;
+        let dst = MacAddress::parse(&pkt[0..6])?;
+        let src = MacAddress::parse(&pkt[6..12])?;
+        let mut ethertype = EtherType::try_from(&pkt[12..14])?;
+
+        let (vlan, sz) = match ethertype {
+            EtherType::Vlan => {
+                let sz = ETHERNET_FRAME_SIZE + VLAN_TAG_SIZE;
+                if pkt.len() < sz {
+                    return Err(ProtocolError::NotEnoughData(pkt.len(), sz));
+                }
+
+                let tci = u16::from_be_bytes([pkt[14], pkt[15]]);
+                ethertype = EtherType::try_from(&pkt[16..18])?;
+                if ethertype == EtherType::Vlan {
+                    return Err(ProtocolError::MalformedPacket(String::from(
+                        "stacked vlan tags not supported",
+                    )));
+                }
+
+                (Some(tci & VLAN_ID_MASK), sz)
+            }
+            _ => (None, ETHERNET_FRAME_SIZE),
+        };
+
+        pkt.drain(0..sz);
 
         Ok(Self {
             dst,
             src,
+            vlan,
             ethertype,
         })
     }
 
+    /// Returns the size of an untagged ethernet frame header
     pub fn size() -> usize {
         ETHERNET_FRAME_SIZE
     }
 
+    /// Returns the size of this frame's header, including the 802.1Q tag (if present)
+    pub fn header_len(&self) -> usize {
+        match self.vlan {
+            Some(_) => ETHERNET_FRAME_SIZE + VLAN_TAG_SIZE,
+            None => ETHERNET_FRAME_SIZE,
+        }
+    }
+
+    /// Returns a copy of this frame tagged with a VLAN identifier, or untagged if `vlan`
+    /// is None
+    ///
+    /// ### Arguments
+    /// * `vlan` - VLAN identifier (12 bits)
+    pub fn with_vlan(mut self, vlan: Option<u16>) -> Self {
+        self.vlan = vlan.map(|id| id & VLAN_ID_MASK);
+        self
+    }
+
     pub fn gen_reply(&self) -> Self {
         Self {
             dst: self.src,
             src: self.dst,
+            vlan: self.vlan,
             ethertype: self.ethertype,
         }
     }
 
+    /// Writes this frame's header into a buffer, which must be at least `header_len()` bytes
+    ///
+    /// ### Arguments
+    /// * `pkt` - Buffer to write the header into
     pub fn as_bytes(&self, pkt: &mut [u8]) {
         pkt[0..6].copy_from_slice(&self.dst.as_bytes());
         pkt[6..12].copy_from_slice(&self.src.as_bytes());
-        pkt[12..14].copy_from_slice(&self.ethertype.as_u16().to_be_bytes());
+
+        let offset = match self.vlan {
+            Some(vlan) => {
+                pkt[12..14].copy_from_slice(&EtherType::Vlan.as_u16().to_be_bytes());
+                pkt[14..16].copy_from_slice(&vlan.to_be_bytes());
+                VLAN_TAG_SIZE
+            }
+            None => 0,
+        };
+
+        pkt[(12 + offset)..(14 + offset)].copy_from_slice(&self.ethertype.as_u16().to_be_bytes());
     }
 
-    pub fn to_bytes(&self) -> [u8; ETHERNET_FRAME_SIZE] {
-        let mut bytes = [0u8; ETHERNET_FRAME_SIZE];
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.header_len()];
         self.as_bytes(&mut bytes);
         bytes
     }
@@ -88,3 +157,58 @@ impl EthernetPacket {
         Self { frame, payload }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{EtherType, MacAddress};
+
+    use super::EthernetFrame;
+
+    #[test]
+    fn frame_untagged_roundtrip() {
+        let frame = EthernetFrame::new(
+            MacAddress::generate(),
+            MacAddress::broadcast(),
+            EtherType::ARP,
+        );
+
+        let mut pkt = frame.to_bytes();
+        assert_eq!(pkt.len(), 14);
+        pkt.extend_from_slice(&[0xAA; 4]);
+
+        let parsed = EthernetFrame::extract(&mut pkt).unwrap();
+        assert_eq!(parsed.vlan, None);
+        assert_eq!(parsed.ethertype, EtherType::ARP);
+        assert_eq!(pkt, vec![0xAA; 4]);
+    }
+
+    #[test]
+    fn frame_tagged_roundtrip() {
+        let src = MacAddress::generate();
+        let frame =
+            EthernetFrame::new(src, MacAddress::broadcast(), EtherType::IPv4).with_vlan(Some(20));
+
+        let mut pkt = frame.to_bytes();
+        assert_eq!(pkt.len(), 18);
+        assert_eq!(&pkt[12..18], &[0x81, 0x00, 0x00, 0x14, 0x08, 0x00]);
+        pkt.extend_from_slice(&[0xAA; 4]);
+
+        let parsed = EthernetFrame::extract(&mut pkt).unwrap();
+        assert_eq!(parsed.vlan, Some(20));
+        assert_eq!(parsed.src, src);
+        assert_eq!(parsed.ethertype, EtherType::IPv4);
+        assert_eq!(pkt, vec![0xAA; 4]);
+    }
+
+    #[test]
+    fn frame_tag_priority_ignored() {
+        // pcp = 5, dei = 0, vid = 100
+        let mut pkt = vec![0xFF; 12];
+        pkt.extend_from_slice(&[0x81, 0x00, 0xA0, 0x64, 0x86, 0xDD]);
+
+        let parsed = EthernetFrame::extract(&mut pkt).unwrap();
+        assert_eq!(parsed.vlan, Some(100));
+        assert_eq!(parsed.ethertype, EtherType::IPv6);
+        assert!(pkt.is_empty());
+    }
+}
@@ -5,8 +5,8 @@ use std::net::Ipv4Addr;
 use rand::Rng;
 
 use crate::{
-    cast, ph_checksum,
-    protocols::{NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+    adjust_checksum, cast, ph_checksum,
+    protocols::{NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
     ProtocolError,
 };
 
@@ -183,6 +183,25 @@ impl Ipv4Header {
 }
 
 impl Ipv4Packet {
+    /// Builds a new IPv4 packet from a transport layer payload, computing the header
+    /// checksum and (for TCP/UDP) the transport checksum
+    ///
+    /// ### Arguments
+    /// * `src` - Source address
+    /// * `dst` - Destination address
+    /// * `protocol` - Next header protocol (e.g., TCP, UDP, etc)
+    /// * `payload` - Transport layer header and data
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Self {
+        let header = Ipv4Header::new(src, dst, protocol, payload.len() as u16);
+        let mut data = vec![0u8; header.header_length() + payload.len()];
+        header.as_bytes(&mut data);
+        data[header.header_length()..].copy_from_slice(payload);
+
+        let mut pkt = Self { header, data };
+        pkt.fix_transport_checksum();
+        pkt
+    }
+
     /// Parses an IPv4 packet, extracting the header from the start of the data vector
     ///
     /// Note: This does not drain the header from the vector. Use the `payload` function
@@ -210,6 +229,11 @@ impl Ipv4Packet {
         self.header.flags & 0x01 == 0x01
     }
 
+    /// Returns true if this packet must not be fragmented
+    pub fn dont_fragment(&self) -> bool {
+        self.header.flags & 0x02 == 0x02
+    }
+
     /// Returns the offset of the fragment (or zero, if no fragments)
     pub fn fragment_offset(&self) -> u16 {
         self.header.frag_offset
@@ -226,6 +250,26 @@ impl Ipv4Packet {
         self.header.length
     }
 
+    /// Returns the number of hops remaining before this packet is discarded
+    pub fn ttl(&self) -> u8 {
+        self.header.ttl
+    }
+
+    /// Decrements the time to live (as done when forwarding) and recomputes the header
+    /// checksum, returning the new value
+    pub fn decrement_ttl(&mut self) -> u8 {
+        self.header.ttl = self.header.ttl.saturating_sub(1);
+        self.data[8] = self.header.ttl;
+
+        // options are preserved, so the checksum covers the full header
+        let len = self.header.header_length();
+        self.data[10..12].copy_from_slice(&[0x00, 0x00]);
+        let csum = crate::checksum(&self.data[0..len]);
+        self.data[10..12].copy_from_slice(&csum.to_be_bytes());
+
+        self.header.ttl
+    }
+
     /// Returns the source ip address
     pub fn src(&self) -> Ipv4Addr {
         self.header.src
@@ -288,6 +332,57 @@ impl Ipv4Packet {
         }
     }
 
+    /// Splits this packet into fragments no larger than `mtu` bytes (including the header).
+    /// Returns the packet unchanged if it already fits.
+    ///
+    /// Note: Header options (if any) are copied to every fragment
+    ///
+    /// ### Arguments
+    /// * `mtu` - Maximum size of each fragment
+    pub fn fragment(self, mtu: u16) -> Vec<Ipv4Packet> {
+        if self.len() <= mtu {
+            return vec![self];
+        }
+
+        // the payload of every fragment but the last must be a multiple of 8 bytes
+        let hlen = self.header_length();
+        let max = std::cmp::max((usize::from(mtu).saturating_sub(hlen)) / 8 * 8, 8);
+
+        // a fragment being fragmented again keeps its offset and more-fragments flag
+        let offset = usize::from(self.fragment_offset());
+        let more = self.has_fragments();
+
+        let payload = self.payload();
+        let mut fragments = Vec::with_capacity(payload.len().div_ceil(max));
+        for (idx, chunk) in payload.chunks(max).enumerate() {
+            let start = idx * max;
+            let last = start + chunk.len() == payload.len();
+
+            let mut data = Vec::with_capacity(hlen + chunk.len());
+            data.extend_from_slice(&self.data[..hlen]);
+            data.extend_from_slice(chunk);
+
+            let flags: u8 = match last && !more {
+                true => 0x00,
+                false => 0x01,
+            };
+            let flags_frag = (u16::from(flags) << 13) | (((offset + start) / 8) as u16);
+
+            data[2..4].copy_from_slice(&((hlen + chunk.len()) as u16).to_be_bytes());
+            data[6..8].copy_from_slice(&flags_frag.to_be_bytes());
+            data[10..12].copy_from_slice(&[0x00, 0x00]);
+            let csum = crate::checksum(&data[..hlen]);
+            data[10..12].copy_from_slice(&csum.to_be_bytes());
+
+            // the header was copied from this packet, so it always parses
+            if let Ok(pkt) = Self::parse(data) {
+                fragments.push(pkt);
+            }
+        }
+
+        fragments
+    }
+
     /// Applies changes from the header field to the underlying data
     pub fn finalize(&mut self) {
         self.header.flags = 0;
@@ -295,14 +390,15 @@ impl Ipv4Packet {
         self.header.as_bytes(&mut self.data);
     }
 
-    /// Sets the source ip address to the provided value and recomputes the header checksum
+    /// Sets the source ip address to the provided value, recomputes the header checksum and
+    /// updates the transport checksum
     ///
     /// ### Arguments
     /// * `ip` - New src ip address
     pub fn masquerade(&mut self, ip: Ipv4Addr) {
-        self.header.masquerade(ip);
+        let old = self.header.masquerade(ip);
         self.header.as_bytes(&mut self.data);
-        self.fix_transport_checksum();
+        self.adjust_pseudo_header(old, ip);
     }
 
     /// Computes the checksum for this packet
@@ -310,18 +406,38 @@ impl Ipv4Packet {
         u16::from_be_bytes([self.data[10], self.data[11]])
     }
 
-    /// Sets the destination ip address to the provided value and recomputes the header checksum
+    /// Sets the destination ip address to the provided value, recomputes the header checksum
+    /// and updates the transport checksum
     ///
     /// ### Arguments
     /// * `ip` - New destinaton ip address
     pub fn unmasquerade(&mut self, ip: Ipv4Addr) {
-        self.header.unmasquerade(ip);
+        let old = self.header.unmasquerade(ip);
         self.header.as_bytes(&mut self.data);
-        self.fix_transport_checksum();
+        self.adjust_pseudo_header(old, ip);
+    }
+
+    /// Incrementally updates the TCP/UDP checksum after an address in the pseudo-header
+    /// changed.  The checksum covers the whole datagram, so it cannot be recomputed from a
+    /// single fragment; only the first fragment carries the transport header.
+    ///
+    /// ### Arguments
+    /// * `old` - Original address
+    /// * `new` - Updated address
+    fn adjust_pseudo_header(&mut self, old: Ipv4Addr, new: Ipv4Addr) {
+        let proto = self.protocol();
+        if self.fragment_offset() != 0 || !matches!(proto, NET_PROTOCOL_TCP | NET_PROTOCOL_UDP) {
+            return;
+        }
+
+        adjust_transport_checksum(proto, self.payload_mut(), &old.octets(), &new.octets());
     }
 
     /// TCP and UDP both use a pseudo-ip header in their checksum fields
     /// so we'll need to update the TCP/UDP checksum (if necessary)
+    ///
+    /// ICMP does not use a pseudo-header but its checksum is computed
+    /// over the message as well
     fn fix_transport_checksum(&mut self) {
         let src = self.src();
         let dst = self.dest();
@@ -329,6 +445,12 @@ impl Ipv4Packet {
         let payload = self.payload_mut();
 
         let (s, e) = match proto {
+            NET_PROTOCOL_ICMP if payload.len() >= 4 => {
+                payload[2..4].copy_from_slice(&[0, 0]);
+                let sum = crate::checksum(payload);
+                payload[2..4].copy_from_slice(&sum.to_be_bytes());
+                return;
+            }
             NET_PROTOCOL_TCP => (16, 18),
             NET_PROTOCOL_UDP => (6, 8),
             _ => {
@@ -351,6 +473,41 @@ impl Ipv4Packet {
     }
 }
 
+/// Incrementally updates the checksum of a transport header after data covered by the
+/// checksum changed (e.g., a port, or an address in the pseudo-header).  UDP datagrams sent
+/// without a checksum, and headers too short to contain the checksum, are left untouched.
+///
+/// ### Arguments
+/// * `proto` - Transport protocol (TCP, UDP or ICMP)
+/// * `transport` - Transport header and payload
+/// * `old` - Original data
+/// * `new` - Updated data
+pub(crate) fn adjust_transport_checksum(proto: u8, transport: &mut [u8], old: &[u8], new: &[u8]) {
+    let field = match proto {
+        NET_PROTOCOL_TCP => 16,
+        NET_PROTOCOL_UDP => 6,
+        NET_PROTOCOL_ICMP => 2,
+        _ => return,
+    };
+
+    let Some(bytes) = transport.get_mut(field..field + 2) else {
+        return;
+    };
+
+    let csum = cast!(be16, bytes);
+    let csum = match proto {
+        NET_PROTOCOL_UDP if csum == 0 => return,
+        // a computed checksum of zero is transmitted as all ones (RFC 768)
+        NET_PROTOCOL_UDP => match adjust_checksum(csum, old, new) {
+            0 => 0xFFFF,
+            csum => csum,
+        },
+        _ => adjust_checksum(csum, old, new),
+    };
+
+    bytes.copy_from_slice(&csum.to_be_bytes());
+}
+
 #[cfg(test)]
 mod tests {
     use std::{io::Read, net::Ipv4Addr};
@@ -385,4 +542,45 @@ mod tests {
         let tcp_csum = u16::from_be_bytes([payload[16], payload[17]]);
         assert_eq!(tcp_csum, 0xE6E7, "checksum mismatch");
     }
+
+    #[test]
+    fn decrement_ttl_updates_checksum() {
+        let src = Ipv4Addr::new(10, 0, 0, 100);
+        let dst = Ipv4Addr::new(1, 1, 1, 1);
+        let mut pkt = Ipv4Packet::new(src, dst, 17, &[0u8; 8]);
+
+        assert_eq!(pkt.decrement_ttl(), 63);
+        assert_eq!(pkt.as_bytes()[8], 63);
+        assert_eq!(crate::checksum(&pkt.as_bytes()[..20]), 0);
+        assert!(pkt.dont_fragment());
+    }
+
+    #[test]
+    fn fragment_to_mtu() {
+        let src = Ipv4Addr::new(10, 0, 0, 100);
+        let dst = Ipv4Addr::new(1, 1, 1, 1);
+        let payload = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        let pkt = Ipv4Packet::new(src, dst, 17, &payload);
+        let (id, payload) = (pkt.id(), pkt.payload().to_vec());
+
+        let fragments = pkt.fragment(576);
+        assert_eq!(fragments.len(), 2);
+
+        let (first, last) = (&fragments[0], &fragments[1]);
+        assert_eq!((first.len(), first.fragment_offset()), (572, 0));
+        assert_eq!((last.len(), last.fragment_offset()), (468, 552));
+        assert!(first.has_fragments() && !first.dont_fragment());
+        assert!(!last.has_fragments());
+
+        let mut rebuilt = Vec::new();
+        for fragment in &fragments {
+            assert_eq!(fragment.id(), id);
+            assert_eq!(crate::checksum(&fragment.as_bytes()[..20]), 0);
+            rebuilt.extend_from_slice(fragment.payload());
+        }
+        assert_eq!(rebuilt, payload);
+
+        let pkt = Ipv4Packet::new(src, dst, 17, &payload);
+        assert_eq!(pkt.fragment(1500).len(), 1);
+    }
 }
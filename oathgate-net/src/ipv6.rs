@@ -0,0 +1,268 @@
+//! IPv6 related structures
+
+use std::net::Ipv6Addr;
+
+use crate::{
+    cast, ph6_checksum,
+    protocols::{NET_PROTOCOL_ICMPV6, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+    ProtocolError,
+};
+
+/// Size of the (fixed) IPv6 header
+pub const IPV6_HDR_SZ: usize = 40;
+
+/// Represents the (fixed) Ipv6 header
+#[derive(Debug)]
+pub struct Ipv6Header {
+    pub version: u8,
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub length: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+}
+
+#[derive(Debug)]
+pub struct Ipv6Packet {
+    header: Ipv6Header,
+    data: Vec<u8>,
+}
+
+impl Ipv6Header {
+    /// Creates a new IPv6 header from the supplied values
+    ///
+    /// ### Arguments
+    /// * `src` - Source address
+    /// * `dst` - Destination address
+    /// * `next_header` - Next header protocol (e.g., ICMPv6, TCP, UDP, etc)
+    /// * `length` - Length of the payload data
+    pub fn new(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, length: u16) -> Self {
+        Self {
+            version: 6,
+            traffic_class: 0,
+            flow_label: 0,
+            length,
+            next_header,
+            hop_limit: 64,
+            src,
+            dst,
+        }
+    }
+
+    /// Extracts the IPv6 header from a slice of bytes, or returns an error
+    /// if the supplied buffer is too small
+    ///
+    /// ### Arguments
+    /// * `hdr` - Buffer containing ipv6 header
+    pub fn extract_from_slice(hdr: &[u8]) -> Result<Self, ProtocolError> {
+        if hdr.len() < IPV6_HDR_SZ {
+            return Err(ProtocolError::NotEnoughData(hdr.len(), IPV6_HDR_SZ));
+        }
+
+        let vtf = cast!(be32, hdr[0..4]);
+
+        Ok(Self {
+            version: (vtf >> 28) as u8,
+            traffic_class: ((vtf >> 20) & 0xFF) as u8,
+            flow_label: vtf & 0x000F_FFFF,
+            length: cast!(be16, hdr[4..6]),
+            next_header: hdr[6],
+            hop_limit: hdr[7],
+            src: Ipv6Addr::from(cast!(be128, hdr[8..24])),
+            dst: Ipv6Addr::from(cast!(be128, hdr[24..40])),
+        })
+    }
+
+    /// Writes this header into the first 40 bytes of the buffer
+    ///
+    /// ### Arguments
+    /// * `buf` - Buffer to write header into
+    pub fn as_bytes(&self, buf: &mut [u8]) {
+        let vtf = (u32::from(self.version) << 28)
+            | (u32::from(self.traffic_class) << 20)
+            | (self.flow_label & 0x000F_FFFF);
+
+        buf[0..4].copy_from_slice(&vtf.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.length.to_be_bytes());
+        buf[6] = self.next_header;
+        buf[7] = self.hop_limit;
+        buf[8..24].copy_from_slice(&self.src.octets());
+        buf[24..40].copy_from_slice(&self.dst.octets());
+    }
+}
+
+impl Ipv6Packet {
+    /// Builds a new IPv6 packet from an upper-layer payload, computing the
+    /// checksum for ICMPv6/TCP/UDP payloads
+    ///
+    /// ### Arguments
+    /// * `src` - Source address
+    /// * `dst` - Destination address
+    /// * `next_header` - Next header protocol (e.g., ICMPv6, TCP, UDP, etc)
+    /// * `payload` - Upper-layer header and data
+    pub fn new(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, payload: &[u8]) -> Self {
+        let header = Ipv6Header::new(src, dst, next_header, payload.len() as u16);
+        let mut data = vec![0u8; IPV6_HDR_SZ + payload.len()];
+        header.as_bytes(&mut data);
+        data[IPV6_HDR_SZ..].copy_from_slice(payload);
+
+        let mut pkt = Self { header, data };
+        pkt.fix_transport_checksum();
+        pkt
+    }
+
+    /// Parses an IPv6 packet, extracting the header from the start of the data vector
+    ///
+    /// Any data beyond the length stored in the header (i.e., ethernet padding) is removed
+    ///
+    /// ### Arguments
+    /// * `data` - An Ipv6 packet, including the header
+    pub fn parse(mut data: Vec<u8>) -> Result<Self, ProtocolError> {
+        let header = Ipv6Header::extract_from_slice(&data)?;
+        if header.version != 6 {
+            return Err(ProtocolError::MalformedPacket(format!(
+                "not an ipv6 packet, version = {}",
+                header.version
+            )));
+        }
+
+        let len = IPV6_HDR_SZ + usize::from(header.length);
+        if data.len() < len {
+            return Err(ProtocolError::NotEnoughData(data.len(), len));
+        }
+
+        data.truncate(len);
+        Ok(Self { header, data })
+    }
+
+    /// Returns the next header (i.e., transport layer) protocol
+    pub fn next_header(&self) -> u8 {
+        self.header.next_header
+    }
+
+    /// Returns the number of hops remaining before this packet is discarded
+    pub fn hop_limit(&self) -> u8 {
+        self.header.hop_limit
+    }
+
+    /// Sets the hop limit of this packet
+    ///
+    /// ### Arguments
+    /// * `hop_limit` - New hop limit
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.header.hop_limit = hop_limit;
+        self.data[7] = hop_limit;
+    }
+
+    /// Decrements the hop limit (as done when forwarding), returning the new value
+    pub fn decrement_hop_limit(&mut self) -> u8 {
+        let hop_limit = self.header.hop_limit.saturating_sub(1);
+        self.set_hop_limit(hop_limit);
+        hop_limit
+    }
+
+    /// Returns the length of the payload, as stored in the ipv6 header
+    pub fn payload_length(&self) -> u16 {
+        self.header.length
+    }
+
+    /// Returns the source ip address
+    pub fn src(&self) -> Ipv6Addr {
+        self.header.src
+    }
+
+    /// Returns the destination ip address
+    pub fn dest(&self) -> Ipv6Addr {
+        self.header.dst
+    }
+
+    /// Returns the slice of data containing the Ipv6 packet's payload
+    pub fn payload(&self) -> &[u8] {
+        &self.data[IPV6_HDR_SZ..]
+    }
+
+    /// Returns the slice of data containing the Ipv6 packet's payload
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.data[IPV6_HDR_SZ..]
+    }
+
+    /// Returns this packet as a slice of bytes, including the header
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns this packet as a vector of bytes
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// ICMPv6, TCP and UDP all use a pseudo-ip header in their checksum fields
+    fn fix_transport_checksum(&mut self) {
+        let src = self.src();
+        let dst = self.dest();
+        let proto = self.next_header();
+        let payload = self.payload_mut();
+
+        let (s, e) = match proto {
+            NET_PROTOCOL_ICMPV6 => (2, 4),
+            NET_PROTOCOL_TCP => (16, 18),
+            NET_PROTOCOL_UDP => (6, 8),
+            _ => return,
+        };
+
+        if payload.len() < e {
+            return;
+        }
+
+        payload[s..e].copy_from_slice(&[0, 0]);
+        let sum = ph6_checksum(src, dst, proto, payload);
+        payload[s..e].copy_from_slice(&sum.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use crate::{ph6_checksum, protocols::NET_PROTOCOL_ICMPV6};
+
+    use super::{Ipv6Packet, IPV6_HDR_SZ};
+
+    #[test]
+    fn ipv6_build_and_parse() {
+        let src: Ipv6Addr = "fe80::1".parse().unwrap();
+        let dst: Ipv6Addr = "ff02::1".parse().unwrap();
+        let payload = [128, 0, 0, 0, 0x12, 0x34, 0x00, 0x01, 0xAA];
+
+        let mut pkt = Ipv6Packet::new(src, dst, NET_PROTOCOL_ICMPV6, &payload);
+        pkt.set_hop_limit(255);
+
+        let mut data = pkt.into_bytes();
+        assert_eq!(data[0] >> 4, 6);
+        assert_eq!(data.len(), IPV6_HDR_SZ + payload.len());
+
+        // ethernet padding is stripped
+        data.extend_from_slice(&[0, 0, 0]);
+        let pkt = Ipv6Packet::parse(data).unwrap();
+        assert_eq!(pkt.src(), src);
+        assert_eq!(pkt.dest(), dst);
+        assert_eq!(pkt.hop_limit(), 255);
+        assert_eq!(pkt.next_header(), NET_PROTOCOL_ICMPV6);
+        assert_eq!(pkt.payload().len(), payload.len());
+
+        // a valid checksum sums to zero
+        assert_eq!(
+            ph6_checksum(src, dst, NET_PROTOCOL_ICMPV6, pkt.payload()),
+            0
+        );
+    }
+
+    #[test]
+    fn ipv6_parse_wrong_version() {
+        let mut data = vec![0u8; IPV6_HDR_SZ];
+        data[0] = 0x45;
+        assert!(Ipv6Packet::parse(data).is_err());
+    }
+}
@@ -1,15 +1,21 @@
+pub mod conntrack;
 mod frame;
 mod ipv4;
+mod ipv6;
 mod macros;
 pub mod nat;
 pub mod protocols;
+pub mod stats;
 pub mod types;
 
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use self::stats::Counters;
 
 pub use self::{
     frame::{EthernetFrame, EthernetPacket},
     ipv4::{Ipv4Header, Ipv4Packet},
+    ipv6::{Ipv6Header, Ipv6Packet, IPV6_HDR_SZ},
 };
 
 #[derive(thiserror::Error, Debug)]
@@ -28,6 +34,9 @@ pub trait Switch: Clone + Send + Sync {
     /// Returns the port associated with the new switch device
     fn connect<P: SwitchPort + 'static>(&self, port: P) -> usize;
 
+    /// Disconnects the device connected to a port, allowing the port to be reused
+    fn disconnect(&self, port: usize);
+
     /// Process a packet, sending it to the correct device
     fn process(&self, port: usize, pkt: Vec<u8>) -> Result<(), ProtocolError>;
 }
@@ -40,6 +49,18 @@ pub trait SwitchPort: Send + Sync {
     /// * `frame` - Ethernet frame header
     /// * `pkt` - Ethernet frame payload
     fn enqueue(&self, frame: EthernetFrame, pkt: Vec<u8>);
+
+    /// Closes the device's connection, disconnecting it from the switch.  Returns false if
+    /// the device cannot be disconnected
+    fn close(&self) -> bool {
+        false
+    }
+
+    /// Returns the counters of each of the device's queues, if the device keeps any
+    /// (e.g., the virtqueues of a virtio device, indexed by queue)
+    fn queue_counters(&self) -> Vec<Counters> {
+        Vec::new()
+    }
 }
 
 /// Computes the checksum used in various networking protocols
@@ -60,7 +81,31 @@ pub fn checksum(data: &[u8]) -> u16 {
         sum += u32::from_be_bytes([0x00, 0x00, b0, b1]);
     }
 
-    !(((sum & 0xFFFF) + ((sum >> 16) & 0xFFFF)) as u16)
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Incrementally updates an internet checksum after 16-bit aligned data changed (RFC 1624)
+///
+/// ### Arguments
+/// * `csum` - Original checksum
+/// * `old` - Original data
+/// * `new` - Updated data
+pub fn adjust_checksum(csum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = u32::from(!csum);
+    for (o, n) in old.chunks(2).zip(new.chunks(2)) {
+        sum += u32::from(!cast!(be16, o));
+        sum += u32::from(cast!(be16, n));
+    }
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
 }
 
 /// Computes the pseudo-header checksum as used by TCP and UDP
@@ -93,5 +138,44 @@ pub fn ph_checksum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, data: &[u8]) -> u16
         sum += u32::from_be_bytes([0x00, 0x00, b0, b1]);
     }
 
-    !(((sum & 0xFFFF) + ((sum >> 16) & 0xFFFF)) as u16)
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Computes the IPv6 pseudo-header checksum as used by TCP, UDP and ICMPv6
+///
+/// ### Arguments
+/// * `src` - Source IPv6 Address
+/// * `dst` - Destination IPv6 Address
+/// * `proto` - Next Header (i.e. 58 for ICMPv6)
+/// * `data` - Upper-layer header + payload
+pub fn ph6_checksum(src: Ipv6Addr, dst: Ipv6Addr, proto: u8, data: &[u8]) -> u16 {
+    let mut sum = 0;
+    for b in src.octets().chunks(2).chain(dst.octets().chunks(2)) {
+        sum += u32::from_be_bytes([0x00, 0x00, b[0], b[1]]);
+    }
+
+    let len = data.len() as u32;
+    sum += len >> 16;
+    sum += len & 0xFFFF;
+    sum += u32::from(proto);
+
+    for b in data.chunks(2) {
+        let b0 = b[0];
+        let b1 = match b.len() {
+            1 => 0x00,
+            _ => b[1],
+        };
+
+        sum += u32::from_be_bytes([0x00, 0x00, b0, b1]);
+    }
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
 }
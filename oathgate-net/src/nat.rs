@@ -1,126 +1,1443 @@
 //! NAT table implementation
+//!
+//! The `NatTable` performs source NAT (aka masquerading) with port translation and connection
+//! tracking.  Each outbound flow is identified by its 5-tuple (protocol, source address/port and
+//! destination address/port) and is assigned a unique source port (or ICMP identifier) from a
+//! configurable range.  Entries expire after a protocol-specific idle timeout.
+//!
+//! Port forwards (destination NAT) allow flows to be opened from the WAN: packets arriving on a
+//! forwarded port are translated to the address and port of a LAN device, and the resulting flow
+//! is tracked like any other.
+//!
+//! Only the first fragment of a fragmented datagram carries the transport header.  The
+//! translation applied to the first fragment is remembered (keyed by the datagram's ip id) so
+//! the remaining fragments are translated to the same flow.
 
-use std::{collections::HashMap, net::Ipv4Addr};
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    cast,
-    protocols::{NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
-    Ipv4Packet,
+    adjust_checksum, cast, checksum,
+    ipv4::adjust_transport_checksum,
+    protocols::{
+        icmp::{
+            ICMP_TY_DESTINATION_UNREACHABLE, ICMP_TY_ECHO_REPLY, ICMP_TY_ECHO_REQUEST,
+            ICMP_TY_PARAMETER_PROBLEM, ICMP_TY_REDIRECT, ICMP_TY_SOURCE_QUENCH,
+            ICMP_TY_TIMESTAMP_REPLY, ICMP_TY_TIMESTAMP_REQUEST, ICMP_TY_TIME_EXCEEDED,
+        },
+        tcp::{TCP_FLAG_ACK, TCP_FLAG_FIN, TCP_FLAG_RST, TCP_FLAG_SYN},
+        NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP,
+    },
+    Ipv4Header, Ipv4Packet, ProtocolError,
 };
 
+/// Minimum interval between sweeps for expired entries when inserting new entries
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time to wait for the remaining fragments of a datagram after the first fragment
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Settings that control port allocation, timeouts and size of a `NatTable`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NatConfig {
+    /// First port (inclusive) used when allocating source ports / ICMP identifiers
+    pub port_start: u16,
+
+    /// Last port (inclusive) used when allocating source ports / ICMP identifiers
+    pub port_end: u16,
+
+    /// Maximum number of entries (connections) tracked by the table
+    pub max_entries: usize,
+
+    /// Idle timeout, in seconds, of an established TCP connection
+    pub tcp_established_timeout: u64,
+
+    /// Idle timeout, in seconds, of a TCP connection being opened or closed
+    pub tcp_transitory_timeout: u64,
+
+    /// Idle timeout, in seconds, of a TCP connection after a RST or FIN in both directions
+    pub tcp_closed_timeout: u64,
+
+    /// Idle timeout, in seconds, of a UDP flow
+    pub udp_timeout: u64,
+
+    /// Idle timeout, in seconds, of an ICMP query (e.g., echo request)
+    pub icmp_timeout: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NatError {
+    #[error("no ports available for translation")]
+    PortsExhausted,
+
+    #[error("nat table is full, max entries = {0}")]
+    TableFull(usize),
+
+    #[error("unsupported protocol: {0}")]
+    UnsupportedProtocol(u8),
+
+    #[error("no translation found for non-initial fragment")]
+    Fragment,
+
+    #[error("no nat entry found for icmp error")]
+    NoEntry,
+
+    #[error("protocol: {0}")]
+    Protocol(#[from] ProtocolError),
+}
+
+/// The 5-tuple identifying a flow, as seen on the LAN side of the NAT
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct FlowKey {
+    /// Transport protocol (TCP, UDP, ICMP)
+    pub protocol: u8,
+
+    /// Source (internal) address
+    pub src: Ipv4Addr,
+
+    /// Source port, or ICMP identifier
+    pub src_port: u16,
+
+    /// Destination (remote) address
+    pub dst: Ipv4Addr,
+
+    /// Destination port, or ICMP identifier
+    pub dst_port: u16,
+}
+
+/// Forwards a port on the external address to a LAN device
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortForward {
+    /// Transport protocol (TCP or UDP)
+    pub protocol: u8,
+
+    /// Port on the external address
+    pub port: u16,
+
+    /// Address of the LAN device
+    pub to: Ipv4Addr,
+
+    /// Port on the LAN device
+    pub to_port: u16,
+}
+
+/// Key used to match a packet arriving from the WAN to an existing flow
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct ReplyKey {
+    protocol: u8,
+    remote: Ipv4Addr,
+    remote_port: u16,
+    external_port: u16,
+}
+
+/// Key used to match the fragments of a datagram to the flow of its first fragment
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct FragmentKey {
+    protocol: u8,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    id: u16,
+}
+
+/// Flow of a fragmented datagram, recorded when its first fragment is translated
+#[derive(Clone, Copy, Debug)]
+struct Fragment {
+    flow: FlowKey,
+    last_seen: Instant,
+}
+
+/// Simplified TCP connection state used to select an idle timeout
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TcpState {
+    /// SYN sent, waiting for a reply
+    SynSent,
+
+    /// Traffic has been seen in both directions
+    Established,
+
+    /// A FIN has been seen in one direction
+    FinWait,
+
+    /// A RST or a FIN in both directions has been seen
+    Closed,
+}
+
+/// An entry in the NAT table
+#[derive(Clone, Debug)]
+pub struct NatEntry {
+    /// Original flow (as seen on the LAN side)
+    pub flow: FlowKey,
+
+    /// Source port (or ICMP identifier) used on the WAN side
+    pub external_port: u16,
+
+    /// Tracked state of TCP connections, None for other protocols
+    pub tcp: Option<TcpState>,
+
+    /// Set if the flow was opened from the WAN through a port forward
+    pub forwarded: bool,
+
+    /// Set when a FIN is sent from the LAN
+    fin_out: bool,
+
+    /// Set when a FIN is received from the WAN
+    fin_in: bool,
+
+    /// Last time a packet matched this entry
+    last_seen: Instant,
+}
+
+/// Counters of a `NatTable`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NatStats {
+    /// Number of entries currently tracked
+    pub entries: u64,
+
+    /// Number of entries created
+    pub created: u64,
+
+    /// Number of entries removed after exceeding their idle timeout
+    pub expired: u64,
+
+    /// Number of packets translated from the LAN to the WAN
+    pub outbound: u64,
+
+    /// Number of packets translated from the WAN to the LAN
+    pub inbound: u64,
+
+    /// Number of packets received from the WAN that did not match an entry
+    pub unmatched: u64,
+
+    /// Number of packets that failed to be translated (e.g., ports exhausted)
+    pub failed: u64,
+}
+
 /// Network Address Translation (NAT) table
-#[derive(Default)]
 pub struct NatTable {
-    table: HashMap<NatEntry, Ipv4Addr>,
+    /// Port allocation, timeout and size settings
+    cfg: NatConfig,
+
+    /// Map of LAN-side flows to their translation
+    entries: HashMap<FlowKey, NatEntry>,
+
+    /// Map of WAN-side replies to the LAN-side flow
+    replies: HashMap<ReplyKey, FlowKey>,
+
+    /// Allocated (protocol, port) pairs
+    ports: HashSet<(u8, u16)>,
+
+    /// Port forwards, keyed by (protocol, external port)
+    forwards: HashMap<(u8, u16), PortForward>,
+
+    /// Flows of fragmented datagrams awaiting their remaining fragments
+    fragments: HashMap<FragmentKey, Fragment>,
+
+    /// Next port to attempt when allocating a port
+    next_port: u16,
+
+    /// Last time expired entries were removed
+    last_sweep: Instant,
+
+    /// Translation counters
+    stats: NatStats,
 }
 
-/// An entry in the NAT table
-#[derive(Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub enum NatEntry {
-    Tcp(Ipv4Addr, u16),
-    Udp(Ipv4Addr, u16),
-    Icmp(Ipv4Addr, u16),
+impl Default for NatConfig {
+    fn default() -> Self {
+        Self {
+            port_start: 49152,
+            port_end: 65535,
+            max_entries: 16384,
+            tcp_established_timeout: 7200,
+            tcp_transitory_timeout: 120,
+            tcp_closed_timeout: 10,
+            udp_timeout: 60,
+            icmp_timeout: 30,
+        }
+    }
+}
+
+impl Default for NatTable {
+    fn default() -> Self {
+        Self::new(NatConfig::default())
+    }
+}
+
+impl FlowKey {
+    /// Returns the flow of a (TCP, UDP or ICMP echo) packet sent from the LAN
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet sent by a LAN device, or the first fragment of a datagram
+    pub fn new(pkt: &Ipv4Packet) -> Result<Self, NatError> {
+        let (src_port, dst_port) = flow_ports(pkt.protocol(), pkt.payload())?;
+        Ok(Self {
+            protocol: pkt.protocol(),
+            src: pkt.src(),
+            src_port,
+            dst: pkt.dest(),
+            dst_port,
+        })
+    }
+}
+
+impl NatEntry {
+    /// Returns the time elapsed since a packet last matched this entry
+    pub fn idle(&self) -> Duration {
+        self.last_seen.elapsed()
+    }
+
+    /// Updates the tracked TCP state based on the flags of a segment
+    ///
+    /// ### Arguments
+    /// * `flags` - TCP flags of the segment
+    /// * `outbound` - True if the segment was sent from the LAN
+    fn update_tcp(&mut self, flags: u8, outbound: bool) {
+        if let Some(state) = self.tcp {
+            // forwarded connections are opened from the wan
+            self.tcp = Some(next_tcp_state(
+                state,
+                flags,
+                outbound != self.forwarded,
+                &mut self.fin_out,
+                &mut self.fin_in,
+            ));
+        }
+    }
+
+    /// Returns the idle timeout of this entry based on its protocol and state
+    ///
+    /// ### Arguments
+    /// * `cfg` - NAT configuration containing the timeout values
+    fn timeout(&self, cfg: &NatConfig) -> Duration {
+        flow_timeout(cfg, self.flow.protocol, self.tcp)
+    }
+
+    /// Returns the key used to match replies to this entry
+    fn reply_key(&self) -> ReplyKey {
+        // icmp replies echo the (translated) identifier
+        let remote_port = match self.flow.protocol {
+            NET_PROTOCOL_ICMP => self.external_port,
+            _ => self.flow.dst_port,
+        };
+
+        ReplyKey {
+            protocol: self.flow.protocol,
+            remote: self.flow.dst,
+            remote_port,
+            external_port: self.external_port,
+        }
+    }
+}
+
+impl FragmentKey {
+    /// Returns the key identifying the datagram a packet (fragment) belongs to
+    ///
+    /// ### Arguments
+    /// * `pkt` - Fragment of a datagram
+    fn new(pkt: &Ipv4Packet) -> Self {
+        Self {
+            protocol: pkt.protocol(),
+            src: pkt.src(),
+            dst: pkt.dest(),
+            id: pkt.id(),
+        }
+    }
 }
 
 impl NatTable {
     /// Creates a new, empty NAT table
-    pub fn new() -> Self {
-        Self::default()
+    ///
+    /// ### Arguments
+    /// * `cfg` - Port allocation, timeout and size settings
+    pub fn new(cfg: NatConfig) -> Self {
+        let next_port = cfg.port_start;
+
+        Self {
+            cfg,
+            entries: HashMap::new(),
+            replies: HashMap::new(),
+            ports: HashSet::new(),
+            forwards: HashMap::new(),
+            fragments: HashMap::new(),
+            next_port,
+            last_sweep: Instant::now(),
+            stats: NatStats::default(),
+        }
     }
 
-    /// Inserts an entry into the NAT table based on the src ip, dst ip, and
-    /// protocol specific information
-    pub fn insert(&mut self, pkt: &Ipv4Packet) {
-        match pkt.protocol() {
-            NET_PROTOCOL_ICMP => {
-                let id = cast!(be16, &pkt.payload()[4..6]);
-                self.put_icmp(pkt.dest(), id, pkt.src());
-            }
-            NET_PROTOCOL_TCP => {
-                let port = cast!(be16, &pkt.payload()[0..2]);
-                self.put_tcp(pkt.dest(), port, pkt.src());
-            }
-            NET_PROTOCOL_UDP => {
-                let port = cast!(be16, &pkt.payload()[0..2]);
-                self.put_udp(pkt.dest(), port, pkt.src());
-            }
-            _ => (),
+    /// Returns the counters of this table
+    pub fn stats(&self) -> NatStats {
+        NatStats {
+            entries: self.entries.len() as u64,
+            ..self.stats
         }
     }
 
-    /// Associates a TCP port with a specific address
+    /// Returns the number of entries in the table
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the table contains no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns an iterator over all entries in the table
+    pub fn entries(&self) -> impl Iterator<Item = &NatEntry> {
+        self.entries.values()
+    }
+
+    /// Removes all entries from the table
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.replies.clear();
+        self.ports.clear();
+        self.fragments.clear();
+    }
+
+    /// Removes all entries that have exceeded their idle timeout
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    /// Adds a port forward, allowing flows to be opened from the WAN.  The external port is
+    /// never allocated to outbound flows
     ///
     /// ### Arguments
-    /// * `dst` - Destination IP Address
-    /// * `port` - Destination TCP Port
-    /// * `src` - Source IPv4 Address
-    pub fn put_tcp(&mut self, dst: Ipv4Addr, port: u16, src: Ipv4Addr) {
-        tracing::trace!(?dst, ?src, port, "[nat], inserting tcp entry");
-        self.table.insert(NatEntry::Tcp(dst, port), src);
+    /// * `fwd` - Port to forward and the LAN device to forward it to
+    pub fn add_forward(&mut self, fwd: PortForward) {
+        tracing::debug!(?fwd, "[nat] adding port forward");
+        self.forwards.insert((fwd.protocol, fwd.port), fwd);
     }
 
-    /// Associates a UDP port with a specific address
+    /// Returns true if a packet sent from the LAN belongs to a flow opened through a port
+    /// forward (i.e., it is a reply that must leave through this table's WAN)
     ///
     /// ### Arguments
-    /// * `dst` - Destination IP Address
-    /// * `port` - Destination TCP Port
-    /// * `src` - Source IPv4 Address
-    pub fn put_udp(&mut self, dst: Ipv4Addr, port: u16, src: Ipv4Addr) {
-        tracing::trace!("[nat], inserting udp entry: {src} -> {dst}:{port}");
-        self.table.insert(NatEntry::Udp(dst, port), src);
+    /// * `pkt` - Packet sent by a LAN device
+    pub fn is_forwarded(&self, pkt: &Ipv4Packet) -> bool {
+        let flow = match pkt.fragment_offset() {
+            0 => FlowKey::new(pkt).ok(),
+            _ => self
+                .fragments
+                .get(&FragmentKey::new(pkt))
+                .map(|fragment| fragment.flow),
+        };
+
+        flow.and_then(|flow| self.entries.get(&flow))
+            .map(|entry| entry.forwarded)
+            .unwrap_or(false)
     }
 
-    /// Associates a UDP port with a specific address
+    /// Translates a packet sent from the LAN to the WAN, rewriting the source address to the
+    /// external address and the source port (or ICMP identifier) to an allocated port
     ///
     /// ### Arguments
-    /// * `dst` - Destination IP Address
-    /// * `id` - Unique ICMP identification number
-    /// * `src` - Source IPv4 Address
-    pub fn put_icmp(&mut self, dst: Ipv4Addr, id: u16, src: Ipv4Addr) {
-        tracing::trace!(?dst, ?src, id, "[nat], inserting icmp entry");
-        self.table.insert(NatEntry::Icmp(dst, id), src);
-    }
-
-    /// Returns the NAT'd ipv4 address for a packet, if one exists
-    pub fn get(&self, pkt: &Ipv4Packet) -> Option<Ipv4Addr> {
-        let src = pkt.src();
-
-        match pkt.protocol() {
-            NET_PROTOCOL_ICMP => {
-                let id = cast!(be16, &pkt.payload()[4..6]);
-                tracing::trace!(
-                    id = pkt.id(),
-                    len = pkt.len(),
-                    proto = "icmp",
-                    %src, %id,
-                    "[nat] looking up entry"
-                );
-                self.table.get(&NatEntry::Icmp(src, id)).copied()
+    /// * `pkt` - Packet to translate
+    /// * `external` - IPv4 address of the WAN interface
+    pub fn translate_outbound(
+        &mut self,
+        pkt: &mut Ipv4Packet,
+        external: Ipv4Addr,
+    ) -> Result<(), NatError> {
+        self.translate_outbound_at(pkt, external, Instant::now())
+    }
+
+    /// Translates a packet received from the WAN, restoring the original destination address
+    /// and port.  Returns false if the packet does not match an entry in the table.
+    ///
+    /// ### Arguments
+    /// * `pkt` - Packet to translate
+    pub fn translate_inbound(&mut self, pkt: &mut Ipv4Packet) -> Result<bool, NatError> {
+        self.translate_inbound_at(pkt, Instant::now())
+    }
+
+    fn translate_outbound_at(
+        &mut self,
+        pkt: &mut Ipv4Packet,
+        external: Ipv4Addr,
+        now: Instant,
+    ) -> Result<(), NatError> {
+        let res = self.masquerade_at(pkt, external, now);
+        match res {
+            Ok(()) => self.stats.outbound += 1,
+            Err(_) => self.stats.failed += 1,
+        }
+
+        res
+    }
+
+    fn translate_inbound_at(
+        &mut self,
+        pkt: &mut Ipv4Packet,
+        now: Instant,
+    ) -> Result<bool, NatError> {
+        let res = self.unmasquerade_at(pkt, now);
+        match res {
+            Ok(true) => self.stats.inbound += 1,
+            Ok(false) => self.stats.unmatched += 1,
+            Err(_) => self.stats.failed += 1,
+        }
+
+        res
+    }
+
+    /// Translates the source of a packet sent from the LAN, creating an entry for new flows
+    fn masquerade_at(
+        &mut self,
+        pkt: &mut Ipv4Packet,
+        external: Ipv4Addr,
+        now: Instant,
+    ) -> Result<(), NatError> {
+        if pkt.fragment_offset() != 0 {
+            return self.masquerade_fragment(pkt, external, now);
+        }
+
+        let protocol = pkt.protocol();
+        let payload = pkt.payload();
+        if protocol == NET_PROTOCOL_ICMP && is_icmp_error(payload) {
+            return self.translate_outbound_error(pkt, external);
+        }
+
+        let fragment = FragmentKey::new(pkt);
+
+        let flow = FlowKey::new(pkt)?;
+        let flags = tcp_flags(protocol, payload);
+
+        if !self.entries.contains_key(&flow) {
+            self.insert(flow, flags, None, now)?;
+        }
+
+        let entry = self.entries.get_mut(&flow).ok_or(NatError::NoEntry)?;
+        entry.last_seen = now;
+        entry.update_tcp(flags, true);
+        let external_port = entry.external_port;
+
+        if pkt.has_fragments() {
+            self.fragments.insert(
+                fragment,
+                Fragment {
+                    flow,
+                    last_seen: now,
+                },
+            );
+        }
+
+        set_src_port(protocol, pkt.payload_mut(), external_port);
+        pkt.masquerade(external);
+
+        Ok(())
+    }
+
+    /// Translates the source of a non-initial fragment sent from the LAN, using the flow of
+    /// the datagram's first fragment
+    fn masquerade_fragment(
+        &mut self,
+        pkt: &mut Ipv4Packet,
+        external: Ipv4Addr,
+        now: Instant,
+    ) -> Result<(), NatError> {
+        let flow = self.fragment_flow(pkt, now).ok_or(NatError::Fragment)?;
+        let entry = self.entries.get_mut(&flow).ok_or(NatError::NoEntry)?;
+        entry.last_seen = now;
+
+        pkt.masquerade(external);
+
+        Ok(())
+    }
+
+    /// Restores the destination of a packet received from the WAN, creating an entry for
+    /// flows opened through a port forward
+    fn unmasquerade_at(&mut self, pkt: &mut Ipv4Packet, now: Instant) -> Result<bool, NatError> {
+        if pkt.fragment_offset() != 0 {
+            return Ok(self.unmasquerade_fragment(pkt, now));
+        }
+
+        let protocol = pkt.protocol();
+        let payload = pkt.payload();
+        if protocol == NET_PROTOCOL_ICMP && is_icmp_error(payload) {
+            return self.translate_inbound_error(pkt);
+        }
+
+        let fragment = FragmentKey::new(pkt);
+
+        let (remote_port, external_port) = flow_ports(protocol, payload)?;
+        let flags = tcp_flags(protocol, payload);
+        let key = ReplyKey {
+            protocol,
+            remote: pkt.src(),
+            remote_port,
+            external_port,
+        };
+
+        if !self.replies.contains_key(&key) {
+            let Some(fwd) = self.forwards.get(&(protocol, external_port)).copied() else {
+                return Ok(false);
+            };
+
+            let flow = FlowKey {
+                protocol,
+                src: fwd.to,
+                src_port: fwd.to_port,
+                dst: key.remote,
+                dst_port: remote_port,
+            };
+
+            self.insert(flow, flags, Some(fwd.port), now)?;
+        }
+
+        let entry = match self
+            .replies
+            .get(&key)
+            .and_then(|flow| self.entries.get_mut(flow))
+        {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        entry.last_seen = now;
+        entry.update_tcp(flags, false);
+        let flow = entry.flow;
+
+        if pkt.has_fragments() {
+            self.fragments.insert(
+                fragment,
+                Fragment {
+                    flow,
+                    last_seen: now,
+                },
+            );
+        }
+
+        set_dst_port(protocol, pkt.payload_mut(), flow.src_port);
+        pkt.unmasquerade(flow.src);
+
+        Ok(true)
+    }
+
+    /// Restores the destination of a non-initial fragment received from the WAN, using the
+    /// flow of the datagram's first fragment.  Returns false if the first fragment was not
+    /// translated.
+    fn unmasquerade_fragment(&mut self, pkt: &mut Ipv4Packet, now: Instant) -> bool {
+        let Some(entry) = self
+            .fragment_flow(pkt, now)
+            .and_then(|flow| self.entries.get_mut(&flow))
+        else {
+            return false;
+        };
+
+        entry.last_seen = now;
+        let src = entry.flow.src;
+        pkt.unmasquerade(src);
+
+        true
+    }
+
+    /// Returns the flow of the datagram a non-initial fragment belongs to, forgetting the
+    /// datagram once its last fragment is seen
+    ///
+    /// ### Arguments
+    /// * `pkt` - Non-initial fragment
+    /// * `now` - Current time
+    fn fragment_flow(&mut self, pkt: &Ipv4Packet, now: Instant) -> Option<FlowKey> {
+        let key = FragmentKey::new(pkt);
+        match pkt.has_fragments() {
+            true => self.fragments.get_mut(&key).map(|fragment| {
+                fragment.last_seen = now;
+                fragment.flow
+            }),
+            false => self.fragments.remove(&key).map(|fragment| fragment.flow),
+        }
+    }
+
+    /// Translates an ICMP error sent by a LAN device about a packet it received from the WAN
+    fn translate_outbound_error(
+        &mut self,
+        pkt: &mut Ipv4Packet,
+        external: Ipv4Addr,
+    ) -> Result<(), NatError> {
+        // the quoted packet was sent from the remote to the LAN device
+        let quoted = QuotedPacket::parse(pkt.payload())?;
+        let flow = FlowKey {
+            protocol: quoted.protocol,
+            src: quoted.dst,
+            src_port: quoted.dst_port,
+            dst: quoted.src,
+            dst_port: quoted.src_port,
+        };
+
+        let entry = self.entries.get(&flow).ok_or(NatError::NoEntry)?;
+        quoted.rewrite(pkt.payload_mut(), false, external, entry.external_port);
+        pkt.masquerade(external);
+
+        Ok(())
+    }
+
+    /// Translates an ICMP error received from the WAN about a packet sent from the LAN
+    fn translate_inbound_error(&mut self, pkt: &mut Ipv4Packet) -> Result<bool, NatError> {
+        // the quoted packet was sent from the WAN interface to the remote
+        let quoted = QuotedPacket::parse(pkt.payload())?;
+        let key = ReplyKey {
+            protocol: quoted.protocol,
+            remote: quoted.dst,
+            remote_port: quoted.dst_port,
+            external_port: quoted.src_port,
+        };
+
+        let flow = match self.replies.get(&key) {
+            Some(flow) => *flow,
+            None => return Ok(false),
+        };
+
+        quoted.rewrite(pkt.payload_mut(), true, flow.src, flow.src_port);
+        pkt.unmasquerade(flow.src);
+
+        Ok(true)
+    }
+
+    /// Creates a new entry for a flow, allocating an external port unless the flow was
+    /// opened through a port forward
+    ///
+    /// ### Arguments
+    /// * `flow` - LAN-side flow to track
+    /// * `flags` - TCP flags of the first segment (zero for other protocols)
+    /// * `forwarded` - External port of the port forward that opened the flow
+    /// * `now` - Current time
+    fn insert(
+        &mut self,
+        flow: FlowKey,
+        flags: u8,
+        forwarded: Option<u16>,
+        now: Instant,
+    ) -> Result<(), NatError> {
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL
+            || self.entries.len() >= self.cfg.max_entries
+        {
+            self.expire_at(now);
+        }
+
+        if self.entries.len() >= self.cfg.max_entries {
+            return Err(NatError::TableFull(self.cfg.max_entries));
+        }
+
+        let external_port = match forwarded {
+            Some(port) => port,
+            None => self.allocate_port(flow.protocol, flow.src_port)?,
+        };
+
+        let tcp = match flow.protocol {
+            NET_PROTOCOL_TCP if flags & TCP_FLAG_SYN == TCP_FLAG_SYN => Some(TcpState::SynSent),
+            NET_PROTOCOL_TCP => Some(TcpState::Established),
+            _ => None,
+        };
+
+        let entry = NatEntry {
+            flow,
+            external_port,
+            tcp,
+            forwarded: forwarded.is_some(),
+            fin_out: false,
+            fin_in: false,
+            last_seen: now,
+        };
+
+        tracing::trace!(?flow, external_port, "[nat] inserting entry");
+        self.replies.insert(entry.reply_key(), flow);
+        self.entries.insert(flow, entry);
+        self.stats.created += 1;
+
+        Ok(())
+    }
+
+    /// Allocates an unused port for a protocol, preferring the original port if it is
+    /// within the configured range
+    ///
+    /// ### Arguments
+    /// * `protocol` - Transport protocol
+    /// * `preferred` - Original source port / ICMP identifier
+    fn allocate_port(&mut self, protocol: u8, preferred: u16) -> Result<u16, NatError> {
+        let (start, end) = (self.cfg.port_start, self.cfg.port_end);
+
+        let forwards = &self.forwards;
+        let mut allocate = |port: u16| {
+            (start..=end).contains(&port)
+                && !forwards.contains_key(&(protocol, port))
+                && self.ports.insert((protocol, port))
+        };
+
+        if allocate(preferred) {
+            return Ok(preferred);
+        }
+
+        let range = u32::from(end.saturating_sub(start)) + 1;
+        for _ in 0..range {
+            let port = self.next_port;
+            self.next_port = match port >= end || port < start {
+                true => start,
+                false => port + 1,
+            };
+
+            if allocate(port) {
+                return Ok(port);
             }
-            NET_PROTOCOL_TCP => {
-                let port = cast!(be16, &pkt.payload()[2..4]);
-                tracing::trace!(
-                    id = pkt.id(),
-                    len = pkt.len(),
-                    proto = "tcp",
-                    %src, %port,
-                    "[nat] looking up entry"
-                );
+        }
+
+        Err(NatError::PortsExhausted)
+    }
+
+    /// Removes all entries that have exceeded their idle timeout
+    ///
+    /// ### Arguments
+    /// * `now` - Current time
+    fn expire_at(&mut self, now: Instant) {
+        let cfg = &self.cfg;
+        let mut expired = Vec::new();
+        self.entries.retain(|_, entry| {
+            let alive = now.duration_since(entry.last_seen) < entry.timeout(cfg);
+            if !alive {
+                expired.push((entry.reply_key(), entry.external_port));
+            }
+            alive
+        });
+
+        self.stats.expired += expired.len() as u64;
+        for (key, port) in expired {
+            tracing::trace!(?key, "[nat] expiring entry");
+            self.replies.remove(&key);
+            self.ports.remove(&(key.protocol, port));
+        }
+
+        self.fragments
+            .retain(|_, fragment| now.duration_since(fragment.last_seen) < FRAGMENT_TIMEOUT);
+
+        self.last_sweep = now;
+    }
+}
 
-                self.table.get(&NatEntry::Tcp(src, port)).copied()
+/// Header fields of a packet quoted in the payload of an ICMP error message
+pub(crate) struct QuotedPacket {
+    /// Offset of the quoted ip header in the ICMP payload
+    ip_offset: usize,
+
+    /// Offset of the quoted transport header in the ICMP payload
+    transport_offset: usize,
+
+    pub(crate) protocol: u8,
+    pub(crate) src: Ipv4Addr,
+    pub(crate) src_port: u16,
+    pub(crate) dst: Ipv4Addr,
+    pub(crate) dst_port: u16,
+}
+
+impl QuotedPacket {
+    /// Parses the packet quoted by an ICMP error message
+    ///
+    /// ### Arguments
+    /// * `icmp` - ICMP message (header + payload)
+    pub(crate) fn parse(icmp: &[u8]) -> Result<Self, NatError> {
+        const ICMP_ERR_HDR_SZ: usize = 8;
+
+        let hdr = Ipv4Header::extract_from_slice(icmp.get(ICMP_ERR_HDR_SZ..).unwrap_or(&[]))?;
+        let transport_offset = ICMP_ERR_HDR_SZ + hdr.header_length();
+        let transport = icmp.get(transport_offset..).unwrap_or(&[]);
+        let (src_port, dst_port) = flow_ports(hdr.protocol, transport)?;
+
+        Ok(Self {
+            ip_offset: ICMP_ERR_HDR_SZ,
+            transport_offset,
+            protocol: hdr.protocol,
+            src: hdr.src,
+            src_port,
+            dst: hdr.dst,
+            dst_port,
+        })
+    }
+
+    /// Rewrites the source (or destination) address and port of the quoted packet, updating
+    /// the quoted header checksums and (incrementally) the checksum of the ICMP message
+    ///
+    /// ### Arguments
+    /// * `icmp` - ICMP message (header + payload)
+    /// * `src` - True to rewrite the source fields, false to rewrite the destination fields
+    /// * `ip` - New address
+    /// * `port` - New port (or ICMP identifier)
+    fn rewrite(&self, icmp: &mut [u8], src: bool, ip: Ipv4Addr, port: u16) {
+        let ip_field = match src {
+            true => self.ip_offset + 12,
+            false => self.ip_offset + 16,
+        };
+
+        let (port_field, csum_field) = match (self.protocol, src) {
+            (NET_PROTOCOL_ICMP, _) => (4, 2),
+            (NET_PROTOCOL_TCP, true) => (0, 16),
+            (NET_PROTOCOL_TCP, false) => (2, 16),
+            (_, true) => (0, 6),
+            (_, false) => (2, 6),
+        };
+        let port_field = self.transport_offset + port_field;
+        let csum_field = self.transport_offset + csum_field;
+
+        // every rewritten field is 16-bit aligned and ends before the quoted transport checksum
+        let end = std::cmp::min(icmp.len(), self.transport_offset + 18) & !1;
+        let original = icmp[self.ip_offset..end].to_vec();
+
+        let mut old = [0u8; 6];
+        old[0..4].copy_from_slice(&icmp[ip_field..ip_field + 4]);
+        old[4..6].copy_from_slice(&icmp[port_field..port_field + 2]);
+
+        let mut new = [0u8; 6];
+        new[0..4].copy_from_slice(&ip.octets());
+        new[4..6].copy_from_slice(&port.to_be_bytes());
+
+        icmp[ip_field..ip_field + 4].copy_from_slice(&new[0..4]);
+        icmp[port_field..port_field + 2].copy_from_slice(&new[4..6]);
+
+        // quoted transport checksum, if it was included in the quote.  ICMP checksums do
+        // not cover the ip addresses and a zero UDP checksum means no checksum was computed
+        if icmp.len() >= csum_field + 2 {
+            let csum = cast!(be16, icmp[csum_field..csum_field + 2]);
+            let csum = match self.protocol {
+                NET_PROTOCOL_ICMP => adjust_checksum(csum, &old[4..6], &new[4..6]),
+                NET_PROTOCOL_UDP if csum == 0 => 0,
+                _ => adjust_checksum(csum, &old, &new),
+            };
+            icmp[csum_field..csum_field + 2].copy_from_slice(&csum.to_be_bytes());
+        }
+
+        // quoted ip header checksum
+        let hdr_end = self.transport_offset;
+        icmp[self.ip_offset + 10..self.ip_offset + 12].copy_from_slice(&[0, 0]);
+        let csum = checksum(&icmp[self.ip_offset..hdr_end]);
+        icmp[self.ip_offset + 10..self.ip_offset + 12].copy_from_slice(&csum.to_be_bytes());
+
+        let csum = cast!(be16, icmp[2..4]);
+        let csum = adjust_checksum(csum, &original, &icmp[self.ip_offset..end]);
+        icmp[2..4].copy_from_slice(&csum.to_be_bytes());
+    }
+}
+
+/// Returns true if the ICMP message is an error message that quotes another packet
+///
+/// ### Arguments
+/// * `icmp` - ICMP message (header + payload)
+pub(crate) fn is_icmp_error(icmp: &[u8]) -> bool {
+    matches!(
+        icmp.first().copied(),
+        Some(
+            ICMP_TY_DESTINATION_UNREACHABLE
+                | ICMP_TY_SOURCE_QUENCH
+                | ICMP_TY_REDIRECT
+                | ICMP_TY_TIME_EXCEEDED
+                | ICMP_TY_PARAMETER_PROBLEM
+        )
+    )
+}
+
+/// Extracts the source and destination ports from a transport header.  For ICMP queries, the
+/// identifier is returned as both the source and destination port.
+///
+/// ### Arguments
+/// * `protocol` - Transport protocol
+/// * `transport` - Transport header and payload
+pub(crate) fn flow_ports(protocol: u8, transport: &[u8]) -> Result<(u16, u16), NatError> {
+    match protocol {
+        NET_PROTOCOL_TCP | NET_PROTOCOL_UDP => match transport.len() {
+            0..=3 => Err(ProtocolError::NotEnoughData(transport.len(), 4).into()),
+            _ => Ok((cast!(be16, transport[0..2]), cast!(be16, transport[2..4]))),
+        },
+        NET_PROTOCOL_ICMP => match transport.first().copied() {
+            Some(
+                ICMP_TY_ECHO_REQUEST
+                | ICMP_TY_ECHO_REPLY
+                | ICMP_TY_TIMESTAMP_REQUEST
+                | ICMP_TY_TIMESTAMP_REPLY,
+            ) if transport.len() >= 8 => {
+                let id = cast!(be16, transport[4..6]);
+                Ok((id, id))
             }
-            NET_PROTOCOL_UDP => {
-                let port = cast!(be16, &pkt.payload()[2..4]);
-                tracing::trace!(
-                    id = pkt.id(),
-                    len = pkt.len(),
-                    proto = "icmp",
-                    %src, %port,
-                    "[nat] looking up entry"
+            Some(ty) if transport.len() >= 8 => Err(ProtocolError::MalformedPacket(format!(
+                "unable to translate icmp type {ty}"
+            ))
+            .into()),
+            _ => Err(ProtocolError::NotEnoughData(transport.len(), 8).into()),
+        },
+        protocol => Err(NatError::UnsupportedProtocol(protocol)),
+    }
+}
+
+/// Returns the TCP flags of a segment, or zero if not a TCP segment
+pub(crate) fn tcp_flags(protocol: u8, transport: &[u8]) -> u8 {
+    match protocol {
+        NET_PROTOCOL_TCP => transport.get(13).copied().unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// Returns the next state of a tracked TCP connection after a segment is seen
+///
+/// ### Arguments
+/// * `state` - Current state of the connection
+/// * `flags` - TCP flags of the segment
+/// * `outbound` - True if the segment was sent by the side that opened the connection
+/// * `fin_out` - Set when a FIN is sent by the side that opened the connection
+/// * `fin_in` - Set when a FIN is sent by the other side
+pub(crate) fn next_tcp_state(
+    state: TcpState,
+    flags: u8,
+    outbound: bool,
+    fin_out: &mut bool,
+    fin_in: &mut bool,
+) -> TcpState {
+    if flags & TCP_FLAG_RST == TCP_FLAG_RST {
+        TcpState::Closed
+    } else if flags & TCP_FLAG_SYN == TCP_FLAG_SYN && outbound && state == TcpState::Closed {
+        // connection re-opened using the same 5-tuple
+        *fin_out = false;
+        *fin_in = false;
+        TcpState::SynSent
+    } else if flags & TCP_FLAG_FIN == TCP_FLAG_FIN {
+        match outbound {
+            true => *fin_out = true,
+            false => *fin_in = true,
+        }
+
+        match *fin_out && *fin_in {
+            true => TcpState::Closed,
+            false => TcpState::FinWait,
+        }
+    } else if state == TcpState::SynSent && !outbound && flags & TCP_FLAG_ACK == TCP_FLAG_ACK {
+        TcpState::Established
+    } else {
+        state
+    }
+}
+
+/// Returns the idle timeout of a flow based on its protocol and (TCP) state
+///
+/// ### Arguments
+/// * `cfg` - NAT configuration containing the timeout values
+/// * `protocol` - Transport protocol of the flow
+/// * `tcp` - Tracked state of a TCP connection
+pub(crate) fn flow_timeout(cfg: &NatConfig, protocol: u8, tcp: Option<TcpState>) -> Duration {
+    let secs = match (protocol, tcp) {
+        (NET_PROTOCOL_TCP, Some(TcpState::Established)) => cfg.tcp_established_timeout,
+        (NET_PROTOCOL_TCP, Some(TcpState::Closed)) => cfg.tcp_closed_timeout,
+        (NET_PROTOCOL_TCP, _) => cfg.tcp_transitory_timeout,
+        (NET_PROTOCOL_UDP, _) => cfg.udp_timeout,
+        _ => cfg.icmp_timeout,
+    };
+
+    Duration::from_secs(secs)
+}
+
+/// Sets the source port (or ICMP identifier) in a transport header, updating the checksum
+fn set_src_port(protocol: u8, transport: &mut [u8], port: u16) {
+    match protocol {
+        NET_PROTOCOL_ICMP => set_port(protocol, transport, 4, port),
+        _ => set_port(protocol, transport, 0, port),
+    }
+}
+
+/// Sets the destination port (or ICMP identifier) in a transport header, updating the checksum
+fn set_dst_port(protocol: u8, transport: &mut [u8], port: u16) {
+    match protocol {
+        NET_PROTOCOL_ICMP => set_port(protocol, transport, 4, port),
+        _ => set_port(protocol, transport, 2, port),
+    }
+}
+
+/// Replaces a port (or ICMP identifier) and incrementally updates the transport checksum, as
+/// the checksum of a fragmented datagram cannot be recomputed from its first fragment
+///
+/// ### Arguments
+/// * `protocol` - Transport protocol
+/// * `transport` - Transport header and payload
+/// * `field` - Offset of the port in the transport header
+/// * `port` - New port (or ICMP identifier)
+fn set_port(protocol: u8, transport: &mut [u8], field: usize, port: u16) {
+    let old = [transport[field], transport[field + 1]];
+    let new = port.to_be_bytes();
+    transport[field..field + 2].copy_from_slice(&new);
+    adjust_transport_checksum(protocol, transport, &old, &new);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        checksum, ph_checksum,
+        protocols::{NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+        Ipv4Header, Ipv4Packet,
+    };
+
+    use super::{NatConfig, NatError, NatTable, PortForward, TcpState};
+
+    const WAN: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+    const VM1: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 101);
+    const VM2: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 102);
+
+    fn packet(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Ipv4Packet {
+        Ipv4Packet::new(src, dst, protocol, payload)
+    }
+
+    fn udp(src: Ipv4Addr, sport: u16, dst: Ipv4Addr, dport: u16) -> Ipv4Packet {
+        let mut payload = [0u8; 12];
+        payload[0..2].copy_from_slice(&sport.to_be_bytes());
+        payload[2..4].copy_from_slice(&dport.to_be_bytes());
+        payload[4..6].copy_from_slice(&12u16.to_be_bytes());
+        packet(src, dst, NET_PROTOCOL_UDP, &payload)
+    }
+
+    fn tcp(src: Ipv4Addr, sport: u16, dst: Ipv4Addr, dport: u16, flags: u8) -> Ipv4Packet {
+        let mut payload = [0u8; 20];
+        payload[0..2].copy_from_slice(&sport.to_be_bytes());
+        payload[2..4].copy_from_slice(&dport.to_be_bytes());
+        payload[12] = 0x50;
+        payload[13] = flags;
+        packet(src, dst, NET_PROTOCOL_TCP, &payload)
+    }
+
+    fn ports(pkt: &Ipv4Packet) -> (u16, u16) {
+        let p = pkt.payload();
+        (
+            u16::from_be_bytes([p[0], p[1]]),
+            u16::from_be_bytes([p[2], p[3]]),
+        )
+    }
+
+    #[test]
+    fn nat_same_remote_port_no_collision() {
+        let mut nat = NatTable::default();
+
+        let mut a = udp(VM1, 50000, REMOTE, 53);
+        let mut b = udp(VM2, 50000, REMOTE, 53);
+        nat.translate_outbound(&mut a, WAN).unwrap();
+        nat.translate_outbound(&mut b, WAN).unwrap();
+
+        assert_eq!(a.src(), WAN);
+        assert_eq!(b.src(), WAN);
+        assert_ne!(ports(&a).0, ports(&b).0, "source ports must differ");
+
+        // replies are routed back to the correct vm
+        let mut reply = udp(REMOTE, 53, WAN, ports(&b).0);
+        assert!(nat.translate_inbound(&mut reply).unwrap());
+        assert_eq!(reply.dest(), VM2);
+        assert_eq!(ports(&reply).1, 50000);
+
+        let mut reply = udp(REMOTE, 53, WAN, ports(&a).0);
+        assert!(nat.translate_inbound(&mut reply).unwrap());
+        assert_eq!(reply.dest(), VM1);
+    }
+
+    #[test]
+    fn nat_port_allocated_from_range() {
+        let cfg = NatConfig {
+            port_start: 40000,
+            port_end: 40001,
+            ..Default::default()
+        };
+        let mut nat = NatTable::new(cfg);
+
+        let mut a = udp(VM1, 1234, REMOTE, 53);
+        nat.translate_outbound(&mut a, WAN).unwrap();
+        assert_eq!(ports(&a).0, 40000);
+
+        let mut b = udp(VM1, 1235, REMOTE, 53);
+        nat.translate_outbound(&mut b, WAN).unwrap();
+        assert_eq!(ports(&b).0, 40001);
+
+        let mut c = udp(VM1, 1236, REMOTE, 53);
+        let res = nat.translate_outbound(&mut c, WAN);
+        assert!(matches!(res, Err(NatError::PortsExhausted)));
+    }
+
+    #[test]
+    fn nat_unknown_inbound_not_translated() {
+        let mut nat = NatTable::default();
+        let mut pkt = udp(REMOTE, 53, WAN, 50000);
+        assert!(!nat.translate_inbound(&mut pkt).unwrap());
+    }
+
+    #[test]
+    fn nat_udp_entry_expires() {
+        let mut nat = NatTable::default();
+        let now = Instant::now();
+
+        let mut pkt = udp(VM1, 50000, REMOTE, 53);
+        nat.translate_outbound_at(&mut pkt, WAN, now).unwrap();
+        assert_eq!(nat.len(), 1);
+
+        nat.expire_at(now + Duration::from_secs(59));
+        assert_eq!(nat.len(), 1);
+
+        nat.expire_at(now + Duration::from_secs(61));
+        assert!(nat.is_empty());
+    }
+
+    #[test]
+    fn nat_stats() {
+        let mut nat = NatTable::default();
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            let mut pkt = udp(VM1, 50000, REMOTE, 53);
+            nat.translate_outbound_at(&mut pkt, WAN, now).unwrap();
+        }
+
+        let mut reply = udp(REMOTE, 53, WAN, 50000);
+        assert!(nat.translate_inbound_at(&mut reply, now).unwrap());
+
+        let mut unknown = udp(REMOTE, 53, WAN, 50001);
+        assert!(!nat.translate_inbound_at(&mut unknown, now).unwrap());
+
+        nat.expire_at(now + Duration::from_secs(61));
+
+        let stats = nat.stats();
+        assert_eq!(stats.entries, 0);
+        assert_eq!(stats.created, 1);
+        assert_eq!(stats.expired, 1);
+        assert_eq!(stats.outbound, 2);
+        assert_eq!(stats.inbound, 1);
+        assert_eq!(stats.unmatched, 1);
+        assert_eq!(stats.failed, 0);
+    }
+
+    #[test]
+    fn nat_tcp_state_tracking() {
+        let mut nat = NatTable::default();
+        let now = Instant::now();
+
+        let mut syn = tcp(VM1, 50000, REMOTE, 443, 0x02);
+        nat.translate_outbound_at(&mut syn, WAN, now).unwrap();
+        let ext = ports(&syn).0;
+
+        let mut synack = tcp(REMOTE, 443, WAN, ext, 0x12);
+        assert!(nat.translate_inbound_at(&mut synack, now).unwrap());
+        let state = nat.entries().next().unwrap().tcp;
+        assert_eq!(state, Some(TcpState::Established));
+
+        // established connections survive longer than udp flows
+        nat.expire_at(now + Duration::from_secs(3600));
+        assert_eq!(nat.len(), 1);
+
+        let mut rst = tcp(REMOTE, 443, WAN, ext, 0x04);
+        assert!(nat.translate_inbound_at(&mut rst, now).unwrap());
+        let state = nat.entries().next().unwrap().tcp;
+        assert_eq!(state, Some(TcpState::Closed));
+
+        nat.expire_at(now + Duration::from_secs(11));
+        assert!(nat.is_empty());
+    }
+
+    #[test]
+    fn nat_tcp_fin_both_directions_closes() {
+        let mut nat = NatTable::default();
+
+        let mut pkt = tcp(VM1, 50000, REMOTE, 443, 0x10);
+        nat.translate_outbound(&mut pkt, WAN).unwrap();
+        let ext = ports(&pkt).0;
+
+        let mut fin = tcp(VM1, 50000, REMOTE, 443, 0x11);
+        nat.translate_outbound(&mut fin, WAN).unwrap();
+        assert_eq!(nat.entries().next().unwrap().tcp, Some(TcpState::FinWait));
+
+        let mut fin = tcp(REMOTE, 443, WAN, ext, 0x11);
+        nat.translate_inbound(&mut fin).unwrap();
+        assert_eq!(nat.entries().next().unwrap().tcp, Some(TcpState::Closed));
+    }
+
+    #[test]
+    fn nat_icmp_echo_identifier() {
+        let mut nat = NatTable::default();
+
+        let mut echo = [0u8; 16];
+        echo[0] = 8;
+        echo[4..6].copy_from_slice(&7u16.to_be_bytes());
+        let mut req = packet(VM1, REMOTE, NET_PROTOCOL_ICMP, &echo);
+        nat.translate_outbound(&mut req, WAN).unwrap();
+        let id = u16::from_be_bytes([req.payload()[4], req.payload()[5]]);
+        assert_eq!(checksum(req.payload()), 0, "icmp checksum invalid");
+
+        echo[0] = 0;
+        echo[4..6].copy_from_slice(&id.to_be_bytes());
+        let mut reply = packet(REMOTE, WAN, NET_PROTOCOL_ICMP, &echo);
+        assert!(nat.translate_inbound(&mut reply).unwrap());
+        assert_eq!(reply.dest(), VM1);
+        assert_eq!(&reply.payload()[4..6], &7u16.to_be_bytes());
+        assert_eq!(checksum(reply.payload()), 0, "icmp checksum invalid");
+    }
+
+    #[test]
+    fn nat_icmp_error_translated() {
+        let mut nat = NatTable::default();
+
+        let mut out = udp(VM1, 50000, REMOTE, 33434);
+        nat.translate_outbound(&mut out, WAN).unwrap();
+
+        // port unreachable from the remote quoting the translated packet
+        let mut icmp = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        icmp.extend_from_slice(&out.as_bytes()[0..28]);
+        let router = Ipv4Addr::new(203, 0, 113, 1);
+        let mut err = packet(router, WAN, NET_PROTOCOL_ICMP, &icmp);
+
+        assert!(nat.translate_inbound(&mut err).unwrap());
+        assert_eq!(err.dest(), VM1);
+
+        let quoted = &err.payload()[8..];
+        let hdr = Ipv4Header::extract_from_slice(quoted).unwrap();
+        assert_eq!(hdr.src, VM1);
+        assert_eq!(hdr.dst, REMOTE);
+        assert_eq!(&quoted[20..22], &50000u16.to_be_bytes());
+        assert_eq!(checksum(&quoted[0..20]), 0, "quoted ip checksum invalid");
+        assert_eq!(checksum(err.payload()), 0, "icmp checksum invalid");
+    }
+
+    #[test]
+    fn nat_fragmented_datagram() {
+        let mut nat = NatTable::default();
+
+        // a large datagram, split in two fragments (e.g., an EDNS reply)
+        let datagram = |src, sport: u16, dst, dport: u16| {
+            let mut payload = (0..1200).map(|i| i as u8).collect::<Vec<_>>();
+            payload[0..2].copy_from_slice(&sport.to_be_bytes());
+            payload[2..4].copy_from_slice(&dport.to_be_bytes());
+            payload[4..6].copy_from_slice(&1200u16.to_be_bytes());
+            payload[6..8].copy_from_slice(&[0, 0]);
+            packet(src, dst, NET_PROTOCOL_UDP, &payload).fragment(576)
+        };
+
+        // the reassembled datagram must have a valid udp checksum
+        let reassemble = |fragments: &[Ipv4Packet]| {
+            let (src, dst) = (fragments[0].src(), fragments[0].dest());
+            let mut data = Vec::new();
+            for fragment in fragments {
+                assert_eq!(
+                    checksum(&fragment.as_bytes()[..20]),
+                    0,
+                    "ip checksum invalid"
                 );
-                self.table.get(&NatEntry::Udp(src, port)).copied()
+                data.extend_from_slice(fragment.payload());
             }
-            _ => None,
+            assert_eq!(ph_checksum(src, dst, NET_PROTOCOL_UDP, &data), 0);
+            data
+        };
+
+        let mut out = datagram(VM1, 50000, REMOTE, 53);
+        assert_eq!(out.len(), 3);
+        for fragment in out.iter_mut() {
+            nat.translate_outbound(fragment, WAN).unwrap();
+            assert_eq!(fragment.src(), WAN);
         }
+        let ext = ports(&out[0]).0;
+        reassemble(&out);
+
+        let mut reply = datagram(REMOTE, 53, WAN, ext);
+        for fragment in reply.iter_mut() {
+            assert!(nat.translate_inbound(fragment).unwrap());
+            assert_eq!(fragment.dest(), VM1);
+        }
+        let data = reassemble(&reply);
+        assert_eq!(&data[0..4], &[0, 53, 0xC3, 0x50]);
+        assert_eq!(nat.len(), 1);
+
+        // fragments of datagrams whose first fragment was not seen are not translated
+        let mut reply = datagram(REMOTE, 53, WAN, ext);
+        assert!(!nat.translate_inbound(&mut reply[1]).unwrap());
+
+        let mut out = datagram(VM1, 50000, REMOTE, 53);
+        let res = nat.translate_outbound(&mut out[2], WAN);
+        assert!(matches!(res, Err(NatError::Fragment)));
+    }
+
+    #[test]
+    fn nat_port_forward() {
+        let mut nat = NatTable::default();
+        nat.add_forward(PortForward {
+            protocol: NET_PROTOCOL_TCP,
+            port: 2222,
+            to: VM1,
+            to_port: 22,
+        });
+
+        // other protocols and ports are not forwarded
+        assert!(!nat
+            .translate_inbound(&mut udp(REMOTE, 40000, WAN, 2222))
+            .unwrap());
+
+        let mut syn = tcp(REMOTE, 40000, WAN, 2222, 0x02);
+        assert!(nat.translate_inbound(&mut syn).unwrap());
+        assert_eq!(syn.dest(), VM1);
+        assert_eq!(ports(&syn), (40000, 22));
+
+        let mut synack = tcp(VM1, 22, REMOTE, 40000, 0x12);
+        assert!(nat.is_forwarded(&synack));
+        nat.translate_outbound(&mut synack, WAN).unwrap();
+        assert_eq!(synack.src(), WAN);
+        assert_eq!(ports(&synack), (2222, 40000));
+
+        let mut ack = tcp(REMOTE, 40000, WAN, 2222, 0x10);
+        assert!(nat.translate_inbound(&mut ack).unwrap());
+        let entry = nat.entries().next().unwrap();
+        assert!(entry.forwarded);
+        assert_eq!(entry.tcp, Some(TcpState::Established));
+
+        // connections opened by the device from the forwarded port are not forwarded flows
+        let mut syn = tcp(VM1, 22, REMOTE, 443, 0x02);
+        assert!(!nat.is_forwarded(&syn));
+        nat.translate_outbound(&mut syn, WAN).unwrap();
+        assert!(!nat.is_forwarded(&tcp(VM1, 22, REMOTE, 443, 0x10)));
+    }
+
+    #[test]
+    fn nat_forwarded_port_not_allocated() {
+        let cfg = NatConfig {
+            port_start: 40000,
+            port_end: 40001,
+            ..Default::default()
+        };
+        let mut nat = NatTable::new(cfg);
+        nat.add_forward(PortForward {
+            protocol: NET_PROTOCOL_UDP,
+            port: 40000,
+            to: VM2,
+            to_port: 53,
+        });
+
+        let mut pkt = udp(VM1, 40000, REMOTE, 53);
+        nat.translate_outbound(&mut pkt, WAN).unwrap();
+        assert_eq!(ports(&pkt).0, 40001);
+    }
+
+    #[test]
+    fn nat_table_size_cap() {
+        let cfg = NatConfig {
+            max_entries: 2,
+            ..Default::default()
+        };
+        let mut nat = NatTable::new(cfg);
+
+        nat.translate_outbound(&mut udp(VM1, 1, REMOTE, 53), WAN)
+            .unwrap();
+        nat.translate_outbound(&mut udp(VM1, 2, REMOTE, 53), WAN)
+            .unwrap();
+        let res = nat.translate_outbound(&mut udp(VM1, 3, REMOTE, 53), WAN);
+        assert!(matches!(res, Err(NatError::TableFull(2))));
+
+        // existing flows continue to be translated
+        nat.translate_outbound(&mut udp(VM1, 1, REMOTE, 53), WAN)
+            .unwrap();
     }
 }
@@ -48,6 +48,26 @@ pub struct ArpPacket {
 }
 
 impl ArpPacket {
+    /// Builds an ARP (ipv4) request asking for the hardware address of `tpa`
+    ///
+    /// ### Arguments
+    /// * `sha` - MAC address of the host sending the request
+    /// * `spa` - IPv4 address of the host sending the request
+    /// * `tpa` - IPv4 address to resolve
+    pub fn request(sha: MacAddress, spa: Ipv4Addr, tpa: Ipv4Addr) -> Self {
+        Self {
+            hardware_type: 1,
+            protocol_type: EtherType::IPv4,
+            hardware_len: 6,
+            protocol_len: 4,
+            operation: 1,
+            sha,
+            spa: IpAddr::V4(spa),
+            tha: MacAddress::zero(),
+            tpa: IpAddr::V4(tpa),
+        }
+    }
+
     /// Parses an ARP packet from a byte buffer
     ///
     /// The byte buffer is expected to be in network (big) endian format
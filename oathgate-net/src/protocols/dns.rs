@@ -0,0 +1,319 @@
+//! DNS message structures
+
+use std::net::Ipv4Addr;
+
+use crate::{cast, ProtocolError};
+
+/// Size of a DNS message header
+pub const DNS_HDR_SZ: usize = 12;
+
+/// Well-known port of a DNS server
+pub const DNS_PORT: u16 = 53;
+
+pub const DNS_TY_A: u16 = 1;
+pub const DNS_TY_PTR: u16 = 12;
+pub const DNS_TY_AAAA: u16 = 28;
+pub const DNS_CLASS_IN: u16 = 1;
+
+pub const DNS_RCODE_NOERROR: u8 = 0;
+pub const DNS_RCODE_FORMERR: u8 = 1;
+pub const DNS_RCODE_SERVFAIL: u8 = 2;
+pub const DNS_RCODE_NXDOMAIN: u8 = 3;
+pub const DNS_RCODE_NOTIMP: u8 = 4;
+pub const DNS_RCODE_REFUSED: u8 = 5;
+
+/// Header flags
+const DNS_FLAG_QR: u16 = 0x8000;
+const DNS_FLAG_AA: u16 = 0x0400;
+const DNS_FLAG_RD: u16 = 0x0100;
+const DNS_FLAG_RA: u16 = 0x0080;
+
+/// Mask of the opcode in the header flags
+const DNS_OPCODE_MASK: u16 = 0x7800;
+
+/// Maximum length of a single label / an entire name
+const DNS_MAX_LABEL_LEN: usize = 63;
+const DNS_MAX_NAME_LEN: usize = 255;
+
+/// Compression pointer to the name of the first question (directly after the header)
+const DNS_PTR_QUESTION: [u8; 2] = [0xC0, DNS_HDR_SZ as u8];
+
+/// Data contained in a resource record
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DnsRecord {
+    /// IPv4 address
+    A(Ipv4Addr),
+
+    /// Domain name pointer (reverse lookup)
+    Ptr(String),
+}
+
+/// A DNS message containing (at least) one question
+#[derive(Clone, Debug)]
+pub struct DnsQuery {
+    /// Transaction id
+    pub id: u16,
+
+    /// Header flags
+    pub flags: u16,
+
+    /// Name being queried, lowercase and without a trailing dot
+    pub name: String,
+
+    /// Type of record being queried (i.e., A, PTR)
+    pub qtype: u16,
+
+    /// Class of record being queried (i.e., IN)
+    pub qclass: u16,
+
+    /// Offset of the end of the first question
+    question_end: usize,
+}
+
+impl DnsQuery {
+    /// Parses the header and first question of a DNS message
+    ///
+    /// ### Arguments
+    /// * `data` - DNS message
+    pub fn parse(data: &[u8]) -> Result<Self, ProtocolError> {
+        if data.len() < DNS_HDR_SZ {
+            return Err(ProtocolError::NotEnoughData(data.len(), DNS_HDR_SZ));
+        }
+
+        let qdcount = cast!(be16, data[4..6]);
+        if qdcount == 0 {
+            return Err(ProtocolError::MalformedPacket(
+                "dns message has no questions".into(),
+            ));
+        }
+
+        let (name, offset) = parse_name(data, DNS_HDR_SZ)?;
+        if data.len() < offset + 4 {
+            return Err(ProtocolError::NotEnoughData(data.len(), offset + 4));
+        }
+
+        Ok(Self {
+            id: cast!(be16, data[0..2]),
+            flags: cast!(be16, data[2..4]),
+            name,
+            qtype: cast!(be16, data[offset..offset + 2]),
+            qclass: cast!(be16, data[offset + 2..offset + 4]),
+            question_end: offset + 4,
+        })
+    }
+
+    /// Returns true if this message is a response
+    pub fn is_response(&self) -> bool {
+        self.flags & DNS_FLAG_QR == DNS_FLAG_QR
+    }
+
+    /// Returns the opcode of this message (0 is a standard query)
+    pub fn opcode(&self) -> u8 {
+        ((self.flags & DNS_OPCODE_MASK) >> 11) as u8
+    }
+
+    /// Writes an authoritative response to this query, returning the number of bytes written
+    ///
+    /// The response contains the original question followed by the answers
+    ///
+    /// ### Arguments
+    /// * `data` - Original query (used to copy the question)
+    /// * `rcode` - Response code
+    /// * `ttl` - Time to live of the answers, in seconds
+    /// * `answers` - Answers to the question
+    /// * `buf` - Buffer to write response into
+    pub fn reply(
+        &self,
+        data: &[u8],
+        rcode: u8,
+        ttl: u32,
+        answers: &[DnsRecord],
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        let question = &data[DNS_HDR_SZ..self.question_end];
+
+        let flags =
+            DNS_FLAG_QR | DNS_FLAG_AA | DNS_FLAG_RA | (self.flags & DNS_FLAG_RD) | u16::from(rcode);
+
+        let mut msg = Vec::with_capacity(self.question_end + answers.len() * 32);
+        msg.extend_from_slice(&self.id.to_be_bytes());
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes()); // qdcount
+        msg.extend_from_slice(&(answers.len() as u16).to_be_bytes()); // ancount
+        msg.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // nscount, arcount
+        msg.extend_from_slice(question);
+
+        for answer in answers {
+            let (ty, rdata) = match answer {
+                DnsRecord::A(ip) => (DNS_TY_A, ip.octets().to_vec()),
+                DnsRecord::Ptr(name) => (DNS_TY_PTR, encode_name(name)?),
+            };
+
+            msg.extend_from_slice(&DNS_PTR_QUESTION);
+            msg.extend_from_slice(&ty.to_be_bytes());
+            msg.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+            msg.extend_from_slice(&ttl.to_be_bytes());
+            msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&rdata);
+        }
+
+        if buf.len() < msg.len() {
+            return Err(ProtocolError::NotEnoughData(buf.len(), msg.len()));
+        }
+
+        buf[..msg.len()].copy_from_slice(&msg);
+        Ok(msg.len())
+    }
+}
+
+/// Parses a (possibly compressed) domain name, returning the lowercase name and the offset
+/// of the first byte after the name
+///
+/// ### Arguments
+/// * `data` - DNS message
+/// * `offset` - Offset of the start of the name
+fn parse_name(data: &[u8], mut offset: usize) -> Result<(String, usize), ProtocolError> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *data
+            .get(offset)
+            .ok_or(ProtocolError::NotEnoughData(data.len(), offset + 1))?;
+
+        match len {
+            0 => {
+                offset += 1;
+                break;
+            }
+            len if len & 0xC0 == 0xC0 => {
+                let ptr = data
+                    .get(offset + 1)
+                    .ok_or(ProtocolError::NotEnoughData(data.len(), offset + 2))?;
+
+                // guard against pointer loops
+                jumps += 1;
+                if jumps > 16 {
+                    return Err(ProtocolError::MalformedPacket(
+                        "dns name has too many compression pointers".into(),
+                    ));
+                }
+
+                end.get_or_insert(offset + 2);
+                offset = (usize::from(len & 0x3F) << 8) | usize::from(*ptr);
+            }
+            len if usize::from(len) > DNS_MAX_LABEL_LEN => {
+                return Err(ProtocolError::MalformedPacket(format!(
+                    "dns label too long: {len}"
+                )));
+            }
+            len => {
+                let start = offset + 1;
+                let label = data.get(start..start + usize::from(len)).ok_or(
+                    ProtocolError::NotEnoughData(data.len(), start + usize::from(len)),
+                )?;
+
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+
+                if name.len() > DNS_MAX_NAME_LEN {
+                    return Err(ProtocolError::MalformedPacket("dns name too long".into()));
+                }
+
+                offset = start + usize::from(len);
+            }
+        }
+    }
+
+    Ok((name, end.unwrap_or(offset)))
+}
+
+/// Encodes a domain name as a sequence of labels (without compression)
+///
+/// ### Arguments
+/// * `name` - Domain name (i.e., `host.example.com`)
+fn encode_name(name: &str) -> Result<Vec<u8>, ProtocolError> {
+    let mut buf = Vec::with_capacity(name.len() + 2);
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        if label.len() > DNS_MAX_LABEL_LEN {
+            return Err(ProtocolError::MalformedPacket(format!(
+                "dns label too long: {label}"
+            )));
+        }
+
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{DnsQuery, DnsRecord, DNS_RCODE_NOERROR, DNS_TY_A, DNS_TY_PTR};
+
+    const QUERY: [u8; 29] = [
+        0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // header
+        0x03, b'W', b'e', b'b', 0x04, b'T', b'e', b's', b't', 0x00, // web.test
+        0x00, 0x01, 0x00, 0x01, // A, IN
+        0x00, 0x00, 0x00, // trailing garbage
+    ];
+
+    #[test]
+    fn dns_parse_query() {
+        let query = DnsQuery::parse(&QUERY).unwrap();
+        assert_eq!(query.id, 0x1234);
+        assert_eq!(query.name, "web.test");
+        assert_eq!(query.qtype, DNS_TY_A);
+        assert_eq!(query.opcode(), 0);
+        assert!(!query.is_response());
+    }
+
+    #[test]
+    fn dns_reply_with_answers() {
+        let query = DnsQuery::parse(&QUERY).unwrap();
+        let answers = [
+            DnsRecord::A(Ipv4Addr::new(10, 0, 0, 5)),
+            DnsRecord::Ptr(String::from("web.test")),
+        ];
+
+        let mut buf = [0u8; 512];
+        let sz = query
+            .reply(&QUERY, DNS_RCODE_NOERROR, 60, &answers, &mut buf)
+            .unwrap();
+
+        let reply = DnsQuery::parse(&buf[..sz]).unwrap();
+        assert!(reply.is_response());
+        assert_eq!(reply.id, 0x1234);
+        assert_eq!(reply.name, "web.test");
+        assert_eq!(&buf[6..8], &[0x00, 0x02]);
+
+        // first answer: pointer to question, A record, address
+        assert_eq!(&buf[26..28], &[0xC0, 0x0C]);
+        assert_eq!(&buf[28..30], &DNS_TY_A.to_be_bytes());
+        assert_eq!(&buf[36..38], &[0x00, 0x04]);
+        assert_eq!(&buf[38..42], &[10, 0, 0, 5]);
+
+        // second answer: ptr record containing an encoded name
+        assert_eq!(&buf[44..46], &DNS_TY_PTR.to_be_bytes());
+        assert_eq!(&buf[54..64], b"\x03web\x04test\x00");
+        assert_eq!(sz, 64);
+    }
+
+    #[test]
+    fn dns_parse_pointer_loop() {
+        let mut data = QUERY[..12].to_vec();
+        data.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+        assert!(DnsQuery::parse(&data).is_err());
+    }
+}
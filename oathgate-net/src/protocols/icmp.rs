@@ -1,18 +1,42 @@
 //! ICMP related structures
 
-use crate::{checksum, Ipv4Header, ProtocolError};
+use crate::{cast, checksum, Ipv4Header, Ipv4Packet, ProtocolError};
 
 pub const ICMP_HDR_SZ: usize = 4;
 pub const ICMP_TY_ECHO_REPLY: u8 = 0;
 pub const ICMP_TY_DESTINATION_UNREACHABLE: u8 = 3;
+pub const ICMP_TY_SOURCE_QUENCH: u8 = 4;
+pub const ICMP_TY_REDIRECT: u8 = 5;
 pub const ICMP_TY_ECHO_REQUEST: u8 = 8;
+pub const ICMP_TY_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_TY_PARAMETER_PROBLEM: u8 = 12;
+pub const ICMP_TY_TIMESTAMP_REQUEST: u8 = 13;
+pub const ICMP_TY_TIMESTAMP_REPLY: u8 = 14;
+
+/// Number of bytes of the original datagram's payload included in an error message
+const ICMP_ERROR_QUOTE_SZ: usize = 8;
 
 #[derive(Debug)]
 pub enum IcmpType {
-    EchoReply { id: u16, seq: u16, data: Vec<u8> },
-    DestinationUnreachable(DestinationUnreachableCode, [u8; 28]),
+    EchoReply {
+        id: u16,
+        seq: u16,
+        data: Vec<u8>,
+    },
+    DestinationUnreachable {
+        code: DestinationUnreachableCode,
+        /// MTU of the next hop (only set when fragmentation is required)
+        mtu: u16,
+        /// IPv4 header and start of the payload of the original datagram
+        quote: Vec<u8>,
+    },
     Redirect,
-    EchoRequest { id: u16, seq: u16, data: Vec<u8> },
+    EchoRequest {
+        id: u16,
+        seq: u16,
+        data: Vec<u8>,
+    },
+    TimeExceeded(TimeExceededCode, Vec<u8>),
 }
 
 #[derive(Debug)]
@@ -35,17 +59,28 @@ pub enum DestinationUnreachableCode {
     PrecedenceCutoff,
 }
 
+#[derive(Debug)]
+pub enum TimeExceededCode {
+    TtlExceeded,
+    FragmentReassembly,
+}
+
 impl IcmpType {
     pub fn as_u8(&self) -> u16 {
         match self {
             Self::EchoReply { .. } => 0,
-            Self::DestinationUnreachable(code, _) => {
+            Self::DestinationUnreachable { code, .. } => {
                 let ty: u16 = 3;
                 let code: u16 = code.as_u8().into();
                 (ty << 8) | code
             }
             Self::Redirect => 5,
             Self::EchoRequest { .. } => 8,
+            Self::TimeExceeded(code, _) => {
+                let ty: u16 = 11;
+                let code: u16 = code.as_u8().into();
+                (ty << 8) | code
+            }
         }
     }
 }
@@ -73,29 +108,160 @@ impl DestinationUnreachableCode {
     }
 }
 
+impl TryFrom<u8> for DestinationUnreachableCode {
+    type Error = ProtocolError;
+
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        match code {
+            0 => Ok(Self::NetworkUnreachable),
+            1 => Ok(Self::HostUnreachable),
+            2 => Ok(Self::ProtocolUnreachable),
+            3 => Ok(Self::PortUnreachable),
+            4 => Ok(Self::FragmentationRequired),
+            5 => Ok(Self::SourceRouteFailed),
+            6 => Ok(Self::NetworkUnknown),
+            7 => Ok(Self::HostUnknown),
+            8 => Ok(Self::SourceHostIsolated),
+            9 => Ok(Self::NetworkAdminProhibited),
+            10 => Ok(Self::HostAdminProhibited),
+            11 => Ok(Self::NetworkUnreachableToS),
+            12 => Ok(Self::HostUnreachableToS),
+            13 => Ok(Self::CommAdminProhibited),
+            14 => Ok(Self::HostPrecedenceViolation),
+            15 => Ok(Self::PrecedenceCutoff),
+            code => Err(ProtocolError::MalformedPacket(format!(
+                "invalid destination unreachable code: {code}"
+            ))),
+        }
+    }
+}
+
+impl TimeExceededCode {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            TimeExceededCode::TtlExceeded => 0,
+            TimeExceededCode::FragmentReassembly => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct IcmpPacket {
     ty: IcmpType,
 }
 
 impl IcmpPacket {
-    pub fn echo_request() -> Self {
-        todo!("implement this")
+    /// Creates a new echo request (ping)
+    ///
+    /// ### Arguments
+    /// * `id` - Identifier used to match replies to requests
+    /// * `seq` - Sequence number of the request
+    /// * `data` - Data to be echoed back
+    pub fn echo_request(id: u16, seq: u16, data: &[u8]) -> Self {
+        Self {
+            ty: IcmpType::EchoRequest {
+                id,
+                seq,
+                data: data.to_vec(),
+            },
+        }
     }
 
-    pub fn echo_reply() -> Self {
-        todo!("implement this")
+    /// Creates a new echo reply
+    ///
+    /// ### Arguments
+    /// * `id` - Identifier of the request
+    /// * `seq` - Sequence number of the request
+    /// * `data` - Data contained in the request
+    pub fn echo_reply(id: u16, seq: u16, data: &[u8]) -> Self {
+        Self {
+            ty: IcmpType::EchoReply {
+                id,
+                seq,
+                data: data.to_vec(),
+            },
+        }
     }
 
+    /// Creates a destination unreachable message from a (rebuilt) header and payload
+    ///
+    /// ### Arguments
+    /// * `code` - Reason the destination is unreachable
+    /// * `hdr` - IPv4 header of the original datagram
+    /// * `payload` - Payload of the original datagram
     pub fn destination_unreachable(
         code: DestinationUnreachableCode,
         hdr: &Ipv4Header,
         payload: &[u8],
     ) -> Self {
-        let mut buf = [0u8; 28];
-        hdr.as_bytes(&mut buf);
-        buf[20..28].copy_from_slice(&payload[0..8]);
+        let len = std::cmp::min(payload.len(), ICMP_ERROR_QUOTE_SZ);
+        let mut quote = vec![0u8; 20 + len];
+        hdr.as_bytes(&mut quote);
+        quote[20..].copy_from_slice(&payload[..len]);
+        Self {
+            ty: IcmpType::DestinationUnreachable {
+                code,
+                mtu: 0,
+                quote,
+            },
+        }
+    }
+
+    /// Creates a destination unreachable message for a datagram that could not be delivered
+    ///
+    /// ### Arguments
+    /// * `code` - Reason the destination is unreachable
+    /// * `pkt` - Original datagram
+    pub fn unreachable(code: DestinationUnreachableCode, pkt: &Ipv4Packet) -> Self {
+        Self {
+            ty: IcmpType::DestinationUnreachable {
+                code,
+                mtu: 0,
+                quote: quote(pkt),
+            },
+        }
+    }
+
+    /// Creates a fragmentation needed message for a datagram that is too large for the next
+    /// hop but has the don't fragment flag set (used for path MTU discovery)
+    ///
+    /// ### Arguments
+    /// * `mtu` - MTU of the next hop
+    /// * `pkt` - Original datagram
+    pub fn fragmentation_needed(mtu: u16, pkt: &Ipv4Packet) -> Self {
         Self {
-            ty: IcmpType::DestinationUnreachable(code, buf),
+            ty: IcmpType::DestinationUnreachable {
+                code: DestinationUnreachableCode::FragmentationRequired,
+                mtu,
+                quote: quote(pkt),
+            },
+        }
+    }
+
+    /// Creates a time exceeded message for a datagram that was discarded
+    ///
+    /// ### Arguments
+    /// * `code` - Reason the datagram was discarded
+    /// * `pkt` - Original datagram
+    pub fn time_exceeded(code: TimeExceededCode, pkt: &Ipv4Packet) -> Self {
+        Self {
+            ty: IcmpType::TimeExceeded(code, quote(pkt)),
+        }
+    }
+
+    /// Returns the type (and contents) of this message
+    pub fn ty(&self) -> &IcmpType {
+        &self.ty
+    }
+
+    /// Returns the size of this message, in bytes
+    pub fn size(&self) -> usize {
+        match &self.ty {
+            IcmpType::EchoRequest { data, .. } | IcmpType::EchoReply { data, .. } => 8 + data.len(),
+            IcmpType::DestinationUnreachable { quote, .. } | IcmpType::TimeExceeded(_, quote) => {
+                8 + quote.len()
+            }
+            IcmpType::Redirect => 0,
         }
     }
 
@@ -121,7 +287,29 @@ impl IcmpPacket {
                     ty: IcmpType::EchoRequest { id, seq, data },
                 })
             }
-            _ => todo!(),
+            ICMP_TY_DESTINATION_UNREACHABLE | ICMP_TY_TIME_EXCEEDED if data.len() < 8 => {
+                Err(ProtocolError::NotEnoughData(data.len(), 8))
+            }
+            ICMP_TY_DESTINATION_UNREACHABLE => Ok(IcmpPacket {
+                ty: IcmpType::DestinationUnreachable {
+                    code: DestinationUnreachableCode::try_from(data[1])?,
+                    mtu: cast!(be16, data[6..8]),
+                    quote: data[8..].to_vec(),
+                },
+            }),
+            ICMP_TY_TIME_EXCEEDED => {
+                let code = match data[1] {
+                    0 => TimeExceededCode::TtlExceeded,
+                    _ => TimeExceededCode::FragmentReassembly,
+                };
+
+                Ok(IcmpPacket {
+                    ty: IcmpType::TimeExceeded(code, data[8..].to_vec()),
+                })
+            }
+            ty => Err(ProtocolError::MalformedPacket(format!(
+                "unsupported icmp type: {ty}"
+            ))),
         }
     }
 
@@ -154,16 +342,127 @@ impl IcmpPacket {
                 end
             }
             IcmpType::Redirect => 0,
-            IcmpType::DestinationUnreachable(code, data) => {
+            IcmpType::DestinationUnreachable { code, mtu, quote } => {
+                let end = 8 + quote.len();
                 buf[0] = ICMP_TY_DESTINATION_UNREACHABLE;
                 buf[1] = code.as_u8();
+                buf[2..6].copy_from_slice(&[0, 0, 0, 0]);
+                buf[6..8].copy_from_slice(&mtu.to_be_bytes());
+                buf[8..end].copy_from_slice(quote);
+
+                let csum = checksum(&buf[0..end]);
+                buf[2..4].copy_from_slice(&csum.to_be_bytes());
+                end
+            }
+            IcmpType::TimeExceeded(code, quote) => {
+                let end = 8 + quote.len();
+                buf[0] = ICMP_TY_TIME_EXCEEDED;
+                buf[1] = code.as_u8();
                 buf[2..8].copy_from_slice(&[0, 0, 0, 0, 0, 0]);
-                buf[8..36].copy_from_slice(data.as_slice());
+                buf[8..end].copy_from_slice(quote);
 
-                let csum = checksum(&buf[0..36]);
+                let csum = checksum(&buf[0..end]);
                 buf[2..4].copy_from_slice(&csum.to_be_bytes());
-                36
+                end
+            }
+        }
+    }
+}
+
+/// Returns true if an ICMP message type is an error message (which must never trigger
+/// another error message)
+///
+/// ### Arguments
+/// * `ty` - ICMP message type
+pub fn is_error(ty: u8) -> bool {
+    matches!(
+        ty,
+        ICMP_TY_DESTINATION_UNREACHABLE
+            | ICMP_TY_SOURCE_QUENCH
+            | ICMP_TY_REDIRECT
+            | ICMP_TY_TIME_EXCEEDED
+            | ICMP_TY_PARAMETER_PROBLEM
+    )
+}
+
+/// Returns the part of a datagram quoted in an error message: the original ipv4 header
+/// (including options) and the first 8 bytes of the payload
+///
+/// ### Arguments
+/// * `pkt` - Original datagram
+fn quote(pkt: &Ipv4Packet) -> Vec<u8> {
+    let data = pkt.as_bytes();
+    let len = std::cmp::min(data.len(), pkt.header_length() + ICMP_ERROR_QUOTE_SZ);
+    data[..len].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{checksum, protocols::NET_PROTOCOL_UDP, Ipv4Packet};
+
+    use super::{IcmpPacket, IcmpType, TimeExceededCode, ICMP_TY_TIME_EXCEEDED};
+
+    fn datagram() -> Ipv4Packet {
+        let payload = [
+            0x9c, 0x40, 0x82, 0x9b, 0x00, 0x10, 0x00, 0x00, 1, 2, 3, 4, 5, 6, 7, 8,
+        ];
+        Ipv4Packet::new(
+            Ipv4Addr::new(10, 0, 0, 100),
+            Ipv4Addr::new(1, 1, 1, 1),
+            NET_PROTOCOL_UDP,
+            &payload,
+        )
+    }
+
+    #[test]
+    fn icmp_echo_roundtrip() {
+        let request = IcmpPacket::echo_request(0x1234, 7, b"ping");
+        let mut buf = [0u8; 64];
+        let sz = request.as_bytes(&mut buf);
+        assert_eq!(sz, request.size());
+        assert_eq!(checksum(&buf[..sz]), 0);
+
+        match IcmpPacket::parse(&buf[..sz]).unwrap().ty() {
+            IcmpType::EchoRequest { id, seq, data } => {
+                let reply = IcmpPacket::echo_reply(*id, *seq, data);
+                assert!(matches!(reply.ty(), IcmpType::EchoReply { seq: 7, .. }));
+            }
+            ty => panic!("unexpected icmp type: {ty:?}"),
+        }
+    }
+
+    #[test]
+    fn icmp_time_exceeded_quotes_datagram() {
+        let pkt = datagram();
+        let icmp = IcmpPacket::time_exceeded(TimeExceededCode::TtlExceeded, &pkt);
+
+        let mut buf = [0u8; 64];
+        let sz = icmp.as_bytes(&mut buf);
+        assert_eq!(sz, 8 + 20 + 8);
+        assert_eq!(buf[0], ICMP_TY_TIME_EXCEEDED);
+        assert_eq!(checksum(&buf[..sz]), 0);
+
+        // original header (as received) and the first 8 bytes of the payload
+        assert_eq!(&buf[8..sz], &pkt.as_bytes()[..28]);
+    }
+
+    #[test]
+    fn icmp_fragmentation_needed_mtu() {
+        let icmp = IcmpPacket::fragmentation_needed(1420, &datagram());
+
+        let mut buf = [0u8; 64];
+        let sz = icmp.as_bytes(&mut buf);
+        assert_eq!(&buf[0..2], &[3, 4]);
+        assert_eq!(&buf[6..8], &1420u16.to_be_bytes());
+
+        match IcmpPacket::parse(&buf[..sz]).unwrap().ty() {
+            IcmpType::DestinationUnreachable { mtu, quote, .. } => {
+                assert_eq!(*mtu, 1420);
+                assert_eq!(quote.len(), 28);
             }
+            ty => panic!("unexpected icmp type: {ty:?}"),
         }
     }
 }
@@ -0,0 +1,332 @@
+//! ICMPv6 and Neighbor Discovery (NDP) related structures
+
+use std::net::Ipv6Addr;
+
+use crate::{
+    cast,
+    types::{Ipv6Network, MacAddress},
+    ProtocolError, IPV6_HDR_SZ,
+};
+
+pub const ICMPV6_HDR_SZ: usize = 4;
+pub const ICMPV6_TY_DESTINATION_UNREACHABLE: u8 = 1;
+pub const ICMPV6_TY_PACKET_TOO_BIG: u8 = 2;
+pub const ICMPV6_TY_TIME_EXCEEDED: u8 = 3;
+pub const ICMPV6_TY_PARAMETER_PROBLEM: u8 = 4;
+pub const ICMPV6_TY_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_TY_ECHO_REPLY: u8 = 129;
+pub const ICMPV6_TY_ROUTER_SOLICIT: u8 = 133;
+pub const ICMPV6_TY_ROUTER_ADVERT: u8 = 134;
+pub const ICMPV6_TY_NEIGHBOR_SOLICIT: u8 = 135;
+pub const ICMPV6_TY_NEIGHBOR_ADVERT: u8 = 136;
+
+/// Hop limit required on all neighbor discovery messages
+pub const NDP_HOP_LIMIT: u8 = 255;
+
+/// Neighbor advertisement flags
+pub const NDP_NA_FLAG_ROUTER: u8 = 0x80;
+pub const NDP_NA_FLAG_SOLICITED: u8 = 0x40;
+pub const NDP_NA_FLAG_OVERRIDE: u8 = 0x20;
+
+/// Neighbor discovery option types
+const NDP_OPT_SOURCE_LL: u8 = 1;
+const NDP_OPT_TARGET_LL: u8 = 2;
+const NDP_OPT_PREFIX_INFO: u8 = 3;
+const NDP_OPT_MTU: u8 = 5;
+
+/// Minimum MTU of an IPv6 link, which error messages must fit within
+const IPV6_MIN_MTU: usize = 1280;
+
+/// All-nodes link-local multicast address (ff02::1)
+pub const IPV6_ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// All-routers link-local multicast address (ff02::2)
+pub const IPV6_ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+/// A neighbor discovery message received from a host
+#[derive(Debug, Eq, PartialEq)]
+pub enum NdpPacket {
+    /// Host is looking for routers on the link
+    RouterSolicit { source: Option<MacAddress> },
+
+    /// Host is resolving the link-layer address of `target`
+    NeighborSolicit {
+        target: Ipv6Addr,
+        source: Option<MacAddress>,
+    },
+
+    /// Host is announcing the link-layer address of `target`
+    NeighborAdvert {
+        target: Ipv6Addr,
+        flags: u8,
+        mac: Option<MacAddress>,
+    },
+}
+
+/// A router advertisement announcing a prefix for stateless address autoconfiguration
+#[derive(Debug)]
+pub struct RouterAdvert {
+    /// Link-layer address of the router
+    pub mac: MacAddress,
+
+    /// Prefix advertised as on-link (and for autoconfiguration, if a /64)
+    pub prefix: Ipv6Network,
+
+    /// Lifetime of the router as a default router, in seconds (zero if not a default router)
+    pub lifetime: u16,
+
+    /// MTU of the link
+    pub mtu: u32,
+
+    /// True if hosts should obtain addresses (and other configuration) via DHCPv6
+    pub managed: bool,
+}
+
+impl NdpPacket {
+    /// Parses a neighbor discovery message from an ICMPv6 payload
+    ///
+    /// ### Arguments
+    /// * `data` - ICMPv6 header and payload
+    pub fn parse(data: &[u8]) -> Result<Self, ProtocolError> {
+        if data.len() < 8 {
+            return Err(ProtocolError::NotEnoughData(data.len(), 8));
+        }
+
+        match data[0] {
+            ICMPV6_TY_ROUTER_SOLICIT => Ok(Self::RouterSolicit {
+                source: link_addr_option(&data[8..], NDP_OPT_SOURCE_LL)?,
+            }),
+            ICMPV6_TY_NEIGHBOR_SOLICIT => {
+                let target = parse_target(data)?;
+                Ok(Self::NeighborSolicit {
+                    target,
+                    source: link_addr_option(&data[24..], NDP_OPT_SOURCE_LL)?,
+                })
+            }
+            ICMPV6_TY_NEIGHBOR_ADVERT => {
+                let target = parse_target(data)?;
+                Ok(Self::NeighborAdvert {
+                    target,
+                    flags: data[4],
+                    mac: link_addr_option(&data[24..], NDP_OPT_TARGET_LL)?,
+                })
+            }
+            ty => Err(ProtocolError::MalformedPacket(format!(
+                "not a neighbor discovery message: {ty}"
+            ))),
+        }
+    }
+
+    /// Returns the link-layer address contained in this message, if present
+    pub fn link_addr(&self) -> Option<MacAddress> {
+        match self {
+            Self::RouterSolicit { source } => *source,
+            Self::NeighborSolicit { source, .. } => *source,
+            Self::NeighborAdvert { mac, .. } => *mac,
+        }
+    }
+}
+
+impl RouterAdvert {
+    /// Returns this router advertisement as an ICMPv6 message (with an empty checksum)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&[ICMPV6_TY_ROUTER_ADVERT, 0, 0, 0]);
+        buf.push(64); // current hop limit
+        buf.push(match self.managed {
+            true => 0xC0, // managed address + other configuration
+            false => 0x00,
+        });
+        buf.extend_from_slice(&self.lifetime.to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]); // reachable time (unspecified)
+        buf.extend_from_slice(&[0, 0, 0, 0]); // retrans timer (unspecified)
+
+        buf.extend_from_slice(&[NDP_OPT_SOURCE_LL, 1]);
+        buf.extend_from_slice(self.mac.as_bytes());
+
+        buf.extend_from_slice(&[NDP_OPT_MTU, 1, 0, 0]);
+        buf.extend_from_slice(&self.mtu.to_be_bytes());
+
+        // on-link, and autonomous if hosts can generate an address from the prefix
+        let bits = self.prefix.subnet_mask_bits();
+        let flags = match bits {
+            64 => 0xC0,
+            _ => 0x80,
+        };
+
+        buf.extend_from_slice(&[NDP_OPT_PREFIX_INFO, 4, bits, flags]);
+        buf.extend_from_slice(&86400u32.to_be_bytes()); // valid lifetime
+        buf.extend_from_slice(&14400u32.to_be_bytes()); // preferred lifetime
+        buf.extend_from_slice(&[0, 0, 0, 0]); // reserved
+        buf.extend_from_slice(&self.prefix.network().octets());
+
+        buf
+    }
+}
+
+/// Builds a neighbor advertisement for `target` (with an empty checksum)
+///
+/// ### Arguments
+/// * `target` - Address being advertised
+/// * `mac` - Link-layer address of the target
+/// * `flags` - Advertisement flags (i.e., `NDP_NA_FLAG_ROUTER`)
+pub fn neighbor_advert(target: Ipv6Addr, mac: MacAddress, flags: u8) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(32);
+    buf.extend_from_slice(&[ICMPV6_TY_NEIGHBOR_ADVERT, 0, 0, 0, flags, 0, 0, 0]);
+    buf.extend_from_slice(&target.octets());
+    buf.extend_from_slice(&[NDP_OPT_TARGET_LL, 1]);
+    buf.extend_from_slice(mac.as_bytes());
+    buf
+}
+
+/// Builds a neighbor solicitation for `target` (with an empty checksum)
+///
+/// ### Arguments
+/// * `target` - Address to resolve
+/// * `mac` - Link-layer address of the sender
+pub fn neighbor_solicit(target: Ipv6Addr, mac: MacAddress) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(32);
+    buf.extend_from_slice(&[ICMPV6_TY_NEIGHBOR_SOLICIT, 0, 0, 0, 0, 0, 0, 0]);
+    buf.extend_from_slice(&target.octets());
+    buf.extend_from_slice(&[NDP_OPT_SOURCE_LL, 1]);
+    buf.extend_from_slice(mac.as_bytes());
+    buf
+}
+
+/// Builds an echo reply from an echo request (with an empty checksum)
+///
+/// ### Arguments
+/// * `request` - ICMPv6 echo request header and payload
+pub fn echo_reply(request: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    if request.len() < 8 {
+        return Err(ProtocolError::NotEnoughData(request.len(), 8));
+    }
+
+    let mut buf = request.to_vec();
+    buf[0] = ICMPV6_TY_ECHO_REPLY;
+    buf[1] = 0;
+    buf[2..4].copy_from_slice(&[0, 0]);
+    Ok(buf)
+}
+
+/// Builds a time exceeded (hop limit exceeded in transit) message (with an empty checksum)
+///
+/// ### Arguments
+/// * `original` - Discarded packet, including the ipv6 header
+pub fn time_exceeded(original: &[u8]) -> Vec<u8> {
+    // quote as much of the packet as fits in the minimum mtu
+    let len = std::cmp::min(original.len(), IPV6_MIN_MTU - IPV6_HDR_SZ - 8);
+
+    let mut buf = Vec::with_capacity(8 + len);
+    buf.extend_from_slice(&[ICMPV6_TY_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0]);
+    buf.extend_from_slice(&original[..len]);
+    buf
+}
+
+/// Returns true if an ICMPv6 message type is an error message (which must never trigger
+/// another error message)
+///
+/// ### Arguments
+/// * `ty` - ICMPv6 message type
+pub fn is_error(ty: u8) -> bool {
+    ty < ICMPV6_TY_ECHO_REQUEST
+}
+
+/// Returns the solicited-node multicast address for an address (ff02::1:ffXX:XXXX)
+///
+/// ### Arguments
+/// * `ip` - Unicast address
+pub fn solicited_node(ip: Ipv6Addr) -> Ipv6Addr {
+    let ip = ip.octets();
+    Ipv6Addr::from([
+        0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, ip[13], ip[14], ip[15],
+    ])
+}
+
+/// Parses the target address of a neighbor solicitation / advertisement
+fn parse_target(data: &[u8]) -> Result<Ipv6Addr, ProtocolError> {
+    if data.len() < 24 {
+        return Err(ProtocolError::NotEnoughData(data.len(), 24));
+    }
+
+    Ok(Ipv6Addr::from(cast!(be128, data[8..24])))
+}
+
+/// Searches neighbor discovery options for a link-layer address option
+///
+/// ### Arguments
+/// * `opts` - Options following the fixed part of the message
+/// * `ty` - Type of option to find (source / target link-layer address)
+fn link_addr_option(mut opts: &[u8], ty: u8) -> Result<Option<MacAddress>, ProtocolError> {
+    while opts.len() >= 2 {
+        // option length is in units of 8 bytes, including the type and length fields
+        let len = usize::from(opts[1]) * 8;
+        if len == 0 || opts.len() < len {
+            return Err(ProtocolError::MalformedPacket(String::from(
+                "invalid ndp option length",
+            )));
+        }
+
+        if opts[0] == ty {
+            return MacAddress::parse(&opts[2..len]).map(Some);
+        }
+
+        opts = &opts[len..];
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use crate::types::MacAddress;
+
+    use super::{
+        neighbor_advert, neighbor_solicit, solicited_node, NdpPacket, RouterAdvert,
+        NDP_NA_FLAG_ROUTER,
+    };
+
+    #[test]
+    fn ndp_solicit_advert_roundtrip() {
+        let mac: MacAddress = "52:54:00:de:ad:01".parse().unwrap();
+        let target: Ipv6Addr = "fd00::1".parse().unwrap();
+
+        let ns = NdpPacket::parse(&neighbor_solicit(target, mac)).unwrap();
+        assert_eq!(
+            ns,
+            NdpPacket::NeighborSolicit {
+                target,
+                source: Some(mac)
+            }
+        );
+
+        let na = NdpPacket::parse(&neighbor_advert(target, mac, NDP_NA_FLAG_ROUTER)).unwrap();
+        assert_eq!(na.link_addr(), Some(mac));
+        assert_eq!(
+            solicited_node(target),
+            "ff02::1:ff00:1".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn ndp_router_advert() {
+        let ra = RouterAdvert {
+            mac: "52:54:00:de:ad:01".parse().unwrap(),
+            prefix: "fd00:67:213::1/64".parse().unwrap(),
+            lifetime: 1800,
+            mtu: 1500,
+            managed: false,
+        };
+
+        let buf = ra.to_bytes();
+        assert_eq!(buf.len(), 64);
+        assert_eq!(buf[5], 0x00);
+        assert_eq!(&buf[6..8], &1800u16.to_be_bytes());
+
+        // prefix information option: /64, on-link + autonomous, network address
+        assert_eq!(&buf[32..36], &[3, 4, 64, 0xC0]);
+        assert_eq!(&buf[48..56], &[0xfd, 0x00, 0x00, 0x67, 0x02, 0x13, 0, 0]);
+    }
+}
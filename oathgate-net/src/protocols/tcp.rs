@@ -0,0 +1,197 @@
+//! TCP segment header
+
+use crate::{cast, ProtocolError};
+
+/// Size of a TCP header without any options
+pub const TCP_HDR_SZ: usize = 20;
+
+pub const TCP_FLAG_FIN: u8 = 0x01;
+pub const TCP_FLAG_SYN: u8 = 0x02;
+pub const TCP_FLAG_RST: u8 = 0x04;
+pub const TCP_FLAG_PSH: u8 = 0x08;
+pub const TCP_FLAG_ACK: u8 = 0x10;
+
+/// TCP option kinds
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+#[derive(Clone, Debug, Default)]
+pub struct TcpHeader {
+    /// Source port
+    pub src_port: u16,
+
+    /// Destination port
+    pub dst_port: u16,
+
+    /// Sequence number of the first byte of data
+    pub seq: u32,
+
+    /// Next sequence number expected by the sender (if ACK flag is set)
+    pub ack: u32,
+
+    /// Length of the header (including options), in bytes
+    pub hdr_len: usize,
+
+    /// Control flags (SYN, ACK, FIN, etc.)
+    pub flags: u8,
+
+    /// Size of the receive window
+    pub window: u16,
+
+    /// Maximum segment size option, if present
+    pub mss: Option<u16>,
+}
+
+impl TcpHeader {
+    /// Creates a new TCP header with no options
+    ///
+    /// ### Arguments
+    /// * `src_port` - Source port
+    /// * `dst_port` - Destination port
+    /// * `seq` - Sequence number
+    /// * `ack` - Acknowledgement number
+    /// * `flags` - Control flags
+    /// * `window` - Receive window size
+    pub fn new(src_port: u16, dst_port: u16, seq: u32, ack: u32, flags: u8, window: u16) -> Self {
+        Self {
+            src_port,
+            dst_port,
+            seq,
+            ack,
+            hdr_len: TCP_HDR_SZ,
+            flags,
+            window,
+            mss: None,
+        }
+    }
+
+    /// Parses a TCP header from the start of a segment
+    ///
+    /// ### Arguments
+    /// * `data` - TCP segment (header + payload)
+    pub fn parse(data: &[u8]) -> Result<Self, ProtocolError> {
+        if data.len() < TCP_HDR_SZ {
+            return Err(ProtocolError::NotEnoughData(data.len(), TCP_HDR_SZ));
+        }
+
+        let hdr_len = usize::from(data[12] >> 4) * 4;
+        if hdr_len < TCP_HDR_SZ {
+            return Err(ProtocolError::MalformedPacket(format!(
+                "tcp header length too small: {hdr_len}"
+            )));
+        }
+
+        if data.len() < hdr_len {
+            return Err(ProtocolError::NotEnoughData(data.len(), hdr_len));
+        }
+
+        // walk the options looking for the maximum segment size
+        let mut mss = None;
+        let mut opts = &data[TCP_HDR_SZ..hdr_len];
+        while let Some(&kind) = opts.first() {
+            match kind {
+                TCP_OPT_END => break,
+                TCP_OPT_NOP => opts = &opts[1..],
+                _ => {
+                    let len = usize::from(*opts.get(1).unwrap_or(&0));
+                    if len < 2 || len > opts.len() {
+                        break;
+                    }
+
+                    if kind == TCP_OPT_MSS && len == 4 {
+                        mss = Some(cast!(be16, opts[2..4]));
+                    }
+
+                    opts = &opts[len..];
+                }
+            }
+        }
+
+        Ok(Self {
+            src_port: cast!(be16, data[0..2]),
+            dst_port: cast!(be16, data[2..4]),
+            seq: cast!(be32, data[4..8]),
+            ack: cast!(be32, data[8..12]),
+            hdr_len,
+            flags: data[13],
+            window: cast!(be16, data[14..16]),
+            mss,
+        })
+    }
+
+    /// Returns true if all of the provided flags are set
+    ///
+    /// ### Arguments
+    /// * `flags` - Flag(s) to check
+    pub fn has_flags(&self, flags: u8) -> bool {
+        self.flags & flags == flags
+    }
+
+    /// Returns the size of this header when serialized, in bytes
+    pub fn size(&self) -> usize {
+        match self.mss {
+            Some(_) => TCP_HDR_SZ + 4,
+            None => TCP_HDR_SZ,
+        }
+    }
+
+    /// Writes this header into a buffer, returning the number of bytes written.
+    ///
+    /// The checksum field is zeroed and must be computed over the entire segment
+    ///
+    /// ### Arguments
+    /// * `buf` - Buffer to write header into, must be at least `size()` bytes
+    pub fn as_bytes(&self, buf: &mut [u8]) -> usize {
+        let sz = self.size();
+
+        buf[0..2].copy_from_slice(&self.src_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ack.to_be_bytes());
+        buf[12] = ((sz / 4) as u8) << 4;
+        buf[13] = self.flags;
+        buf[14..16].copy_from_slice(&self.window.to_be_bytes());
+        buf[16..20].copy_from_slice(&[0x00, 0x00, 0x00, 0x00]); // checksum, urgent pointer
+
+        if let Some(mss) = self.mss {
+            buf[20] = TCP_OPT_MSS;
+            buf[21] = 4;
+            buf[22..24].copy_from_slice(&mss.to_be_bytes());
+        }
+
+        sz
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TcpHeader, TCP_FLAG_ACK, TCP_FLAG_SYN};
+
+    #[test]
+    fn tcp_header_roundtrip() {
+        let mut hdr = TcpHeader::new(443, 50000, 1000, 2000, TCP_FLAG_SYN | TCP_FLAG_ACK, 65535);
+        hdr.mss = Some(1460);
+
+        let mut buf = [0u8; 24];
+        let sz = hdr.as_bytes(&mut buf);
+        assert_eq!(sz, 24);
+
+        let parsed = TcpHeader::parse(&buf).unwrap();
+        assert_eq!(parsed.src_port, 443);
+        assert_eq!(parsed.dst_port, 50000);
+        assert_eq!(parsed.seq, 1000);
+        assert_eq!(parsed.ack, 2000);
+        assert_eq!(parsed.hdr_len, 24);
+        assert_eq!(parsed.window, 65535);
+        assert_eq!(parsed.mss, Some(1460));
+        assert!(parsed.has_flags(TCP_FLAG_SYN | TCP_FLAG_ACK));
+    }
+
+    #[test]
+    fn tcp_header_bad_offset() {
+        let mut buf = [0u8; 20];
+        buf[12] = 0x40;
+        assert!(TcpHeader::parse(&buf).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! Traffic counters
+//!
+//! `Counters` are plain values, owned by the thread updating them or taken as a snapshot of
+//! `AtomicCounters`, which are shared between the thread moving packets and the threads
+//! reporting the counters.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// Number of packets (and bytes) moved in one direction, along with the number of packets
+/// dropped or that failed to be processed
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Counters {
+    pub packets: u64,
+    pub bytes: u64,
+    pub dropped: u64,
+    pub errors: u64,
+}
+
+/// Counters shared between threads
+#[derive(Debug, Default)]
+pub struct AtomicCounters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Counters of both directions of an interface.  Received (rx) packets entered the bridge
+/// through the interface, transmitted (tx) packets left the bridge through the interface
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct InterfaceCounters {
+    pub rx: Counters,
+    pub tx: Counters,
+}
+
+/// Counters of both directions of an interface, shared between threads
+#[derive(Debug, Default)]
+pub struct InterfaceStats {
+    pub rx: AtomicCounters,
+    pub tx: AtomicCounters,
+}
+
+impl Counters {
+    /// Counts a packet
+    ///
+    /// ### Arguments
+    /// * `bytes` - Size of the packet
+    pub fn record(&mut self, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+
+    /// Counts a dropped packet
+    pub fn record_drop(&mut self) {
+        self.dropped += 1;
+    }
+
+    /// Counts a packet that failed to be processed
+    pub fn record_error(&mut self) {
+        self.errors += 1;
+    }
+}
+
+impl AtomicCounters {
+    /// Counts a packet
+    ///
+    /// ### Arguments
+    /// * `bytes` - Size of the packet
+    pub fn record(&self, bytes: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a dropped packet
+    pub fn record_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a packet that failed to be processed
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the counters
+    pub fn load(&self) -> Counters {
+        Counters {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl InterfaceStats {
+    /// Returns the current value of the counters
+    pub fn load(&self) -> InterfaceCounters {
+        InterfaceCounters {
+            rx: self.rx.load(),
+            tx: self.tx.load(),
+        }
+    }
+}
@@ -17,6 +17,9 @@ pub enum EtherType {
     IPv4 = 0x0800,
     IPv6 = 0x86DD,
     ARP = 0x0806,
+
+    /// IEEE 802.1Q tag, only seen while parsing tagged frames
+    Vlan = 0x8100,
 }
 
 impl TryFrom<u16> for EtherType {
@@ -27,6 +30,7 @@ impl TryFrom<u16> for EtherType {
             x if x == EtherType::IPv4 as u16 => Ok(EtherType::IPv4),
             x if x == EtherType::IPv6 as u16 => Ok(EtherType::IPv6),
             x if x == EtherType::ARP as u16 => Ok(EtherType::ARP),
+            x if x == EtherType::Vlan as u16 => Ok(EtherType::Vlan),
             _ => Err(ProtocolError::MalformedPacket(format!(
                 "unknown ethertype: 0x{value:04x}"
             ))),
@@ -2,6 +2,7 @@
 
 use std::{
     fmt::{Debug, Display},
+    net::Ipv6Addr,
     os::fd::AsRawFd,
     str::FromStr,
 };
@@ -25,6 +26,11 @@ impl MacAddress {
         Self([0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF])
     }
 
+    /// Returns the unspecified (all zeros) MacAddress (00:00:00:00:00:00)
+    pub const fn zero() -> Self {
+        Self([0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+    }
+
     /// Parses a MAC address from a byte buffer
     ///
     /// ### Arguments
@@ -97,6 +103,31 @@ impl MacAddress {
     pub fn is_broadcast(&self) -> bool {
         *self == Self::broadcast()
     }
+
+    /// Returns true if this MAC is a multicast (or broadcast) address
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 == 0x01
+    }
+
+    /// Returns the MAC address an IPv6 multicast address maps to (33:33:xx:xx:xx:xx)
+    ///
+    /// ### Arguments
+    /// * `ip` - IPv6 multicast address
+    pub fn ipv6_multicast(ip: Ipv6Addr) -> Self {
+        let ip = ip.octets();
+        Self([0x33, 0x33, ip[12], ip[13], ip[14], ip[15]])
+    }
+
+    /// Returns the IPv6 link-local address derived from this MAC (modified EUI-64)
+    pub fn link_local(&self) -> Ipv6Addr {
+        let mut ip = [0u8; 16];
+        ip[0..2].copy_from_slice(&[0xfe, 0x80]);
+        ip[8..11].copy_from_slice(&self.0[0..3]);
+        ip[8] ^= 0x02; // flip the universal/local bit
+        ip[11..13].copy_from_slice(&[0xff, 0xfe]);
+        ip[13..16].copy_from_slice(&self.0[3..6]);
+        Ipv6Addr::from(ip)
+    }
 }
 
 impl TryFrom<&[i8]> for MacAddress {
@@ -222,6 +253,17 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn mac_ipv6_addresses() {
+        let mac: MacAddress = "52:54:00:de:ad:01".parse().unwrap();
+        assert_eq!(mac.link_local().to_string(), "fe80::5054:ff:fede:ad01");
+        assert!(!mac.is_multicast());
+
+        let mcast = MacAddress::ipv6_multicast("ff02::1:ffde:ad01".parse().unwrap());
+        assert_eq!(mcast.to_string(), "33:33:ff:de:ad:01");
+        assert!(mcast.is_multicast());
+    }
+
     #[test]
     fn parse_mac_bad_not_hex() {
         let input = "52:54:00:dg:ad:01";
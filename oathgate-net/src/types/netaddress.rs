@@ -176,6 +176,37 @@ impl PartialEq<Ipv4Addr> for Ipv4Network {
     }
 }
 
+impl Display for Ipv6Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mask = u128::from(self.mask).count_ones();
+        write!(f, "{}/{}", self.ip, mask)
+    }
+}
+
+impl FromStr for Ipv6Network {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split("/");
+        let ip = parts.next().ok_or("missing ip component")?;
+        let mask = parts.next().unwrap_or("128");
+
+        let ip: Ipv6Addr = ip.parse().map_err(|_| "unable to parse ip address")?;
+        let mask: u8 = mask.parse().map_err(|_| "unable to parse subnet mask")?;
+        if mask > 128 {
+            return Err("subnet mask too large");
+        }
+
+        Ok(Self::new(ip, mask))
+    }
+}
+
+impl PartialEq<Ipv6Addr> for Ipv6Network {
+    fn eq(&self, other: &Ipv6Addr) -> bool {
+        self.ip == *other
+    }
+}
+
 struct Ipv4NetworkVisitor;
 
 impl<'de> Visitor<'de> for Ipv4NetworkVisitor {
@@ -212,11 +243,47 @@ impl Serialize for Ipv4Network {
     }
 }
 
+struct Ipv6NetworkVisitor;
+
+impl<'de> Visitor<'de> for Ipv6NetworkVisitor {
+    type Value = Ipv6Network;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a network address, like fd00::1/64")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.parse::<Ipv6Network>()
+            .map_err(|e| E::custom(e.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv6Network {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(Ipv6NetworkVisitor)
+    }
+}
+
+impl Serialize for Ipv6Network {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
-    use super::Ipv4Network;
+    use super::{Ipv4Network, Ipv6Network};
 
     #[test]
     fn create_cidr_ipv4() {
@@ -288,4 +355,14 @@ mod tests {
         assert_eq!("10.10.10.128", &net.to_string(), "network mismatch");
         assert_eq!("10.10.10.255", &broadcast.to_string(), "broadcast mismatch");
     }
+
+    #[test]
+    fn parse_ipv6_string() {
+        let cidr: Ipv6Network = "fd00:67:213::1/64".parse().unwrap();
+        assert_eq!("fd00:67:213::1/64", &cidr.to_string());
+        assert_eq!(cidr.subnet_mask_bits(), 64);
+        assert_eq!(cidr.network(), "fd00:67:213::".parse::<Ipv6Addr>().unwrap());
+        assert!(cidr.contains("fd00:67:213::abcd".parse::<Ipv6Addr>().unwrap()));
+        assert!(!cidr.contains("fd00:67:214::1".parse::<Ipv6Addr>().unwrap()));
+    }
 }
@@ -4,7 +4,7 @@ use std::{
 };
 
 use oathgate_net::types::MacAddress;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 
 use crate::HypervisorError;
 
@@ -14,6 +14,10 @@ pub struct MachineConfig {
     pub memory: String,
     pub kernel: KernelConfig,
     pub disk: DiskConfig,
+
+    /// Network adapters to attach to the machine
+    #[serde(default)]
+    pub networks: Vec<NetDevConfig>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -36,8 +40,18 @@ pub struct DiskConfig {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct NetDevConfig {
+    /// Path on host system to the bridge's vhost-user socket
+    pub socket: PathBuf,
+
     /// MAC address of this network adapter
-    pub mac: Option<MacAddress>,
+    pub mac: MacAddress,
+
+    /// Number of transmit/receive queue pairs (at least one)
+    #[serde(
+        default = "NetDevConfig::default_queues",
+        deserialize_with = "NetDevConfig::deserialize_queues"
+    )]
+    pub queues: u8,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -77,7 +91,16 @@ impl MachineConfig {
         let cpu = cpu.into();
         let memory = memory.into();
 
-        Self { cpu, memory, kernel, disk }
+        Self { cpu, memory, kernel, disk, networks: Vec::new() }
+    }
+
+    /// Attaches a network adapter to this machine
+    ///
+    /// ### Arguments
+    /// * `netdev` - Network adapter configuration
+    pub fn add_network(&mut self, netdev: NetDevConfig) -> &mut Self {
+        self.networks.push(netdev);
+        self
     }
 
     /// Loads a configuration file from reader
@@ -134,3 +157,120 @@ impl DiskConfig {
         }
     }
 }
+
+impl NetDevConfig {
+    /// Creates a new network adapter with a single transmit/receive queue pair
+    ///
+    /// ### Arguments
+    /// * `socket` - Path to the bridge's vhost-user socket
+    /// * `mac` - MAC address of the network adapter
+    pub fn new<P: Into<PathBuf>>(socket: P, mac: MacAddress) -> Self {
+        Self {
+            socket: socket.into(),
+            mac,
+            queues: Self::default_queues(),
+        }
+    }
+
+    fn default_queues() -> u8 {
+        1
+    }
+
+    /// Deserializes the number of queue pairs, rejecting zero as an adapter needs at least
+    /// one pair to exchange packets
+    fn deserialize_queues<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+        match u8::deserialize(deserializer)? {
+            0 => Err(D::Error::custom("queues must be at least 1")),
+            queues => Ok(queues),
+        }
+    }
+
+    /// Returns the qemu arguments (chardev, netdev, device) to attach this adapter
+    ///
+    /// ### Arguments
+    /// * `idx` - Index of this adapter, used to generate unique ids
+    pub fn as_qemu_args(&self, idx: usize) -> [String; 6] {
+        let socket = self.socket.display();
+        let mac = self.mac;
+        let queues = self.queues;
+
+        let device = match queues {
+            0 | 1 => format!("virtio-net-pci,netdev=net{idx},mac={mac}"),
+            _ => format!(
+                "virtio-net-pci,netdev=net{idx},mac={mac},mq=on,vectors={}",
+                2 * u16::from(queues) + 2
+            ),
+        };
+
+        [
+            String::from("-chardev"),
+            format!("socket,id=chr{idx},path={socket}"),
+            String::from("-netdev"),
+            format!("type=vhost-user,id=net{idx},chardev=chr{idx},queues={queues}"),
+            String::from("-device"),
+            device,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MachineConfig, NetDevConfig};
+
+    #[test]
+    fn netdev_unique_ids() {
+        let a = NetDevConfig::new("/tmp/a.sock", "52:54:00:00:00:01".parse().unwrap());
+        let b = NetDevConfig::new("/tmp/b.sock", "52:54:00:00:00:02".parse().unwrap());
+
+        let a = a.as_qemu_args(0);
+        let b = b.as_qemu_args(1);
+
+        assert_eq!(a[1], "socket,id=chr0,path=/tmp/a.sock");
+        assert_eq!(b[1], "socket,id=chr1,path=/tmp/b.sock");
+        assert_eq!(b[3], "type=vhost-user,id=net1,chardev=chr1,queues=1");
+        assert_eq!(b[5], "virtio-net-pci,netdev=net1,mac=52:54:00:00:00:02");
+    }
+
+    #[test]
+    fn netdev_multiqueue() {
+        let mut netdev = NetDevConfig::new("/tmp/a.sock", "52:54:00:00:00:01".parse().unwrap());
+        netdev.queues = 2;
+
+        let args = netdev.as_qemu_args(0);
+        assert_eq!(args[3], "type=vhost-user,id=net0,chardev=chr0,queues=2");
+        assert_eq!(args[5], "virtio-net-pci,netdev=net0,mac=52:54:00:00:00:01,mq=on,vectors=6");
+    }
+
+    #[test]
+    fn netdev_rejects_zero_queues() {
+        let yaml = "socket: /tmp/a.sock\nmac: 52:54:00:00:00:01\nqueues: 0\n";
+
+        let error = serde_yaml::from_str::<NetDevConfig>(yaml).unwrap_err();
+        assert!(error.to_string().contains("queues must be at least 1"), "{error}");
+    }
+
+    #[test]
+    fn machine_yaml_networks() {
+        let yaml = r#"
+cpu: q35
+memory: 512m
+kernel:
+    path: ./vmlinux
+    root: /dev/vda
+disk:
+    path: ./disk.img
+networks:
+    - socket: /tmp/oathgate/networks/lan.sock
+      mac: 52:54:00:de:ad:01
+    - socket: /tmp/oathgate/networks/dmz.sock
+      mac: 52:54:00:de:ad:02
+      queues: 2
+"#;
+
+        let cfg = MachineConfig::read_yaml(yaml.as_bytes()).unwrap();
+        assert_eq!(cfg.networks.len(), 2);
+        assert_eq!(cfg.networks[0].mac.to_string(), "52:54:00:de:ad:01");
+        assert_eq!(cfg.networks[0].queues, 1);
+        assert_eq!(cfg.networks[1].queues, 2);
+    }
+}
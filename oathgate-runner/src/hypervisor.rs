@@ -6,7 +6,6 @@ mod vm;
 use std::{
     borrow::Cow,
     os::fd::{AsRawFd, OwnedFd},
-    path::Path,
     sync::Arc,
 };
 
@@ -59,14 +58,15 @@ impl Hypervisor {
     /// Creates a new hypervisor bound to the specified vhost port on the hypervisor CID (aka 2)
     ///
     /// ### Arguments
-    /// * `port` - Port to bind on vhost socket
-    pub fn new<P: AsRef<Path>, S: Into<String>>(
-        networks: &[P],
+    /// * `name` - Name of this hypervisor
+    /// * `cid` - Context id of the virtual machine
+    /// * `config` - Machine configuration
+    pub fn new<S: Into<String>>(
         name: S,
         cid: u32,
         config: MachineConfig,
     ) -> Result<Self, HypervisorError> {
-        let vm = VmHandle::new(networks, cid, config)?;
+        let vm = VmHandle::new(cid, config)?;
 
         tracing::debug!(
             "binding hypervisor socket (cid = {}, port = {})",
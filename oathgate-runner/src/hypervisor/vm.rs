@@ -3,12 +3,9 @@
 use std::{
     fmt::Debug,
     io,
-    path::Path,
     process::{Child, Command, Stdio},
 };
 
-use oathgate_net::types::MacAddress;
-
 use crate::config::MachineConfig;
 
 macro_rules! cmd {
@@ -33,10 +30,9 @@ impl VmHandle {
     /// Creates a new handle to virtual machine
     ///
     /// ### Arguments
-    /// * `sockets` - Path to network bridge socket
     /// * `cid` - Context id of this virtual machine
     /// * `machine` - Machine configuration
-    pub fn new<P: AsRef<Path>>(sockets: &[P], cid: u32, machine: MachineConfig) -> io::Result<Self> {
+    pub fn new(cid: u32, machine: MachineConfig) -> io::Result<Self> {
         tracing::debug!("launching vm, cid = {cid:04x}");
 
         let mut cmd = cmd!(
@@ -72,16 +68,8 @@ impl VmHandle {
             format!("vhost-vsock-pci,guest-cid={cid}")
         );
 
-        for socket in sockets {
-            let socket = socket.as_ref(); 
-            let mac = MacAddress::generate();
-
-            cmd.arg("-chardev");
-            cmd.arg(format!("socket,id=chr0,path={}", socket.display()));
-            cmd.arg("-netdev");
-            cmd.arg("type=vhost-user,id=net0,chardev=chr0,queues=1");
-            cmd.arg("-device");
-            cmd.arg(format!("virtio-net-pci,netdev=net0,mac={mac}"));
+        for (idx, netdev) in machine.networks.iter().enumerate() {
+            cmd.args(netdev.as_qemu_args(idx));
         }
 
         cmd
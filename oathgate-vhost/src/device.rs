@@ -4,7 +4,10 @@ use std::{
     io::{IoSlice, IoSliceMut},
     num::NonZeroUsize,
     os::fd::{AsRawFd, FromRawFd, RawFd},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     usize,
 };
 
@@ -17,7 +20,10 @@ use nix::{
     },
     unistd,
 };
-use oathgate_net::{EthernetFrame, EthernetPacket, Switch, SwitchPort};
+use oathgate_net::{
+    stats::{AtomicCounters, Counters},
+    EthernetFrame, EthernetPacket, Switch, SwitchPort,
+};
 use parking_lot::lock_api::Mutex;
 use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
 
@@ -178,12 +184,24 @@ pub struct VirtioDevice<S> {
 
     /// Port on the router this device is connected to
     router_port: usize,
+
+    /// Switch the device is connected to
+    switch: S,
+
+    /// Set when the switch asks the device to close its connection
+    closed: Arc<AtomicBool>,
 }
 
 #[derive(Clone, Debug)]
 pub struct VirtioDeviceRxQueue {
     queue: DeviceRxQueue,
     waker: Arc<Waker>,
+
+    /// Set when the switch asks the device to close its connection
+    closed: Arc<AtomicBool>,
+
+    /// Counters of each virtqueue, indexed by queue
+    stats: Vec<Arc<AtomicCounters>>,
 }
 
 #[derive(Clone, Debug)]
@@ -216,6 +234,18 @@ impl SwitchPort for VirtioDeviceRxQueue {
         drop(queue);
         self.waker.wake().ok();
     }
+
+    /// Asks the device thread to close its connection to the front-end
+    fn close(&self) -> bool {
+        self.closed.store(true, Ordering::Relaxed);
+        self.waker.wake().ok();
+        true
+    }
+
+    /// Returns the counters of each virtqueue (even queues receive, odd queues transmit)
+    fn queue_counters(&self) -> Vec<Counters> {
+        self.stats.iter().map(|stats| stats.load()).collect()
+    }
 }
 
 impl<S: Switch + 'static> VirtioDevice<S> {
@@ -226,19 +256,28 @@ impl<S: Switch + 'static> VirtioDevice<S> {
     pub fn new(switch: S, opts: DeviceOpts) -> AppResult<Self> {
         let poll = Poll::new()?;
         let waker = Waker::new(poll.registry(), TOKEN_WAKE)?;
-        let rx = VirtioDeviceRxQueue {
-            queue: Arc::new(Mutex::new(VecDeque::new())),
-            waker: Arc::new(waker),
-        };
 
         // for a net device, we need pairs of queues for transmit and received:
         // 0: receive0
         // 1: transmit0
         let txrx_queues: usize = (opts.device_queues * 2) as usize;
 
+        let rx = VirtioDeviceRxQueue {
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            waker: Arc::new(waker),
+            closed: Arc::new(AtomicBool::new(false)),
+            stats: (0..txrx_queues).map(|_| Arc::default()).collect(),
+        };
+        let closed = Arc::clone(&rx.closed);
+
         let mut queues = Vec::with_capacity(txrx_queues);
-        for _ in 0..txrx_queues {
-            queues.push(VirtQueue::new(QUEUE_MAX_SIZE, switch.clone(), rx.queue())?);
+        for stats in &rx.stats {
+            queues.push(VirtQueue::new(
+                QUEUE_MAX_SIZE,
+                switch.clone(),
+                rx.queue(),
+                Arc::clone(stats),
+            )?);
         }
 
         let router_port = switch.connect(rx);
@@ -252,6 +291,8 @@ impl<S: Switch + 'static> VirtioDevice<S> {
             num_queues: opts.device_queues.into(),
             kick_fds: HashMap::new(),
             router_port,
+            switch,
+            closed,
         })
     }
 
@@ -262,6 +303,9 @@ impl<S: Switch + 'static> VirtioDevice<S> {
                 if let Err(error) = self.run(strm) {
                     tracing::warn!(?error, "unable to run device thread");
                 }
+
+                // free the port for the next device
+                self.switch.disconnect(self.router_port);
             })?;
         Ok(())
     }
@@ -287,10 +331,18 @@ impl<S: Switch + 'static> VirtioDevice<S> {
                                     // no more data, stop the loop
                                     break 'read;
                                 }
+                                Err(Error::Disconnected) => {
+                                    tracing::info!("[device] front-end disconnected");
+                                    return Ok(());
+                                }
                                 Err(e) => Err(e)?,
                             }
                         }
                     }
+                    TOKEN_WAKE if self.closed.load(Ordering::Relaxed) => {
+                        tracing::info!("[device] closed by switch, disconnecting front-end");
+                        return Ok(());
+                    }
                     TOKEN_WAKE => {
                         let vq = self.get_virtqueue_mut(0)?;
                         vq.handle_rx_queued()?;
@@ -333,6 +385,11 @@ impl<S: Switch + 'static> VirtioDevice<S> {
             let rmsg =
                 socket::recvmsg::<()>(strm, &mut iovs, Some(&mut cmsgs), MsgFlags::MSG_DONTWAIT)?;
 
+            // a readable socket with no data means the front-end closed the connection
+            if rmsg.bytes == 0 {
+                return Err(Error::Disconnected);
+            }
+
             match rmsg.iovs().count() {
                 1 => {
                     let ancillary = rmsg.cmsgs()?.collect::<VecDeque<_>>();
@@ -64,6 +64,9 @@ pub enum Error {
     #[error("vhost header is missing")]
     HeaderMissing,
 
+    #[error("front-end disconnected")]
+    Disconnected,
+
     #[error("mmap: {0}")]
     Mmap(#[from] vm_memory::mmap::Error),
 
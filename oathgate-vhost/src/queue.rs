@@ -5,10 +5,11 @@ use std::{
     io::{Read, Write},
     ops::Deref,
     os::fd::{FromRawFd, RawFd},
+    sync::Arc,
 };
 
 use nix::unistd;
-use oathgate_net::Switch;
+use oathgate_net::{stats::AtomicCounters, Switch};
 use virtio_queue::{Queue, QueueOwnedT, QueueT};
 use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 
@@ -29,6 +30,9 @@ pub struct VirtQueue<S> {
     kick_fd: Option<RawFd>,
     switch: S,
     pending: DeviceRxQueue,
+
+    /// Packets moved through this queue, shared with the device's switch port
+    stats: Arc<AtomicCounters>,
 }
 
 impl<S: Switch> VirtQueue<S> {
@@ -36,10 +40,14 @@ impl<S: Switch> VirtQueue<S> {
     ///
     /// ### Arguments
     /// * `max_size` - Maximum size of the virtqueue
+    /// * `switch` - Switch to send packets read from the driver to
+    /// * `rx_queue` - Packets waiting to be written to the driver
+    /// * `stats` - Counters of the packets moved through this queue
     pub fn new(
         max_size: u16,
         switch: S,
         rx_queue: DeviceRxQueue,
+        stats: Arc<AtomicCounters>,
     ) -> Result<Self, virtio_queue::Error> {
         Ok(Self {
             enabled: false,
@@ -53,6 +61,7 @@ impl<S: Switch> VirtQueue<S> {
             kick_fd: None,
             switch,
             pending: rx_queue,
+            stats,
         })
     }
 
@@ -193,7 +202,11 @@ impl<S: Switch> VirtQueue<S> {
             tracing::trace!(?idx, "[kick-tx] header: {hdr:02x?}");
             tracing::trace!(?idx, "[kick-tx] data: {pkt:02x?}");
 
-            self.switch.process(switch_port, pkt).unwrap();
+            self.stats.record(len);
+            if let Err(error) = self.switch.process(switch_port, pkt) {
+                tracing::warn!(?error, "[kick-tx] unable to process packet");
+                self.stats.record_error();
+            }
 
             self.queue.add_used(mem.deref(), head_idx, len as u32)?;
         }
@@ -271,6 +284,7 @@ impl<S: Switch> VirtQueue<S> {
             tracing::trace!("[queue] packet: {:02x?}", &pkt.payload);
 
             self.queue.add_used(mem.deref(), head_idx, sz as u32)?;
+            self.stats.record(frame.len() + pkt.payload.len());
         }
 
         // notify client
@@ -1,10 +1,18 @@
 //! Bridge commands and structures
 
-use std::path::PathBuf;
+mod inspect;
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::PathBuf,
+};
 
 use anyhow::{anyhow, Context};
 use clap::Subcommand;
-use oathgate_bridge::{BridgeBuilder, BridgeConfig};
+use nix::sys::signal::Signal;
+use oathgate_bridge::{BridgeBuilder, BridgeConfig, ControlClient, Dhcp6Lease, DhcpLease};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
 
 use crate::{
     database::{Device, DeviceType},
@@ -13,7 +21,8 @@ use crate::{
     State,
 };
 
-use super::LogFormat;
+use self::inspect::InspectCommand;
+use super::{AsTable, LogFormat};
 
 #[derive(Debug, Subcommand)]
 pub enum BridgeCommand {
@@ -29,7 +38,7 @@ pub enum BridgeCommand {
 
     /// Starts a bridge, spawning a new process/daemon
     Start {
-        /// Path to pcap file, or omit to disable pcap
+        /// Path to pcapng file, or omit to use the bridge's capture configuration
         #[clap(short, long)]
         pcap: Option<PathBuf>,
 
@@ -55,6 +64,49 @@ pub enum BridgeCommand {
         format: LogFormat,
     },
 
+    /// Lists the active DHCP leases of a bridge
+    Leases {
+        /// Name of bridge to list leases
+        name: String,
+    },
+
+    /// Streams the traffic of a running bridge (in pcapng format) until interrupted
+    Capture {
+        /// Only capture frames matching this (tcpdump-like) expression
+        #[clap(short, long)]
+        filter: Option<String>,
+
+        /// Maximum number of bytes captured of each frame
+        #[clap(short, long, default_value_t = 65535)]
+        snaplen: u32,
+
+        /// Path to file to save the capture, or `-` for stdout
+        #[clap(short, long, default_value = "-")]
+        write: PathBuf,
+
+        /// Name of bridge to capture
+        name: String,
+    },
+
+    /// Replaces the configuration of a bridge, applying it to the bridge if it is running.
+    /// Connected devices stay connected
+    Update {
+        /// Name of bridge to update
+        name: String,
+
+        /// Path to the new bridge configuration file
+        config: PathBuf,
+    },
+
+    /// Inspects (and manages) a running bridge
+    Inspect {
+        /// Name of bridge to inspect
+        name: String,
+
+        #[clap(subcommand)]
+        command: InspectCommand,
+    },
+
     /// Stops an existing oathgate bridge
     Stop {
         /// Name of bridge to stop
@@ -78,6 +130,15 @@ impl BridgeCommand {
             Self::Start { pcap, name, .. } => start_bridge(state, name, pcap),
             Self::List => list_bridges(state),
             Self::Logs { name, format } => print_logs(state, name, format),
+            Self::Leases { name } => list_leases(state, name),
+            Self::Capture {
+                filter,
+                snaplen,
+                write,
+                name,
+            } => capture_bridge(state, name, filter, snaplen, write),
+            Self::Update { name, config } => update_bridge(state, name, config),
+            Self::Inspect { name, command } => inspect_bridge(state, name, command),
             Self::Stop { name } => stop_bridge(state, name),
             Self::Delete { name } => delete_bridge(state, name),
             Self::Test => {
@@ -136,7 +197,7 @@ fn create_bridge(state: &State, config: PathBuf, name: Option<String>) -> anyhow
 /// * `state` - Application state
 /// * `config` - Path to bridge configuration file
 /// * `name` - Name of bridge (or None to generate one)
-/// * `pcap` - Path to file to save pcap (or None to disable pcap)
+/// * `pcap` - Path to file to save pcapng (or None to use the capture configuration)
 fn start_bridge(state: &State, name: String, pcap: Option<PathBuf>) -> anyhow::Result<()> {
     let bar = super::spinner("starting network");
 
@@ -150,10 +211,13 @@ fn start_bridge(state: &State, name: String, pcap: Option<PathBuf>) -> anyhow::R
         .build(config, &name)?;
 
     let logger = state.subscriber(device.id())?;
-    let pid = Forker::with_subscriber(logger).fork(move |sfd| {
-        bridge.run(sfd)?;
-        Ok(())
-    })?;
+    // SIGHUP reloads the bridge's configuration
+    let pid = Forker::with_subscriber(logger)
+        .block(Signal::SIGHUP)
+        .fork(move |sfd| {
+            bridge.run(sfd)?;
+            Ok(())
+        })?;
 
     device.set_started(pid.as_raw());
     device
@@ -204,6 +268,169 @@ fn print_logs(state: &State, name: String, format: LogFormat) -> anyhow::Result<
     Ok(())
 }
 
+/// Prints the active DHCP (and DHCPv6) leases of every network of a bridge.  The leases of a
+/// running bridge are requested through its control socket
+///
+/// ### Arguments
+/// * `state` - Application state
+/// * `name` - Name of bridge
+fn list_leases(state: &State, name: String) -> anyhow::Result<()> {
+    let device = get_bridge(state, &name)?;
+
+    let mut leases = match device.is_running() {
+        true => {
+            let path = oathgate_bridge::control_path(state.network_dir(), &name);
+            ControlClient::connect(&path)
+                .and_then(|client| client.leases())
+                .context("unable to request leases from bridge")?
+        }
+        false => {
+            let config: BridgeConfig = device.config()?;
+            oathgate_bridge::load_leases(state.network_dir(), &name, &config)
+                .context("unable to load leases")?
+        }
+    };
+    leases.retain(|lease| lease.is_active());
+    leases.sort_by_key(|lease| lease.ip);
+
+    let mut leases6 = oathgate_bridge::load_leases6(state.network_dir(), &name)
+        .context("unable to load dhcpv6 leases")?;
+    leases6.retain(|lease| lease.is_active());
+    leases6.sort_by_key(|lease| lease.ip);
+
+    match (leases.is_empty(), leases6.is_empty()) {
+        (true, true) => println!("no active leases found!"),
+        (false, true) => super::draw_table(&leases),
+        (true, false) => super::draw_table(&leases6),
+        (false, false) => {
+            super::draw_table(&leases);
+            println!();
+            super::draw_table(&leases6);
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the stored configuration of a bridge and, if the bridge is running, reloads it
+/// without disconnecting its devices.  The configuration is only stored once it is valid and,
+/// for a running bridge, once the bridge has applied it
+///
+/// ### Arguments
+/// * `state` - Application state
+/// * `name` - Name of bridge to update
+/// * `config` - Path to the new bridge configuration file
+fn update_bridge(state: &State, name: String, config: PathBuf) -> anyhow::Result<()> {
+    let mut device = get_bridge(state, &name)?;
+
+    let cfg = BridgeConfig::load(&config).context("failed to parse bridge config")?;
+    oathgate_bridge::validate_config(&cfg).context("invalid bridge config")?;
+    device.set_config(&cfg)?;
+
+    if device.is_running() {
+        let bar = super::spinner("reloading network");
+        let path = oathgate_bridge::control_path(state.network_dir(), &name);
+        ControlClient::connect(&path)
+            .and_then(|client| client.reload(cfg))
+            .context("unable to reload bridge, configuration not saved")?;
+        bar.finish_with_message("network reloaded");
+    }
+
+    device
+        .save(state.db())
+        .context("unable to save device in database")?;
+
+    if !device.is_running() {
+        println!("configuration saved, it applies the next time the bridge starts");
+    }
+
+    Ok(())
+}
+
+/// Connects to the control socket of a running bridge, returning the bridge and the client.
+/// Returns an error if the bridge is not running
+///
+/// ### Arguments
+/// * `state` - Application state
+/// * `name` - Name of bridge
+fn connect_bridge(state: &State, name: &str) -> anyhow::Result<(Device, ControlClient)> {
+    let device = get_bridge(state, name)?;
+    if !device.is_running() {
+        return Err(anyhow!("bridge '{name}' is not running"));
+    }
+
+    let path = oathgate_bridge::control_path(state.network_dir(), name);
+    let client = ControlClient::connect(&path).context("unable to connect to bridge")?;
+    Ok((device, client))
+}
+
+/// Inspects (or manages) a running bridge through its control socket
+///
+/// ### Arguments
+/// * `state` - Application state
+/// * `name` - Name of bridge
+/// * `command` - Inspect command to execute
+fn inspect_bridge(state: &State, name: String, command: InspectCommand) -> anyhow::Result<()> {
+    let (device, client) = connect_bridge(state, &name)?;
+    let config: BridgeConfig = device.config()?;
+    command.execute(client, config)
+}
+
+/// Streams the traffic of a running bridge to a file (or stdout) until the bridge stops or the
+/// command is interrupted
+///
+/// ### Arguments
+/// * `state` - Application state
+/// * `name` - Name of bridge
+/// * `filter` - Only capture frames matching this expression (or None to capture all frames)
+/// * `snaplen` - Maximum number of bytes captured of each frame
+/// * `write` - Path to file to save the capture (or `-` for stdout)
+fn capture_bridge(
+    state: &State,
+    name: String,
+    filter: Option<String>,
+    snaplen: u32,
+    write: PathBuf,
+) -> anyhow::Result<()> {
+    let (_, client) = connect_bridge(state, &name)?;
+    let mut stream = client
+        .capture(filter, snaplen)
+        .context("unable to start capture")?;
+
+    let mut out: Box<dyn Write> = match write.to_str() {
+        Some("-") => Box::new(std::io::stdout().lock()),
+        _ => {
+            eprintln!("capturing to {}, press ctrl-c to stop", write.display());
+            Box::new(File::create(&write).context("unable to create capture file")?)
+        }
+    };
+
+    // flush each read so the capture can be followed live (e.g., piped into tcpdump)
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let sz = stream.read(&mut buf)?;
+        if sz == 0 {
+            break;
+        }
+
+        out.write_all(&buf[..sz])?;
+        out.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Formats the expiration time of a lease
+///
+/// ### Arguments
+/// * `expires` - Time the lease expires, in seconds since the unix epoch
+fn lease_expiry(expires: u64) -> String {
+    i64::try_from(expires)
+        .ok()
+        .and_then(|ts| OffsetDateTime::from_unix_timestamp(ts).ok())
+        .and_then(|ts| ts.format(&Rfc2822).ok())
+        .unwrap_or_else(|| expires.to_string())
+}
+
 /// Deletes a bridge, stopping it if it is running
 ///
 /// ### Arguments
@@ -219,7 +446,55 @@ fn delete_bridge(state: &State, name: String) -> anyhow::Result<()> {
 
     super::confirm(state, "Delete bridge?")?;
     device.delete(state.db())?;
+    let leases = match device.config::<BridgeConfig>() {
+        Ok(config) => oathgate_bridge::lease_paths(state.network_dir(), &name, &config),
+        Err(_) => vec![oathgate_bridge::lease_path(state.network_dir(), &name)],
+    };
+    for path in leases {
+        std::fs::remove_file(path).ok();
+    }
+    std::fs::remove_file(oathgate_bridge::lease6_path(state.network_dir(), &name)).ok();
     println!("delete device");
 
     Ok(())
 }
+
+impl AsTable for DhcpLease {
+    fn header() -> &'static [&'static str] {
+        &["MAC", "IP", "Hostname", "Expires"]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        widths[0] = std::cmp::max(widths[0], self.mac.to_string().len());
+        widths[1] = std::cmp::max(widths[1], self.ip.to_string().len());
+        widths[2] = std::cmp::max(widths[2], self.hostname.as_deref().unwrap_or("-").len());
+        widths[3] = std::cmp::max(widths[3], lease_expiry(self.expires).len());
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        self.print_field(self.mac, widths[0]);
+        self.print_field(self.ip, widths[1]);
+        self.print_field(self.hostname.as_deref().unwrap_or("-"), widths[2]);
+        self.print_field(lease_expiry(self.expires), widths[3]);
+    }
+}
+
+impl AsTable for Dhcp6Lease {
+    fn header() -> &'static [&'static str] {
+        &["DUID", "IP", "IAID", "Expires"]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        widths[0] = std::cmp::max(widths[0], self.duid.len());
+        widths[1] = std::cmp::max(widths[1], self.ip.to_string().len());
+        widths[2] = std::cmp::max(widths[2], self.iaid.to_string().len());
+        widths[3] = std::cmp::max(widths[3], lease_expiry(self.expires).len());
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        self.print_field(&self.duid, widths[0]);
+        self.print_field(self.ip, widths[1]);
+        self.print_field(self.iaid, widths[2]);
+        self.print_field(lease_expiry(self.expires), widths[3]);
+    }
+}
@@ -0,0 +1,471 @@
+//! Inspection and runtime management of a running bridge, through its control socket
+
+use anyhow::Context;
+use clap::Subcommand;
+use oathgate_bridge::{
+    BridgeConfig, BridgeStatus, ControlClient, Counters, FlushTable, MacInfo, NatInfo,
+    NeighborInfo, PortInfo, PortMode, RuleCounters,
+};
+use oathgate_net::{
+    protocols::{NET_PROTOCOL_ICMP, NET_PROTOCOL_TCP, NET_PROTOCOL_UDP},
+    stats::{Counters as TrafficCounters, InterfaceCounters},
+};
+
+use crate::cmd::{draw_table, AsTable};
+
+#[derive(Debug, Subcommand)]
+pub enum InspectCommand {
+    /// Prints an overview of the bridge
+    Status,
+
+    /// Lists the devices connected to the bridge's switch
+    Ports,
+
+    /// Lists the MAC addresses learned by the switch
+    Macs,
+
+    /// Lists the neighbors learned by the router (ARP/NDP)
+    #[clap(alias = "neighbors")]
+    Arp,
+
+    /// Lists the connections tracked by each WAN's NAT table
+    Nat,
+
+    /// Lists the active DHCP leases of every network
+    Leases,
+
+    /// Prints the traffic counters of each port, WAN and service, and the firewall's rule
+    /// counters
+    Counters,
+
+    /// Disconnects the device connected to a switch port
+    Kick {
+        /// Switch port of the device (see `ports`)
+        port: usize,
+    },
+
+    /// Removes all entries from one of the bridge's tables
+    Flush {
+        /// Table to flush (mac, arp or nat)
+        table: FlushTable,
+    },
+
+    /// Applies the bridge's stored configuration to the running bridge
+    Reload,
+}
+
+impl InspectCommand {
+    /// Executes the command against a running bridge
+    ///
+    /// ### Arguments
+    /// * `client` - Client connected to the bridge's control socket
+    /// * `config` - Stored configuration of the bridge
+    pub fn execute(self, client: ControlClient, config: BridgeConfig) -> anyhow::Result<()> {
+        match self {
+            Self::Status => print_status(client.status()?),
+            Self::Ports => print_rows(&client.ports()?, "no devices connected!"),
+            Self::Macs => print_rows(&client.mac_table()?, "no mac addresses learned!"),
+            Self::Arp => print_rows(&client.neighbors()?, "no neighbors learned!"),
+            Self::Nat => print_rows(&client.nat()?, "no connections tracked!"),
+            Self::Leases => print_rows(&client.leases()?, "no active leases found!"),
+            Self::Counters => print_counters(client.counters()?),
+            Self::Kick { port } => {
+                client.kick(port).context("unable to kick device")?;
+                println!("device on port {port} disconnected");
+            }
+            Self::Flush { table } => {
+                client.flush(table).context("unable to flush table")?;
+                println!("table flushed");
+            }
+            Self::Reload => {
+                client
+                    .reload(config)
+                    .context("unable to reload configuration")?;
+                println!("configuration reloaded");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Draws a table of rows, or a message if there are no rows
+///
+/// ### Arguments
+/// * `rows` - Rows to draw
+/// * `empty` - Message to print if there are no rows
+fn print_rows<T: AsTable>(rows: &[T], empty: &str) {
+    match rows.is_empty() {
+        true => println!("{empty}"),
+        false => draw_table(rows),
+    }
+}
+
+/// Prints an overview of a bridge
+///
+/// ### Arguments
+/// * `status` - Status returned by the bridge
+fn print_status(status: BridgeStatus) {
+    println!("name:   {}", status.name);
+    println!("pid:    {}", status.pid);
+    println!("uptime: {}s", status.uptime);
+    println!("ports:  {}", status.ports);
+    println!("macs:   {}", status.macs);
+
+    for wan in status.wans {
+        let state = match wan.running {
+            true => "running",
+            false => "failed",
+        };
+
+        match wan.nat_entries {
+            Some(entries) => println!("wan:    {} ({state}, {entries} nat entries)", wan.name),
+            None => println!("wan:    {} ({state})", wan.name),
+        }
+    }
+}
+
+/// Traffic counters of one of the bridge's interfaces (a port, WAN or protocol handler)
+struct InterfaceRow {
+    name: String,
+    counters: InterfaceCounters,
+}
+
+/// Traffic counters of one of a switch port's queues
+struct QueueRow {
+    port: usize,
+    queue: usize,
+    counters: TrafficCounters,
+}
+
+/// Prints the counters of a bridge
+///
+/// ### Arguments
+/// * `counters` - Counters returned by the bridge
+fn print_counters(counters: Counters) {
+    let mut interfaces = Vec::new();
+    let mut queues = Vec::new();
+
+    for port in counters.ports {
+        let name = match port.name {
+            Some(name) => format!("port {} ({name})", port.port),
+            None => format!("port {}", port.port),
+        };
+
+        interfaces.push(InterfaceRow {
+            name,
+            counters: port.counters,
+        });
+
+        queues.extend(
+            port.queues
+                .into_iter()
+                .enumerate()
+                .map(|(queue, counters)| QueueRow {
+                    port: port.port,
+                    queue,
+                    counters,
+                }),
+        );
+    }
+
+    for wan in &counters.wans {
+        interfaces.push(InterfaceRow {
+            name: format!("wan {}", wan.name),
+            counters: wan.counters,
+        });
+    }
+
+    for lan in &counters.lans {
+        interfaces.extend(lan.protocols.iter().map(|handler| InterfaceRow {
+            name: format!("vlan {} {}", lan.vlan, protocol_name(handler.protocol)),
+            counters: handler.counters,
+        }));
+    }
+
+    print_rows(&interfaces, "no interfaces!");
+
+    if !queues.is_empty() {
+        println!();
+        draw_table(&queues);
+    }
+
+    println!();
+    for wan in counters.wans {
+        if let Some(nat) = wan.nat {
+            println!(
+                "nat {}: {} entries, {} created, {} expired, {} outbound, {} inbound, {} unmatched, {} failed",
+                wan.name,
+                nat.entries,
+                nat.created,
+                nat.expired,
+                nat.outbound,
+                nat.inbound,
+                nat.unmatched,
+                nat.failed
+            );
+        }
+    }
+
+    for lan in counters.lans {
+        for service in lan.services {
+            let values = service
+                .counters
+                .iter()
+                .chain(service.gauges.iter())
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            println!("vlan {} {}: {values}", lan.vlan, service.service);
+        }
+    }
+
+    println!();
+    print_rows(&counters.firewall, "no firewall rules configured!");
+    if let Some(dropped) = counters.capture_dropped {
+        println!("capture: {dropped} frames dropped");
+    }
+}
+
+/// Formats the VLAN membership of a switch port
+///
+/// ### Arguments
+/// * `mode` - VLAN membership of the port
+fn port_mode(mode: &PortMode) -> String {
+    match mode {
+        PortMode::Access(vlan) => format!("access {vlan}"),
+        PortMode::Trunk { native, allowed } => {
+            let allowed = allowed
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+
+            match native {
+                Some(native) => format!("trunk {allowed} (native {native})"),
+                None => format!("trunk {allowed}"),
+            }
+        }
+        PortMode::Monitor => String::from("monitor"),
+    }
+}
+
+/// Returns the name of a transport protocol
+///
+/// ### Arguments
+/// * `protocol` - IPv4 protocol number
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        NET_PROTOCOL_ICMP => String::from("icmp"),
+        NET_PROTOCOL_TCP => String::from("tcp"),
+        NET_PROTOCOL_UDP => String::from("udp"),
+        protocol => protocol.to_string(),
+    }
+}
+
+impl AsTable for PortInfo {
+    fn header() -> &'static [&'static str] {
+        &["Port", "Name", "Mode", "MACs"]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        widths[0] = std::cmp::max(widths[0], self.port.to_string().len());
+        widths[1] = std::cmp::max(widths[1], self.name.as_deref().unwrap_or("-").len());
+        widths[2] = std::cmp::max(widths[2], port_mode(&self.mode).len());
+        widths[3] = std::cmp::max(widths[3], self.macs.to_string().len());
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        self.print_field(self.port, widths[0]);
+        self.print_field(self.name.as_deref().unwrap_or("-"), widths[1]);
+        self.print_field(port_mode(&self.mode), widths[2]);
+        self.print_field(self.macs, widths[3]);
+    }
+}
+
+impl AsTable for MacInfo {
+    fn header() -> &'static [&'static str] {
+        &["VLAN", "MAC", "Port", "Age"]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        widths[0] = std::cmp::max(widths[0], self.vlan.to_string().len());
+        widths[1] = std::cmp::max(widths[1], self.mac.to_string().len());
+        widths[2] = std::cmp::max(widths[2], self.port.to_string().len());
+        widths[3] = std::cmp::max(widths[3], format!("{}s", self.age).len());
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        self.print_field(self.vlan, widths[0]);
+        self.print_field(self.mac, widths[1]);
+        self.print_field(self.port, widths[2]);
+        self.print_field(format!("{}s", self.age), widths[3]);
+    }
+}
+
+impl AsTable for NeighborInfo {
+    fn header() -> &'static [&'static str] {
+        &["IP", "MAC", "Expires"]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        widths[0] = std::cmp::max(widths[0], self.ip.to_string().len());
+        widths[1] = std::cmp::max(widths[1], self.mac.to_string().len());
+        widths[2] = std::cmp::max(widths[2], format!("{}s", self.expires).len());
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        self.print_field(self.ip, widths[0]);
+        self.print_field(self.mac, widths[1]);
+        self.print_field(format!("{}s", self.expires), widths[2]);
+    }
+}
+
+impl AsTable for NatInfo {
+    fn header() -> &'static [&'static str] {
+        &[
+            "WAN",
+            "Protocol",
+            "Source",
+            "Destination",
+            "External",
+            "State",
+            "Idle",
+        ]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        let state = nat_state(self);
+        widths[0] = std::cmp::max(widths[0], self.wan.len());
+        widths[1] = std::cmp::max(widths[1], protocol_name(self.flow.protocol).len());
+        widths[2] = std::cmp::max(widths[2], nat_source(self).len());
+        widths[3] = std::cmp::max(widths[3], nat_destination(self).len());
+        widths[4] = std::cmp::max(widths[4], self.external_port.to_string().len());
+        widths[5] = std::cmp::max(widths[5], state.len());
+        widths[6] = std::cmp::max(widths[6], format!("{}s", self.idle).len());
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        self.print_field(&self.wan, widths[0]);
+        self.print_field(protocol_name(self.flow.protocol), widths[1]);
+        self.print_field(nat_source(self), widths[2]);
+        self.print_field(nat_destination(self), widths[3]);
+        self.print_field(self.external_port, widths[4]);
+        self.print_field(nat_state(self), widths[5]);
+        self.print_field(format!("{}s", self.idle), widths[6]);
+    }
+}
+
+/// Formats the LAN side of a NAT entry
+fn nat_source(entry: &NatInfo) -> String {
+    format!("{}:{}", entry.flow.src, entry.flow.src_port)
+}
+
+/// Formats the remote side of a NAT entry
+fn nat_destination(entry: &NatInfo) -> String {
+    format!("{}:{}", entry.flow.dst, entry.flow.dst_port)
+}
+
+/// Formats the tracked state of a NAT entry, noting entries opened through a port forward
+fn nat_state(entry: &NatInfo) -> String {
+    let state = entry
+        .tcp
+        .map(|state| format!("{state:?}").to_lowercase())
+        .unwrap_or_else(|| String::from("-"));
+
+    match entry.forwarded {
+        true => format!("{state} (forwarded)"),
+        false => state,
+    }
+}
+
+impl AsTable for RuleCounters {
+    fn header() -> &'static [&'static str] {
+        &["Rule", "Action", "Packets", "Bytes"]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        widths[0] = std::cmp::max(widths[0], self.name.len());
+        widths[1] = std::cmp::max(widths[1], format!("{:?}", self.action).len());
+        widths[2] = std::cmp::max(widths[2], self.packets.to_string().len());
+        widths[3] = std::cmp::max(widths[3], self.bytes.to_string().len());
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        self.print_field(&self.name, widths[0]);
+        self.print_field(format!("{:?}", self.action), widths[1]);
+        self.print_field(self.packets, widths[2]);
+        self.print_field(self.bytes, widths[3]);
+    }
+}
+
+impl InterfaceRow {
+    /// Returns the values of the row's columns
+    fn columns(&self) -> [String; 9] {
+        let InterfaceCounters { rx, tx } = self.counters;
+        [
+            self.name.clone(),
+            rx.packets.to_string(),
+            rx.bytes.to_string(),
+            rx.dropped.to_string(),
+            rx.errors.to_string(),
+            tx.packets.to_string(),
+            tx.bytes.to_string(),
+            tx.dropped.to_string(),
+            tx.errors.to_string(),
+        ]
+    }
+}
+
+impl AsTable for InterfaceRow {
+    fn header() -> &'static [&'static str] {
+        &[
+            "Interface",
+            "RX Packets",
+            "RX Bytes",
+            "RX Dropped",
+            "RX Errors",
+            "TX Packets",
+            "TX Bytes",
+            "TX Dropped",
+            "TX Errors",
+        ]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        for (width, column) in widths.iter_mut().zip(self.columns()) {
+            *width = std::cmp::max(*width, column.len());
+        }
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        for (width, column) in widths.iter().zip(self.columns()) {
+            self.print_field(column, *width);
+        }
+    }
+}
+
+impl AsTable for QueueRow {
+    fn header() -> &'static [&'static str] {
+        &["Port", "Queue", "Packets", "Bytes", "Dropped", "Errors"]
+    }
+
+    fn update_col_width(&self, widths: &mut [usize]) {
+        widths[0] = std::cmp::max(widths[0], self.port.to_string().len());
+        widths[1] = std::cmp::max(widths[1], self.queue.to_string().len());
+        widths[2] = std::cmp::max(widths[2], self.counters.packets.to_string().len());
+        widths[3] = std::cmp::max(widths[3], self.counters.bytes.to_string().len());
+        widths[4] = std::cmp::max(widths[4], self.counters.dropped.to_string().len());
+        widths[5] = std::cmp::max(widths[5], self.counters.errors.to_string().len());
+    }
+
+    fn as_table_row(&self, widths: &[usize]) {
+        self.print_field(self.port, widths[0]);
+        self.print_field(self.queue, widths[1]);
+        self.print_field(self.counters.packets, widths[2]);
+        self.print_field(self.counters.bytes, widths[3]);
+        self.print_field(self.counters.dropped, widths[4]);
+        self.print_field(self.counters.errors, widths[5]);
+    }
+}
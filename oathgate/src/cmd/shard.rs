@@ -190,7 +190,7 @@ fn shard_run(state: &State, name: String) -> anyhow::Result<()> {
 
     println!("{cfg:#?}");
 
-    let mut hv = Hypervisor::new::<&str, &str>(&[], shard.name(), shard.cid(), cfg)
+    let mut hv = Hypervisor::new(shard.name(), shard.cid(), cfg)
         .context("unable to create hypervisor")?;
 
     let logger = state.subscriber(shard.id())?;
@@ -209,12 +209,6 @@ fn deploy_template(
 
     shard.save(&state.db())?;
 
-    /*
-    for net in networks {
-        shard.add_device_ref(state.db(), &net, "")?;
-    }
-    */
-
     bar.finish_with_message("deployment complete");
 
     Ok(())
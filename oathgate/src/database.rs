@@ -49,6 +49,20 @@ impl Database {
         Ok(db)
     }
 
+    /// Opens a private, in-memory database (used by tests)
+    #[cfg(test)]
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        let db = Self {
+            conn: Arc::new(Mutex::new(conn)),
+            path: PathBuf::from(":memory:"),
+        };
+
+        db.migrate().context("database migration failed")?;
+
+        Ok(db)
+    }
+
     /// Applies migrations against the database, if necessary
     pub fn migrate(&self) -> anyhow::Result<()> {
         self.transaction(|conn| {
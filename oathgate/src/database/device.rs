@@ -231,6 +231,15 @@ impl Device {
         Ok(serde_json::from_value(self.cfg.clone())?)
     }
 
+    /// Replaces the configuration object stored in this device entry
+    ///
+    /// ### Arguments
+    /// * `config` - New configuration of the device
+    pub fn set_config<V: Serialize>(&mut self, config: &V) -> anyhow::Result<()> {
+        self.cfg = serde_json::to_value(config)?;
+        Ok(())
+    }
+
     /// Parses a Device from a sqlite row
     ///
     /// ### Arguments
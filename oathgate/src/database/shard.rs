@@ -4,8 +4,8 @@ use std::path::PathBuf;
 
 use anyhow::{anyhow, Context};
 use oathgate_net::types::MacAddress;
-use oathgate_runner::config::{DiskConfig, KernelConfig, MachineConfig};
-use rusqlite::{params, OptionalExtension, Row};
+use oathgate_runner::config::{DiskConfig, KernelConfig, MachineConfig, NetDevConfig};
+use rusqlite::{params, Connection, OptionalExtension, Row};
 use uuid::Uuid;
 
 use crate::{
@@ -48,6 +48,9 @@ pub struct ShardBuilder {
 
     /// Disk image to use for shard
     boot_disk: Option<DiskImage>,
+
+    /// Networks (and the MAC address of the interface) to connect to the shard
+    networks: Vec<(Device, MacAddress)>,
 }
 
 #[derive(Debug)]
@@ -80,7 +83,7 @@ pub struct ShardNetwork {
     network_id: Uuid,
 
     /// MAC address of the shard's interface
-    mac: String,
+    mac: MacAddress,
 }
 
 impl Shard {
@@ -135,7 +138,10 @@ impl Shard {
             ",
             )?;
 
-            let shard = stmt.query_row(params![name], Self::from_row).optional()?;
+            let mut shard = stmt.query_row(params![name], Self::from_row).optional()?;
+            if let Some(ref mut shard) = shard {
+                shard.networks = ShardNetwork::load(conn, shard.id())?;
+            }
 
             Ok(shard)
         })?;
@@ -177,11 +183,15 @@ impl Shard {
             ",
             )?;
 
-            let shards = stmt
+            let mut shards = stmt
                 .query_map(params![], Self::from_row)?
                 .filter_map(|dev| dev.ok())
                 .collect::<Vec<_>>();
 
+            for shard in shards.iter_mut() {
+                shard.networks = ShardNetwork::load(conn, shard.id())?;
+            }
+
             Ok(shards)
         })?;
 
@@ -217,6 +227,10 @@ impl Shard {
                 ),
             )?;
 
+            for net in &self.networks {
+                net.save(conn)?;
+            }
+
             Ok(())
         })
         .context("unable to save state in database")?;
@@ -242,50 +256,31 @@ impl Shard {
         Ok(())
     }
 
-    /// Associates a device with this shard
+    /// Returns the networks (bridges) this shard is connected to, along with the MAC address
+    /// assigned to the shard's interface on each network
     ///
-    /// ### Argumnets
-    /// * `db` - Database connection
-    /// * `dev` - Device to associate with shard
-    pub fn add_device_ref(
-        &self,
-        db: &Database,
-        dev: &Device,
-        interface: &str,
-    ) -> anyhow::Result<()> {
-        db.transaction(|conn| {
-            conn.execute(
-                "INSERT INTO
-                    shard_devices (device_id, shard_id, interface)
-                VALUES
-                    (?1, ?2, ?3)
-                ON CONFLICT(device_id, shard_id) DO NOTHING",
-                (dev.id(), self.id(), interface),
-            )?;
-
-            Ok(())
-        })?;
-
-        Ok(())
-    }
-
-    pub fn networks(&self, db: &Database) -> anyhow::Result<Vec<Device>> {
+    /// ### Arguments
+    /// * `db` - Reference to the database
+    pub fn networks(&self, db: &Database) -> anyhow::Result<Vec<(Device, MacAddress)>> {
         let networks = db.transaction(|conn| {
             let mut stmt = conn.prepare(
                 "
                 SELECT
-                    d.id, d.pid, d.name, d.device, d.config
+                    d.id, d.pid, d.name, d.device, d.config, shard_networks.mac
                 FROM devices AS d
-                INNER JOIN shard_devices ON
-                    shard_devices.device_id = d.id
+                INNER JOIN shard_networks ON
+                    shard_networks.network_id = d.id
                 WHERE
-                    shard_devices.shard_id = ?1",
+                    shard_networks.shard_id = ?1",
             )?;
 
             let devices = stmt
-                .query_map(params![self.id()], Device::from_row)?
-                .filter_map(|dev| dev.ok())
-                .collect::<Vec<_>>();
+                .query_map(params![self.id()], |row| {
+                    let dev = Device::from_row(row)?;
+                    let mac = parse_mac(row, 5)?;
+                    Ok((dev, mac))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
 
             Ok(devices)
         })?;
@@ -299,6 +294,7 @@ impl Shard {
     /// * `db` - Reference to the database
     pub fn delete(&self, db: &Database) -> anyhow::Result<()> {
         db.transaction(|conn| {
+            conn.execute("DELETE FROM shard_networks WHERE shard_id = ?1", (&self.id(),))?;
             conn.execute("DELETE FROM shards WHERE id = ?1", (&self.id(),))?;
             Ok(())
         })?;
@@ -386,6 +382,9 @@ impl Shard {
 
     /// Generates a `MachineConfig` to start this shard
     ///
+    /// Each network is resolved to the socket of the corresponding bridge, returning an error
+    /// if a bridge is not running.
+    ///
     /// ### Arguments
     /// * `state` - Application State
     pub fn generate_machine_config(&self, state: &State) -> anyhow::Result<MachineConfig> {
@@ -401,7 +400,20 @@ impl Shard {
 
         let memory = format!("{}m", self.params.memory);
 
-        let cfg = MachineConfig::new(&self.params.cpu, memory, kernel_cfg, disk_cfg);
+        let mut cfg = MachineConfig::new(&self.params.cpu, memory, kernel_cfg, disk_cfg);
+
+        // resolve each network to the bridge's socket, bridges must be running to connect
+        for (dev, mac) in self.networks(state.db())? {
+            if !dev.is_running() {
+                return Err(anyhow!(
+                    "bridge '{}' is not running, start it with `oathgate bridge start {}`",
+                    dev.name(),
+                    dev.name()
+                ));
+            }
+
+            cfg.add_network(NetDevConfig::new(dev.uds(state), mac));
+        }
 
         Ok(cfg)
     }
@@ -430,6 +442,62 @@ impl ShardParams {
     }
 }
 
+impl ShardNetwork {
+    /// Loads all networks associated with a shard
+    ///
+    /// ### Arguments
+    /// * `conn` - Connection to the database
+    /// * `shard_id` - Unique id of the shard
+    fn load(conn: &Connection, shard_id: Uuid) -> rusqlite::Result<Vec<Self>> {
+        let mut stmt = conn.prepare(
+            "SELECT shard_id, network_id, mac FROM shard_networks WHERE shard_id = ?1",
+        )?;
+
+        let networks = stmt
+            .query_map(params![shard_id], |row| {
+                Ok(Self {
+                    shard_id: row.get(0)?,
+                    network_id: row.get(1)?,
+                    mac: parse_mac(row, 2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(networks)
+    }
+
+    /// Inserts (or updates) this shard/network association in the database
+    ///
+    /// ### Arguments
+    /// * `conn` - Connection to the database
+    fn save(&self, conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO
+                shard_networks (network_id, shard_id, mac)
+             VALUES
+                (?1, ?2, ?3)
+             ON CONFLICT(network_id, shard_id) DO UPDATE SET
+                mac = excluded.mac
+            ",
+            (self.network_id, self.shard_id, self.mac.to_string()),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Parses a MAC address stored as text in a sqlite row
+///
+/// ### Arguments
+/// * `row` - Row returned from database
+/// * `idx` - Index of the column containing the MAC address
+fn parse_mac(row: &Row<'_>, idx: usize) -> rusqlite::Result<MacAddress> {
+    let mac: String = row.get(idx)?;
+    mac.parse::<MacAddress>().map_err(|error| {
+        rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, error.into())
+    })
+}
+
 impl AsTable for Shard {
     fn header() -> &'static [&'static str] {
         &["Name", "State", "Context Id"]
@@ -504,14 +572,16 @@ impl ShardBuilder {
     /// * `net` - Network to connect
     /// * `mac` - MAC address of network interface
     pub fn add_network(&mut self, net: Device, mac: MacAddress) -> &mut Self {
+        self.networks.push((net, mac));
         self
     }
 
     /// Build the shard and configuration to store in database
     pub fn build(self, state: &State) -> anyhow::Result<Shard> {
         // insert shard params
+        let id = state.generate_id();
         let params = ShardParams {
-            id: state.generate_id(),
+            id,
             cid: state.generate_cid(),
             name: self.name.ok_or_else(|| anyhow!("name field is required"))?,
             state: ProcessState::Stopped,
@@ -529,9 +599,114 @@ impl ShardBuilder {
             boot_disk: self
                 .boot_disk
                 .ok_or_else(|| anyhow!("boot disk field is required"))?,
-            networks: Vec::new(),
+            networks: self
+                .networks
+                .into_iter()
+                .map(|(net, mac)| ShardNetwork {
+                    shard_id: id,
+                    network_id: net.id(),
+                    mac,
+                })
+                .collect(),
         };
 
         Ok(shard)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use oathgate_net::types::MacAddress;
+
+    use crate::{
+        database::{
+            image::{DiskFormat, DiskImage},
+            kernel::Kernel,
+            Device, DeviceType,
+        },
+        State,
+    };
+
+    use super::{Shard, ShardBuilder};
+
+    fn setup(name: &str) -> (State, MacAddress) {
+        let base = std::env::temp_dir()
+            .join(format!("oathgate-test-{name}-{}", std::process::id()));
+        let state = State::in_memory(base).expect("unable to create state");
+
+        let kernel = Kernel::new(state.ctx(), "kernel-hash", "linux", "6.1.0", true);
+        kernel.save(state.db()).unwrap();
+
+        let image =
+            DiskImage::new(state.ctx(), "image-hash", "debian", DiskFormat::Qcow2, Some(1));
+        image.save(state.db()).unwrap();
+
+        let dev = Device::new(state.ctx(), "br0", DeviceType::Bridge, &serde_json::json!({}));
+        dev.save(state.db()).unwrap();
+
+        let mac = MacAddress::generate();
+
+        let mut builder = ShardBuilder::default();
+        builder
+            .name(name)
+            .cpu("q35")
+            .memory(512)
+            .kernel(kernel)
+            .boot_disk(image)
+            .add_network(dev, mac);
+
+        let shard = builder.build(&state).unwrap();
+        shard.save(state.db()).unwrap();
+
+        (state, mac)
+    }
+
+    #[test]
+    fn shard_network_mac_round_trip() {
+        let (state, mac) = setup("round-trip");
+
+        let shard = Shard::get(state.db(), "round-trip")
+            .unwrap()
+            .expect("shard not found");
+        assert_eq!(shard.networks.len(), 1);
+        assert_eq!(shard.networks[0].mac, mac);
+
+        let networks = shard.networks(state.db()).unwrap();
+        assert_eq!(networks.len(), 1);
+        assert_eq!(networks[0].0.name(), "br0");
+        assert_eq!(networks[0].1, mac);
+
+        // saving again must upsert the association and keep the same MAC
+        shard.save(state.db()).unwrap();
+        let shard = Shard::get(state.db(), "round-trip")
+            .unwrap()
+            .expect("shard not found");
+        let networks = shard.networks(state.db()).unwrap();
+        assert_eq!(networks.len(), 1);
+        assert_eq!(networks[0].1, mac);
+
+        std::fs::remove_dir_all(&state.base).ok();
+    }
+
+    #[test]
+    fn shard_machine_config_requires_running_bridge() {
+        let (state, mac) = setup("machine-config");
+        let shard = Shard::get(state.db(), "machine-config")
+            .unwrap()
+            .expect("shard not found");
+
+        let err = shard.generate_machine_config(&state).unwrap_err();
+        assert!(err.to_string().contains("bridge 'br0' is not running"), "{err}");
+
+        // mark the bridge as running (our own pid is always alive) and reuse the stored MAC
+        let (mut dev, _) = shard.networks(state.db()).unwrap().remove(0);
+        dev.set_started(std::process::id() as i32);
+        dev.save(state.db()).unwrap();
+
+        let cfg = shard.generate_machine_config(&state).unwrap();
+        assert_eq!(cfg.networks.len(), 1);
+        assert_eq!(cfg.networks[0].mac, mac);
+
+        std::fs::remove_dir_all(&state.base).ok();
+    }
+}
@@ -22,6 +22,9 @@ pub struct Forker {
 
     /// working directory to use after fork (or none to leave alone)
     cwd: Option<PathBuf>,
+
+    /// additional signals to block and receive via the signal fd (SIGTERM is always blocked)
+    signals: Vec<Signal>,
 }
 
 impl Forker {
@@ -33,6 +36,7 @@ impl Forker {
         Self {
             subscriber: Some(Box::new(subscriber)),
             cwd: None,
+            signals: Vec::new(),
         }
     }
 
@@ -42,14 +46,27 @@ impl Forker {
         self
     }
 
+    /// Blocks an additional signal in the forked process so it is delivered through the
+    /// signal fd instead of its default action
+    ///
+    /// ### Arguments
+    /// * `signal` - Signal to block (e.g., SIGHUP to reload a bridge)
+    pub fn block(mut self, signal: Signal) -> Self {
+        self.signals.push(signal);
+        self
+    }
+
     /// Execute the fork, returning the PID of the newly spawned child process
     ///
     /// ### Arguments
     /// * `f` - Function to execute in the child process
     pub fn fork<F: FnOnce(SignalFd) -> anyhow::Result<()>>(self, f: F) -> anyhow::Result<Pid> {
-        // block SIGTERM before forking
+        // block SIGTERM (and any requested signals) before forking
         let mut sigmask = SigSet::empty();
         sigmask.add(Signal::SIGTERM);
+        for signal in &self.signals {
+            sigmask.add(*signal);
+        }
         sigmask.thread_block()?;
 
         let sfd = SignalFd::with_flags(&sigmask, SfdFlags::SFD_NONBLOCK)?;
@@ -151,6 +151,23 @@ impl State {
         })
     }
 
+    /// Creates a new state object backed by an in-memory database (used by tests)
+    ///
+    /// ### Arguments
+    /// * `base` - Path to the base / working directory
+    #[cfg(test)]
+    pub fn in_memory(base: PathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            base,
+            database: Database::open_in_memory()?,
+            no_confirm: true,
+            ctx: NoContext,
+            rng: Mutex::new(rand::thread_rng()),
+            name_gen: Mutex::new(names::Generator::default()),
+            max_log_level: tracing::Level::WARN,
+        })
+    }
+
     /// Returns the tracing subscriber that will be installed in child process when forked
     pub fn subscriber(&self, device_id: Uuid) -> anyhow::Result<SqliteSubscriber> {
         SqliteSubscriber::builder()